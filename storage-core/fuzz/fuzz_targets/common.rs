@@ -0,0 +1,23 @@
+use std::sync::OnceLock;
+use storage_core::common::config::TMP_STORAGE_ROOT;
+use tokio::runtime::Runtime;
+
+/// Runs the future on a runtime shared by all the inputs.
+/// Chunks received on the way are stored in a temporary directory.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+    TMP_STORAGE_ROOT.get_or_init(|| {
+        let root = std::env::temp_dir().join(format!("storage-core-fuzz-{}", std::process::id()));
+        std::fs::create_dir_all(&root).expect("Couldn't create temporary storage");
+        root
+    });
+
+    RUNTIME
+        .get_or_init(|| {
+            tokio::runtime::Builder::new_current_thread()
+                .build()
+                .expect("Couldn't create runtime")
+        })
+        .block_on(future)
+}
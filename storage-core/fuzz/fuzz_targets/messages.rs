@@ -0,0 +1,33 @@
+//! Receives every kind of message from arbitrary bytes.
+//! The first byte selects the message, the rest is the received frame.
+//!
+//! Run from `storage-core` with `cargo +nightly fuzz run messages`.
+
+#![no_main]
+
+mod common;
+
+use common::block_on;
+use libfuzzer_sys::fuzz_target;
+use storage_core::common::*;
+
+fn recv<M: Message>(mut frame: &[u8]) {
+    let _ = block_on(M::recv_with_request_id(&mut frame));
+}
+
+const MESSAGES: &[fn(&[u8])] = &[
+    recv::<MetadataServerExternalMessage>,
+    recv::<MetadataServerInternalMessage>,
+    recv::<ChunkserverExternalMessage>,
+    recv::<MetadataServerAdminMessage>,
+    recv::<ChunkserverInternalMessage>,
+    recv::<ClientMessage>,
+    recv::<AdminMessage>,
+    recv::<HandshakeMessage>,
+];
+
+fuzz_target!(|data: &[u8]| {
+    if let Some((&selector, frame)) = data.split_first() {
+        MESSAGES[selector as usize % MESSAGES.len()](frame);
+    }
+});
@@ -0,0 +1,66 @@
+//! Receives every payload type from arbitrary bytes.
+//! The first byte selects the payload type, the rest is the received length and payload.
+//!
+//! Run from `storage-core` with `cargo +nightly fuzz run payloads`.
+
+#![no_main]
+
+mod common;
+
+use common::block_on;
+use libfuzzer_sys::fuzz_target;
+use storage_core::common::*;
+
+fn recv<P: MessagePayload>(mut frame: &[u8]) {
+    let _ = block_on(P::recv_payload(&mut frame));
+}
+
+const PAYLOADS: &[fn(&[u8])] = &[
+    recv::<ChunkServerDiscoverPayload>,
+    recv::<AcceptNewChunkServerPayload>,
+    recv::<HeartbeatPayload>,
+    recv::<HeartbeatResponsePayload>,
+    recv::<ChunkPlacementRequestPayload>,
+    recv::<ChunkPlacementResponsePayload>,
+    recv::<UploadChunkPayload>,
+    recv::<GetFilePlacementRequestPayload>,
+    recv::<GetFilePlacementResponsePayload>,
+    recv::<DownloadChunkRequestPayload>,
+    recv::<DownloadChunkResponsePayload>,
+    recv::<RequestStatusPayload>,
+    recv::<GetClientFolderStructureRequestPayload>,
+    recv::<GetClientFolderStructureResponsePayload>,
+    recv::<UpdateClientFolderStructurePayload>,
+    recv::<ListChunkserversRequestPayload>,
+    recv::<ListChunkserversResponsePayload>,
+    recv::<GetFileChunkMapRequestPayload>,
+    recv::<GetFileChunkMapResponsePayload>,
+    recv::<ListUnderReplicatedChunksRequestPayload>,
+    recv::<ListUnderReplicatedChunksResponsePayload>,
+    recv::<DrainChunkserverRequestPayload>,
+    recv::<RebalanceRequestPayload>,
+    recv::<FsckRequestPayload>,
+    recv::<FsckResponsePayload>,
+    recv::<ReplicationScheduledPayload>,
+    recv::<HelloPayload>,
+    recv::<HelloAcceptedPayload>,
+    recv::<CopyFilePayload>,
+    recv::<DeleteFilePayload>,
+    recv::<SnapshotDirectoryRequestPayload>,
+    recv::<SnapshotDirectoryResponsePayload>,
+    recv::<ListFileVersionsRequestPayload>,
+    recv::<ListFileVersionsResponsePayload>,
+    recv::<WriteFileRequestPayload>,
+    recv::<WriteFileResponsePayload>,
+    recv::<WriteChunkPayload>,
+    recv::<GrantLeaseRequestPayload>,
+    recv::<LeaseGrantedPayload>,
+    recv::<StaleReplicaPayload>,
+    recv::<UploadFailoverPayload>,
+];
+
+fuzz_target!(|data: &[u8]| {
+    if let Some((&selector, frame)) = data.split_first() {
+        PAYLOADS[selector as usize % PAYLOADS.len()](frame);
+    }
+});
@@ -0,0 +1,28 @@
+use crate::config::CertsCommand;
+use anyhow::Result;
+use std::path::Path;
+use storage_core::common::server::ca::{CA_DIR, CertificateAuthority, CertificateUsage, KEY_FILE};
+
+pub(crate) fn run_certs_command(dir: &Path, command: CertsCommand) -> Result<()> {
+    match command {
+        CertsCommand::InitCa { common_name } => {
+            CertificateAuthority::create(dir, &common_name)?;
+            println!(
+                "Created the cluster CA in {}, keep {} secret",
+                dir.join(CA_DIR).display(),
+                dir.join(CA_DIR).join(KEY_FILE).display()
+            );
+        }
+        CertsCommand::Issue { names, client } => {
+            let usage = if client {
+                CertificateUsage::Client
+            } else {
+                CertificateUsage::Server
+            };
+            let bundle = CertificateAuthority::load(dir)?.issue(dir, names, usage)?;
+            println!("Issued certificate bundle {}", bundle.display());
+        }
+    }
+
+    Ok(())
+}
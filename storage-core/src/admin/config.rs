@@ -0,0 +1,80 @@
+use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use storage_core::common::server::certificate_provider::DEV_CERTIFICATES_DIR;
+use uuid::Uuid;
+
+#[derive(Parser, Debug)]
+#[clap(name = "admin")]
+pub(super) struct AdminOpt {
+    /// Certificate to verify the metadata server against, in PEM or DER format.
+    /// The CA of the certificate bundle by default.
+    #[clap(long = "ca-cert")]
+    pub(super) ca_cert: Option<PathBuf>,
+    /// Directory of the certificate bundle issued by `admin certs issue --client`, whose
+    /// certificate authenticates the admin to the metadata server and whose CA is trusted.
+    #[clap(long = "certificates")]
+    pub(super) certificates: Option<PathBuf>,
+    /// Metadata server hostname.
+    #[clap(long = "metadata-server-hostname", default_value = "metadata-server")]
+    pub(super) metadata_server_hostname: String,
+    /// Metadata server address for administrative communication.
+    #[clap(long = "metadata-server-addr", default_value = "[::1]:4444")]
+    pub(super) metadata_server_addr: SocketAddr,
+    #[clap(subcommand)]
+    pub(super) command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub(super) enum Command {
+    /// Manage the cluster CA and the certificates it issues, without connecting to the cluster.
+    Certs {
+        /// Directory of the CA and the issued certificate bundles.
+        #[clap(long = "dir", default_value = DEV_CERTIFICATES_DIR)]
+        dir: PathBuf,
+        #[clap(subcommand)]
+        command: CertsCommand,
+    },
+    #[clap(flatten)]
+    Cluster(AdminCommand),
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub(super) enum CertsCommand {
+    /// Create the cluster CA.
+    InitCa {
+        /// Common name of the CA certificate.
+        #[clap(long = "common-name", default_value = "storage cluster CA")]
+        common_name: String,
+    },
+    /// Issue a certificate bundle signed by the cluster CA.
+    Issue {
+        /// Hostnames and IP addresses the certificate is valid for. The first one names the bundle
+        /// and, for chunkservers, has to be the chunkserver's hostname.
+        #[clap(required = true)]
+        names: Vec<String>,
+        /// Issue a certificate only for authenticating clients, instead of a server certificate.
+        #[clap(long)]
+        client: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub(super) enum AdminCommand {
+    /// List active chunkservers.
+    Chunkservers,
+    /// Show where the chunks of a file are stored.
+    File { filename: String },
+    /// List chunks stored on fewer chunkservers than required.
+    UnderReplicated,
+    /// Move all chunks out of a chunkserver and stop placing new chunks on it.
+    Drain { server_id: Uuid },
+    /// Move chunks from the most loaded chunkservers to the least loaded ones.
+    Rebalance,
+    /// Check consistency of the files with the stored chunks and print the report as JSON.
+    Fsck {
+        /// Schedule re-replication of chunks and removal of orphaned and broken copies.
+        #[clap(long)]
+        repair: bool,
+    },
+}
@@ -0,0 +1,181 @@
+//! Inspects and manages the cluster through the **metadata server**.
+//!
+//! # Example usage
+//! ```bash
+//!   cargo run --bin admin -- chunkservers
+//!   cargo run --bin admin -- file <FILENAME>
+//!   cargo run --bin admin -- under-replicated
+//!   cargo run --bin admin -- drain <SERVER_ID>
+//!   cargo run --bin admin -- rebalance
+//!   cargo run --bin admin -- fsck [--repair]
+//!   cargo run --bin admin -- certs init-ca
+//!   cargo run --bin admin -- certs issue <HOSTNAME> [<HOSTNAME | IP>...]
+//!   cargo run --bin admin -- certs issue --client <NAME>
+//! ```
+//!
+//! The admin authenticates to the metadata server with a certificate signed by the cluster CA,
+//! provide a bundle issued with `certs issue --client` with `--certificates`. In debug mode
+//! a certificate signed by the development CA of servers running in the same directory
+//! is generated instead.
+//!
+//! `certs` creates the cluster CA and issues the certificate bundles passed to the servers
+//! with `--certificates`, by default in the `certificates` directory.
+
+use crate::certs::run_certs_command;
+use crate::config::{AdminCommand, AdminOpt, Command};
+use crate::setup::admin_endpoint;
+use clap::Parser;
+use storage_core::common::protocol;
+use storage_core::common::types::{ChunkStatus, ChunkserverStatus};
+use storage_core::common::{
+    DrainChunkserverRequestPayload, FsckRequestPayload, GetFileChunkMapRequestPayload,
+    ListChunkserversRequestPayload, ListUnderReplicatedChunksRequestPayload,
+    MetadataServerAdminClient, RebalanceRequestPayload,
+};
+
+mod certs;
+mod config;
+mod setup;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .expect("Failed to install rustls crypto provider");
+
+    let opt = AdminOpt::parse();
+    let command = match &opt.command {
+        Command::Certs { dir, command } => return run_certs_command(dir, command.clone()),
+        Command::Cluster(command) => command.clone(),
+    };
+    let endpoint = admin_endpoint(&opt)?;
+
+    let conn = protocol::connect(
+        &endpoint,
+        opt.metadata_server_addr,
+        &opt.metadata_server_hostname,
+    )
+    .await?;
+
+    let result = run_command(MetadataServerAdminClient::new(conn.clone()), command).await;
+
+    conn.close(0u32.into(), b"done");
+    endpoint.wait_idle().await;
+
+    result
+}
+
+async fn run_command(
+    admin: MetadataServerAdminClient,
+    command: AdminCommand,
+) -> anyhow::Result<()> {
+    match command {
+        AdminCommand::Chunkservers => {
+            let payload = admin
+                .list_chunkservers(ListChunkserversRequestPayload {})
+                .await?;
+            print_chunkservers(&payload.chunkservers);
+        }
+        AdminCommand::File { filename } => {
+            let payload = admin
+                .fetch_file_chunk_map(GetFileChunkMapRequestPayload { filename })
+                .await?;
+            print_chunks(&payload.chunks);
+        }
+        AdminCommand::UnderReplicated => {
+            let payload = admin
+                .list_under_replicated_chunks(ListUnderReplicatedChunksRequestPayload {})
+                .await?;
+            print_chunks(&payload.chunks);
+        }
+        AdminCommand::Drain { server_id } => {
+            let payload = admin
+                .drain_chunkserver(DrainChunkserverRequestPayload { server_id })
+                .await?;
+            println!("Scheduled moving {} chunks", payload.scheduled_chunks);
+        }
+        AdminCommand::Rebalance => {
+            let payload = admin.rebalance(RebalanceRequestPayload {}).await?;
+            println!("Scheduled moving {} chunks", payload.scheduled_chunks);
+        }
+        AdminCommand::Fsck { repair } => {
+            let payload = admin.fsck(FsckRequestPayload { repair }).await?;
+            println!("{}", serde_json::to_string_pretty(&payload.report)?);
+        }
+    }
+
+    Ok(())
+}
+
+fn print_chunkservers(chunkservers: &[ChunkserverStatus]) {
+    println!(
+        "{:<36}  {:<16}  {:<10}  {:>16}  {:>10}  {:>8}  {:<8}",
+        "SERVER ID", "HOSTNAME", "RACK", "AVAILABLE SPACE", "HEARTBEAT", "CHUNKS", "STATE"
+    );
+    for server in chunkservers {
+        println!(
+            "{:<36}  {:<16}  {:<10}  {:>16}  {:>9}s  {:>8}  {:<8}",
+            server.server_id,
+            server.hostname,
+            server.rack_id,
+            server.available_space,
+            server.secs_since_heartbeat,
+            server.chunk_count,
+            if server.draining {
+                "draining"
+            } else {
+                "active"
+            }
+        );
+    }
+}
+
+fn print_chunks(chunks: &[ChunkStatus]) {
+    for chunk in chunks {
+        println!("chunk {}", chunk.chunk_id);
+        if let Some(references) = chunk.references.filter(|&references| references > 1) {
+            println!("  shared:    {} references", references);
+        }
+        if !chunk.stripes.is_empty() {
+            print_stripes(&chunk.stripes);
+            continue;
+        }
+
+        let primary = chunk
+            .primary
+            .map_or_else(|| "none".to_string(), |primary| primary.to_string());
+
+        println!("  primary:   {}", primary);
+        for replica in chunk.replicas.iter() {
+            println!("  replica:   {}", replica);
+        }
+        match chunk.required_copies {
+            Some(required) => println!(
+                "  stored on: {} of {} chunkservers",
+                chunk.stored_on.len(),
+                required
+            ),
+            None => println!("  stored on: {} chunkservers", chunk.stored_on.len()),
+        }
+    }
+}
+
+fn print_stripes(stripes: &[ChunkStatus]) {
+    for stripe in stripes {
+        let server = stripe
+            .primary
+            .map_or_else(|| "none".to_string(), |primary| primary.to_string());
+        let state = if stripe.stored_on.is_empty() {
+            "missing"
+        } else {
+            "stored"
+        };
+
+        println!("  stripe:    {} on {} ({})", stripe.chunk_id, server, state);
+    }
+    println!(
+        "  stored:    {} of {} stripes",
+        stripes.iter().filter(|s| !s.stored_on.is_empty()).count(),
+        stripes.len()
+    );
+}
@@ -0,0 +1,32 @@
+use crate::config::AdminOpt;
+use anyhow::Result;
+use quinn::Endpoint;
+use quinn::crypto::rustls::QuicClientConfig;
+use std::sync::Arc;
+use storage_core::common;
+use storage_core::common::server::{CertificateReloader, tls};
+
+/// Name of the certificate generated for the admin in debug mode.
+const DEV_ADMIN_NAME: &str = "admin";
+
+/// Endpoint authenticating the admin to the metadata server with the certificate of its
+/// bundle, see `admin certs issue --client`, and trusting the bundle's CA.
+pub(crate) fn admin_endpoint(options: &AdminOpt) -> Result<Endpoint> {
+    let certificate_provider = common::certificate_provider(
+        Some(DEV_ADMIN_NAME.to_string()),
+        None,
+        None,
+        options.certificates.clone(),
+    )?;
+    let roots = tls::internal_ca_roots(options.ca_cert.as_deref(), &*certificate_provider)?;
+    let certificate = CertificateReloader::new(certificate_provider)?;
+    let client_crypto = tls::internal_client_crypto(roots, certificate)?;
+
+    let client_config =
+        quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(client_crypto)?));
+
+    let mut endpoint = Endpoint::client("[::]:0".parse()?)?;
+    endpoint.set_default_client_config(client_config);
+
+    Ok(endpoint)
+}
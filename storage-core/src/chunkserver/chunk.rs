@@ -1,8 +1,268 @@
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use storage_core::common::config::{FINAL_STORAGE_ROOT, TMP_STORAGE_ROOT, cluster_config};
+use storage_core::common::metrics;
+use storage_core::common::types::StoredChunk;
+use storage_core::common::{ErrorCode, ErrorPayload, UploadChunkPayload, WriteChunkPayload};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::sync::Mutex;
+use tracing::{debug, info};
 use uuid::Uuid;
 
 pub(crate) type ChunkId = Uuid;
 
 pub(crate) struct Chunk {
+    #[allow(dead_code)]
     pub(crate) id: ChunkId,
     pub(crate) size: u64,
+    /// Number of writes applied to the chunk, see [`write_chunk`].
+    pub(crate) version: u64,
+}
+
+/// Changes to the stored chunks which haven't been reported to the MetadataServer yet.
+#[derive(Default)]
+pub(crate) struct ChunkChanges {
+    pub(crate) added: Vec<StoredChunk>,
+    pub(crate) removed: Vec<ChunkId>,
+}
+
+pub(crate) fn chunk_path(chunk_id: ChunkId) -> PathBuf {
+    FINAL_STORAGE_ROOT
+        .get()
+        .expect("Final storage path not initialized via config")
+        .join(chunk_id.to_string())
+}
+
+/// File the version of a chunk which has been written to is kept in, next to the chunk.
+fn version_path(chunk_path: &Path) -> PathBuf {
+    chunk_path.with_extension("version")
+}
+
+async fn write_version(chunk_id: ChunkId, version: u64) -> anyhow::Result<()> {
+    if version == 0 {
+        return Ok(());
+    }
+    Ok(fs::write(version_path(&chunk_path(chunk_id)), version.to_string()).await?)
+}
+
+/// Reads chunks which were stored in the final storage before the chunkserver started,
+/// so that they're reported to the MetadataServer.
+pub(crate) fn load_stored_chunks(
+    final_storage_root: &Path,
+) -> anyhow::Result<scc::HashMap<ChunkId, Chunk>> {
+    let chunks = scc::HashMap::new();
+    for entry in std::fs::read_dir(final_storage_root)? {
+        let entry = entry?;
+        let Some(id) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| ChunkId::parse_str(name).ok())
+        else {
+            continue;
+        };
+
+        let size = entry.metadata()?.len();
+        let version = match std::fs::read_to_string(version_path(&entry.path())) {
+            Ok(version) => version.trim().parse()?,
+            Err(e) if e.kind() == ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if chunks.insert_sync(id, Chunk { id, size, version }).is_ok() {
+            metrics::CHUNKS.inc();
+            metrics::DISK_USED.add(size as i64);
+        }
+    }
+
+    Ok(chunks)
+}
+
+/// Space in the final storage which may still be used by chunks, in bytes.
+pub(crate) fn available_space() -> u64 {
+    let available_space = fs2::available_space(
+        FINAL_STORAGE_ROOT
+            .get()
+            .expect("Final storage path not initialized via config"),
+    )
+    .unwrap_or(0);
+
+    // We allow up to 90% usage of the disk.
+    available_space * 9 / 10
+}
+
+/// Moves a received chunk to the final storage.
+/// Fails with [`ErrorPayload`] if the chunk has already been stored or doesn't fit on the disk.
+pub(crate) async fn store_chunk(
+    chunks: &scc::HashMap<ChunkId, Chunk>,
+    chunk_changes: &Mutex<ChunkChanges>,
+    payload: UploadChunkPayload,
+) -> anyhow::Result<()> {
+    // The size of the received file is reported, so that broken transfers can be detected.
+    let size = fs::metadata(&payload.chunk_transfer.data).await?.len();
+    let chunk = Chunk {
+        id: payload.chunk_id,
+        size,
+        version: payload.version,
+    };
+
+    if size > available_space() {
+        return Err(ErrorPayload::new(
+            ErrorCode::OutOfSpace,
+            format!("Chunk of {size} bytes doesn't fit on the chunkserver"),
+        )
+        .into());
+    }
+
+    if chunks.insert_async(payload.chunk_id, chunk).await.is_err() {
+        // Chunk was already uploaded, the received copy is removed with the transfer.
+        return Err(ErrorPayload::new(
+            ErrorCode::ChunkAlreadyUploaded,
+            format!("Chunk {} has already been uploaded", payload.chunk_id),
+        )
+        .into());
+    }
+
+    if let Err(e) = fs::rename(&payload.chunk_transfer.data, chunk_path(payload.chunk_id)).await {
+        chunks.remove_async(&payload.chunk_id).await;
+        if e.kind() == ErrorKind::StorageFull {
+            return Err(
+                ErrorPayload::new(ErrorCode::OutOfSpace, "Chunkserver's disk is full").into(),
+            );
+        }
+        return Err(e.into());
+    }
+    write_version(payload.chunk_id, payload.version).await?;
+
+    metrics::CHUNKS.inc();
+    metrics::DISK_USED.add(size as i64);
+    metrics::BYTES_UPLOADED.inc_by(size);
+    info!(size, "Chunk stored");
+
+    chunk_changes.lock().await.added.push(StoredChunk {
+        chunk_id: payload.chunk_id,
+        size,
+        version: payload.version,
+    });
+
+    Ok(())
+}
+
+/// Writes the received data into a stored chunk at the payload's offset and brings the chunk
+/// to the payload's version. Data past the end of the chunk is appended in place, as readers
+/// only read the chunk up to the size it had when they started. An overwritten chunk is
+/// replaced by a modified copy instead, which readers of the chunk don't see.
+pub(crate) async fn write_chunk(
+    chunks: &scc::HashMap<ChunkId, Chunk>,
+    chunk_changes: &Mutex<ChunkChanges>,
+    payload: &WriteChunkPayload,
+) -> anyhow::Result<()> {
+    let chunk_id = payload.chunk_id;
+    let Some(size) = chunks.read_async(&chunk_id, |_, chunk| chunk.size).await else {
+        return Err(ErrorPayload::new(
+            ErrorCode::ChunkNotFound,
+            format!("Chunk {} isn't stored on the chunkserver", chunk_id),
+        )
+        .into());
+    };
+
+    let max_chunk_size = cluster_config().max_chunk_size;
+    let Some(end) = payload
+        .offset
+        .checked_add(payload.chunk_size)
+        .filter(|&end| end <= max_chunk_size)
+    else {
+        return Err(ErrorPayload::new(
+            ErrorCode::InvalidRequest,
+            format!(
+                "Write past the maximum chunk size of {} bytes",
+                max_chunk_size
+            ),
+        )
+        .into());
+    };
+    let new_size = size.max(end);
+    if new_size - size > available_space() {
+        return Err(ErrorPayload::new(
+            ErrorCode::OutOfSpace,
+            "Write doesn't fit on the chunkserver",
+        )
+        .into());
+    }
+
+    let path = chunk_path(chunk_id);
+    if payload.offset >= size {
+        write_at(&path, payload).await?;
+    } else {
+        let copy = TMP_STORAGE_ROOT
+            .get()
+            .expect("Temporary storage not initialized via config")
+            .join(format!("{}-{}", chunk_id, Uuid::new_v4()));
+        let written = async {
+            fs::copy(&path, &copy).await?;
+            write_at(&copy, payload).await?;
+            fs::rename(&copy, &path).await
+        }
+        .await;
+        if let Err(e) = written {
+            let _ = fs::remove_file(&copy).await;
+            return Err(e.into());
+        }
+    }
+    write_version(chunk_id, payload.version).await?;
+
+    chunks
+        .update_async(&chunk_id, |_, chunk| {
+            chunk.size = new_size;
+            chunk.version = payload.version;
+        })
+        .await;
+    metrics::DISK_USED.add((new_size - size) as i64);
+    metrics::BYTES_UPLOADED.inc_by(payload.chunk_size);
+    debug!(%chunk_id, offset = payload.offset, version = payload.version, "Chunk written");
+
+    // Reported again, with its new size and version.
+    chunk_changes.lock().await.added.push(StoredChunk {
+        chunk_id,
+        size: new_size,
+        version: payload.version,
+    });
+
+    Ok(())
+}
+
+/// Copies the received data of the write into the file at the write's offset.
+async fn write_at(path: &Path, payload: &WriteChunkPayload) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(path).await?;
+    file.seek(SeekFrom::Start(payload.offset)).await?;
+    let mut data = fs::File::open(&payload.chunk_transfer.data)
+        .await?
+        .take(payload.chunk_size);
+    tokio::io::copy(&mut data, &mut file).await?;
+    file.sync_all().await
+}
+
+/// Removes a chunk from the final storage.
+pub(crate) async fn delete_chunk(
+    chunks: &scc::HashMap<ChunkId, Chunk>,
+    chunk_changes: &Mutex<ChunkChanges>,
+    chunk_id: ChunkId,
+) -> anyhow::Result<()> {
+    let Some((_, chunk)) = chunks.remove_async(&chunk_id).await else {
+        return Ok(());
+    };
+
+    metrics::CHUNKS.dec();
+    metrics::DISK_USED.sub(chunk.size as i64);
+    debug!(%chunk_id, "Chunk deleted");
+
+    chunk_changes.lock().await.removed.push(chunk_id);
+    let path = chunk_path(chunk_id);
+    fs::remove_file(&path).await?;
+    if let Err(e) = fs::remove_file(version_path(&path)).await
+        && e.kind() != ErrorKind::NotFound
+    {
+        return Err(e.into());
+    }
+
+    Ok(())
 }
@@ -2,10 +2,15 @@ use super::types::Hostname;
 use clap::Parser;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use storage_core::common::telemetry::LogFormat;
 
 #[derive(Parser, Debug)]
 #[clap(name = "server")]
 pub(super) struct ChunkserverOpt {
+    /// Configuration file in TOML, setting any of the other flags by its long name.
+    /// Flags given on the command line take precedence over it.
+    #[clap(long = "config")]
+    pub(super) config: Option<PathBuf>,
     /// File to log TLS keys to for debugging
     #[clap(long = "keylog", default_value = "false")]
     pub(super) keylog: bool,
@@ -21,7 +26,15 @@ pub(super) struct ChunkserverOpt {
     /// TLS certificate in PEM format
     #[clap(short = 'c', long = "cert", requires = "key")]
     pub(super) cert: Option<PathBuf>,
+    /// Directory of the certificate bundle issued by `admin certs issue`.
+    #[clap(long = "certificates", conflicts_with = "cert")]
+    pub(super) certificates: Option<PathBuf>,
+    /// Certificate of the cluster CA in PEM format, which signs the certificates of the metadata
+    /// server and the chunkservers. Defaults to the CA of the certificate bundle.
+    #[clap(long = "internal-ca")]
+    pub(super) internal_ca: Option<PathBuf>,
     /// Chunkserver's hostname for client and other chunkserver to connect to.
+    /// Has to match the name in the chunkserver's certificate.
     #[clap(long = "chunkserver-hostname", default_value = "chunkserver")]
     pub(super) chunkserver_hostname: Hostname,
     /// Address advertised to clients to connect to.
@@ -36,6 +49,15 @@ pub(super) struct ChunkserverOpt {
     /// Address to listen on for connection from internal servers.
     #[clap(long = "internal-socket-addr")]
     pub(super) internal_socket_addr: SocketAddr,
+    /// Format of the logs.
+    #[clap(long = "log-format", value_enum, default_value = "pretty")]
+    pub(super) log_format: LogFormat,
+    /// Log filter directives, e.g. `info,storage_core=debug`. Overrides `RUST_LOG`.
+    #[clap(long = "log-filter")]
+    pub(super) log_filter: Option<String>,
+    /// Address to serve metrics in Prometheus format on (HTTP `GET /metrics`).
+    #[clap(long = "metrics-addr", default_value = "[::1]:9101")]
+    pub(super) metrics_addr: SocketAddr,
     /// Metadata server hostname.
     #[clap(long = "metadata-server-hostname", default_value = "metadata-server")]
     pub(super) metadata_server_hostname: Hostname,
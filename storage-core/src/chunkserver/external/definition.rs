@@ -1,19 +1,25 @@
-use crate::chunk::Chunk;
-use crate::types::{ChunkId, ServerId};
-use quinn::{Connection, Endpoint, SendStream};
+use crate::chunk::{Chunk, ChunkChanges, chunk_path, store_chunk};
+use crate::internal::ChunkserverInternal;
+use crate::types::{ChunkId, ServerLocation};
+use async_trait::async_trait;
+use quinn::{Connection, Endpoint};
 use std::sync::Arc;
 use std::sync::atomic::AtomicU64;
-use storage_core::common::config::FINAL_STORAGE_ROOT;
+use storage_core::common::metrics;
 use storage_core::common::{
-    ChunkTransfer, ClientMessage, DownloadChunkRequestPayload, DownloadChunkResponsePayload,
-    Message, RequestStatusPayload, UploadChunkPayload,
+    ChunkTransfer, ChunkserverExternalHandler, DownloadChunkRequestPayload,
+    DownloadChunkResponsePayload, ErrorCode, ErrorPayload, UploadChunkPayload, WriteChunkPayload,
 };
-use tokio::{fs, join};
+use tokio::sync::Mutex;
+use tracing::{Span, field};
 
 /// 'ChunkserverExternal' is a struct used for communication with clients.
 #[derive(Clone)]
 pub struct ChunkserverExternal {
     chunks: Arc<scc::HashMap<ChunkId, Chunk>>,
+    chunk_changes: Arc<Mutex<ChunkChanges>>,
+    /// Applies writes as the primary of the written chunks.
+    internal: ChunkserverInternal,
 
     /// Counter of client requests since last heartbeat
     pub(super) requests_since_heartbeat: Arc<AtomicU64>,
@@ -22,100 +28,87 @@ pub struct ChunkserverExternal {
     #[allow(dead_code)]
     internal_endpoint: Arc<Endpoint>,
     #[allow(dead_code)]
-    chunkserver_connections: Arc<scc::HashMap<ServerId, Connection>>,
+    chunkserver_connections: Arc<scc::HashMap<ServerLocation, Connection>>,
 }
 
 impl ChunkserverExternal {
     pub(crate) fn new(
         chunks: Arc<scc::HashMap<ChunkId, Chunk>>,
+        chunk_changes: Arc<Mutex<ChunkChanges>>,
+        internal: ChunkserverInternal,
         requests_since_heartbeat: Arc<AtomicU64>,
         client_endpoint: Arc<Endpoint>,
         internal_endpoint: Arc<Endpoint>,
-        chunkserver_connections: Arc<scc::HashMap<ServerId, Connection>>,
+        chunkserver_connections: Arc<scc::HashMap<ServerLocation, Connection>>,
     ) -> Self {
         ChunkserverExternal {
             chunks,
+            chunk_changes,
+            internal,
             requests_since_heartbeat,
             client_endpoint,
             internal_endpoint,
             chunkserver_connections,
         }
     }
+}
 
-    pub(super) async fn handle_upload(
-        &self,
-        send: &mut SendStream,
-        payload: UploadChunkPayload,
-    ) -> anyhow::Result<()> {
-        let chunk = Chunk {
-            id: payload.chunk_id,
-            size: payload.chunk_size,
-        };
-
-        if self
-            .chunks
-            .insert_async(payload.chunk_id, chunk)
-            .await
-            .is_err()
-        {
-            // File was already uploaded
-            let _ = join!(
-                fs::remove_file(&payload.chunk_transfer.data),
-                ClientMessage::RequestStatus(RequestStatusPayload::InvalidRequest).send(send)
-            );
-
-            return Ok(());
-        }
-
-        let chunk_final_path = FINAL_STORAGE_ROOT
-            .get()
-            .expect("Final storage path not initialized via config")
-            .join(payload.chunk_id.to_string());
-
-        fs::rename(&payload.chunk_transfer.data, &chunk_final_path).await?;
-
-        ClientMessage::RequestStatus(RequestStatusPayload::Ok)
-            .send(send)
-            .await?;
+#[async_trait]
+impl ChunkserverExternalHandler for ChunkserverExternal {
+    async fn upload_chunk(&self, payload: UploadChunkPayload) -> anyhow::Result<()> {
+        Span::current().record("chunk_id", field::display(payload.chunk_id));
+        store_chunk(&self.chunks, &self.chunk_changes, payload).await
+    }
 
-        Ok(())
+    async fn write_chunk(&self, payload: WriteChunkPayload) -> anyhow::Result<()> {
+        Span::current().record("chunk_id", field::display(payload.chunk_id));
+        self.internal.write_as_primary(payload).await
     }
 
-    pub(super) async fn handle_download(
+    async fn download_chunk(
         &self,
-        send: &mut SendStream,
         payload: DownloadChunkRequestPayload,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<DownloadChunkResponsePayload> {
+        Span::current().record("chunk_id", field::display(payload.chunk_id));
         let chunk_size = self
             .chunks
             .read_async(&payload.chunk_id, |_, chunk| chunk.size)
             .await;
 
         let Some(chunk_size) = chunk_size else {
-            // Chunk doesn't exist
-            let _ = ClientMessage::RequestStatus(RequestStatusPayload::InvalidRequest)
-                .send(send)
-                .await;
+            return Err(ErrorPayload::new(
+                ErrorCode::ChunkNotFound,
+                format!("Chunk {} isn't stored on the chunkserver", payload.chunk_id),
+            )
+            .into());
+        };
 
-            return Ok(());
+        // Ranges reaching past the end of the chunk are cut at the end.
+        let (offset, length) = match payload.range {
+            Some(range) if range.offset > chunk_size => {
+                return Err(ErrorPayload::new(
+                    ErrorCode::InvalidRequest,
+                    format!(
+                        "Range starts at {}, past the end of chunk {} of {} bytes",
+                        range.offset, payload.chunk_id, chunk_size
+                    ),
+                )
+                .into());
+            }
+            Some(range) => (range.offset, range.length.min(chunk_size - range.offset)),
+            None => (0, chunk_size),
         };
 
-        let chunk_path = FINAL_STORAGE_ROOT
-            .get()
-            .expect("Final storage path not initialized via config")
-            .join(payload.chunk_id.to_string());
+        // The chunk is sent with the response, after the handler returns.
+        metrics::BYTES_DOWNLOADED.inc_by(length);
 
-        ClientMessage::DownloadChunkResponse(DownloadChunkResponsePayload {
+        Ok(DownloadChunkResponsePayload {
             chunk_id: payload.chunk_id,
-            chunk_size,
-            chunk_transfer: ChunkTransfer {
-                offset: None,
-                data: chunk_path,
-            },
+            chunk_size: length,
+            chunk_transfer: ChunkTransfer::from_file(
+                chunk_path(payload.chunk_id),
+                (offset > 0).then_some(offset),
+            ),
         })
-        .send(send)
-        .await?;
-
-        Ok(())
     }
 }
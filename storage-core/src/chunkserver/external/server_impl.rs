@@ -1,13 +1,15 @@
 use crate::external::ChunkserverExternal;
 use async_trait::async_trait;
-use quinn::{Endpoint, RecvStream, SendStream};
+use quinn::{Endpoint, SendStream};
 use std::sync::atomic::Ordering;
-use storage_core::common::ChunkserverExternalMessage::{DownloadChunkRequest, UploadChunk};
-use storage_core::common::ClientMessage::RequestStatus;
-use storage_core::common::{ChunkserverExternalMessage, Message, QuicServer, RequestStatusPayload};
+use storage_core::common::{ChunkserverExternalHandler, ChunkserverExternalMessage, QuicServer};
 
 #[async_trait]
 impl QuicServer for ChunkserverExternal {
+    type Request = ChunkserverExternalMessage;
+
+    const NAME: &'static str = "chunkserver_external";
+
     fn listening_endpoint(&self) -> &Endpoint {
         self.client_endpoint.as_ref()
     }
@@ -18,23 +20,12 @@ impl QuicServer for ChunkserverExternal {
 
     async fn handle_request(
         &self,
-        mut send: SendStream,
-        mut recv: RecvStream,
+        send: &mut SendStream,
+        request: Self::Request,
     ) -> anyhow::Result<()> {
         self.requests_since_heartbeat
             .fetch_add(1, Ordering::Relaxed);
 
-        let res = match ChunkserverExternalMessage::recv(&mut recv).await? {
-            UploadChunk(payload) => self.handle_upload(&mut send, payload).await,
-            DownloadChunkRequest(payload) => self.handle_download(&mut send, payload).await,
-        };
-
-        if res.is_err() {
-            let _ = RequestStatus(RequestStatusPayload::InternalServerError)
-                .send(&mut send)
-                .await;
-        }
-
-        Ok(())
+        self.dispatch(send, request).await
     }
 }
@@ -1,27 +1,42 @@
-use crate::chunk::{Chunk, ChunkId};
-use crate::types::{Hostname, RackId, ServerId};
+use crate::chunk::{
+    Chunk, ChunkChanges, ChunkId, available_space, chunk_path, delete_chunk, store_chunk,
+};
+use crate::internal::write::ChunkWrites;
+use crate::types::{Hostname, RackId, ServerId, ServerLocation};
+use anyhow::Context;
 use arc_swap::ArcSwap;
+use async_trait::async_trait;
 use quinn::{Connection, Endpoint};
+use std::mem;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
-use storage_core::common::config::{FINAL_STORAGE_ROOT, HEARTBEAT_INTERVAL};
+use storage_core::common::config::{cluster_config, set_cluster_config};
+use storage_core::common::metrics;
+use storage_core::common::protocol;
+use storage_core::common::rpc::RpcError;
+use storage_core::common::shutdown::Shutdown;
+use storage_core::common::telemetry::with_request_id;
+use storage_core::common::types::StoredChunk;
 use storage_core::common::{
-    ChunkServerDiscoverPayload, HeartbeatPayload, Message, MetadataServerInternalMessage,
+    ChunkServerDiscoverPayload, ChunkTransfer, ChunkserverInternalClient,
+    ChunkserverInternalHandler, ChunkserverLocation, ErrorCode, HeartbeatPayload,
+    HeartbeatResponsePayload, MetadataServerInternalClient, UploadChunkPayload, WriteChunkPayload,
 };
-use storage_core::dbg_println;
 use tokio::sync::Mutex;
 use tokio::time::sleep;
+use tracing::{Instrument, Span, debug, field, info, info_span, warn};
 use uuid::Uuid;
 
 /// 'ChunkserverInternal' is a struct that is used for communication with 'MetadataServer' and other 'Chunkservers'
 /// # Tasks include:
 /// * sending stats to 'MetadataServer' via heartbeat
 /// * ensuring consistency of the states of all chunk's replicas across different 'Chunkservers'
+/// * copying and removing chunks as instructed by 'MetadataServer'
 #[derive(Clone)]
 pub struct ChunkserverInternal {
     /// Unique identifier of the chunkserver.
-    server_id: ServerId,
+    pub(super) server_id: ServerId,
     /// Chunkserver's hostname for communication setup.
     hostname: Arc<Hostname>,
     rack_id: Arc<RackId>,
@@ -33,7 +48,9 @@ pub struct ChunkserverInternal {
     /// Counter of client requests since last heartbeat
     pub(super) requests_since_heartbeat: Arc<AtomicU64>,
 
-    chunks: Arc<scc::HashMap<ChunkId, Chunk>>,
+    pub(super) chunks: Arc<scc::HashMap<ChunkId, Chunk>>,
+    pub(super) chunk_changes: Arc<Mutex<ChunkChanges>>,
+    pub(super) writes: Arc<ChunkWrites>,
 
     pub(super) internal_endpoint: Arc<Endpoint>,
 
@@ -42,11 +59,15 @@ pub struct ChunkserverInternal {
 
     metadata_reconnect_lock: Arc<Mutex<()>>,
     metadata_server_connection: Arc<ArcSwap<Option<Connection>>>,
-    #[allow(dead_code)]
-    chunkserver_connections: Arc<scc::HashMap<ServerId, Connection>>,
+    chunkserver_connections: Arc<scc::HashMap<ServerLocation, Connection>>,
+
+    /// Held while sending a heartbeat, so that no heartbeat follows the leaving one.
+    heartbeat_lock: Arc<Mutex<()>>,
+    shutdown: Shutdown,
 }
 
 impl ChunkserverInternal {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         chunkserver_hostname: Hostname,
         rack_id: RackId,
@@ -54,10 +75,12 @@ impl ChunkserverInternal {
         external_address: SocketAddr,
         requests_since_heartbeat: Arc<AtomicU64>,
         chunks: Arc<scc::HashMap<ChunkId, Chunk>>,
+        chunk_changes: Arc<Mutex<ChunkChanges>>,
         internal_endpoint: Arc<Endpoint>,
         metadata_server_addr: SocketAddr,
         metadata_server_hostname: Hostname,
-        chunkserver_connections: Arc<scc::HashMap<ServerId, Connection>>,
+        chunkserver_connections: Arc<scc::HashMap<ServerLocation, Connection>>,
+        shutdown: Shutdown,
     ) -> Self {
         ChunkserverInternal {
             server_id: Uuid::new_v4(),
@@ -67,16 +90,20 @@ impl ChunkserverInternal {
             external_address,
             requests_since_heartbeat,
             chunks,
+            chunk_changes,
+            writes: Arc::new(scc::HashMap::new()),
             internal_endpoint,
             metadata_server_addr,
             metadata_server_hostname,
             metadata_reconnect_lock: Arc::new(Mutex::new(())),
             metadata_server_connection: Arc::new(ArcSwap::from_pointee(None)),
             chunkserver_connections,
+            heartbeat_lock: Arc::new(Mutex::new(())),
+            shutdown,
         }
     }
 
-    async fn get_metadata_server_connection(&mut self) -> anyhow::Result<Connection> {
+    pub(super) async fn get_metadata_server_connection(&self) -> anyhow::Result<Connection> {
         let guard = self.metadata_server_connection.load();
 
         let Some(conn) = guard
@@ -101,10 +128,12 @@ impl ChunkserverInternal {
             .as_ref()
             .filter(|x| x.close_reason().is_none())
         else {
-            let connecting = self
-                .internal_endpoint
-                .connect(self.metadata_server_addr, &self.metadata_server_hostname)?;
-            let new_conn = connecting.await?;
+            let new_conn = protocol::connect(
+                &self.internal_endpoint,
+                self.metadata_server_addr,
+                &self.metadata_server_hostname,
+            )
+            .await?;
 
             self.metadata_server_connection
                 .store(Arc::new(Some(new_conn.clone())));
@@ -123,58 +152,204 @@ impl ChunkserverInternal {
         &self,
         metadata_server_conn: Connection,
     ) -> anyhow::Result<()> {
-        let mut stored_chunks_ids = Vec::new();
+        let mut stored_chunks = Vec::new();
         self.chunks
-            .iter_async(|k, _| {
-                stored_chunks_ids.push(k.clone());
+            .iter_async(|k, chunk| {
+                stored_chunks.push(StoredChunk {
+                    chunk_id: *k,
+                    size: chunk.size,
+                    version: chunk.version,
+                });
                 true
             })
             .await;
 
-        let (mut send, mut _recv) = metadata_server_conn.open_bi().await?;
+        debug!("Discovering Metadata server");
 
-        dbg_println!("Discovering Metadata server");
+        let accepted = MetadataServerInternalClient::new(metadata_server_conn)
+            .discover_chunkserver(ChunkServerDiscoverPayload {
+                server_id: self.server_id,
+                hostname: self.hostname.to_string(),
+                rack_id: self.rack_id.to_string(),
+                internal_address: self.internal_address,
+                external_address: self.external_address,
+                stored_chunks,
+            })
+            .await?;
 
-        MetadataServerInternalMessage::ChunkServerDiscover(ChunkServerDiscoverPayload {
-            server_id: self.server_id,
-            hostname: self.hostname.to_string(),
-            rack_id: self.rack_id.to_string(),
-            internal_address: self.internal_address,
-            external_address: self.external_address,
-            stored_chunks: stored_chunks_ids,
-        })
-        .send(&mut send)
-        .await
+        let Some(config) = accepted.cluster_config else {
+            info!("Metadata server sent no cluster configuration, keeping the local one");
+            return Ok(());
+        };
+        config.validate().context("Invalid cluster configuration")?;
+        if config != cluster_config() {
+            info!(?config, "Cluster configuration received");
+            set_cluster_config(config);
+        }
 
-        // TODO: maybe the MetadataServer will return some answer
+        Ok(())
     }
 
-    pub(super) async fn send_heartbeat(&mut self) -> anyhow::Result<()> {
-        let conn = self.get_metadata_server_connection().await?;
+    /// Sends heartbeats until the shutdown. The first one discovers the metadata server,
+    /// which tells the chunkserver how often to send the following ones.
+    pub(super) async fn send_heartbeat(&self) {
         loop {
-            sleep(HEARTBEAT_INTERVAL).await;
+            match self.heartbeat(false).await {
+                Ok(instructions) => self.execute_instructions(instructions).await,
+                Err(e) => warn!(error = ?e, "Heartbeat failed"),
+            }
 
-            let client_requests_count = self.requests_since_heartbeat.swap(0, Ordering::Relaxed);
-            let available_space = fs2::available_space(
-                FINAL_STORAGE_ROOT
-                    .get()
-                    .expect("Final storage path not initialized via config"),
-            )
-            .unwrap_or(0);
+            tokio::select! {
+                _ = sleep(cluster_config().heartbeat_interval) => {}
+                _ = self.shutdown.triggered() => return,
+            }
+        }
+    }
 
-            // We allow up to 90% usage of the disk.
-            let available_space = available_space * 9 / 10;
+    /// Sends the final heartbeat, after which the metadata server forgets the chunkserver.
+    /// Has to be called once the servers have stopped, so that it reports all the stored chunks.
+    pub(crate) async fn leave(&self) -> anyhow::Result<()> {
+        self.heartbeat(true).await?;
+        info!("Left the cluster");
+        Ok(())
+    }
+
+    async fn heartbeat(&self, leaving: bool) -> anyhow::Result<HeartbeatResponsePayload> {
+        let _lock = self.heartbeat_lock.lock().await;
+        if self.shutdown.is_triggered() && !leaving {
+            return Ok(HeartbeatResponsePayload::default());
+        }
+
+        let conn = self.get_metadata_server_connection().await?;
+
+        let client_requests_count = self.requests_since_heartbeat.swap(0, Ordering::Relaxed);
+        let available_space = available_space();
+        metrics::DISK_AVAILABLE
+            .with_label_values(&[self.server_id.to_string()])
+            .set(available_space as i64);
 
-            let (mut send, _recv) = conn.open_bi().await?;
+        let changes = mem::take(&mut *self.chunk_changes.lock().await);
 
-            dbg_println!("Sending heartbeat");
-            MetadataServerInternalMessage::Heartbeat(HeartbeatPayload {
+        debug!(leaving, "Sending heartbeat");
+        let heartbeat = MetadataServerInternalClient::new(conn.clone())
+            .heartbeat(HeartbeatPayload {
                 server_id: self.server_id,
                 client_requests_count,
                 available_space,
+                added_chunks: changes.added,
+                removed_chunks: changes.removed,
+                leaving,
             })
-            .send(&mut send)
-            .await?;
+            .await;
+
+        match heartbeat {
+            Ok(instructions) => Ok(instructions),
+            // The metadata server considered the chunkserver inactive and forgot it, the
+            // discovery reports all the stored chunks again.
+            Err(RpcError::Status(error))
+                if error.code == ErrorCode::ChunkserverNotFound && !leaving =>
+            {
+                warn!("Metadata server forgot the chunkserver, discovering it again");
+                self.metadata_server_handshake(conn).await?;
+                Ok(HeartbeatResponsePayload::default())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn execute_instructions(&self, instructions: HeartbeatResponsePayload) {
+        for chunk_id in instructions.delete {
+            if let Err(e) = delete_chunk(&self.chunks, &self.chunk_changes, chunk_id).await {
+                warn!(%chunk_id, error = ?e, "Couldn't delete chunk");
+            }
+            self.forget_writes(chunk_id).await;
         }
+
+        for order in instructions.replicate {
+            let server_clone = self.clone();
+            let span = info_span!(
+                "replication",
+                request_id = %order.request_id,
+                chunk_id = %order.target.chunk_id,
+                target = %order.target.server_location
+            );
+
+            // Copies are sent within the request which created the chunk.
+            tokio::spawn(
+                with_request_id(order.request_id, async move {
+                    if let Err(e) = server_clone.replicate_chunk(order.target).await {
+                        warn!(error = ?e, "Couldn't replicate chunk");
+                    }
+                })
+                .instrument(span),
+            );
+        }
+    }
+
+    /// Sends a copy of the chunk to the chunkserver with the given location.
+    async fn replicate_chunk(&self, target: ChunkserverLocation) -> anyhow::Result<()> {
+        let (chunk_size, version) = self
+            .chunks
+            .read_async(&target.chunk_id, |_, chunk| (chunk.size, chunk.version))
+            .await
+            .context("Chunk to replicate isn't stored")?;
+
+        let conn = self.get_chunkserver_connection(&target).await?;
+        let result = ChunkserverInternalClient::new(conn)
+            .store_replica(UploadChunkPayload {
+                chunk_id: target.chunk_id,
+                chunk_size,
+                version,
+                chunk_transfer: ChunkTransfer::from_file(chunk_path(target.chunk_id), None),
+            })
+            .await;
+
+        // The chunk has been sent even if the replica couldn't be stored.
+        if !matches!(result, Err(RpcError::Transport(_))) {
+            metrics::BYTES_DOWNLOADED.inc_by(chunk_size);
+        }
+
+        result.context("Replica not stored")
+    }
+
+    pub(super) async fn get_chunkserver_connection(
+        &self,
+        location: &ChunkserverLocation,
+    ) -> anyhow::Result<Connection> {
+        if let Some(conn) = self
+            .chunkserver_connections
+            .read_async(&location.server_location, |_, conn| conn.clone())
+            .await
+            && conn.close_reason().is_none()
+        {
+            return Ok(conn);
+        }
+
+        let conn = protocol::connect(
+            &self.internal_endpoint,
+            location.server_location,
+            &location.server_hostname,
+        )
+        .await?;
+
+        let _ = self
+            .chunkserver_connections
+            .upsert_async(location.server_location, conn.clone())
+            .await;
+
+        Ok(conn)
+    }
+}
+
+#[async_trait]
+impl ChunkserverInternalHandler for ChunkserverInternal {
+    async fn store_replica(&self, payload: UploadChunkPayload) -> anyhow::Result<()> {
+        Span::current().record("chunk_id", field::display(payload.chunk_id));
+        store_chunk(&self.chunks, &self.chunk_changes, payload).await
+    }
+
+    async fn apply_write(&self, payload: WriteChunkPayload) -> anyhow::Result<()> {
+        Span::current().record("chunk_id", field::display(payload.chunk_id));
+        self.apply_forwarded_write(payload).await
     }
 }
@@ -1,4 +1,5 @@
 pub(crate) mod definition;
 pub(crate) mod server_impl;
+pub(crate) mod write;
 
 pub use self::definition::ChunkserverInternal;
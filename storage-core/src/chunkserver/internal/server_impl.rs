@@ -1,24 +1,29 @@
 use crate::internal::definition::ChunkserverInternal;
 use async_trait::async_trait;
-use quinn::{Endpoint, RecvStream, SendStream};
-use storage_core::common::QuicServer;
+use quinn::{Endpoint, SendStream};
+use storage_core::common::{ChunkserverInternalHandler, ChunkserverInternalMessage, QuicServer};
 
 #[async_trait]
 impl QuicServer for ChunkserverInternal {
+    type Request = ChunkserverInternalMessage;
+
+    const NAME: &'static str = "chunkserver_internal";
+
     fn listening_endpoint(&self) -> &Endpoint {
         self.internal_endpoint.as_ref()
     }
 
     async fn setup(&self) -> anyhow::Result<()> {
-        let mut server_clone = self.clone();
+        let server_clone = self.clone();
         tokio::spawn(async move { server_clone.send_heartbeat().await });
         Ok(())
     }
 
-    async fn handle_request(&self, send: SendStream, recv: RecvStream) -> anyhow::Result<()> {
-        todo!(
-            "currently chunkserver doesn't receive any messages from other servers, \
-            in future add leases messages, chunks version checks, chunks forwarding in case of an error.",
-        )
+    async fn handle_request(
+        &self,
+        send: &mut SendStream,
+        request: Self::Request,
+    ) -> anyhow::Result<()> {
+        self.dispatch(send, request).await
     }
 }
@@ -0,0 +1,207 @@
+//! Writes into stored chunks.
+//!
+//! Clients send writes to the primary of the chunk, which holds a lease on the chunk granted
+//! by the metadata server while writing to it. The primary applies the writes one at a time,
+//! numbering them with the chunk's versions, and forwards every write to the replicas listed
+//! in the lease. Replicas apply only the write following the last one they applied, so that
+//! a replica which missed a write fails the following ones as well. Such replicas are reported
+//! to the metadata server, which drops their copy and copies the chunk again.
+
+use crate::chunk::{ChunkId, write_chunk};
+use crate::internal::ChunkserverInternal;
+use crate::types::ServerId;
+use anyhow::{Context, anyhow};
+use futures::future::join_all;
+use std::sync::Arc;
+use std::time::Duration;
+use storage_core::common::rpc::RpcError;
+use storage_core::common::{
+    ChunkTransfer, ChunkserverInternalClient, ErrorCode, ErrorPayload, GrantLeaseRequestPayload,
+    LeaseReplica, MetadataServerInternalClient, StaleReplicaPayload, WriteChunkPayload,
+};
+use tokio::sync::Mutex;
+use tokio::time::{Instant, timeout_at};
+use tracing::{debug, warn};
+
+/// Lease on a chunk held by the chunkserver as the chunk's primary.
+pub(crate) struct Lease {
+    expires_at: Instant,
+    /// Replicas the writes are forwarded to, without the ones which failed a write.
+    replicas: Vec<LeaseReplica>,
+}
+
+/// Writes of every chunk written to, applied under the lock of the chunk one at a time.
+/// Holds the lease on chunks the chunkserver is the primary of.
+pub(crate) type ChunkWrites = scc::HashMap<ChunkId, Arc<Mutex<Option<Lease>>>>;
+
+impl ChunkserverInternal {
+    async fn write_lock(&self, chunk_id: ChunkId) -> Arc<Mutex<Option<Lease>>> {
+        self.writes
+            .entry_async(chunk_id)
+            .await
+            .or_default()
+            .get()
+            .clone()
+    }
+
+    /// Forgets the lease and writes of a deleted chunk.
+    pub(super) async fn forget_writes(&self, chunk_id: ChunkId) {
+        self.writes.remove_async(&chunk_id).await;
+    }
+
+    /// Applies a write received from a client as the primary of the chunk
+    /// and forwards it to the replicas.
+    pub(crate) async fn write_as_primary(
+        &self,
+        mut payload: WriteChunkPayload,
+    ) -> anyhow::Result<()> {
+        let chunk_id = payload.chunk_id;
+        let lock = self.write_lock(chunk_id).await;
+        let mut lease = lock.lock().await;
+
+        let Some(version) = self
+            .chunks
+            .read_async(&chunk_id, |_, chunk| chunk.version)
+            .await
+        else {
+            return Err(ErrorPayload::new(
+                ErrorCode::ChunkNotFound,
+                format!("Chunk {} isn't stored on the chunkserver", chunk_id),
+            )
+            .into());
+        };
+
+        let held = match &mut *lease {
+            Some(held) if held.expires_at > Instant::now() => held,
+            expired => expired.insert(self.acquire_lease(chunk_id, version).await?),
+        };
+
+        payload.version = version + 1;
+        write_chunk(&self.chunks, &self.chunk_changes, &payload).await?;
+
+        // Writes reaching a replica after the lease expired could interleave with the writes
+        // of the next primary, the replica is considered stale instead.
+        let expires_at = held.expires_at;
+        let results = join_all(held.replicas.iter().map(|replica| async {
+            timeout_at(expires_at, self.forward_write(replica, &payload))
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("Write not applied before the lease expired")))
+        }))
+        .await;
+
+        let mut stale = Vec::new();
+        for (replica, result) in held.replicas.iter().zip(results) {
+            if let Err(e) = result {
+                warn!(%chunk_id, replica = %replica.server_id, error = ?e, "Replica failed write");
+                stale.push(replica.server_id);
+            }
+        }
+        if stale.is_empty() {
+            return Ok(());
+        }
+
+        held.replicas
+            .retain(|replica| !stale.contains(&replica.server_id));
+        for replica_id in stale {
+            if let Err(e) = self.report_stale_replica(chunk_id, replica_id).await {
+                // The replica stays listed by the metadata server, so the lease is acquired
+                // again with the next write, which fails on the replica as well.
+                *lease = None;
+                return Err(e.context("Write not applied to every replica"));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn acquire_lease(&self, chunk_id: ChunkId, version: u64) -> anyhow::Result<Lease> {
+        let requested_at = Instant::now();
+        let conn = self.get_metadata_server_connection().await?;
+        let granted = MetadataServerInternalClient::new(conn)
+            .grant_lease(GrantLeaseRequestPayload {
+                server_id: self.server_id,
+                chunk_id,
+                version,
+            })
+            .await
+            .map_err(|e| match e {
+                // E.g. the lease is held by another chunkserver, which the client retries.
+                RpcError::Status(error) => anyhow::Error::from(error),
+                e => anyhow::Error::from(e).context("Couldn't acquire lease"),
+            })?;
+
+        debug!(%chunk_id, duration = ?granted.duration, "Lease acquired");
+        Ok(Lease {
+            expires_at: requested_at + lease_margin(granted.duration),
+            replicas: granted.replicas,
+        })
+    }
+
+    async fn forward_write(
+        &self,
+        replica: &LeaseReplica,
+        payload: &WriteChunkPayload,
+    ) -> anyhow::Result<()> {
+        let conn = self.get_chunkserver_connection(&replica.location).await?;
+        ChunkserverInternalClient::new(conn)
+            .apply_write(WriteChunkPayload {
+                chunk_id: payload.chunk_id,
+                offset: payload.offset,
+                chunk_size: payload.chunk_size,
+                version: payload.version,
+                chunk_transfer: ChunkTransfer::from_file(payload.chunk_transfer.data.clone(), None),
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn report_stale_replica(
+        &self,
+        chunk_id: ChunkId,
+        replica_id: ServerId,
+    ) -> anyhow::Result<()> {
+        let conn = self.get_metadata_server_connection().await?;
+        MetadataServerInternalClient::new(conn)
+            .report_stale_replica(StaleReplicaPayload {
+                server_id: self.server_id,
+                chunk_id,
+                replica_id,
+            })
+            .await
+            .context("Couldn't report stale replica")
+    }
+
+    /// Applies a write forwarded by the primary of the chunk, if it follows the last one.
+    pub(super) async fn apply_forwarded_write(
+        &self,
+        payload: WriteChunkPayload,
+    ) -> anyhow::Result<()> {
+        let lock = self.write_lock(payload.chunk_id).await;
+        let _lock = lock.lock().await;
+
+        let version = self
+            .chunks
+            .read_async(&payload.chunk_id, |_, chunk| chunk.version)
+            .await;
+        if let Some(version) = version
+            && version + 1 != payload.version
+        {
+            return Err(ErrorPayload::new(
+                ErrorCode::ChunkVersionMismatch,
+                format!(
+                    "Write of version {} doesn't follow version {} of chunk {}",
+                    payload.version, version, payload.chunk_id
+                ),
+            )
+            .into());
+        }
+
+        write_chunk(&self.chunks, &self.chunk_changes, &payload).await
+    }
+}
+
+/// Part of the lease's duration the primary relies on holding it, leaving room for the
+/// request granting it and clock drift.
+fn lease_margin(duration: Duration) -> Duration {
+    duration.mul_f64(0.9)
+}
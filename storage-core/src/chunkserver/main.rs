@@ -1,7 +1,7 @@
 //! Creates and runs **chunkserver**.
 //!
 //! # Running in Debug Mode
-//! - **Behavior:** Prints debugging info about operations and auto-generates self-signed certificates.
+//! - **Behavior:** Prints debugging info about operations and auto-generates certificates signed by a development CA.
 //! - **Example usage**
 //! ```bash
 //!   cargo run --bin chunkserver -- \
@@ -14,17 +14,37 @@
 //! # Running in Release Mode
 //! - **Command:** `cargo run --release --bin chunkserver -- [OPTIONS]`
 //! - **Requirements:**
-//!   - Valid TLS certificate files (`--cert`, `--key`)
+//!   - A certificate bundle issued by the cluster CA (`--certificates`, see `admin certs`),
+//!     or TLS certificate files (`--cert`, `--key`) with the cluster CA (`--internal-ca`)
 //!   - All required arguments must be provided (see `--help` for full list)
 //!   - Run `cargo run --release --bin chunkserver -- --help` for details
-//! - **WARNING:** Self-signed certificates are NOT available in release builds for security reasons.
+//! - **WARNING:** The development CA is NOT available in release builds for security reasons.
+//! - Certificate files are reloaded on `SIGHUP` and when they change, without dropping connections.
+//!
+//! # Logging
+//! Logs are written to stdout at `debug` level in debug builds (`info` for dependencies)
+//! and `info` level in release builds.
+//! Use `--log-filter` (or `RUST_LOG`) to change the levels and `--log-format json` for machine-readable output.
+//!
+//! # Configuration
+//! Flags may also be set in a TOML file passed with `--config`, by their long names,
+//! e.g. `rack-id = "rack-2"`. Flags given on the command line take precedence over the file.
+//! Settings of the whole cluster, like the heartbeat interval, are received from the metadata
+//! server on discovery.
 
 use crate::external::ChunkserverExternal;
 use crate::internal::ChunkserverInternal;
-use clap::Parser;
 use config::ChunkserverOpt;
 use setup::chunkserver_setup;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use storage_core::common::QuicServer;
+use storage_core::common::config_file::parse_with_config_file;
+use storage_core::common::metrics::serve_metrics;
+use storage_core::common::server::CertificateReloader;
+use storage_core::common::shutdown::{Shutdown, wait_for_signal};
+use storage_core::common::telemetry::init_tracing;
+use tracing::info;
 
 mod chunk;
 mod config;
@@ -33,28 +53,56 @@ mod internal;
 mod setup;
 mod types;
 
-
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     rustls::crypto::ring::default_provider()
         .install_default()
         .expect("Failed to install rustls crypto provider");
 
-    let opt = ChunkserverOpt::parse();
-    let (internal_chunkservers, external_chunkserver) =
-        chunkserver_setup(opt).expect("Couldn't setup chunkservers");
+    let opt: ChunkserverOpt = parse_with_config_file()?;
+    init_tracing(opt.log_format, opt.log_filter.as_deref());
+    let metrics_addr = opt.metrics_addr;
+    let shutdown = Shutdown::new();
+    let (internal_chunkservers, external_chunkserver, certificate) =
+        chunkserver_setup(opt, shutdown.clone()).expect("Couldn't setup chunkservers");
 
-    run(internal_chunkservers, external_chunkserver).await
+    run(
+        internal_chunkservers,
+        external_chunkserver,
+        certificate,
+        metrics_addr,
+        shutdown,
+    )
+    .await
 }
 
 async fn run(
     internal_chunkserver: ChunkserverInternal,
     external_chunkserver: ChunkserverExternal,
+    certificate: Arc<CertificateReloader>,
+    metrics_addr: SocketAddr,
+    shutdown: Shutdown,
 ) -> anyhow::Result<()> {
-    let internal_handle = tokio::spawn(async move { internal_chunkserver.run().await });
-    let external_handle = tokio::spawn(async move { external_chunkserver.run().await });
+    let servers = async {
+        tokio::try_join!(
+            internal_chunkserver.run(shutdown.clone()),
+            external_chunkserver.run(shutdown.clone())
+        )
+    };
+    tokio::pin!(servers);
+    let certificate_handle = tokio::spawn(certificate.run());
+    let metrics_handle = tokio::spawn(serve_metrics(metrics_addr));
 
     // If one of the sides of the server crashes, we want to exit immediately.
-    let _ = tokio::try_join!(internal_handle, external_handle)?;
-    Ok(())
+    tokio::select! {
+        result = &mut servers => return result.map(|_| ()),
+        result = certificate_handle => return result?,
+        result = metrics_handle => return result?,
+        result = wait_for_signal() => result?,
+    }
+
+    info!("Shutting down");
+    shutdown.trigger();
+    servers.await?;
+    internal_chunkserver.leave().await
 }
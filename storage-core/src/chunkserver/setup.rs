@@ -1,20 +1,27 @@
 use super::config::ChunkserverOpt;
+use crate::chunk::{ChunkChanges, load_stored_chunks};
 use crate::external::ChunkserverExternal;
 use crate::internal::ChunkserverInternal;
 use anyhow::Result;
 use quinn::Endpoint;
 use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
-use rustls::pki_types::CertificateDer;
-use rustls_platform_verifier::BuilderVerifierExt;
+use std::fs;
 use std::sync::Arc;
 use std::sync::atomic::AtomicU64;
-use std::fs;
 use storage_core::common;
 use storage_core::common::config::{FINAL_STORAGE_ROOT, TMP_STORAGE_ROOT};
+use storage_core::common::server::{CertificateReloader, tls};
+use storage_core::common::shutdown::Shutdown;
+use tokio::sync::Mutex;
 
 pub(crate) fn chunkserver_setup(
     options: ChunkserverOpt,
-) -> Result<(ChunkserverInternal, ChunkserverExternal)> {
+    shutdown: Shutdown,
+) -> Result<(
+    ChunkserverInternal,
+    ChunkserverExternal,
+    Arc<CertificateReloader>,
+)> {
     // Load static variables
     let final_storage_root = std::env::current_dir()?.join(options.final_root);
     let tmp_storage_root = std::env::current_dir()?.join(options.tmp_root);
@@ -22,6 +29,8 @@ pub(crate) fn chunkserver_setup(
     fs::create_dir_all(final_storage_root.clone()).expect("Couldn't create final storage root");
     fs::create_dir_all(tmp_storage_root.clone()).expect("Couldn't create tmp storage root");
 
+    let chunks = Arc::new(load_stored_chunks(&final_storage_root)?);
+
     FINAL_STORAGE_ROOT
         .set(final_storage_root)
         .expect("Final storage root set failed");
@@ -34,21 +43,28 @@ pub(crate) fn chunkserver_setup(
         Some(options.chunkserver_hostname.clone()),
         options.key,
         options.cert.clone(),
+        options.certificates,
     )?;
-    let (certs, key) = certificate_provider.get_certificate()?;
+    let internal_roots =
+        tls::internal_ca_roots(options.internal_ca.as_deref(), &*certificate_provider)?;
+    let certificate = CertificateReloader::new(certificate_provider)?;
 
-    let mut server_crypto = rustls::ServerConfig::builder()
+    let mut internal_crypto =
+        tls::internal_server_crypto(internal_roots.clone(), certificate.clone())?;
+    let mut internal_client_crypto =
+        tls::internal_client_crypto(internal_roots, certificate.clone())?;
+
+    let mut client_crypto = rustls::ServerConfig::builder()
         .with_no_client_auth()
-        .with_single_cert(certs, key)?;
+        .with_cert_resolver(certificate.clone());
 
-    server_crypto.alpn_protocols = common::ALPN_QUIC_HTTP.iter().map(|&x| x.into()).collect();
+    client_crypto.alpn_protocols = common::ALPN_QUIC_HTTP.iter().map(|&x| x.into()).collect();
     if options.keylog {
-        server_crypto.key_log = Arc::new(rustls::KeyLogFile::new());
+        client_crypto.key_log = Arc::new(rustls::KeyLogFile::new());
+        internal_crypto.key_log = client_crypto.key_log.clone();
+        internal_client_crypto.key_log = client_crypto.key_log.clone();
     }
 
-    let client_crypto = server_crypto.clone();
-    let internal_crypto = server_crypto;
-
     let mut client_config =
         quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(client_crypto)?));
     let transport_config = Arc::get_mut(&mut client_config.transport).unwrap();
@@ -62,33 +78,10 @@ pub(crate) fn chunkserver_setup(
     let mut internal_endpoint = Endpoint::server(internal_config, options.internal_socket_addr)
         .expect("Couldn't create internal endpoint");
 
-    let mut client_crypto = rustls::ClientConfig::builder()
-        .with_platform_verifier()
-        .expect("Could not load platform certificates")
-        .with_no_client_auth();
-
-    // TODO: Temporary solution to make servers accept self-signed certificates.
-    if options.cert.is_none() {
-        let path = std::env::current_dir()
-            .expect("Couldn't get current directory")
-            .join("certificates")
-            .join(options.metadata_server_hostname.clone());
-        let cert_path = path.join("cert.der");
-        let server_cert_der = std::fs::read(&cert_path).expect("Unable to read certificate");
-        let mut roots = rustls::RootCertStore::empty();
-        roots
-            .add(CertificateDer::from(server_cert_der.as_ref()))
-            .expect("Couldn't add server certificate");
-
-        client_crypto = rustls::ClientConfig::builder()
-            .with_root_certificates(roots)
-            .with_no_client_auth();
-    }
-
-    client_crypto.alpn_protocols = common::ALPN_QUIC_HTTP.iter().map(|&x| x.into()).collect();
-
+    // Connections to the metadata server and other chunkservers are authenticated
+    // with the chunkserver's certificate.
     let client_config = quinn::ClientConfig::new(Arc::new(
-        QuicClientConfig::try_from(client_crypto).expect("couldn't create client config"),
+        QuicClientConfig::try_from(internal_client_crypto).expect("couldn't create client config"),
     ));
 
     internal_endpoint.set_default_client_config(client_config);
@@ -98,7 +91,7 @@ pub(crate) fn chunkserver_setup(
     let clients_endpoint = Arc::new(clients_endpoint);
 
     let requests_since_heartbeat = Arc::new(AtomicU64::new(0));
-    let chunks = Arc::new(scc::HashMap::new());
+    let chunk_changes = Arc::new(Mutex::new(ChunkChanges::default()));
     let chunkserver_connections = Arc::new(scc::HashMap::new());
 
     let internal_chunkserver = ChunkserverInternal::new(
@@ -108,19 +101,23 @@ pub(crate) fn chunkserver_setup(
         options.advertised_external_addr,
         requests_since_heartbeat.clone(),
         chunks.clone(),
+        chunk_changes.clone(),
         internal_endpoint.clone(),
         options.metadata_server_addr,
         options.metadata_server_hostname,
         chunkserver_connections.clone(),
+        shutdown,
     );
 
     let external_chunkserver = ChunkserverExternal::new(
         chunks,
+        chunk_changes,
+        internal_chunkserver.clone(),
         requests_since_heartbeat,
         clients_endpoint,
         internal_endpoint,
         chunkserver_connections,
     );
 
-    Ok((internal_chunkserver, external_chunkserver))
+    Ok((internal_chunkserver, external_chunkserver, certificate))
 }
@@ -1,6 +1,8 @@
+use std::net::SocketAddr;
 use uuid::Uuid;
 
 pub(crate) type ServerId = Uuid;
 pub(crate) type ChunkId = Uuid;
 pub(crate) type Hostname = String;
 pub(crate) type RackId = String;
+pub(crate) type ServerLocation = SocketAddr;
@@ -0,0 +1,135 @@
+use anyhow::{Result, bail};
+use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+use storage_core::common::config::ClusterConfig;
+use storage_core::common::transfer::TransferLimits;
+use storage_core::common::types::Replication;
+
+#[derive(Parser, Debug)]
+#[clap(name = "client")]
+pub(super) struct ClientOpt {
+    /// Certificate to verify the servers against, in PEM or DER format.
+    #[clap(long = "ca-cert")]
+    pub(super) ca_cert: Option<PathBuf>,
+    /// Directory of the certificate bundle issued by `admin certs issue`, whose CA is trusted.
+    #[clap(long = "certificates", conflicts_with = "ca_cert")]
+    pub(super) certificates: Option<PathBuf>,
+    /// Metadata server hostname.
+    #[clap(long = "metadata-server-hostname", default_value = "metadata-server")]
+    pub(super) metadata_server_hostname: String,
+    /// Metadata server address for communication with clients.
+    #[clap(long = "metadata-server-addr", default_value = "[::1]:4422")]
+    pub(super) metadata_server_addr: SocketAddr,
+    /// Directory the downloaded chunks are received into, before they are written to the
+    /// local file. A directory in the system's temporary directory by default.
+    #[clap(long = "tmp-root")]
+    pub(super) tmp_root: Option<PathBuf>,
+    /// Log filter directives, e.g. `info,storage_core=debug`.
+    #[clap(long = "log-filter", default_value = "warn")]
+    pub(super) log_filter: String,
+    /// Chunks transferred at the same time.
+    #[clap(
+        long = "max-concurrent-chunks",
+        default_value_t = TransferLimits::default().max_concurrent_chunks
+    )]
+    pub(super) max_concurrent_chunks: usize,
+    /// Chunks transferred at the same time to or from a single chunkserver.
+    #[clap(
+        long = "max-chunks-per-server",
+        default_value_t = TransferLimits::default().max_chunks_per_server
+    )]
+    pub(super) max_chunks_per_server: usize,
+    /// Bytes transferred per second on average, not limited by default.
+    #[clap(long = "bandwidth-limit")]
+    pub(super) bandwidth_limit: Option<NonZeroU64>,
+    /// Print the progress of the transfer every second.
+    #[clap(long)]
+    pub(super) progress: bool,
+    #[clap(subcommand)]
+    pub(super) command: ClientCommand,
+}
+
+impl ClientOpt {
+    pub(super) fn validate(&self) -> Result<()> {
+        if self.max_concurrent_chunks == 0 {
+            bail!("Maximum number of concurrent chunks has to be positive");
+        }
+        if self.max_chunks_per_server == 0 {
+            bail!("Maximum number of chunks per chunkserver has to be positive");
+        }
+        Ok(())
+    }
+
+    pub(super) fn transfer_limits(&self) -> TransferLimits {
+        TransferLimits {
+            max_concurrent_chunks: self.max_concurrent_chunks,
+            max_chunks_per_server: self.max_chunks_per_server,
+            bytes_per_second: self.bandwidth_limit,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub(super) enum ClientCommand {
+    /// Upload a local file.
+    Upload {
+        local_path: PathBuf,
+        filename: String,
+        /// Storage class, number of copies or `rs-<data>-<parity>` erasure coding of the file.
+        #[clap(long, default_value_t)]
+        replication: Replication,
+        /// Resume an interrupted upload, sending only the chunks which weren't stored.
+        #[clap(long)]
+        resume: bool,
+        /// Journal of the upload, `<LOCAL_PATH>.upload.journal` by default.
+        #[clap(long)]
+        journal: Option<PathBuf>,
+        /// File with the secret of the tenant, e.g. its encryption key. The file is split into
+        /// chunks by its content, which are hashed with a key derived from the secret, and
+        /// chunks the tenant already stored aren't uploaded again.
+        #[clap(long = "dedup-key-file")]
+        dedup_key_file: Option<PathBuf>,
+        /// Maximum size of the chunks a deduplicated file is split into, can't exceed the
+        /// maximum chunk size of the cluster.
+        #[clap(
+            long = "dedup-max-chunk-size",
+            requires = "dedup_key_file",
+            default_value_t = ClusterConfig::DEFAULT.max_chunk_size
+        )]
+        dedup_max_chunk_size: u64,
+    },
+    /// Download a file into a local file.
+    Download {
+        filename: String,
+        local_path: PathBuf,
+        /// Resume an interrupted download, skipping the chunks already in the local file.
+        #[clap(long)]
+        resume: bool,
+        /// Journal of the download, `<LOCAL_PATH>.download.journal` by default.
+        #[clap(long)]
+        journal: Option<PathBuf>,
+    },
+    /// Download a range of a file into a local file.
+    Read {
+        filename: String,
+        local_path: PathBuf,
+        /// Offset of the range in the file.
+        #[clap(long, default_value_t = 0)]
+        offset: u64,
+        /// Length of the range, cut at the end of the file.
+        #[clap(long)]
+        length: u64,
+    },
+}
+
+impl ClientCommand {
+    pub(super) fn name(&self) -> &'static str {
+        match self {
+            ClientCommand::Upload { .. } => "upload",
+            ClientCommand::Download { .. } => "download",
+            ClientCommand::Read { .. } => "read",
+        }
+    }
+}
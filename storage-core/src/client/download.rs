@@ -0,0 +1,250 @@
+use anyhow::{Context, Result, bail};
+use futures::stream::{FuturesUnordered, StreamExt};
+use quinn::Endpoint;
+use std::io::SeekFrom;
+use std::path::Path;
+use storage_core::common::journal::{DownloadJournal, DownloadedChunk, content_hash};
+use storage_core::common::read::chunk_ranges;
+use storage_core::common::transfer::TransferScheduler;
+use storage_core::common::types::ByteRange;
+use storage_core::common::{
+    GetFilePlacementRequestPayload, GetFilePlacementResponsePayload, MetadataServerExternalClient,
+};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::warn;
+
+/// Downloads the file into the local file, skipping the chunks of an interrupted download
+/// which are already in the local file.
+pub(crate) async fn download(
+    metadata_server: &MetadataServerExternalClient,
+    endpoint: &Endpoint,
+    scheduler: &TransferScheduler,
+    filename: String,
+    local_path: &Path,
+    resume: bool,
+    journal_path: &Path,
+) -> Result<()> {
+    let placement = fetch_placement(metadata_server, &filename).await?;
+
+    let mut journal = if resume {
+        let journal = DownloadJournal::load(journal_path)
+            .await
+            .context("No download to resume")?;
+        if journal.filename != filename {
+            bail!(
+                "Journal belongs to the download of {}, not {}",
+                journal.filename,
+                filename
+            );
+        }
+        journal
+    } else {
+        DownloadJournal {
+            filename,
+            chunks: Vec::new(),
+        }
+    };
+    journal
+        .chunks
+        .resize(placement.chunks_locations.len(), None);
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(!resume)
+        .open(local_path)
+        .await
+        .with_context(|| format!("Couldn't open {}", local_path.display()))?;
+    let file_size = placement.chunk_sizes.iter().sum();
+    file.set_len(file_size).await?;
+
+    let mut offsets = Vec::with_capacity(placement.chunk_sizes.len());
+    let mut remaining = Vec::new();
+    let mut offset = 0;
+    for (idx, (locations, &size)) in placement
+        .chunks_locations
+        .iter()
+        .zip(placement.chunk_sizes.iter())
+        .enumerate()
+    {
+        offsets.push(offset);
+        let verified = match journal.downloaded(idx, locations.chunk_id, size) {
+            Some(downloaded) => {
+                content_hash(&read_at(&mut file, offset, size).await?) == downloaded.hash
+            }
+            None => false,
+        };
+        if !verified {
+            journal.chunks[idx] = None;
+            remaining.push((idx, locations, size));
+        }
+        offset += size;
+    }
+    journal.save(journal_path).await?;
+    if resume {
+        println!(
+            "Resuming download of {}, {} of {} chunks remaining",
+            journal.filename,
+            remaining.len(),
+            journal.chunks.len()
+        );
+    }
+
+    scheduler.add_pending(
+        remaining.len() as u64,
+        remaining.iter().map(|(_, _, size)| size).sum(),
+    );
+    // All chunks are queued at once, the scheduler decides which of them are downloaded.
+    let mut downloads: FuturesUnordered<_> = remaining
+        .into_iter()
+        .map(|(idx, locations, size)| async move {
+            let range = ByteRange {
+                offset: 0,
+                length: size,
+            };
+            let data = scheduler
+                .transfer(
+                    locations.transfer_servers(),
+                    size,
+                    |connections| async move {
+                        locations
+                            .download_range(range, endpoint, &connections)
+                            .await
+                    },
+                )
+                .await;
+            (idx, locations.chunk_id, size, data)
+        })
+        .collect();
+
+    let mut failed = 0;
+    while let Some((idx, chunk_id, size, data)) = downloads.next().await {
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                warn!(%chunk_id, error = ?e, "Couldn't download chunk");
+                failed += 1;
+                continue;
+            }
+        };
+
+        file.seek(SeekFrom::Start(offsets[idx])).await?;
+        file.write_all(&data).await?;
+        // The chunk is journaled only once it's on the disk.
+        file.sync_data().await?;
+
+        journal.chunks[idx] = Some(DownloadedChunk {
+            chunk_id,
+            size,
+            hash: content_hash(&data),
+        });
+        journal.save(journal_path).await?;
+    }
+
+    if failed > 0 {
+        bail!(
+            "{} of {} chunks weren't downloaded, the download can be resumed with --resume",
+            failed,
+            journal.chunks.len()
+        );
+    }
+
+    tokio::fs::remove_file(journal_path).await?;
+    println!(
+        "Downloaded {} ({} bytes in {} chunks)",
+        journal.filename,
+        file_size,
+        journal.chunks.len()
+    );
+    Ok(())
+}
+
+/// Downloads the range of the file into the local file, the range is cut at the end
+/// of the file.
+pub(crate) async fn read_range(
+    metadata_server: &MetadataServerExternalClient,
+    endpoint: &Endpoint,
+    scheduler: &TransferScheduler,
+    filename: String,
+    range: ByteRange,
+    local_path: &Path,
+) -> Result<()> {
+    let placement = fetch_placement(metadata_server, &filename).await?;
+
+    let mut file = File::create(local_path)
+        .await
+        .with_context(|| format!("Couldn't create {}", local_path.display()))?;
+
+    // Only the covered part of every chunk is downloaded, as with `read_file_range`. The
+    // parts are queued at once like the chunks of a download, the scheduler limits how many
+    // of them are downloaded at the same time, and each is written at its offset in the
+    // local file as soon as it's downloaded.
+    let placement = &placement;
+    let ranges = chunk_ranges(&placement.chunk_sizes, range);
+    let length = ranges.iter().map(|(_, range)| range.length).sum();
+    file.set_len(length).await?;
+    scheduler.add_pending(ranges.len() as u64, length);
+    let mut offset = 0;
+    let mut reads: FuturesUnordered<_> = ranges
+        .into_iter()
+        .map(|(idx, range)| {
+            let part_offset = offset;
+            offset += range.length;
+            async move {
+                let locations = &placement.chunks_locations[idx];
+                let data = scheduler
+                    .transfer(
+                        locations.transfer_servers(),
+                        range.length,
+                        |connections| async move {
+                            locations
+                                .download_range(range, endpoint, &connections)
+                                .await
+                        },
+                    )
+                    .await;
+                (part_offset, data)
+            }
+        })
+        .collect();
+
+    while let Some((part_offset, data)) = reads.next().await {
+        let data = data?;
+        file.seek(SeekFrom::Start(part_offset)).await?;
+        file.write_all(&data).await?;
+    }
+    file.flush().await?;
+
+    println!(
+        "Read {} bytes of {} at offset {}",
+        length, filename, range.offset
+    );
+    Ok(())
+}
+
+async fn fetch_placement(
+    metadata_server: &MetadataServerExternalClient,
+    filename: &str,
+) -> Result<GetFilePlacementResponsePayload> {
+    let placement = metadata_server
+        .fetch_file_placement(GetFilePlacementRequestPayload {
+            filename: filename.to_owned(),
+            version: None,
+            as_of: None,
+        })
+        .await
+        .with_context(|| format!("Couldn't fetch the placement of {}", filename))?;
+    if placement.chunk_sizes.len() != placement.chunks_locations.len() {
+        bail!("Metadata server didn't report the sizes of the chunks");
+    }
+    Ok(placement)
+}
+
+async fn read_at(file: &mut File, offset: u64, size: u64) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut data = vec![0; size as usize];
+    file.read_exact(&mut data).await?;
+    Ok(data)
+}
@@ -1 +1,159 @@
-fn main() {}
+//! Uploads and downloads files through the **metadata server** and the chunkservers.
+//!
+//! # Example usage
+//! ```bash
+//!   cargo run --bin client -- upload <LOCAL_PATH> <FILENAME> [--replication <REPLICATION>]
+//!   cargo run --bin client -- upload <LOCAL_PATH> <FILENAME> --dedup-key-file <KEY_FILE>
+//!   cargo run --bin client -- upload <LOCAL_PATH> <FILENAME> --resume
+//!   cargo run --bin client -- download <FILENAME> <LOCAL_PATH> [--resume]
+//!   cargo run --bin client -- read <FILENAME> <LOCAL_PATH> [--offset <OFFSET>] --length <LENGTH>
+//! ```
+//!
+//! Chunks are transferred concurrently within the limits of `--max-concurrent-chunks`,
+//! `--max-chunks-per-server` and `--bandwidth-limit`, see
+//! [`storage_core::common::transfer`].
+//!
+//! Interrupted transfers leave a journal next to the local file, with which `--resume`
+//! transfers only the remaining chunks, see [`storage_core::common::journal`].
+//!
+//! In debug mode the development CA of servers running in the same directory is trusted.
+//! Otherwise, provide the certificate to trust with `--ca-cert`, or a bundle issued
+//! by the cluster CA with `--certificates`.
+
+use crate::config::{ClientCommand, ClientOpt};
+use crate::download::{download, read_range};
+use crate::setup::{client_endpoint, setup_tmp_root};
+use crate::upload::{Chunking, upload};
+use clap::Parser;
+use std::sync::Arc;
+use std::time::Duration;
+use storage_core::common::journal::{DownloadJournal, UploadJournal};
+use storage_core::common::telemetry::{LogFormat, init_tracing, with_request_id};
+use storage_core::common::transfer::TransferScheduler;
+use storage_core::common::types::ByteRange;
+use storage_core::common::{MetadataServerExternalClient, protocol};
+use tracing::{Instrument, info, info_span};
+use uuid::Uuid;
+
+mod config;
+mod download;
+mod setup;
+mod upload;
+
+/// How often the progress of the transfer is printed with `--progress`.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .expect("Failed to install rustls crypto provider");
+
+    let opt = ClientOpt::parse();
+    opt.validate()?;
+    init_tracing(LogFormat::Pretty, Some(&opt.log_filter));
+    setup_tmp_root(&opt)?;
+    let endpoint = client_endpoint(&opt)?;
+
+    let conn = protocol::connect(
+        &endpoint,
+        opt.metadata_server_addr,
+        &opt.metadata_server_hostname,
+    )
+    .await?;
+    let metadata_server = MetadataServerExternalClient::new(conn.clone());
+    let scheduler = Arc::new(TransferScheduler::new(opt.transfer_limits()));
+    let progress = opt.progress.then(|| {
+        let scheduler = scheduler.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PROGRESS_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                eprintln!("{}", scheduler.progress());
+            }
+        })
+    });
+
+    // All messages of the command carry the same request id, so that the command can be
+    // followed through the logs of the metadata server and the chunkservers.
+    let request_id = Uuid::new_v4();
+    let span = info_span!("request", %request_id, command = opt.command.name());
+    let command = async {
+        info!("Request started");
+        match opt.command {
+            ClientCommand::Upload {
+                local_path,
+                filename,
+                replication,
+                resume,
+                journal,
+                dedup_key_file,
+                dedup_max_chunk_size,
+            } => {
+                let journal = journal.unwrap_or_else(|| UploadJournal::path_for(&local_path));
+                let chunking = match dedup_key_file {
+                    Some(path) => Chunking::by_content(&path, dedup_max_chunk_size).await?,
+                    None => Chunking::Fixed,
+                };
+                upload(
+                    &metadata_server,
+                    &endpoint,
+                    &scheduler,
+                    &local_path,
+                    filename,
+                    replication,
+                    chunking,
+                    resume,
+                    &journal,
+                )
+                .await
+            }
+            ClientCommand::Download {
+                filename,
+                local_path,
+                resume,
+                journal,
+            } => {
+                let journal = journal.unwrap_or_else(|| DownloadJournal::path_for(&local_path));
+                download(
+                    &metadata_server,
+                    &endpoint,
+                    &scheduler,
+                    filename,
+                    &local_path,
+                    resume,
+                    &journal,
+                )
+                .await
+            }
+            ClientCommand::Read {
+                filename,
+                local_path,
+                offset,
+                length,
+            } => {
+                read_range(
+                    &metadata_server,
+                    &endpoint,
+                    &scheduler,
+                    filename,
+                    ByteRange { offset, length },
+                    &local_path,
+                )
+                .await
+            }
+        }
+    };
+    let result = with_request_id(request_id, command).instrument(span).await;
+
+    if let Some(progress) = progress {
+        progress.abort();
+        eprintln!("{}", scheduler.progress());
+    }
+    scheduler.close();
+    conn.close(0u32.into(), b"done");
+    endpoint.wait_idle().await;
+
+    result
+}
@@ -0,0 +1,76 @@
+use crate::config::ClientOpt;
+use anyhow::{Context, Result};
+use quinn::Endpoint;
+use quinn::crypto::rustls::QuicClientConfig;
+use rustls_platform_verifier::BuilderVerifierExt;
+use std::sync::Arc;
+use std::time::Duration;
+use storage_core::common;
+use storage_core::common::config::TMP_STORAGE_ROOT;
+use storage_core::common::server::ca::CA_CERT_FILE;
+use storage_core::common::server::certificate_provider::dev_ca_certificate_path;
+use storage_core::common::server::read_certificates;
+
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+pub(crate) fn client_endpoint(options: &ClientOpt) -> Result<Endpoint> {
+    let ca_cert = options
+        .ca_cert
+        .clone()
+        .or_else(|| {
+            options
+                .certificates
+                .as_ref()
+                .map(|dir| dir.join(CA_CERT_FILE))
+        })
+        .or_else(|| {
+            // CA signing the certificates generated by servers in debug mode.
+            cfg!(debug_assertions).then(dev_ca_certificate_path)
+        });
+
+    let mut client_crypto = match ca_cert {
+        Some(cert_path) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in read_certificates(&cert_path)? {
+                roots.add(cert)?;
+            }
+
+            rustls::ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth()
+        }
+        None => rustls::ClientConfig::builder()
+            .with_platform_verifier()
+            .context("Could not load platform certificates")?
+            .with_no_client_auth(),
+    };
+
+    client_crypto.alpn_protocols = common::ALPN_QUIC_HTTP.iter().map(|&x| x.into()).collect();
+
+    let mut client_config =
+        quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(client_crypto)?));
+    // The connection to the metadata server stays idle while chunks are transferred.
+    let mut transport_config = quinn::TransportConfig::default();
+    transport_config.keep_alive_interval(Some(KEEPALIVE_INTERVAL));
+    client_config.transport_config(Arc::new(transport_config));
+
+    let mut endpoint = Endpoint::client("[::]:0".parse()?)?;
+    endpoint.set_default_client_config(client_config);
+
+    Ok(endpoint)
+}
+
+/// Creates the directory the downloaded chunks are received into.
+pub(crate) fn setup_tmp_root(options: &ClientOpt) -> Result<()> {
+    let tmp_root = options
+        .tmp_root
+        .clone()
+        .unwrap_or_else(|| std::env::temp_dir().join("storage-client"));
+    std::fs::create_dir_all(&tmp_root)
+        .with_context(|| format!("Couldn't create {}", tmp_root.display()))?;
+    TMP_STORAGE_ROOT
+        .set(tmp_root)
+        .expect("Temporary storage root set twice");
+
+    Ok(())
+}
@@ -0,0 +1,335 @@
+use anyhow::{Context, Result, bail};
+use futures::stream::{FuturesUnordered, StreamExt};
+use quinn::Endpoint;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::time::SystemTime;
+use storage_core::common::dedup::{DedupKey, split_file};
+use storage_core::common::journal::{UploadJournal, UploadedChunk};
+use storage_core::common::telemetry::{current_request_id, with_request_id};
+use storage_core::common::transfer::TransferScheduler;
+use storage_core::common::types::{ChunkLocations, Replication};
+use storage_core::common::{
+    ChunkPlacementRequestPayload, ChunkserverLocation, MetadataServerExternalClient,
+    UploadFailoverPayload,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::{info, warn};
+
+/// How the local file is split into chunks.
+pub(crate) enum Chunking {
+    /// Into chunks of the maximum size, chosen by the metadata server.
+    Fixed,
+    /// By its content into chunks of at most `max_chunk_size` bytes, which are hashed with
+    /// the tenant's key, see [`storage_core::common::dedup`].
+    Content { key: DedupKey, max_chunk_size: u64 },
+}
+
+impl Chunking {
+    /// Splits by content with the key derived from the tenant's secret in the file.
+    pub(crate) async fn by_content(secret_path: &Path, max_chunk_size: u64) -> Result<Self> {
+        let secret = tokio::fs::read(secret_path)
+            .await
+            .with_context(|| format!("Couldn't read {}", secret_path.display()))?;
+        if secret.is_empty() {
+            bail!("Deduplication secret in {} is empty", secret_path.display());
+        }
+        Ok(Chunking::Content {
+            key: DedupKey::derive(&secret),
+            max_chunk_size,
+        })
+    }
+}
+
+/// Uploads the local file, or the chunks of an interrupted upload which weren't stored.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn upload(
+    metadata_server: &MetadataServerExternalClient,
+    endpoint: &Endpoint,
+    scheduler: &TransferScheduler,
+    local_path: &Path,
+    filename: String,
+    replication: Replication,
+    chunking: Chunking,
+    resume: bool,
+    journal_path: &Path,
+) -> Result<()> {
+    let metadata = tokio::fs::metadata(local_path)
+        .await
+        .with_context(|| format!("Couldn't read {}", local_path.display()))?;
+    let (file_size, modified) = (metadata.len(), metadata.modified()?);
+
+    let journal = if resume {
+        let journal = UploadJournal::load(journal_path)
+            .await
+            .context("No upload to resume")?;
+        journal.check_matches(&filename, file_size, modified)?;
+        journal
+    } else {
+        let journal = place_file(
+            metadata_server,
+            local_path,
+            filename,
+            file_size,
+            modified,
+            replication,
+            chunking,
+        )
+        .await?;
+        journal.save(journal_path).await?;
+        journal
+    };
+
+    // The chunks are sent as part of the request which placed them, so that their failovers
+    // are accepted by the metadata server when the upload is resumed.
+    let request_id = journal.request_id.unwrap_or_else(current_request_id);
+    if resume {
+        info!(%request_id, "Resuming the upload placed by the request");
+    }
+    let upload = upload_remaining(
+        metadata_server,
+        endpoint,
+        scheduler,
+        local_path,
+        journal,
+        resume,
+        journal_path,
+    );
+    with_request_id(request_id, upload).await
+}
+
+/// Uploads the chunks of the journal which weren't stored yet.
+async fn upload_remaining(
+    metadata_server: &MetadataServerExternalClient,
+    endpoint: &Endpoint,
+    scheduler: &TransferScheduler,
+    local_path: &Path,
+    mut journal: UploadJournal,
+    resume: bool,
+    journal_path: &Path,
+) -> Result<()> {
+    let remaining: Vec<_> = journal
+        .remaining()
+        .map(|(idx, chunk)| (idx, chunk.locations.clone(), chunk.offset, chunk.length))
+        .collect();
+    if resume {
+        println!(
+            "Resuming upload of {}, {} of {} chunks remaining",
+            journal.filename,
+            remaining.len(),
+            journal.chunks.len()
+        );
+    }
+
+    scheduler.add_pending(
+        remaining.len() as u64,
+        remaining.iter().map(|(_, _, _, length)| length).sum(),
+    );
+    // All chunks are queued at once, the scheduler decides which of them are sent.
+    let mut uploads: FuturesUnordered<_> = remaining
+        .into_iter()
+        .map(|(idx, locations, offset, length)| async move {
+            let uploaded = upload_chunk(
+                metadata_server,
+                &locations,
+                local_path,
+                offset,
+                length,
+                endpoint,
+                scheduler,
+            )
+            .await;
+            (idx, locations.chunk_id, uploaded)
+        })
+        .collect();
+
+    let mut failed = 0;
+    while let Some((idx, chunk_id, uploaded)) = uploads.next().await {
+        match uploaded {
+            Ok(()) => {
+                journal.chunks[idx].confirmed = true;
+                journal.save(journal_path).await?;
+            }
+            Err(e) => {
+                warn!(%chunk_id, error = ?e, "Couldn't upload chunk");
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!(
+            "{} of {} chunks weren't uploaded, the upload can be resumed with --resume",
+            failed,
+            journal.chunks.len()
+        );
+    }
+
+    tokio::fs::remove_file(journal_path).await?;
+    println!(
+        "Uploaded {} ({} bytes in {} chunks)",
+        journal.filename,
+        journal.file_size,
+        journal.chunks.len()
+    );
+    Ok(())
+}
+
+async fn place_file(
+    metadata_server: &MetadataServerExternalClient,
+    local_path: &Path,
+    filename: String,
+    file_size: u64,
+    modified: SystemTime,
+    replication: Replication,
+    chunking: Chunking,
+) -> Result<UploadJournal> {
+    let file_chunks = match &chunking {
+        Chunking::Fixed => None,
+        Chunking::Content {
+            key,
+            max_chunk_size,
+        } => Some(
+            split_file(local_path, key, *max_chunk_size)
+                .await
+                .with_context(|| format!("Couldn't split {}", local_path.display()))?,
+        ),
+    };
+
+    let placement = metadata_server
+        .place_file(ChunkPlacementRequestPayload {
+            filename: filename.clone(),
+            file_size: file_size as usize,
+            replication,
+            chunks: file_chunks
+                .as_ref()
+                .map(|chunks| chunks.iter().map(|chunk| chunk.digest).collect()),
+            versioning: None,
+        })
+        .await
+        .context("Couldn't place the file")?;
+
+    // Offsets and lengths of the chunks in the local file.
+    let extents: Vec<(u64, u64)> = match file_chunks {
+        Some(chunks) => chunks
+            .iter()
+            .map(|chunk| (chunk.offset, chunk.digest.size))
+            .collect(),
+        None => {
+            let chunk_size = placement
+                .chunk_size
+                .context("Metadata server didn't report the size of the chunks")?;
+            (0..placement.selected_chunkservers.len() as u64)
+                .map(|idx| idx * chunk_size)
+                .map(|offset| (offset, chunk_size.min(file_size - offset)))
+                .collect()
+        }
+    };
+    if extents.len() != placement.selected_chunkservers.len() {
+        bail!("Metadata server placed a different number of chunks");
+    }
+
+    let chunks: Vec<_> = placement
+        .selected_chunkservers
+        .into_iter()
+        .zip(extents)
+        .map(|(locations, (offset, length))| UploadedChunk {
+            // Deduplicated chunks are already stored.
+            confirmed: locations.stored,
+            locations,
+            offset,
+            length,
+        })
+        .collect();
+    if let Chunking::Content { .. } = chunking {
+        let stored = chunks.iter().filter(|chunk| chunk.confirmed).count();
+        println!(
+            "{} of {} chunks of {} are already stored",
+            stored,
+            chunks.len(),
+            filename
+        );
+    }
+
+    Ok(UploadJournal {
+        filename,
+        request_id: Some(current_request_id()),
+        file_size,
+        modified,
+        chunks,
+    })
+}
+
+/// Uploads the chunk to its primary, or to one of its alternates if that fails.
+async fn upload_chunk(
+    metadata_server: &MetadataServerExternalClient,
+    locations: &ChunkLocations,
+    local_path: &Path,
+    offset: u64,
+    length: u64,
+    endpoint: &Endpoint,
+    scheduler: &TransferScheduler,
+) -> Result<()> {
+    if let Some(stripes) = &locations.stripes {
+        return scheduler
+            .transfer(
+                locations.transfer_servers(),
+                length,
+                |connections| async move {
+                    let mut file = tokio::fs::File::open(local_path).await?;
+                    file.seek(SeekFrom::Start(offset)).await?;
+                    let mut chunk = vec![0; length as usize];
+                    file.read_exact(&mut chunk).await?;
+
+                    stripes.upload(&chunk, endpoint, &connections).await
+                },
+            )
+            .await;
+    }
+
+    let primary = locations
+        .primary
+        .as_ref()
+        .context("Chunk isn't assigned to any chunkserver")?;
+    let send = async |location: &ChunkserverLocation| {
+        scheduler
+            .transfer([location.server_location], length, |connections| {
+                location
+                    .clone()
+                    .with_metadata(local_path.to_path_buf(), offset, length)
+                    .send(endpoint.clone(), connections)
+            })
+            .await
+    };
+
+    let mut error = match send(primary).await {
+        Ok(_) => return Ok(()),
+        Err(e) => e,
+    };
+    let mut failed = primary;
+    for alternate in locations.alternates.iter() {
+        warn!(
+            chunk_id = %locations.chunk_id,
+            server = %failed.server_location,
+            error = ?error,
+            "Couldn't upload chunk, retrying on an alternate"
+        );
+        if let Err(e) = send(alternate).await {
+            (failed, error) = (alternate, e);
+            continue;
+        }
+
+        // Otherwise the metadata server would learn where the chunk is only from heartbeats.
+        metadata_server
+            .report_upload_failover(UploadFailoverPayload {
+                chunk_id: locations.chunk_id,
+                failed: primary.server_location,
+                stored_on: alternate.server_location,
+            })
+            .await
+            .context("Couldn't report the chunkserver which stored the chunk")?;
+        return Ok(());
+    }
+
+    Err(error)
+}
@@ -1,10 +1,14 @@
-use crate::common::UploadChunkPayload;
 use crate::common::messages::chunk_transfer::ChunkTransfer;
-use crate::common::messages::messages::{ChunkserverExternalMessage, Message};
+use crate::common::messages::messages::ChunkserverExternalClient;
+use crate::common::protocol;
+use crate::common::rpc::RpcError;
 use crate::common::types::{ChunkId, Hostname, ServerConnections, ServerLocation};
+use crate::common::{ErrorCode, UploadChunkPayload, WriteChunkPayload};
+use anyhow::Context;
 use quinn::{Connection, Endpoint};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::sync::Arc;
 use uuid::Uuid;
 
 #[derive(Debug)]
@@ -12,66 +16,111 @@ pub struct SendChunkMetadata {
     chunk_id: ChunkId,
     server_location: ServerLocation,
     server_hostname: Hostname,
-    offset: u64,
     chunk_size: u64,
-    file_path: PathBuf,
+    chunk_transfer: ChunkTransfer,
 }
 
 impl SendChunkMetadata {
-    async fn send(
+    pub async fn send(
         self,
         endpoint: Endpoint,
         connections: ServerConnections,
     ) -> anyhow::Result<ChunkId> {
-        let conn = connections
-            .try_get_with(self.server_location, async {
-                self.connect_to_server(&endpoint).await
-            })
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to connect: {}", e))?;
+        let conn = chunkserver_connection(
+            &endpoint,
+            &connections,
+            self.server_location,
+            &self.server_hostname,
+        )
+        .await?;
 
-        if conn.close_reason().is_some() {
-            connections.invalidate(&self.server_location).await;
+        self.send_chunk(conn).await
+    }
 
-            // TODO: right now we only try to reconnect one time. In future, the metadataserver
-            // TODO: might return some number of backup chunkservers, which will be used for in case of errors.
-            let new_conn = self.connect_to_server(&endpoint).await?;
-            connections
-                .insert(self.server_location, new_conn.clone())
-                .await;
+    /// Writes the data into the stored chunk at `chunk_offset`, through the chunk's primary.
+    pub async fn write_at(
+        self,
+        chunk_offset: u64,
+        endpoint: Endpoint,
+        connections: ServerConnections,
+    ) -> anyhow::Result<ChunkId> {
+        let conn = chunkserver_connection(
+            &endpoint,
+            &connections,
+            self.server_location,
+            &self.server_hostname,
+        )
+        .await?;
 
-            return self.send_chunk(new_conn).await;
-        }
+        let payload = WriteChunkPayload {
+            chunk_id: self.chunk_id,
+            offset: chunk_offset,
+            chunk_size: self.chunk_size,
+            version: 0,
+            chunk_transfer: self.chunk_transfer,
+        };
 
-        self.send_chunk(conn).await
-    }
+        ChunkserverExternalClient::new(conn)
+            .write_chunk(payload)
+            .await
+            .with_context(|| format!("Write to chunk {} failed", self.chunk_id))?;
 
-    async fn connect_to_server(&self, endpoint: &Endpoint) -> anyhow::Result<Connection> {
-        let connecting = endpoint.connect(self.server_location, &self.server_hostname)?;
-        let conn = connecting.await?;
-        Ok(conn)
+        Ok(self.chunk_id)
     }
 
     async fn send_chunk(self, conn: Connection) -> anyhow::Result<ChunkId> {
         let payload = UploadChunkPayload {
             chunk_id: self.chunk_id,
             chunk_size: self.chunk_size,
-            chunk_transfer: ChunkTransfer {
-                offset: Some(self.offset),
-                data: self.file_path,
-            },
+            version: 0,
+            chunk_transfer: self.chunk_transfer,
         };
 
-        let message = ChunkserverExternalMessage::UploadChunk(payload);
-        let (mut send, mut recv) = conn.open_bi().await?;
-
-        message.send(&mut send).await?;
-        send.finish()?;
+        match ChunkserverExternalClient::new(conn)
+            .upload_chunk(payload)
+            .await
+        {
+            Ok(()) => {}
+            // Sent again, e.g. by a resumed upload, after the response to the first send was lost.
+            Err(RpcError::Status(error)) if error.code == ErrorCode::ChunkAlreadyUploaded => {}
+            Err(e) => {
+                return Err(anyhow::Error::from(e))
+                    .with_context(|| format!("Chunk {} upload failed", self.chunk_id));
+            }
+        }
 
         Ok(self.chunk_id)
     }
 }
 
+/// Returns the connection to the chunkserver kept in `connections`,
+/// connecting to the chunkserver if there is none or it has been closed.
+pub(crate) async fn chunkserver_connection(
+    endpoint: &Endpoint,
+    connections: &ServerConnections,
+    server_location: ServerLocation,
+    server_hostname: &str,
+) -> anyhow::Result<Connection> {
+    let conn = connections
+        .try_get_with(server_location, async {
+            protocol::connect(endpoint, server_location, server_hostname).await
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to connect: {}", e))?;
+
+    if conn.close_reason().is_some() {
+        connections.invalidate(&server_location).await;
+
+        // Reconnects only once, uploads which still fail are retried on the chunk's alternates.
+        let new_conn = protocol::connect(endpoint, server_location, server_hostname).await?;
+        connections.insert(server_location, new_conn.clone()).await;
+
+        return Ok(new_conn);
+    }
+
+    Ok(conn)
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ChunkserverLocation {
     pub chunk_id: ChunkId,
@@ -88,18 +137,35 @@ impl ChunkserverLocation {
         }
     }
 
-    fn with_file_path(self, file_path: PathBuf, chunk_size: u64) -> SendChunkMetadata {
+    pub fn with_file_path(self, file_path: PathBuf, chunk_size: u64) -> SendChunkMetadata {
         self.with_metadata(file_path, 0, chunk_size)
     }
 
-    fn with_metadata(self, file_path: PathBuf, offset: u64, chunk_size: u64) -> SendChunkMetadata {
+    pub fn with_metadata(
+        self,
+        file_path: PathBuf,
+        offset: u64,
+        chunk_size: u64,
+    ) -> SendChunkMetadata {
+        self.with_transfer(
+            chunk_size,
+            ChunkTransfer::from_file(file_path, Some(offset)),
+        )
+    }
+
+    /// Prepares sending a chunk held in memory, e.g. a stripe of an erasure-coded chunk.
+    pub fn with_bytes(self, bytes: impl Into<Arc<[u8]>>) -> SendChunkMetadata {
+        let bytes = bytes.into();
+        self.with_transfer(bytes.len() as u64, ChunkTransfer::from_bytes(bytes))
+    }
+
+    fn with_transfer(self, chunk_size: u64, chunk_transfer: ChunkTransfer) -> SendChunkMetadata {
         SendChunkMetadata {
             chunk_id: self.chunk_id,
             server_location: self.server_location,
             server_hostname: self.server_hostname,
-            file_path,
-            offset,
             chunk_size,
+            chunk_transfer,
         }
     }
 }
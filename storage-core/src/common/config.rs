@@ -1,13 +1,84 @@
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
-use std::sync::OnceLock;
+use std::sync::{OnceLock, RwLock};
 use std::time::Duration;
 
 pub static TMP_STORAGE_ROOT: OnceLock<PathBuf> = OnceLock::new();
 pub static FINAL_STORAGE_ROOT: OnceLock<PathBuf> = OnceLock::new();
 
-pub const MAX_CHUNK_SIZE: usize = 1024 * 1024 * 64; // 64 MB
-pub const N_CHUNK_REPLICAS: usize = 2;
-pub const MAX_SPAWNED_TASKS: usize = 16;
-pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
-pub const HEARTBEAT_MARGIN: Duration = Duration::from_secs(10);
-pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// Maximum size of an encoded message, e.g. a request or an error.
+pub const MAX_MESSAGE_SIZE: u32 = 1024 * 64; // 64 KB
+/// Maximum size of an encoded message listing chunks or chunkservers, e.g. a heartbeat.
+pub const MAX_LIST_MESSAGE_SIZE: u32 = 1024 * 1024 * 64; // 64 MB
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const METRICS_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+/// How often certificate files are checked for changes, they're also reloaded on `SIGHUP`.
+pub const CERTIFICATE_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+/// How long requests in progress are allowed to run after a shutdown has been requested.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Settings which have to be the same on all servers of the cluster.
+/// The metadata server is configured with them and sends them to chunkservers on discovery.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClusterConfig {
+    /// Size of the chunks files are split into, in bytes.
+    pub max_chunk_size: u64,
+    /// Number of copies of every chunk stored besides the primary one.
+    pub n_chunk_replicas: usize,
+    /// How often chunkservers send heartbeats to the metadata server.
+    pub heartbeat_interval: Duration,
+}
+
+impl ClusterConfig {
+    pub const DEFAULT: ClusterConfig = ClusterConfig {
+        max_chunk_size: 1024 * 1024 * 64, // 64 MB
+        n_chunk_replicas: 2,
+        heartbeat_interval: Duration::from_secs(60),
+    };
+
+    pub fn validate(&self) -> Result<()> {
+        if self.max_chunk_size == 0 {
+            bail!("Maximum chunk size has to be positive");
+        }
+        if self.heartbeat_interval.is_zero() {
+            bail!("Heartbeat interval has to be positive");
+        }
+        Ok(())
+    }
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig::DEFAULT
+    }
+}
+
+static CLUSTER_CONFIG: RwLock<ClusterConfig> = RwLock::new(ClusterConfig::DEFAULT);
+
+/// Returns the configuration of the cluster, [`ClusterConfig::DEFAULT`] until it's set.
+pub fn cluster_config() -> ClusterConfig {
+    *CLUSTER_CONFIG.read().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn set_cluster_config(config: ClusterConfig) {
+    *CLUSTER_CONFIG.write().unwrap_or_else(|e| e.into_inner()) = config;
+}
+
+tokio::task_local! {
+    static MAX_RECEIVED_CHUNK_SIZE: u64;
+}
+
+/// Largest chunk accepted from a peer: the one given to [`with_max_received_chunk_size`]
+/// for the current task, or the maximum chunk size of the cluster.
+pub fn max_received_chunk_size() -> u64 {
+    MAX_RECEIVED_CHUNK_SIZE
+        .try_with(|size| *size)
+        .unwrap_or_else(|_| cluster_config().max_chunk_size)
+}
+
+/// Runs the future accepting chunks of up to `size` bytes instead of the maximum chunk size
+/// of the cluster, e.g. the size of a requested chunk known from the file's placement.
+pub async fn with_max_received_chunk_size<F: Future>(size: u64, f: F) -> F::Output {
+    MAX_RECEIVED_CHUNK_SIZE.scope(size, f).await
+}
@@ -0,0 +1,113 @@
+//! Configuration files of the servers, layered under their command line flags.
+//!
+//! A configuration file is in TOML and sets flags by their long names, e.g.
+//! ```toml
+//! metrics-addr = "[::1]:9100"
+//! heartbeat-interval = "10s"
+//! ```
+//! Flags given on the command line take precedence over the file, the file takes precedence
+//! over the defaults. Values are validated the same way as on the command line.
+
+use anyhow::{Context, Result, bail};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Parser};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use toml::Value;
+
+/// Id of the flag passing the configuration file, `--config`.
+pub const CONFIG_ARG: &str = "config";
+
+/// Parses the command line, taking the flags which aren't given on it
+/// from the configuration file passed with `--config`. Exits on invalid flags, like `parse`.
+pub fn parse_with_config_file<T: Parser>() -> Result<T> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let command = T::command();
+    // Flags required by clap may be set only in the file, so errors are reported after it's read.
+    let matches = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+        .unwrap_or_else(|e| e.exit());
+
+    let Some(path) = matches.get_one::<PathBuf>(CONFIG_ARG) else {
+        return Ok(T::parse_from(args));
+    };
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Couldn't read configuration file {}", path.display()))?;
+    let table: toml::Table = contents
+        .parse()
+        .with_context(|| format!("Malformed configuration file {}", path.display()))?;
+
+    let file_args = table_to_args(&command, &matches, table)
+        .with_context(|| format!("Invalid configuration file {}", path.display()))?;
+
+    let mut args = args.into_iter();
+    let layered: Vec<OsString> = args
+        .next()
+        .into_iter()
+        .chain(file_args)
+        .chain(args)
+        .collect();
+    let matches = command
+        .try_get_matches_from(layered)
+        .unwrap_or_else(|e| e.exit());
+
+    Ok(T::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()))
+}
+
+/// Turns the settings of the file into flags, skipping the ones given on the command line
+/// or conflicting with them.
+fn table_to_args(
+    command: &clap::Command,
+    matches: &ArgMatches,
+    table: toml::Table,
+) -> Result<Vec<OsString>> {
+    let given_on_command_line =
+        |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    let mut args = Vec::new();
+    for (key, value) in table {
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()))
+        else {
+            bail!("Unknown setting {}", key);
+        };
+        if arg.get_id() == CONFIG_ARG {
+            bail!("Configuration files can't be nested");
+        }
+
+        if given_on_command_line(arg.get_id().as_str())
+            || command
+                .get_arg_conflicts_with(arg)
+                .iter()
+                .any(|other| given_on_command_line(other.get_id().as_str()))
+        {
+            continue;
+        }
+
+        let values = match value {
+            Value::Boolean(set) if matches!(arg.get_action(), ArgAction::SetTrue) => {
+                if set {
+                    args.push(format!("--{}", key).into());
+                }
+                continue;
+            }
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                Value::String(value) => value,
+                Value::Integer(value) => value.to_string(),
+                Value::Float(value) => value.to_string(),
+                Value::Boolean(value) => value.to_string(),
+                _ => bail!("Setting {} has to be a string, a number or a boolean", key),
+            };
+            args.push(format!("--{}={}", key, value).into());
+        }
+    }
+
+    Ok(args)
+}
@@ -0,0 +1,133 @@
+//! Content-defined deduplication of chunks.
+//!
+//! Clients split files into chunks at boundaries found in their content with FastCDC,
+//! so that data inserted into a file changes only the chunks around it, and address every
+//! chunk by its hash keyed with a [`DedupKey`]. The metadata server keeps an index of the
+//! stored chunks by their hashes and answers placement requests with the chunks it already
+//! stores, which clients don't upload again.
+//!
+//! The key belongs to a user or tenant and is shared by its clients, so the same content
+//! hashes differently for every tenant and the hashes don't reveal it to the others. This
+//! keeps deduplication compatible with client-side encryption, as long as chunks are
+//! encrypted deterministically with a key of the same tenant.
+
+use crate::common::types::{ChunkDigest, ContentHash};
+use anyhow::{Result, bail};
+use fastcdc::v2020::{self, FastCDC, Normalization, StreamCDC};
+use std::fmt;
+use std::path::Path;
+
+/// Context of the keys derived with [`DedupKey::derive`], must never change.
+const KEY_CONTEXT: &str = "storage-core 2026-10 chunk deduplication key";
+
+/// Secret key of a user or tenant, which the hashes of its chunks are keyed with.
+#[derive(Clone)]
+pub struct DedupKey([u8; 32]);
+
+impl DedupKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        DedupKey(key)
+    }
+
+    /// Derives the key from a secret of the tenant, e.g. the key its chunks are encrypted with.
+    pub fn derive(secret: &[u8]) -> Self {
+        DedupKey(blake3::derive_key(KEY_CONTEXT, secret))
+    }
+
+    pub fn hash(&self, chunk: &[u8]) -> ContentHash {
+        ContentHash(*blake3::keyed_hash(&self.0, chunk).as_bytes())
+    }
+
+    /// Seed of the gear table, so that the chunk boundaries depend on the key as well.
+    fn gear_seed(&self) -> u64 {
+        let hash = self.hash(b"gear seed");
+        u64::from_le_bytes(hash.0[..8].try_into().unwrap())
+    }
+}
+
+impl fmt::Debug for DedupKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DedupKey(..)")
+    }
+}
+
+/// Chunk of a file, see [`split`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileChunk {
+    /// Offset of the chunk in the file.
+    pub offset: u64,
+    pub digest: ChunkDigest,
+}
+
+/// Minimum, average and maximum size of the chunks. Chunks are at most `max_chunk_size`
+/// bytes and 16 MB, as FastCDC doesn't find boundaries in larger windows.
+fn chunk_sizes(max_chunk_size: u64) -> Result<(u32, u32, u32)> {
+    if max_chunk_size < v2020::MAXIMUM_MIN as u64 {
+        bail!(
+            "Chunks of at most {} bytes are too small to be split by content",
+            max_chunk_size
+        );
+    }
+
+    let max = max_chunk_size.min(v2020::MAXIMUM_MAX as u64) as u32;
+    let avg = (max / 4).clamp(v2020::AVERAGE_MIN, v2020::AVERAGE_MAX);
+    let min = (avg / 4).clamp(v2020::MINIMUM_MIN, v2020::MINIMUM_MAX);
+    Ok((min, avg, max))
+}
+
+/// Splits the data into chunks of at most `max_chunk_size` bytes and hashes them.
+pub fn split(data: &[u8], key: &DedupKey, max_chunk_size: u64) -> Result<Vec<FileChunk>> {
+    let (min, avg, max) = chunk_sizes(max_chunk_size)?;
+    let chunker =
+        FastCDC::with_level_and_seed(data, min, avg, max, Normalization::Level1, key.gear_seed());
+
+    Ok(chunker
+        .map(|chunk| {
+            let content = &data[chunk.offset..chunk.offset + chunk.length];
+            FileChunk {
+                offset: chunk.offset as u64,
+                digest: ChunkDigest {
+                    size: chunk.length as u64,
+                    hash: key.hash(content),
+                },
+            }
+        })
+        .collect())
+}
+
+/// Same as [`split`], but reads the file in chunks instead of as a whole.
+pub async fn split_file(
+    path: impl AsRef<Path>,
+    key: &DedupKey,
+    max_chunk_size: u64,
+) -> Result<Vec<FileChunk>> {
+    let path = path.as_ref().to_path_buf();
+    let key = key.clone();
+    let (min, avg, max) = chunk_sizes(max_chunk_size)?;
+
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(&path)?;
+        let chunker = StreamCDC::with_level_and_seed(
+            file,
+            min,
+            avg,
+            max,
+            Normalization::Level1,
+            key.gear_seed(),
+        );
+
+        chunker
+            .map(|chunk| {
+                let chunk = chunk?;
+                Ok(FileChunk {
+                    offset: chunk.offset,
+                    digest: ChunkDigest {
+                        size: chunk.length as u64,
+                        hash: key.hash(&chunk.data),
+                    },
+                })
+            })
+            .collect()
+    })
+    .await?
+}
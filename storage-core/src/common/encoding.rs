@@ -0,0 +1,83 @@
+//! Encoding of payloads and of metadata stored on disk.
+//!
+//! Every encoded value starts with the version of the encoding, followed by the value in CBOR.
+//! Structs are encoded as maps keyed by field names and enum variants by their names,
+//! so that schemas can evolve without breaking peers and files written by older versions:
+//! * new fields have to be an `Option` or have `#[serde(default)]`,
+//! * unknown fields are ignored, so older versions accept values written by newer ones,
+//! * fields and variants must never be renamed or change their meaning,
+//! * required fields may only be removed once no supported version requires them.
+//!
+//! Compatibility with older versions is checked by decoding the fixtures in `tests/fixtures`.
+
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::path::Path;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// Current version of the encoding.
+pub const ENCODING_VERSION: u8 = 1;
+/// Oldest version of the encoding which can still be decoded.
+pub const MIN_ENCODING_VERSION: u8 = 1;
+
+/// Encodes the value, failing if it exceeds `limit` bytes.
+pub fn encode<T: Serialize>(value: &T, limit: u32) -> Result<Vec<u8>> {
+    let mut bytes = vec![ENCODING_VERSION];
+    ciborium::into_writer(value, &mut bytes)?;
+    if bytes.len() > limit as usize {
+        bail!(
+            "Encoded value of {} bytes exceeds the limit of {} bytes",
+            bytes.len(),
+            limit
+        );
+    }
+
+    Ok(bytes)
+}
+
+/// Decodes a value encoded with any supported version.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    let Some((&version, mut value)) = bytes.split_first() else {
+        bail!("Empty encoded value");
+    };
+    if !(MIN_ENCODING_VERSION..=ENCODING_VERSION).contains(&version) {
+        bail!(
+            "Unsupported encoding version {}, expected {}..={}",
+            version,
+            MIN_ENCODING_VERSION,
+            ENCODING_VERSION
+        );
+    }
+
+    let decoded = ciborium::from_reader(&mut value)?;
+    if !value.is_empty() {
+        bail!("{} unexpected bytes after the encoded value", value.len());
+    }
+
+    Ok(decoded)
+}
+
+/// Writes the value to the file, replacing it only once the value has been completely written.
+pub async fn write_file<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let bytes = encode(value, u32::MAX)?;
+
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp_path)
+        .await
+        .with_context(|| format!("Couldn't create {}", tmp_path.display()))?;
+    file.write_all(&bytes).await?;
+    file.sync_all().await?;
+    fs::rename(&tmp_path, path).await?;
+
+    Ok(())
+}
+
+/// Reads the value from a file written with [`write_file`].
+pub async fn read_file<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let bytes = fs::read(path)
+        .await
+        .with_context(|| format!("Couldn't read {}", path.display()))?;
+    decode(&bytes).with_context(|| format!("Malformed {}", path.display()))
+}
@@ -0,0 +1,190 @@
+//! Erasure coding of chunks with Reed-Solomon codes.
+//!
+//! A chunk of an erasure-coded file is split into `data_stripes` stripes of equal size,
+//! the last one padded with zeros, from which `parity_stripes` stripes are computed.
+//! Every stripe is stored as a chunk of its own on a different chunkserver, and the chunk
+//! can be decoded from any `data_stripes` of its stripes. Clients encode chunks before
+//! uploading them and decode them after downloading.
+
+use crate::common::chunk_send::chunkserver_connection;
+use crate::common::types::{ErasureCoding, ServerConnections, StripeLocations};
+use crate::common::{ChunkserverExternalClient, ChunkserverLocation, DownloadChunkRequestPayload};
+use anyhow::{Context, Result, bail};
+use futures::future::try_join_all;
+use futures::stream::{FuturesUnordered, StreamExt};
+use quinn::Endpoint;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use tracing::warn;
+
+fn codec(coding: ErasureCoding) -> Result<ReedSolomon> {
+    coding.validate()?;
+    Ok(ReedSolomon::new(
+        coding.data_stripes as usize,
+        coding.parity_stripes as usize,
+    )?)
+}
+
+/// Splits the chunk into data stripes and computes the parity stripes,
+/// returns the data stripes followed by the parity stripes.
+pub fn encode(coding: ErasureCoding, chunk: &[u8]) -> Result<Vec<Vec<u8>>> {
+    if chunk.is_empty() {
+        bail!("Empty chunks can't be erasure-coded");
+    }
+
+    let stripe_size = coding.stripe_size(chunk.len() as u64) as usize;
+    let mut stripes: Vec<_> = (0..coding.stripes())
+        .map(|idx| {
+            let start = (idx * stripe_size).min(chunk.len());
+            let end = ((idx + 1) * stripe_size).min(chunk.len());
+            let mut stripe = vec![0; stripe_size];
+            stripe[..end - start].copy_from_slice(&chunk[start..end]);
+            stripe
+        })
+        .collect();
+
+    codec(coding)?.encode(&mut stripes)?;
+    Ok(stripes)
+}
+
+/// Restores a chunk of `chunk_size` bytes from its stripes, None for the missing ones.
+/// At least `data_stripes` of the stripes have to be present.
+pub fn decode(
+    coding: ErasureCoding,
+    mut stripes: Vec<Option<Vec<u8>>>,
+    chunk_size: u64,
+) -> Result<Vec<u8>> {
+    let available = stripes.iter().flatten().count();
+    if available < coding.data_stripes as usize {
+        bail!(
+            "Chunk needs {} stripes to be decoded, only {} are available",
+            coding.data_stripes,
+            available
+        );
+    }
+
+    codec(coding)?.reconstruct_data(&mut stripes)?;
+
+    let mut chunk = Vec::with_capacity(chunk_size as usize);
+    for stripe in stripes.into_iter().take(coding.data_stripes as usize) {
+        chunk.extend(stripe.context("Data stripe not reconstructed")?);
+    }
+    chunk.truncate(chunk_size as usize);
+
+    Ok(chunk)
+}
+
+impl StripeLocations {
+    /// Encodes the chunk and uploads all of its stripes.
+    pub async fn upload(
+        &self,
+        chunk: &[u8],
+        endpoint: &Endpoint,
+        connections: &ServerConnections,
+    ) -> Result<()> {
+        if chunk.len() as u64 != self.chunk_size {
+            bail!(
+                "Chunk has {} bytes, {} were expected",
+                chunk.len(),
+                self.chunk_size
+            );
+        }
+
+        let stripes = encode(self.coding, chunk)?;
+        try_join_all(
+            self.stripes
+                .iter()
+                .zip(stripes)
+                .map(|(location, stripe)| async {
+                    location
+                        .clone()
+                        .context("Stripe isn't assigned to any chunkserver")?
+                        .with_bytes(stripe)
+                        .send(endpoint.clone(), connections.clone())
+                        .await
+                }),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Downloads the data stripes and decodes the chunk. Stripes which aren't available
+    /// or fail to download are reconstructed from the parity stripes.
+    pub async fn download(
+        &self,
+        endpoint: &Endpoint,
+        connections: &ServerConnections,
+    ) -> Result<Vec<u8>> {
+        let stripe_size = self.coding.stripe_size(self.chunk_size);
+        let download = |idx: usize, location: &ChunkserverLocation| {
+            let location = location.clone();
+            async move {
+                let stripe = download_stripe(&location, stripe_size, endpoint, connections).await;
+                (idx, location, stripe)
+            }
+        };
+
+        // Parity stripes are downloaded only in place of the data stripes which couldn't be.
+        let mut remaining = self
+            .stripes
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, location)| Some((idx, location.as_ref()?)));
+        let mut downloads: FuturesUnordered<_> = remaining
+            .by_ref()
+            .take(self.coding.data_stripes as usize)
+            .map(|(idx, location)| download(idx, location))
+            .collect();
+
+        let mut stripes = vec![None; self.stripes.len()];
+        while let Some((idx, location, stripe)) = downloads.next().await {
+            match stripe {
+                Ok(stripe) => stripes[idx] = Some(stripe),
+                Err(e) => {
+                    warn!(
+                        chunk_id = %location.chunk_id,
+                        server = %location.server_location,
+                        error = ?e,
+                        "Couldn't download stripe"
+                    );
+                    if let Some((idx, location)) = remaining.next() {
+                        downloads.push(download(idx, location));
+                    }
+                }
+            }
+        }
+
+        decode(self.coding, stripes, self.chunk_size)
+    }
+}
+
+async fn download_stripe(
+    location: &ChunkserverLocation,
+    stripe_size: u64,
+    endpoint: &Endpoint,
+    connections: &ServerConnections,
+) -> Result<Vec<u8>> {
+    let conn = chunkserver_connection(
+        endpoint,
+        connections,
+        location.server_location,
+        &location.server_hostname,
+    )
+    .await?;
+
+    let response = ChunkserverExternalClient::new(conn)
+        .download_chunk(DownloadChunkRequestPayload {
+            chunk_id: location.chunk_id,
+            range: None,
+        })
+        .await?;
+    if response.chunk_size != stripe_size {
+        bail!(
+            "Stripe has {} bytes, {} were expected",
+            response.chunk_size,
+            stripe_size
+        );
+    }
+
+    Ok(tokio::fs::read(&response.chunk_transfer.data).await?)
+}
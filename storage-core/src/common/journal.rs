@@ -0,0 +1,138 @@
+//! Journals of client uploads and downloads, which make them resumable.
+//!
+//! An upload is placed by the metadata server once, the journal keeps the placement and the
+//! chunks confirmed by the chunkservers, so that a resumed upload sends only the remaining
+//! chunks to the chunkservers they were placed on. The journal of a download keeps the hash
+//! of every chunk written to the local file, a resumed download skips the chunks which are
+//! still in the file with the same content.
+//!
+//! Journals are kept next to the transferred local file and removed once the transfer is done.
+
+use crate::common::encoding;
+use crate::common::telemetry::RequestId;
+use crate::common::types::{ChunkId, ChunkLocations, ContentHash};
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Chunk of the local file placed by the metadata server.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadedChunk {
+    pub locations: ChunkLocations,
+    /// Position of the chunk in the local file.
+    pub offset: u64,
+    pub length: u64,
+    /// Whether the chunkservers stored the chunk.
+    pub confirmed: bool,
+}
+
+/// Upload of a local file, as placed by the metadata server.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadJournal {
+    pub filename: String,
+    /// Id of the request which placed the file. A resumed upload runs with it, as the
+    /// metadata server accepts the failovers of the chunks only from that request.
+    /// None in journals written by clients which didn't keep it.
+    #[serde(default)]
+    pub request_id: Option<RequestId>,
+    /// Size and modification time of the local file, which mustn't change between attempts.
+    pub file_size: u64,
+    pub modified: SystemTime,
+    pub chunks: Vec<UploadedChunk>,
+}
+
+impl UploadJournal {
+    /// Default path of the journal of an upload of the local file.
+    pub fn path_for(local_path: &Path) -> PathBuf {
+        journal_path(local_path, "upload")
+    }
+
+    pub async fn load(path: &Path) -> Result<Self> {
+        encoding::read_file(path).await
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        encoding::write_file(path, self).await
+    }
+
+    /// Checks that the journal belongs to an upload of the same local file to `filename`.
+    pub fn check_matches(
+        &self,
+        filename: &str,
+        file_size: u64,
+        modified: SystemTime,
+    ) -> Result<()> {
+        if self.filename != filename {
+            bail!(
+                "Journal belongs to the upload of {}, not {}",
+                self.filename,
+                filename
+            );
+        }
+        if self.file_size != file_size || self.modified != modified {
+            bail!("Local file changed since the upload started");
+        }
+        Ok(())
+    }
+
+    pub fn remaining(&self) -> impl Iterator<Item = (usize, &UploadedChunk)> {
+        self.chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, chunk)| !chunk.confirmed)
+    }
+}
+
+/// Chunk of the remote file written to the local file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadedChunk {
+    pub chunk_id: ChunkId,
+    pub size: u64,
+    /// Hash of the chunk's content as written to the local file.
+    pub hash: ContentHash,
+}
+
+/// Download of a remote file into a local file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadJournal {
+    pub filename: String,
+    /// Chunks by their position in the file, None for the ones which aren't downloaded.
+    pub chunks: Vec<Option<DownloadedChunk>>,
+}
+
+impl DownloadJournal {
+    /// Default path of the journal of a download into the local file.
+    pub fn path_for(local_path: &Path) -> PathBuf {
+        journal_path(local_path, "download")
+    }
+
+    pub async fn load(path: &Path) -> Result<Self> {
+        encoding::read_file(path).await
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        encoding::write_file(path, self).await
+    }
+
+    /// Returns the chunk downloaded at the position, if it's the same chunk of the same size.
+    /// The content of the chunk in the local file still has to be verified against its hash.
+    pub fn downloaded(&self, idx: usize, chunk_id: ChunkId, size: u64) -> Option<&DownloadedChunk> {
+        self.chunks
+            .get(idx)?
+            .as_ref()
+            .filter(|chunk| chunk.chunk_id == chunk_id && chunk.size == size)
+    }
+}
+
+/// Hash of the content of a downloaded chunk, not keyed unlike the hashes of deduplication.
+pub fn content_hash(data: &[u8]) -> ContentHash {
+    ContentHash(*blake3::hash(data).as_bytes())
+}
+
+fn journal_path(local_path: &Path, kind: &str) -> PathBuf {
+    let mut name = local_path.as_os_str().to_owned();
+    // Encoding replaces the last extension with `tmp` while writing the journal.
+    name.push(format!(".{}.journal", kind));
+    PathBuf::from(name)
+}
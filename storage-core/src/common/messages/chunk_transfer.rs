@@ -1,23 +1,61 @@
-use crate::common::config::TMP_STORAGE_ROOT;
+use crate::common::config::{TMP_STORAGE_ROOT, max_received_chunk_size};
 use crate::common::types::ChunkId;
-use quinn::{RecvStream, SendStream};
+use std::fmt;
 use std::io::SeekFrom;
 use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use uuid::Uuid;
 
-#[derive(Debug, Default)]
+/// Size of the buffer used to write a received chunk to disk.
+const WRITE_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Default)]
 pub struct ChunkTransfer {
     pub offset: Option<u64>,
     pub data: PathBuf,
+    /// Chunk kept in memory instead of a file, e.g. a stripe computed by the client.
+    bytes: Option<Arc<[u8]>>,
+    /// Set for chunks received from a peer, which live in a temporary file
+    /// that is removed once the transfer is dropped.
+    received: bool,
 }
 
 impl ChunkTransfer {
-    pub(crate) async fn send_chunk(
+    /// Creates a transfer of a chunk stored in `data` (starting at `offset`).
+    /// The file is left untouched when the transfer is dropped.
+    pub fn from_file(data: PathBuf, offset: Option<u64>) -> Self {
+        ChunkTransfer {
+            offset,
+            data,
+            bytes: None,
+            received: false,
+        }
+    }
+
+    /// Creates a transfer of a chunk held in memory.
+    pub fn from_bytes(bytes: impl Into<Arc<[u8]>>) -> Self {
+        ChunkTransfer {
+            offset: None,
+            data: PathBuf::new(),
+            bytes: Some(bytes.into()),
+            received: false,
+        }
+    }
+
+    pub(crate) async fn send_chunk<W: AsyncWrite + Unpin + Send>(
         &self,
         chunk_size: u64,
-        send: &mut SendStream,
+        send: &mut W,
     ) -> anyhow::Result<()> {
+        if let Some(bytes) = &self.bytes {
+            let Some(chunk) = bytes.get(..chunk_size as usize) else {
+                anyhow::bail!("Chunk read to few bytes");
+            };
+            return Ok(send.write_all(chunk).await?);
+        }
+
         let mut file = tokio::fs::File::open(&self.data).await?;
 
         if let Some(offset) = self.offset {
@@ -35,32 +73,68 @@ impl ChunkTransfer {
         Ok(())
     }
 
-    pub(crate) async fn recv_chunk(
+    pub(crate) async fn recv_chunk<R: AsyncRead + Unpin + Send>(
         chunk_id: ChunkId,
         chunk_size: u64,
-        recv: &mut RecvStream,
+        recv: &mut R,
     ) -> anyhow::Result<Self> {
+        let max_chunk_size = max_received_chunk_size();
+        if chunk_size > max_chunk_size {
+            anyhow::bail!(
+                "Chunk of {} bytes exceeds the limit of {} bytes",
+                chunk_size,
+                max_chunk_size
+            );
+        }
+
+        // Named uniquely, as several writes into the same chunk may be received at once.
         let data = TMP_STORAGE_ROOT
             .get()
             .expect("Temporary storage not initialized via config")
-            .join(chunk_id.to_string());
+            .join(format!("{}-{}", chunk_id, Uuid::new_v4()));
+
+        // Created before receiving, so that an incomplete chunk is removed.
+        let transfer = ChunkTransfer {
+            offset: None,
+            data,
+            bytes: None,
+            received: true,
+        };
 
-        let file = File::create(&data).await?;
+        let file = File::create(&transfer.data).await?;
         file.set_len(chunk_size).await?;
-        let mut writer = BufWriter::with_capacity(chunk_size as usize, file);
+        let mut writer = BufWriter::with_capacity(WRITE_BUFFER_SIZE, file);
         let mut limited_recv = recv.take(chunk_size);
-        tokio::io::copy(&mut limited_recv, &mut writer).await?;
+        let bytes_received = tokio::io::copy(&mut limited_recv, &mut writer).await?;
+        if bytes_received < chunk_size {
+            anyhow::bail!(
+                "Chunk ended after {} of {} bytes",
+                bytes_received,
+                chunk_size
+            );
+        }
         writer.flush().await?;
 
         writer.into_inner().sync_all().await?;
 
-        Ok(ChunkTransfer { data, offset: None })
+        Ok(transfer)
+    }
+}
+
+impl fmt::Debug for ChunkTransfer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChunkTransfer")
+            .field("offset", &self.offset)
+            .field("data", &self.data)
+            .field("bytes", &self.bytes.as_ref().map(|bytes| bytes.len()))
+            .field("received", &self.received)
+            .finish()
     }
 }
 
 impl Drop for ChunkTransfer {
     fn drop(&mut self) {
-        if self.data.exists() {
+        if self.received && self.data.exists() {
             let _ = std::fs::remove_file(&self.data);
         }
     }
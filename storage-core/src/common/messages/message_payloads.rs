@@ -1,10 +1,17 @@
+use crate::common::ChunkserverLocation;
+use crate::common::config::{ClusterConfig, MAX_LIST_MESSAGE_SIZE};
 use crate::common::messages::chunk_transfer::ChunkTransfer;
-use crate::common::messages::payload::MessagePayload;
-use crate::common::types::{ChunkLocations, Hostname};
-use quinn::{RecvStream, SendStream};
+use crate::common::messages::payload::{MessagePayload, decode, encode, recv_frame};
+use crate::common::protocol::Features;
+use crate::common::types::{
+    ByteRange, ChunkDigest, ChunkLocations, ChunkStatus, ChunkserverStatus, FileVersion,
+    FsckReport, Hostname, Replication, ReplicationOrder, Retention, StoredChunk,
+};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use uuid::Uuid;
 
 type ChunkId = Uuid;
@@ -13,20 +20,22 @@ type RackId = String;
 macro_rules! impl_chunk_payload {
     ($type:ty) => {
         impl MessagePayload for $type {
-            async fn send_payload(&self, send: &mut SendStream) -> anyhow::Result<()> {
-                let metadata_bytes = bincode::serialize(&self)?;
+            async fn send_payload<W: AsyncWrite + Unpin + Send>(
+                &self,
+                send: &mut W,
+            ) -> anyhow::Result<()> {
+                let metadata_bytes = encode(self)?;
                 send.write_u32(metadata_bytes.len() as u32).await?;
                 send.write_all(&metadata_bytes).await?;
 
                 self.chunk_transfer.send_chunk(self.chunk_size, send).await
             }
 
-            async fn recv_payload(recv: &mut RecvStream) -> anyhow::Result<Self> {
-                let len = recv.read_u32().await?;
-
-                let mut buffer = vec![0u8; len as usize];
-                recv.read_exact(&mut buffer).await?;
-                let mut payload: Self = bincode::deserialize(&buffer)?;
+            async fn recv_payload<R: AsyncRead + Unpin + Send>(
+                recv: &mut R,
+            ) -> anyhow::Result<Self> {
+                let buffer = recv_frame::<Self, R>(recv).await?;
+                let mut payload: Self = decode(&buffer)?;
 
                 let chunk_transfer =
                     ChunkTransfer::recv_chunk(payload.chunk_id, payload.chunk_size, recv).await?;
@@ -48,15 +57,21 @@ pub struct ChunkServerDiscoverPayload {
     pub rack_id: RackId,
     pub internal_address: SocketAddr,
     pub external_address: SocketAddr,
-    pub stored_chunks: Vec<ChunkId>, // we will probably need to send also some other info about the chunk, not only the id
+    pub stored_chunks: Vec<StoredChunk>,
+}
+impl MessagePayload for ChunkServerDiscoverPayload {
+    const MAX_SIZE: u32 = MAX_LIST_MESSAGE_SIZE;
 }
-impl MessagePayload for ChunkServerDiscoverPayload {}
 
 /// Sent from MetadataServer to Chunkserver as a response to ChunkServerDiscoverPayload.
-/// Contains new id of the Chunkserver which has been assigned by MetadataServer.
+/// Contains the id the Chunkserver has been accepted with and the configuration of the cluster.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AcceptNewChunkServerPayload {
     pub chunkserver_new_id: Uuid,
+    /// Missing from metadata servers which don't distribute the configuration, the Chunkserver
+    /// keeps its own then.
+    #[serde(default)]
+    pub cluster_config: Option<ClusterConfig>,
 }
 impl MessagePayload for AcceptNewChunkServerPayload {}
 
@@ -70,8 +85,68 @@ pub struct HeartbeatPayload {
     pub client_requests_count: u64,
     /// Available space on the chunkserver's disk in bytes.
     pub available_space: u64,
+    /// Chunks stored by the chunkserver since last heartbeat was sent.
+    pub added_chunks: Vec<StoredChunk>,
+    /// Chunks removed from the chunkserver since last heartbeat was sent.
+    pub removed_chunks: Vec<ChunkId>,
+    /// Final heartbeat of a chunkserver which is shutting down.
+    #[serde(default)]
+    pub leaving: bool,
+}
+impl MessagePayload for HeartbeatPayload {
+    const MAX_SIZE: u32 = MAX_LIST_MESSAGE_SIZE;
+}
+
+/// Sent from the primary Chunkserver of a chunk to MetadataServer to get the lease,
+/// which the primary holds while writing to the chunk.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GrantLeaseRequestPayload {
+    pub server_id: Uuid,
+    pub chunk_id: ChunkId,
+    /// Version of the primary's copy of the chunk.
+    pub version: u64,
+}
+impl MessagePayload for GrantLeaseRequestPayload {}
+
+/// Sent from MetadataServer to Chunkserver as a response to GrantLeaseRequestPayload.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LeaseGrantedPayload {
+    /// How long the lease is held from the request.
+    pub duration: Duration,
+    /// Replicas the writes are forwarded to.
+    pub replicas: Vec<LeaseReplica>,
+}
+impl MessagePayload for LeaseGrantedPayload {}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LeaseReplica {
+    pub server_id: Uuid,
+    /// Internal address of the replica.
+    pub location: ChunkserverLocation,
+}
+
+/// Sent from the primary Chunkserver of a chunk to MetadataServer once a replica failed
+/// to apply a write, so that the replica's copy is dropped and the chunk copied again.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StaleReplicaPayload {
+    pub server_id: Uuid,
+    pub chunk_id: ChunkId,
+    pub replica_id: Uuid,
+}
+impl MessagePayload for StaleReplicaPayload {}
+
+/// Sent from MetadataServer to Chunkserver as a response to HeartbeatPayload.
+/// Contains instructions the Chunkserver has to carry out.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct HeartbeatResponsePayload {
+    /// Chunks to copy to other Chunkservers.
+    pub replicate: Vec<ReplicationOrder>,
+    /// Chunks to remove from the Chunkserver.
+    pub delete: Vec<ChunkId>,
+}
+impl MessagePayload for HeartbeatResponsePayload {
+    const MAX_SIZE: u32 = MAX_LIST_MESSAGE_SIZE;
 }
-impl MessagePayload for HeartbeatPayload {}
 
 /// Sent by Client to MetadataServer.
 /// Sends some data about the file to upload so that MetadataServer may decide
@@ -80,16 +155,39 @@ impl MessagePayload for HeartbeatPayload {}
 pub struct ChunkPlacementRequestPayload {
     pub filename: String,
     pub file_size: usize,
+    /// Number of copies of the file's chunks, the `standard` storage class if missing.
+    #[serde(default)]
+    pub replication: Replication,
+    /// Chunks the client split the file into by its content, to store every chunk only once.
+    /// The file is split into chunks of the maximum size if missing.
+    #[serde(default)]
+    pub chunks: Option<Vec<ChunkDigest>>,
+    /// Uploads the file as a new version if it's versioned, or creates a versioned file.
+    /// Previous versions are kept as long as the retention allows, replacing the retention
+    /// given before. Files uploaded without it can't be uploaded again.
+    #[serde(default)]
+    pub versioning: Option<Retention>,
+}
+impl MessagePayload for ChunkPlacementRequestPayload {
+    const MAX_SIZE: u32 = MAX_LIST_MESSAGE_SIZE;
 }
-impl MessagePayload for ChunkPlacementRequestPayload {}
 
 /// Sent by MetadataServer to Client as a response to UploadChunkServersRequestPayload.
 /// Contains list of Chunkservers (with their addresses) where the chunks have to be stored.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChunkPlacementResponsePayload {
     pub selected_chunkservers: Vec<ChunkLocations>,
+    /// Size of the chunks the file is split into, only the last chunk may be smaller.
+    /// None if the client split the file itself.
+    #[serde(default)]
+    pub chunk_size: Option<u64>,
+    /// Version created by the upload of a versioned file.
+    #[serde(default)]
+    pub version: Option<FileVersion>,
+}
+impl MessagePayload for ChunkPlacementResponsePayload {
+    const MAX_SIZE: u32 = MAX_LIST_MESSAGE_SIZE;
 }
-impl MessagePayload for ChunkPlacementResponsePayload {}
 
 /// Sent from Client to Chunkserver.
 /// Contains a Chunk to be stored on the Chunkserver.
@@ -97,16 +195,41 @@ impl MessagePayload for ChunkPlacementResponsePayload {}
 pub struct UploadChunkPayload {
     pub chunk_id: ChunkId,
     pub chunk_size: u64,
+    /// Version of a copy of a chunk which has been written to.
+    #[serde(default)]
+    pub version: u64,
     #[serde(skip)]
     pub chunk_transfer: ChunkTransfer,
 }
 impl_chunk_payload!(UploadChunkPayload);
 
+/// Sent from Client to the primary Chunkserver of a chunk, to write data into the chunk
+/// at `offset`. The primary applies the write and forwards it to the replicas.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WriteChunkPayload {
+    pub chunk_id: ChunkId,
+    pub offset: u64,
+    /// Number of bytes written.
+    pub chunk_size: u64,
+    /// Version the write brings the chunk to, set by the primary when forwarding the write.
+    #[serde(default)]
+    pub version: u64,
+    #[serde(skip)]
+    pub chunk_transfer: ChunkTransfer,
+}
+impl_chunk_payload!(WriteChunkPayload);
+
 /// Sent from Client to MetadataServer.
 /// Contains the file id, which Client wants to download.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GetFilePlacementRequestPayload {
     pub filename: String,
+    /// Version of a versioned file to fetch instead of the current one.
+    #[serde(default)]
+    pub version: Option<u64>,
+    /// Fetches the version of a versioned file which was current at the given time.
+    #[serde(default)]
+    pub as_of: Option<SystemTime>,
 }
 impl MessagePayload for GetFilePlacementRequestPayload {}
 
@@ -115,14 +238,26 @@ impl MessagePayload for GetFilePlacementRequestPayload {}
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GetFilePlacementResponsePayload {
     pub chunks_locations: Vec<ChunkLocations>,
+    /// Version of a versioned file the chunks belong to.
+    #[serde(default)]
+    pub version: Option<FileVersion>,
+    /// Sizes of the chunks in bytes, in the order of `chunks_locations`.
+    /// Empty if sent by a metadata server which doesn't report them.
+    #[serde(default)]
+    pub chunk_sizes: Vec<u64>,
+}
+impl MessagePayload for GetFilePlacementResponsePayload {
+    const MAX_SIZE: u32 = MAX_LIST_MESSAGE_SIZE;
 }
-impl MessagePayload for GetFilePlacementResponsePayload {}
 
 /// Sent from Client to ChunkServer.
 /// Contains list of chunk ids which it wants to download from the ChunkServer.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DownloadChunkRequestPayload {
     pub chunk_id: ChunkId,
+    /// Part of the chunk to download, the whole chunk if None.
+    #[serde(default)]
+    pub range: Option<ByteRange>,
 }
 impl MessagePayload for DownloadChunkRequestPayload {}
 
@@ -131,6 +266,7 @@ impl MessagePayload for DownloadChunkRequestPayload {}
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DownloadChunkResponsePayload {
     pub chunk_id: ChunkId,
+    /// Bytes sent, only the ones of the range if one was requested.
     pub chunk_size: u64,
     #[serde(skip)]
     pub chunk_transfer: ChunkTransfer,
@@ -141,11 +277,219 @@ impl_chunk_payload!(DownloadChunkResponsePayload);
 #[derive(Serialize, Deserialize, Debug)]
 pub enum RequestStatusPayload {
     Ok,
-    InvalidRequest,
-    InternalServerError,
+    Error(ErrorPayload),
 }
 impl MessagePayload for RequestStatusPayload {}
 
+impl RequestStatusPayload {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        RequestStatusPayload::Error(ErrorPayload::new(code, message))
+    }
+
+    pub fn internal_server_error() -> Self {
+        Self::error(ErrorCode::InternalServerError, "Internal server error")
+    }
+}
+
+/// Reason of a failed request.
+///
+/// Codes are sent as numbers and never change their meaning, new codes may only be added.
+/// Codes unknown to the receiver are decoded as `Unknown`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(from = "u16", into = "u16")]
+#[repr(u16)]
+pub enum ErrorCode {
+    Unknown = 0,
+    InvalidRequest = 1,
+    InternalServerError = 2,
+    NotAuthorized = 3,
+    FileAlreadyExists = 4,
+    FileNotFound = 5,
+    ChunkAlreadyUploaded = 6,
+    ChunkNotFound = 7,
+    /// The chunk exists, but none of its chunkservers can serve it at the moment.
+    ChunkUnavailable = 8,
+    OutOfSpace = 9,
+    /// Too few active chunkservers to store the data with the required number of replicas.
+    NotEnoughChunkservers = 10,
+    ChunkserverNotFound = 11,
+    /// Protocol versions or required features of the peers don't match.
+    IncompatibleProtocol = 12,
+    /// The copy of the chunk is stale, or a write doesn't follow the last one applied to it.
+    ChunkVersionMismatch = 13,
+}
+
+impl From<u16> for ErrorCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1 => ErrorCode::InvalidRequest,
+            2 => ErrorCode::InternalServerError,
+            3 => ErrorCode::NotAuthorized,
+            4 => ErrorCode::FileAlreadyExists,
+            5 => ErrorCode::FileNotFound,
+            6 => ErrorCode::ChunkAlreadyUploaded,
+            7 => ErrorCode::ChunkNotFound,
+            8 => ErrorCode::ChunkUnavailable,
+            9 => ErrorCode::OutOfSpace,
+            10 => ErrorCode::NotEnoughChunkservers,
+            11 => ErrorCode::ChunkserverNotFound,
+            12 => ErrorCode::IncompatibleProtocol,
+            13 => ErrorCode::ChunkVersionMismatch,
+            _ => ErrorCode::Unknown,
+        }
+    }
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(code: ErrorCode) -> Self {
+        code as u16
+    }
+}
+
+/// Describes why a request failed. Can be returned as an error by clients,
+/// so that the code may be checked with `anyhow::Error::downcast_ref`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorPayload {
+    pub code: ErrorCode,
+    /// Human-readable description of the error.
+    pub message: String,
+    /// Time after which the request may succeed if retried, if the error is temporary.
+    pub retry_after: Option<Duration>,
+}
+
+impl ErrorPayload {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ErrorPayload {
+            code,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+}
+
+impl fmt::Display for ErrorPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)?;
+        if let Some(retry_after) = self.retry_after {
+            write!(f, " (retry after {:?})", retry_after)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ErrorPayload {}
+
+impl From<ErrorPayload> for RequestStatusPayload {
+    fn from(error: ErrorPayload) -> Self {
+        RequestStatusPayload::Error(error)
+    }
+}
+
+/// Sent from Client to MetadataServer to create a copy of a file.
+/// The copy shares the chunks of the file, no data is copied.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CopyFilePayload {
+    pub source: String,
+    pub destination: String,
+}
+impl MessagePayload for CopyFilePayload {}
+
+/// Sent from Client to MetadataServer to create a snapshot of a directory:
+/// copies of all files under `directory`, with the directory replaced by `snapshot`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotDirectoryRequestPayload {
+    pub directory: String,
+    pub snapshot: String,
+}
+impl MessagePayload for SnapshotDirectoryRequestPayload {}
+
+/// Sent from MetadataServer to Client as a response to SnapshotDirectoryRequestPayload.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotDirectoryResponsePayload {
+    /// Number of files copied into the snapshot.
+    pub copied_files: u64,
+}
+impl MessagePayload for SnapshotDirectoryResponsePayload {}
+
+/// Sent from Client to MetadataServer to write into an existing file: to append `length`
+/// bytes to it if `offset` is None, or to overwrite `length` bytes of it at `offset`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WriteFileRequestPayload {
+    pub filename: String,
+    pub offset: Option<u64>,
+    pub length: u64,
+}
+impl MessagePayload for WriteFileRequestPayload {}
+
+/// Sent from MetadataServer to Client as a response to WriteFileRequestPayload.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WriteFileResponsePayload {
+    /// Offset in the file the data is written at, the end of the file for appends.
+    pub offset: u64,
+    /// Parts of the data written into every chunk, in the order of the data.
+    pub writes: Vec<ChunkWrite>,
+}
+impl MessagePayload for WriteFileResponsePayload {
+    const MAX_SIZE: u32 = MAX_LIST_MESSAGE_SIZE;
+}
+
+/// Part of the data of a write going into a single chunk.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChunkWrite {
+    /// Offset of the part within the written data.
+    pub offset: u64,
+    pub length: u64,
+    /// Offset within the chunk the part is written at.
+    pub chunk_offset: u64,
+    pub chunk: ChunkLocations,
+    /// Chunk added to the file by an append, which is uploaded instead of written
+    /// through its primary.
+    pub created: bool,
+}
+
+/// Sent from Client to MetadataServer after it uploaded a chunk to one of the chunk's
+/// alternates, because the upload to the chunk's primary failed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadFailoverPayload {
+    pub chunk_id: ChunkId,
+    /// Primary the chunk was placed on.
+    pub failed: SocketAddr,
+    /// Alternate which stored the chunk instead.
+    pub stored_on: SocketAddr,
+}
+impl MessagePayload for UploadFailoverPayload {}
+
+/// Sent from Client to MetadataServer to delete a file.
+/// Chunks of the file are freed once no other file references them.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteFilePayload {
+    pub filename: String,
+}
+impl MessagePayload for DeleteFilePayload {}
+
+/// Sent from Client to MetadataServer to list the versions of a versioned file.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListFileVersionsRequestPayload {
+    pub filename: String,
+}
+impl MessagePayload for ListFileVersionsRequestPayload {}
+
+/// Sent from MetadataServer to Client as a response to ListFileVersionsRequestPayload.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListFileVersionsResponsePayload {
+    /// Versions kept, oldest first and ending with the current one.
+    /// Empty if the file isn't versioned.
+    pub versions: Vec<FileVersion>,
+}
+impl MessagePayload for ListFileVersionsResponsePayload {
+    const MAX_SIZE: u32 = MAX_LIST_MESSAGE_SIZE;
+}
+
 /// Sent (with/once after logging) from client to MetadataServer
 /// (for now, we could offload it to a separate server)
 /// to get client's folder structure.
@@ -163,3 +507,114 @@ impl MessagePayload for GetClientFolderStructureResponsePayload {}
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UpdateClientFolderStructurePayload {}
 impl MessagePayload for UpdateClientFolderStructurePayload {}
+
+/// Sent from Admin to MetadataServer to list all active chunkservers.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListChunkserversRequestPayload {}
+impl MessagePayload for ListChunkserversRequestPayload {}
+
+/// Sent from MetadataServer to Admin as a response to ListChunkserversRequestPayload.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListChunkserversResponsePayload {
+    pub chunkservers: Vec<ChunkserverStatus>,
+}
+impl MessagePayload for ListChunkserversResponsePayload {
+    const MAX_SIZE: u32 = MAX_LIST_MESSAGE_SIZE;
+}
+
+/// Sent from Admin to MetadataServer to inspect where the chunks of a file are placed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetFileChunkMapRequestPayload {
+    pub filename: String,
+}
+impl MessagePayload for GetFileChunkMapRequestPayload {}
+
+/// Sent from MetadataServer to Admin as a response to GetFileChunkMapRequestPayload.
+/// Chunks are listed in the order they appear in the file.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetFileChunkMapResponsePayload {
+    pub chunks: Vec<ChunkStatus>,
+}
+impl MessagePayload for GetFileChunkMapResponsePayload {
+    const MAX_SIZE: u32 = MAX_LIST_MESSAGE_SIZE;
+}
+
+/// Sent from Admin to MetadataServer to list chunks stored on fewer chunkservers than required.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListUnderReplicatedChunksRequestPayload {}
+impl MessagePayload for ListUnderReplicatedChunksRequestPayload {}
+
+/// Sent from MetadataServer to Admin as a response to ListUnderReplicatedChunksRequestPayload.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListUnderReplicatedChunksResponsePayload {
+    pub chunks: Vec<ChunkStatus>,
+}
+impl MessagePayload for ListUnderReplicatedChunksResponsePayload {
+    const MAX_SIZE: u32 = MAX_LIST_MESSAGE_SIZE;
+}
+
+/// Sent from Admin to MetadataServer to move all chunks out of a chunkserver,
+/// e.g. before it's taken down for maintenance.
+/// The chunkserver won't be selected for new chunks anymore.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DrainChunkserverRequestPayload {
+    pub server_id: Uuid,
+}
+impl MessagePayload for DrainChunkserverRequestPayload {}
+
+/// Sent from Admin to MetadataServer to even out the number of chunks stored on chunkservers.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RebalanceRequestPayload {}
+impl MessagePayload for RebalanceRequestPayload {}
+
+/// Sent from Admin to MetadataServer to check consistency of files' metadata
+/// with the chunks reported by chunkservers.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FsckRequestPayload {
+    /// Whether to schedule re-replication of under-replicated chunks
+    /// and removal of orphaned and broken copies of chunks.
+    pub repair: bool,
+}
+impl MessagePayload for FsckRequestPayload {}
+
+/// Sent from MetadataServer to Admin as a response to FsckRequestPayload.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FsckResponsePayload {
+    pub report: FsckReport,
+}
+impl MessagePayload for FsckResponsePayload {
+    const MAX_SIZE: u32 = MAX_LIST_MESSAGE_SIZE;
+}
+
+/// Sent from MetadataServer to Admin as a response to DrainChunkserverRequestPayload
+/// and RebalanceRequestPayload.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplicationScheduledPayload {
+    /// Number of chunks which are going to be moved.
+    pub scheduled_chunks: u64,
+}
+impl MessagePayload for ReplicationScheduledPayload {}
+
+/// Sent by the side opening a connection on its first stream, before any other message.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HelloPayload {
+    /// Newest protocol version supported by the sender.
+    pub protocol_version: u16,
+    /// Oldest protocol version supported by the sender.
+    pub min_protocol_version: u16,
+    /// Optional features supported by the sender.
+    pub features: Features,
+    /// Features without which the sender refuses to communicate.
+    pub required_features: Features,
+}
+impl MessagePayload for HelloPayload {}
+
+/// Sent as a response to HelloPayload when the connection is accepted.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HelloAcceptedPayload {
+    /// Protocol version used on the connection.
+    pub protocol_version: u16,
+    /// Optional features supported by both sides.
+    pub features: Features,
+}
+impl MessagePayload for HelloAcceptedPayload {}
@@ -1,48 +1,208 @@
 use crate::common::messages::message_payloads::*;
+use crate::common::telemetry::RequestId;
 use anyhow::Result;
-use quinn::{RecvStream, SendStream};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use storage_macros::Message;
-use tokio::io::AsyncWriteExt;
+use storage_macros::{Message, Rpc};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 
 #[allow(async_fn_in_trait)]
-pub trait Message: Serialize + DeserializeOwned {
-    async fn send(&self, send: &mut SendStream) -> Result<()>;
-    async fn recv(recv: &mut RecvStream) -> Result<Self>;
+pub trait Message: Serialize + DeserializeOwned + Send {
+    /// Sends the message tagged with the id of the current request.
+    async fn send<W: AsyncWrite + Unpin + Send>(&self, send: &mut W) -> Result<()>;
+    /// Receives the message together with the id of the request it belongs to.
+    fn recv_with_request_id<R: AsyncRead + Unpin + Send>(
+        recv: &mut R,
+    ) -> impl Future<Output = Result<(RequestId, Self)>> + Send;
+    fn recv<R: AsyncRead + Unpin + Send>(
+        recv: &mut R,
+    ) -> impl Future<Output = Result<Self>> + Send {
+        async { Ok(Self::recv_with_request_id(recv).await?.1) }
+    }
+    /// Name of the message variant, used to label metrics.
+    fn name(&self) -> &'static str;
 }
 
-#[derive(Debug, Serialize, Deserialize, Message)]
+/// Message with a variant carrying the payload `P`, implemented by the `Message` derive.
+pub trait Carries<P>: Message {
+    fn from_payload(payload: P) -> Self;
+    /// Returns the payload, or the message itself if it's a different variant.
+    fn into_payload(self) -> Result<P, Self>;
+}
+
+#[derive(Debug, Serialize, Deserialize, Message, Rpc)]
+#[rpc(
+    client = MetadataServerExternalClient,
+    handler = MetadataServerExternalHandler,
+    response = ClientMessage
+)]
 pub enum MetadataServerExternalMessage {
+    #[message(id = 0)]
+    #[rpc(method = place_file, response = ChunkPlacementResponsePayload)]
     ChunkPlacementRequest(ChunkPlacementRequestPayload),
+    #[message(id = 1)]
+    #[rpc(method = fetch_file_placement, response = GetFilePlacementResponsePayload)]
     GetFilePlacementRequest(GetFilePlacementRequestPayload),
+    #[message(id = 2)]
+    #[rpc(method = fetch_folder_structure, response = GetClientFolderStructureResponsePayload)]
     GetClientFolderStructureRequest(GetClientFolderStructureRequestPayload),
+    #[message(id = 3)]
+    #[rpc(method = update_folder_structure)]
     UpdateClientFolderStructure(UpdateClientFolderStructurePayload),
+    #[message(id = 4)]
+    #[rpc(method = copy_file)]
+    CopyFile(CopyFilePayload),
+    #[message(id = 5)]
+    #[rpc(method = snapshot_directory, response = SnapshotDirectoryResponsePayload)]
+    SnapshotDirectoryRequest(SnapshotDirectoryRequestPayload),
+    #[message(id = 6)]
+    #[rpc(method = delete_file)]
+    DeleteFile(DeleteFilePayload),
+    #[message(id = 7)]
+    #[rpc(method = list_file_versions, response = ListFileVersionsResponsePayload)]
+    ListFileVersionsRequest(ListFileVersionsRequestPayload),
+    #[message(id = 8)]
+    #[rpc(method = write_file, response = WriteFileResponsePayload)]
+    WriteFileRequest(WriteFileRequestPayload),
+    #[message(id = 9)]
+    #[rpc(method = report_upload_failover)]
+    UploadFailover(UploadFailoverPayload),
 }
 
-#[derive(Debug, Serialize, Deserialize, Message)]
+#[derive(Debug, Serialize, Deserialize, Message, Rpc)]
+#[rpc(
+    client = MetadataServerInternalClient,
+    handler = MetadataServerInternalHandler,
+    response = ChunkserverInternalMessage
+)]
 pub enum MetadataServerInternalMessage {
+    #[message(id = 0)]
+    #[rpc(method = discover_chunkserver, response = AcceptNewChunkServerPayload)]
     ChunkServerDiscover(ChunkServerDiscoverPayload),
+    #[message(id = 1)]
+    #[rpc(method = heartbeat, response = HeartbeatResponsePayload)]
     Heartbeat(HeartbeatPayload),
+    #[message(id = 2)]
+    #[rpc(method = grant_lease, response = LeaseGrantedPayload)]
+    GrantLeaseRequest(GrantLeaseRequestPayload),
+    #[message(id = 3)]
+    #[rpc(method = report_stale_replica)]
+    StaleReplica(StaleReplicaPayload),
 }
 
-#[derive(Debug, Serialize, Deserialize, Message)]
+#[derive(Debug, Serialize, Deserialize, Message, Rpc)]
+#[rpc(
+    client = ChunkserverExternalClient,
+    handler = ChunkserverExternalHandler,
+    response = ClientMessage
+)]
 pub enum ChunkserverExternalMessage {
+    #[message(id = 0)]
+    #[rpc(method = upload_chunk)]
     UploadChunk(UploadChunkPayload),
+    #[message(id = 1)]
+    #[rpc(method = download_chunk, response = DownloadChunkResponsePayload)]
     DownloadChunkRequest(DownloadChunkRequestPayload),
+    #[message(id = 2)]
+    #[rpc(method = write_chunk)]
+    WriteChunk(WriteChunkPayload),
 }
 
-#[derive(Debug, Serialize, Deserialize, Message)]
+#[derive(Debug, Serialize, Deserialize, Message, Rpc)]
+#[rpc(
+    client = MetadataServerAdminClient,
+    handler = MetadataServerAdminHandler,
+    response = AdminMessage
+)]
+pub enum MetadataServerAdminMessage {
+    #[message(id = 0)]
+    #[rpc(method = list_chunkservers, response = ListChunkserversResponsePayload)]
+    ListChunkserversRequest(ListChunkserversRequestPayload),
+    #[message(id = 1)]
+    #[rpc(method = fetch_file_chunk_map, response = GetFileChunkMapResponsePayload)]
+    GetFileChunkMapRequest(GetFileChunkMapRequestPayload),
+    #[message(id = 2)]
+    #[rpc(method = list_under_replicated_chunks, response = ListUnderReplicatedChunksResponsePayload)]
+    ListUnderReplicatedChunksRequest(ListUnderReplicatedChunksRequestPayload),
+    #[message(id = 3)]
+    #[rpc(method = drain_chunkserver, response = ReplicationScheduledPayload)]
+    DrainChunkserverRequest(DrainChunkserverRequestPayload),
+    #[message(id = 4)]
+    #[rpc(method = rebalance, response = ReplicationScheduledPayload)]
+    RebalanceRequest(RebalanceRequestPayload),
+    #[message(id = 5)]
+    #[rpc(method = fsck, response = FsckResponsePayload)]
+    FsckRequest(FsckRequestPayload),
+}
+
+#[derive(Debug, Serialize, Deserialize, Message, Rpc)]
+#[rpc(
+    client = ChunkserverInternalClient,
+    handler = ChunkserverInternalHandler,
+    response = ClientMessage
+)]
 pub enum ChunkserverInternalMessage {
+    #[message(id = 0)]
     AcceptNewChunkserver(AcceptNewChunkServerPayload),
+    #[message(id = 1)]
+    HeartbeatResponse(HeartbeatResponsePayload),
+    #[message(id = 2)]
+    #[rpc(method = store_replica)]
+    StoreReplica(UploadChunkPayload),
+    #[message(id = 3)]
+    RequestStatus(RequestStatusPayload),
+    #[message(id = 4)]
+    #[rpc(method = apply_write)]
+    ApplyWrite(WriteChunkPayload),
+    #[message(id = 5)]
+    LeaseGranted(LeaseGrantedPayload),
 }
 
 // TODO probably not needed since it's client who initiates a connection
 #[derive(Debug, Serialize, Deserialize, Message)]
 pub enum ClientMessage {
+    #[message(id = 0)]
     ChunkPlacementResponse(ChunkPlacementResponsePayload),
+    #[message(id = 1)]
     GetFilePlacementResponse(GetFilePlacementResponsePayload),
+    #[message(id = 2)]
     DownloadChunkResponse(DownloadChunkResponsePayload),
+    #[message(id = 3)]
     RequestStatus(RequestStatusPayload),
+    #[message(id = 4)]
     GetClientFolderStructureResponse(GetClientFolderStructureResponsePayload),
+    #[message(id = 5)]
+    SnapshotDirectoryResponse(SnapshotDirectoryResponsePayload),
+    #[message(id = 6)]
+    ListFileVersionsResponse(ListFileVersionsResponsePayload),
+    #[message(id = 7)]
+    WriteFileResponse(WriteFileResponsePayload),
+}
+
+#[derive(Debug, Serialize, Deserialize, Message)]
+pub enum AdminMessage {
+    #[message(id = 0)]
+    ListChunkserversResponse(ListChunkserversResponsePayload),
+    #[message(id = 1)]
+    GetFileChunkMapResponse(GetFileChunkMapResponsePayload),
+    #[message(id = 2)]
+    ListUnderReplicatedChunksResponse(ListUnderReplicatedChunksResponsePayload),
+    #[message(id = 3)]
+    ReplicationScheduled(ReplicationScheduledPayload),
+    #[message(id = 4)]
+    FsckResponse(FsckResponsePayload),
+    #[message(id = 5)]
+    RequestStatus(RequestStatusPayload),
+}
+
+/// Exchanged on the first stream of every connection, see [`crate::common::protocol`].
+/// Its IDs must stay the same in all protocol versions.
+#[derive(Debug, Serialize, Deserialize, Message)]
+pub enum HandshakeMessage {
+    #[message(id = 0)]
+    Hello(HelloPayload),
+    #[message(id = 1)]
+    HelloAccepted(HelloAcceptedPayload),
+    #[message(id = 2)]
+    RequestStatus(RequestStatusPayload),
 }
@@ -1,4 +1,5 @@
 pub(crate) mod chunk_transfer;
 pub(crate) mod message_payloads;
+#[allow(clippy::module_inception)]
 pub mod messages;
-mod payload;
+pub mod payload;
@@ -1,22 +1,55 @@
-use quinn::{RecvStream, SendStream};
+use crate::common::config::MAX_MESSAGE_SIZE;
+use crate::common::encoding;
+use anyhow::Context;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-pub(crate) trait MessagePayload: Serialize + DeserializeOwned {
-    async fn send_payload(&self, send: &mut SendStream) -> anyhow::Result<()> {
-        let bytes = bincode::serialize(&self)?;
+/// Body of a message, sent as its `u32` length followed by its [`encoding`].
+#[allow(async_fn_in_trait)]
+pub trait MessagePayload: Serialize + DeserializeOwned {
+    /// Maximum length of the encoded payload, larger payloads are neither sent nor received.
+    /// Doesn't include the chunk streamed after the payloads carrying one.
+    const MAX_SIZE: u32 = MAX_MESSAGE_SIZE;
+
+    async fn send_payload<W: AsyncWrite + Unpin + Send>(&self, send: &mut W) -> anyhow::Result<()> {
+        let bytes = encode(self)?;
         send.write_u32(bytes.len() as u32).await?;
         send.write_all(&bytes).await?;
         Ok(())
     }
 
-    async fn recv_payload(recv: &mut RecvStream) -> anyhow::Result<Self> {
-        let len = recv.read_u32().await?;
-        let mut buffer = vec![0; len as usize];
-        recv.read_exact(&mut buffer).await?;
-        let payload: Self = bincode::deserialize(&buffer)?;
+    async fn recv_payload<R: AsyncRead + Unpin + Send>(recv: &mut R) -> anyhow::Result<Self> {
+        let buffer = recv_frame::<Self, R>(recv).await?;
+        decode(&buffer)
+    }
+}
+
+pub(crate) fn encode<P: MessagePayload>(payload: &P) -> anyhow::Result<Vec<u8>> {
+    encoding::encode(payload, P::MAX_SIZE)
+        .with_context(|| format!("Couldn't encode {}", std::any::type_name::<P>()))
+}
+
+pub(crate) fn decode<P: MessagePayload>(bytes: &[u8]) -> anyhow::Result<P> {
+    encoding::decode(bytes).with_context(|| format!("Malformed {}", std::any::type_name::<P>()))
+}
 
-        Ok(payload)
+/// Receives the length of the payload and its bytes, refusing lengths above `P::MAX_SIZE`
+/// before anything is allocated.
+pub(crate) async fn recv_frame<P: MessagePayload, R: AsyncRead + Unpin + Send>(
+    recv: &mut R,
+) -> anyhow::Result<Vec<u8>> {
+    let len = recv.read_u32().await?;
+    if len > P::MAX_SIZE {
+        anyhow::bail!(
+            "{} of {} bytes exceeds the limit of {} bytes",
+            std::any::type_name::<P>(),
+            len,
+            P::MAX_SIZE
+        );
     }
+
+    let mut buffer = vec![0; len as usize];
+    recv.read_exact(&mut buffer).await?;
+    Ok(buffer)
 }
@@ -0,0 +1,163 @@
+use anyhow::{Context, Result};
+use prometheus::{
+    Encoder, GaugeVec, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+    register_gauge_vec, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec,
+};
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tracing::warn;
+
+/// How long a metrics connection may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of requests handled, by the server and the message variant.
+pub static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "storage_requests_total",
+        "Number of handled requests",
+        &["server", "message"]
+    )
+    .expect("Couldn't register metric")
+});
+
+/// Number of requests which handling failed, by the server and the message variant.
+pub static REQUEST_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "storage_request_errors_total",
+        "Number of requests which handling failed",
+        &["server", "message"]
+    )
+    .expect("Couldn't register metric")
+});
+
+/// Time of handling requests, by the server and the message variant.
+pub static REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "storage_request_duration_seconds",
+        "Time of handling requests in seconds",
+        &["server", "message"]
+    )
+    .expect("Couldn't register metric")
+});
+
+/// Number of open QUIC connections, by the server.
+pub static ACTIVE_CONNECTIONS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "storage_active_connections",
+        "Number of open QUIC connections",
+        &["server"]
+    )
+    .expect("Couldn't register metric")
+});
+
+/// Bytes of chunks stored on the chunkserver, both uploaded by clients and replicated.
+pub static BYTES_UPLOADED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "storage_uploaded_bytes_total",
+        "Bytes of chunks uploaded to the chunkserver"
+    )
+    .expect("Couldn't register metric")
+});
+
+/// Bytes of chunks sent by the chunkserver, both downloaded by clients and replicated.
+pub static BYTES_DOWNLOADED: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "storage_downloaded_bytes_total",
+        "Bytes of chunks downloaded from the chunkserver"
+    )
+    .expect("Couldn't register metric")
+});
+
+/// Number of chunks stored on the chunkserver or known to the MetadataServer.
+pub static CHUNKS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("storage_chunks", "Number of chunks").expect("Couldn't register metric")
+});
+
+/// Number of chunks stored on fewer chunkservers than required.
+pub static UNDER_REPLICATED_CHUNKS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "storage_under_replicated_chunks",
+        "Number of chunks stored on fewer chunkservers than required"
+    )
+    .expect("Couldn't register metric")
+});
+
+/// Time elapsed since the last heartbeat, by the chunkserver.
+pub static HEARTBEAT_LAG: LazyLock<GaugeVec> = LazyLock::new(|| {
+    register_gauge_vec!(
+        "storage_heartbeat_lag_seconds",
+        "Time elapsed since the last heartbeat of the chunkserver in seconds",
+        &["server_id"]
+    )
+    .expect("Couldn't register metric")
+});
+
+/// Available disk space, by the chunkserver.
+pub static DISK_AVAILABLE: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register_int_gauge_vec!(
+        "storage_disk_available_bytes",
+        "Disk space available for chunks in bytes",
+        &["server_id"]
+    )
+    .expect("Couldn't register metric")
+});
+
+/// Disk space used by stored chunks.
+pub static DISK_USED: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "storage_disk_used_bytes",
+        "Disk space used by stored chunks in bytes"
+    )
+    .expect("Couldn't register metric")
+});
+
+/// Serves the metrics in Prometheus text format on `GET /metrics`.
+pub async fn serve_metrics(addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream).await {
+                warn!(error = ?e, "Metrics request failed");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream) -> Result<()> {
+    // Only the request line matters, the rest of the request is ignored.
+    let mut buffer = [0; 1024];
+    // Connections which don't send the request in time are closed.
+    let len = timeout(REQUEST_TIMEOUT, stream.read(&mut buffer))
+        .await
+        .context("Metrics request timed out")??;
+    let request = String::from_utf8_lossy(&buffer[..len]);
+
+    let (status, content_type, body) = if request.starts_with("GET /metrics ") {
+        let mut body = Vec::new();
+        let encoder = TextEncoder::new();
+        encoder.encode(&prometheus::gather(), &mut body)?;
+        ("200 OK", encoder.format_type().to_string(), body)
+    } else {
+        (
+            "404 Not Found",
+            "text/plain".to_string(),
+            b"Not Found".to_vec(),
+        )
+    };
+
+    let header = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(&body).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
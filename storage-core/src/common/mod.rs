@@ -1,15 +1,27 @@
 mod chunk_send;
 pub mod config;
-mod dbg_println;
+pub mod config_file;
+pub mod dedup;
+pub mod encoding;
+pub mod erasure;
+pub mod journal;
 pub mod messages;
-mod server;
+pub mod metrics;
+pub mod protocol;
+pub mod read;
+pub mod rpc;
+pub mod server;
+pub mod shutdown;
+pub mod telemetry;
+pub mod transfer;
 pub mod types;
 
-pub use chunk_send::ChunkserverLocation;
+pub use chunk_send::{ChunkserverLocation, SendChunkMetadata};
 pub use messages::chunk_transfer::ChunkTransfer;
 pub use messages::message_payloads::*;
 pub use messages::messages::*;
-pub use server::{CertificateProvider, QuicServer, certificate_provider};
+pub use messages::payload::MessagePayload;
+pub use server::{CertificateProvider, QuicServer, certificate_provider, peer_identity};
 
 #[allow(unused)]
 pub const ALPN_QUIC_HTTP: &[&[u8]] = &[b"hq-29"];
@@ -0,0 +1,188 @@
+//! Negotiation of the protocol used on a connection.
+//!
+//! The side opening a connection sends [`HandshakeMessage::Hello`] on the first stream,
+//! before any other message. The other side answers with [`HandshakeMessage::HelloAccepted`]
+//! containing the negotiated protocol version and features, or with an
+//! [`ErrorCode::IncompatibleProtocol`] status, after which the connection is closed.
+
+use crate::common::config::HANDSHAKE_TIMEOUT;
+use crate::common::messages::messages::{HandshakeMessage, Message};
+use crate::common::{
+    ErrorCode, ErrorPayload, HelloAcceptedPayload, HelloPayload, RequestStatusPayload,
+};
+use anyhow::{Context, Result};
+use quinn::{Connection, Endpoint, VarInt};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tokio::time::timeout;
+use tracing::debug;
+
+/// Newest version of the protocol. Has to be bumped on every incompatible change of the messages.
+/// Version 2 encodes payloads with [`crate::common::encoding`] instead of positional bincode.
+/// Version 3 may answer the discovery of a chunkserver with the configuration of the cluster.
+/// Version 4 describes erasure-coded chunks by their stripes, without a primary.
+pub const PROTOCOL_VERSION: u16 = 4;
+/// Oldest version of the protocol which this build still understands.
+pub const MIN_PROTOCOL_VERSION: u16 = 4;
+
+/// Application error code of connections closed because of an incompatible protocol.
+/// Connections closed because of other handshake failures use code 0.
+pub const INCOMPATIBLE_PROTOCOL: VarInt = VarInt::from_u32(1);
+
+/// Set of optional protocol features, as bit flags.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features(u64);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+
+    /// Features implemented by this build.
+    pub const SUPPORTED: Features = Features::NONE;
+    /// Features this build refuses to work without.
+    pub const REQUIRED: Features = Features::NONE;
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+}
+
+/// Result of a successful handshake.
+#[derive(Debug, Clone, Copy)]
+pub struct Negotiated {
+    pub protocol_version: u16,
+    pub features: Features,
+}
+
+fn local_hello() -> HelloPayload {
+    HelloPayload {
+        protocol_version: PROTOCOL_VERSION,
+        min_protocol_version: MIN_PROTOCOL_VERSION,
+        features: Features::SUPPORTED,
+        required_features: Features::REQUIRED,
+    }
+}
+
+/// Picks the newest protocol version and the features supported by both sides.
+fn negotiate(local: &HelloPayload, remote: &HelloPayload) -> Result<Negotiated, ErrorPayload> {
+    let protocol_version = local.protocol_version.min(remote.protocol_version);
+    let min_protocol_version = local.min_protocol_version.max(remote.min_protocol_version);
+    if protocol_version < min_protocol_version {
+        return Err(ErrorPayload::new(
+            ErrorCode::IncompatibleProtocol,
+            format!(
+                "Protocol versions {}..={} and {}..={} don't overlap",
+                local.min_protocol_version,
+                local.protocol_version,
+                remote.min_protocol_version,
+                remote.protocol_version
+            ),
+        ));
+    }
+
+    if !remote.features.contains(local.required_features)
+        || !local.features.contains(remote.required_features)
+    {
+        return Err(ErrorPayload::new(
+            ErrorCode::IncompatibleProtocol,
+            format!(
+                "Required features {:?} and {:?} aren't supported by the other side",
+                local.required_features, remote.required_features
+            ),
+        ));
+    }
+
+    Ok(Negotiated {
+        protocol_version,
+        features: local.features.intersection(remote.features),
+    })
+}
+
+/// Connects to the server and negotiates the protocol with it.
+/// Fails with [`ErrorPayload`] if the server rejected the connection.
+pub async fn connect(
+    endpoint: &Endpoint,
+    addr: SocketAddr,
+    server_name: &str,
+) -> Result<Connection> {
+    let conn = endpoint.connect(addr, server_name)?.await?;
+
+    let negotiated = timeout(HANDSHAKE_TIMEOUT, async {
+        let (mut send, mut recv) = conn.open_bi().await?;
+        HandshakeMessage::Hello(local_hello())
+            .send(&mut send)
+            .await?;
+        send.finish()?;
+
+        match HandshakeMessage::recv(&mut recv).await? {
+            HandshakeMessage::HelloAccepted(accepted) => Ok(Negotiated {
+                protocol_version: accepted.protocol_version,
+                features: accepted.features,
+            }),
+            HandshakeMessage::RequestStatus(RequestStatusPayload::Error(error)) => {
+                Err(anyhow::Error::new(error))
+            }
+            response => anyhow::bail!("Unexpected handshake response: {:?}", response),
+        }
+    })
+    .await
+    .context("Handshake timed out")?
+    .with_context(|| format!("Handshake with {} failed", addr))?;
+
+    debug!(
+        %addr,
+        protocol_version = negotiated.protocol_version,
+        features = ?negotiated.features,
+        "Connected"
+    );
+
+    Ok(conn)
+}
+
+/// Receives the handshake on a new connection, closing the connection if it's rejected.
+pub(crate) async fn accept_handshake(conn: &Connection) -> Result<Negotiated> {
+    let result = timeout(HANDSHAKE_TIMEOUT, async {
+        let (mut send, mut recv) = conn.accept_bi().await?;
+        let HandshakeMessage::Hello(hello) = HandshakeMessage::recv(&mut recv).await? else {
+            anyhow::bail!("Connection not started with Hello");
+        };
+
+        match negotiate(&local_hello(), &hello) {
+            Ok(negotiated) => {
+                HandshakeMessage::HelloAccepted(HelloAcceptedPayload {
+                    protocol_version: negotiated.protocol_version,
+                    features: negotiated.features,
+                })
+                .send(&mut send)
+                .await?;
+                send.finish()?;
+
+                Ok(negotiated)
+            }
+            Err(error) => {
+                HandshakeMessage::RequestStatus(error.clone().into())
+                    .send(&mut send)
+                    .await?;
+                send.finish()?;
+                // Wait for the peer to read the rejection, before the connection is closed.
+                let _ = send.stopped().await;
+                conn.close(INCOMPATIBLE_PROTOCOL, error.message.as_bytes());
+
+                Err(anyhow::Error::new(error))
+            }
+        }
+    })
+    .await
+    .context("Handshake timed out")
+    .flatten();
+
+    if result.is_err() {
+        // Does nothing if the connection has already been closed because of the rejection.
+        conn.close(VarInt::from_u32(0), b"Handshake failed");
+    }
+
+    result
+}
@@ -0,0 +1,141 @@
+//! Reads of byte ranges of files.
+//!
+//! A range of a file is mapped onto the chunks it spans, using the sizes of the chunks
+//! from the file's placement, and only the covered part of every chunk is downloaded.
+//! Chunks are read from their primary, or from a replica if the primary fails. Erasure-coded
+//! chunks are decoded from their stripes as a whole, and the range is cut from the chunk.
+
+use crate::common::chunk_send::chunkserver_connection;
+use crate::common::config::with_max_received_chunk_size;
+use crate::common::types::{ByteRange, ChunkLocations, ServerConnections};
+use crate::common::{
+    ChunkserverExternalClient, ChunkserverLocation, DownloadChunkRequestPayload,
+    GetFilePlacementResponsePayload,
+};
+use anyhow::{Context, Result, bail};
+use futures::future::try_join_all;
+use quinn::Endpoint;
+use tracing::warn;
+
+/// Maps the range of a file with chunks of `chunk_sizes` onto the chunks it spans,
+/// returns the index of every such chunk with the range of the chunk to read.
+/// The range is cut at the end of the file.
+pub fn chunk_ranges(chunk_sizes: &[u64], range: ByteRange) -> Vec<(usize, ByteRange)> {
+    let end = range.offset.saturating_add(range.length);
+    let mut ranges = Vec::new();
+    let mut chunk_start = 0;
+    for (idx, &size) in chunk_sizes.iter().enumerate() {
+        let chunk_end = chunk_start + size;
+        let (start, stop) = (range.offset.max(chunk_start), end.min(chunk_end));
+        if start < stop {
+            ranges.push((
+                idx,
+                ByteRange {
+                    offset: start - chunk_start,
+                    length: stop - start,
+                },
+            ));
+        }
+        if chunk_end >= end {
+            break;
+        }
+        chunk_start = chunk_end;
+    }
+    ranges
+}
+
+/// Reads the range of the file with the placement, cut at the end of the file.
+pub async fn read_file_range(
+    placement: &GetFilePlacementResponsePayload,
+    range: ByteRange,
+    endpoint: &Endpoint,
+    connections: &ServerConnections,
+) -> Result<Vec<u8>> {
+    if placement.chunk_sizes.len() != placement.chunks_locations.len() {
+        bail!("Placement of the file doesn't include the sizes of its chunks");
+    }
+
+    let parts = try_join_all(chunk_ranges(&placement.chunk_sizes, range).into_iter().map(
+        |(idx, range)| placement.chunks_locations[idx].download_range(range, endpoint, connections),
+    ))
+    .await?;
+
+    Ok(parts.concat())
+}
+
+impl ChunkLocations {
+    /// Downloads the range of the chunk from its primary, or the replicas if that fails.
+    /// No more than the expected bytes are accepted from the chunkservers, regardless
+    /// of the maximum chunk size known locally.
+    pub async fn download_range(
+        &self,
+        range: ByteRange,
+        endpoint: &Endpoint,
+        connections: &ServerConnections,
+    ) -> Result<Vec<u8>> {
+        if let Some(stripes) = &self.stripes {
+            let chunk = with_max_received_chunk_size(
+                stripes.chunk_size,
+                stripes.download(endpoint, connections),
+            )
+            .await?;
+            let start = (range.offset as usize).min(chunk.len());
+            let end = start + (range.length as usize).min(chunk.len() - start);
+            return Ok(chunk[start..end].to_vec());
+        }
+
+        let mut last_error = None;
+        for location in self.primary.iter().chain(self.replicas.iter()) {
+            let download = download_chunk_range(location, range, endpoint, connections);
+            match with_max_received_chunk_size(range.length, download).await {
+                Ok(data) => return Ok(data),
+                Err(e) => {
+                    warn!(
+                        chunk_id = %location.chunk_id,
+                        server = %location.server_location,
+                        error = ?e,
+                        "Couldn't download chunk range"
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error
+            .unwrap_or_else(|| anyhow::anyhow!("Chunk has no locations"))
+            .context(format!("Couldn't download chunk {}", self.chunk_id)))
+    }
+}
+
+async fn download_chunk_range(
+    location: &ChunkserverLocation,
+    range: ByteRange,
+    endpoint: &Endpoint,
+    connections: &ServerConnections,
+) -> Result<Vec<u8>> {
+    let conn = chunkserver_connection(
+        endpoint,
+        connections,
+        location.server_location,
+        &location.server_hostname,
+    )
+    .await?;
+
+    let response = ChunkserverExternalClient::new(conn)
+        .download_chunk(DownloadChunkRequestPayload {
+            chunk_id: location.chunk_id,
+            range: Some(range),
+        })
+        .await?;
+    if response.chunk_size != range.length {
+        bail!(
+            "Range has {} bytes, {} were expected",
+            response.chunk_size,
+            range.length
+        );
+    }
+
+    tokio::fs::read(&response.chunk_transfer.data)
+        .await
+        .context("Couldn't read downloaded range")
+}
@@ -0,0 +1,124 @@
+//! Request-response exchanges used by the clients and handlers generated by the `Rpc` derive.
+
+use crate::common::messages::messages::{Carries, Message};
+use crate::common::{ErrorCode, ErrorPayload, RequestStatusPayload};
+use anyhow::Result;
+use quinn::Connection;
+use std::fmt;
+use tokio::io::AsyncWrite;
+
+/// Failure of a request sent by a generated client.
+#[derive(Debug)]
+pub enum RpcError {
+    /// The server handled the request and responded with an error.
+    Status(ErrorPayload),
+    /// The request couldn't be sent or the response couldn't be received.
+    Transport(anyhow::Error),
+}
+
+impl RpcError {
+    /// Code of the error the server responded with, if any.
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            RpcError::Status(error) => Some(error.code),
+            RpcError::Transport(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RpcError::Status(error) => write!(f, "{}", error),
+            RpcError::Transport(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RpcError::Status(_) => None,
+            RpcError::Transport(error) => error.source(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(error: anyhow::Error) -> Self {
+        RpcError::Transport(error)
+    }
+}
+
+/// Sends the request on a new stream and receives the response carrying `P`.
+pub async fn call<Req, Resp, P>(conn: &Connection, request: Req) -> Result<P, RpcError>
+where
+    Req: Message,
+    Resp: Carries<P> + Carries<RequestStatusPayload>,
+{
+    let (mut send, mut recv) = conn.open_bi().await.map_err(anyhow::Error::from)?;
+    request.send(&mut send).await?;
+    send.finish().map_err(anyhow::Error::from)?;
+
+    let response = Resp::recv(&mut recv).await?;
+    let response = match <Resp as Carries<P>>::into_payload(response) {
+        Ok(payload) => return Ok(payload),
+        Err(response) => response,
+    };
+
+    let name = response.name();
+    match <Resp as Carries<RequestStatusPayload>>::into_payload(response) {
+        Ok(RequestStatusPayload::Error(error)) => Err(RpcError::Status(error)),
+        _ => Err(anyhow::anyhow!("Unexpected response {}", name).into()),
+    }
+}
+
+/// Sends the request on a new stream and receives its status.
+pub async fn call_status<Req, Resp>(conn: &Connection, request: Req) -> Result<(), RpcError>
+where
+    Req: Message,
+    Resp: Carries<RequestStatusPayload>,
+{
+    match call::<Req, Resp, RequestStatusPayload>(conn, request).await? {
+        RequestStatusPayload::Ok => Ok(()),
+        RequestStatusPayload::Error(error) => Err(RpcError::Status(error)),
+    }
+}
+
+/// Sends the result of a handler as the response carrying `P` or as an error status.
+/// Errors other than [`ErrorPayload`] are reported as `InternalServerError` and returned.
+pub async fn respond<Resp, P, W>(send: &mut W, result: Result<P>) -> Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+    Resp: Carries<P> + Carries<RequestStatusPayload>,
+{
+    let error = match result {
+        Ok(payload) => return <Resp as Carries<P>>::from_payload(payload).send(send).await,
+        Err(error) => error,
+    };
+
+    match error.downcast_ref::<ErrorPayload>() {
+        Some(payload) => {
+            <Resp as Carries<RequestStatusPayload>>::from_payload(payload.clone().into())
+                .send(send)
+                .await
+        }
+        None => {
+            let _ = <Resp as Carries<RequestStatusPayload>>::from_payload(
+                RequestStatusPayload::internal_server_error(),
+            )
+            .send(send)
+            .await;
+            Err(error)
+        }
+    }
+}
+
+/// Sends the result of a handler without a response payload as a status.
+pub async fn respond_status<Resp, W>(send: &mut W, result: Result<()>) -> Result<()>
+where
+    W: AsyncWrite + Unpin + Send,
+    Resp: Carries<RequestStatusPayload>,
+{
+    respond::<Resp, RequestStatusPayload, W>(send, result.map(|()| RequestStatusPayload::Ok)).await
+}
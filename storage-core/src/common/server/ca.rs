@@ -0,0 +1,160 @@
+//! Cluster CA, issuing the certificates of the servers and clients of the cluster.
+//!
+//! Issued certificates are stored as bundles, directories named after the certificate containing:
+//! * `cert.pem` - the certificate,
+//! * `key.pem` - its private key,
+//! * `ca.pem` - the certificate of the cluster CA, trusted by the owner of the bundle.
+//!
+//! The CA is stored next to the bundles in the `ca` directory, as `cert.pem` and `key.pem`.
+
+use anyhow::{Context, Result, bail};
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+    KeyUsagePurpose,
+};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Directory of the CA, relative to the certificates directory.
+pub const CA_DIR: &str = "ca";
+/// Certificate of a bundle or of the CA.
+pub const CERT_FILE: &str = "cert.pem";
+/// Private key of a bundle or of the CA.
+pub const KEY_FILE: &str = "key.pem";
+/// Certificate of the CA included in a bundle.
+pub const CA_CERT_FILE: &str = "ca.pem";
+
+/// What an issued certificate can be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificateUsage {
+    /// Servers of the cluster, which also connect to each other as clients.
+    Server,
+    /// Clients, which only authenticate themselves.
+    Client,
+}
+
+pub struct CertificateAuthority {
+    cert_pem: String,
+    issuer: Issuer<'static, KeyPair>,
+}
+
+impl CertificateAuthority {
+    /// Generates a new CA in the certificates directory, failing if there already is one.
+    pub fn create(dir: &Path, common_name: &str) -> Result<Self> {
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::default();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        let cert = params.self_signed(&key)?;
+
+        write_bundle(
+            &dir.join(CA_DIR),
+            &[(CERT_FILE, &cert.pem()), (KEY_FILE, &key.serialize_pem())],
+        )?;
+
+        Self::load(dir)
+    }
+
+    /// Loads the CA from the certificates directory.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let ca_dir = dir.join(CA_DIR);
+        let cert_pem = fs::read_to_string(ca_dir.join(CERT_FILE))
+            .with_context(|| format!("Couldn't read CA certificate from {}", ca_dir.display()))?;
+        let key_pem = fs::read_to_string(ca_dir.join(KEY_FILE))
+            .with_context(|| format!("Couldn't read CA key from {}", ca_dir.display()))?;
+
+        let key = KeyPair::from_pem(&key_pem).context("Invalid CA key")?;
+        let issuer = Issuer::from_ca_cert_pem(&cert_pem, key).context("Invalid CA certificate")?;
+
+        Ok(CertificateAuthority { cert_pem, issuer })
+    }
+
+    /// Path of the CA certificate in the certificates directory.
+    pub fn cert_path(dir: &Path) -> PathBuf {
+        dir.join(CA_DIR).join(CERT_FILE)
+    }
+
+    /// Issues a certificate for the names, which are hostnames or IP addresses,
+    /// and writes it to a bundle named after the first of them in the certificates directory.
+    pub fn issue(
+        &self,
+        dir: &Path,
+        names: Vec<String>,
+        usage: CertificateUsage,
+    ) -> Result<PathBuf> {
+        let Some(name) = names.first().cloned() else {
+            bail!("No names to issue the certificate for");
+        };
+        if name == CA_DIR || name.contains(['/', '\\']) || name.starts_with('.') {
+            bail!("Invalid certificate name {}", name);
+        }
+
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::new(names)?;
+        params.distinguished_name.push(DnType::CommonName, &name);
+        params.use_authority_key_identifier_extension = true;
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = match usage {
+            CertificateUsage::Server => vec![
+                ExtendedKeyUsagePurpose::ServerAuth,
+                ExtendedKeyUsagePurpose::ClientAuth,
+            ],
+            CertificateUsage::Client => vec![ExtendedKeyUsagePurpose::ClientAuth],
+        };
+        let cert = params.signed_by(&key, &self.issuer)?;
+
+        let bundle = dir.join(&name);
+        write_bundle(
+            &bundle,
+            &[
+                (CERT_FILE, &cert.pem()),
+                (KEY_FILE, &key.serialize_pem()),
+                (CA_CERT_FILE, &self.cert_pem),
+            ],
+        )?;
+
+        Ok(bundle)
+    }
+}
+
+/// Writes the files to a temporary directory which is renamed to `dir` afterwards,
+/// so that processes issuing the same bundle at the same time never mix up their files.
+fn write_bundle(dir: &Path, files: &[(&str, &str)]) -> Result<()> {
+    if dir.exists() {
+        bail!("{} already exists", dir.display());
+    }
+    let parent = dir.parent().context("Bundle has no parent directory")?;
+    let name = dir.file_name().context("Bundle has no name")?;
+    fs::create_dir_all(parent).with_context(|| format!("Couldn't create {}", parent.display()))?;
+
+    let tmp_dir = parent.join(format!(
+        ".{}-{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    fs::create_dir_all(&tmp_dir)?;
+    for (file, contents) in files {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        if *file == KEY_FILE {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options
+            .open(tmp_dir.join(file))
+            .and_then(|mut f| f.write_all(contents.as_bytes()))
+            .with_context(|| format!("Couldn't write {}", file))?;
+    }
+
+    if let Err(e) = fs::rename(&tmp_dir, dir) {
+        let _ = fs::remove_dir_all(&tmp_dir);
+        return Err(e).with_context(|| format!("Couldn't create {}", dir.display()));
+    }
+
+    Ok(())
+}
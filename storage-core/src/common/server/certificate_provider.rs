@@ -1,38 +1,78 @@
+use crate::common::server::ca::{CA_CERT_FILE, CERT_FILE, CertificateAuthority, KEY_FILE};
 use crate::common::types::Hostname;
-use crate::dbg_println;
-use anyhow::{Context, Result, bail};
+use anyhow::{Context, Result};
 use rustls::pki_types::pem::PemObject;
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
-use std::path::PathBuf;
-use std::{fs, io};
-use tracing::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Directory of the certificates generated in debug mode, relative to the working directory.
+pub const DEV_CERTIFICATES_DIR: &str = "certificates";
 
 /// Trait for providing TLS certificates to a server.
-pub trait CertificateProvider {
+pub trait CertificateProvider: Send + Sync {
     fn get_certificate(&self) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>;
+
+    /// Certificates of the CA which issued the provided certificate, if known.
+    fn get_ca_certificates(&self) -> Result<Option<Vec<CertificateDer<'static>>>> {
+        Ok(None)
+    }
 }
 
 pub fn certificate_provider(
     hostname: Option<Hostname>,
     key: Option<PathBuf>,
     cert: Option<PathBuf>,
+    bundle: Option<PathBuf>,
 ) -> Result<Box<dyn CertificateProvider>> {
     if let (Some(key_path), Some(cert_path)) = (key, cert) {
         Ok(Box::new(FileCertificateProvider::new(cert_path, key_path)))
+    } else if let Some(bundle) = bundle {
+        Ok(Box::new(BundleCertificateProvider::new(bundle)))
     } else {
         #[cfg(debug_assertions)]
         {
-            Ok(Box::new(SelfSignedCertificateProvider::new(
-                hostname.expect("Hostname not provided for self-generated certificate"),
-            )))
+            Ok(Box::new(dev_certificate_bundle(hostname.expect(
+                "Hostname not provided for self-generated certificate",
+            ))?))
         }
         #[cfg(not(debug_assertions))]
         {
-            bail!("No TLS certificate files provided in release mode");
+            let _ = hostname;
+            anyhow::bail!("No TLS certificate files provided in release mode");
         }
     }
 }
 
+/// Reads the certificate from a DER file or all the certificates from a PEM file.
+pub fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    if path.extension().is_some_and(|x| x == "der") {
+        return Ok(vec![CertificateDer::from(
+            fs::read(path).context("failed to read certificate file")?,
+        )]);
+    }
+
+    CertificateDer::pem_file_iter(path)
+        .context("failed to read PEM from certificate file")?
+        .collect::<Result<_, _>>()
+        .context("invalid PEM-encoded certificate")
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    if path.extension().is_some_and(|x| x == "der") {
+        return Ok(PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+            fs::read(path).context("failed to read private key file")?,
+        )));
+    }
+
+    PrivateKeyDer::from_pem_file(path).context("failed to read PEM from private key file")
+}
+
+/// Path of the development CA certificate, which signs the certificates generated in debug mode.
+pub fn dev_ca_certificate_path() -> PathBuf {
+    CertificateAuthority::cert_path(Path::new(DEV_CERTIFICATES_DIR))
+}
+
 struct FileCertificateProvider {
     cert_path: PathBuf,
     key_path: PathBuf,
@@ -49,71 +89,71 @@ impl FileCertificateProvider {
 
 impl CertificateProvider for FileCertificateProvider {
     fn get_certificate(&self) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
-        let key = if self.key_path.extension().is_some_and(|x| x == "der") {
-            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
-                fs::read(&self.key_path).context("failed to read private key file")?,
-            ))
-        } else {
-            PrivateKeyDer::from_pem_file(&self.key_path)
-                .context("failed to read PEM from private key file")?
-        };
-
-        let cert_chain = if self.cert_path.extension().is_some_and(|x| x == "der") {
-            vec![CertificateDer::from(
-                fs::read(&self.cert_path).context("failed to read certificate chain file")?,
-            )]
-        } else {
-            CertificateDer::pem_file_iter(&self.cert_path)
-                .context("failed to read PEM from certificate chain file")?
-                .collect::<Result<_, _>>()
-                .context("invalid PEM-encoded certificate")?
-        };
+        let key = read_private_key(&self.key_path)?;
+
+        let cert_chain =
+            read_certificates(&self.cert_path).context("failed to read certificate chain")?;
 
         Ok((cert_chain, key))
     }
 }
 
-#[cfg(debug_assertions)]
-struct SelfSignedCertificateProvider {
-    hostname: Hostname,
+/// Provides the certificate of a bundle issued by the cluster CA, see [`crate::common::server::ca`].
+struct BundleCertificateProvider {
+    path: PathBuf,
 }
 
-impl SelfSignedCertificateProvider {
-    fn new(hostname: Hostname) -> Self {
-        SelfSignedCertificateProvider { hostname }
+impl BundleCertificateProvider {
+    fn new(path: PathBuf) -> BundleCertificateProvider {
+        BundleCertificateProvider { path }
     }
 }
 
-#[cfg(debug_assertions)]
-impl CertificateProvider for SelfSignedCertificateProvider {
+impl CertificateProvider for BundleCertificateProvider {
     fn get_certificate(&self) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
-        let path = std::env::current_dir()?
-            .join("certificates")
-            .join(&self.hostname);
-        let cert_path = path.join("cert.der");
-        let key_path = path.join("key.der");
-        let (cert, key) = match fs::read(&cert_path).and_then(|x| Ok((x, fs::read(&key_path)?))) {
-            Ok((cert, key)) => (
-                CertificateDer::from(cert),
-                PrivateKeyDer::try_from(key).map_err(anyhow::Error::msg)?,
-            ),
-            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
-                info!("generating self-signed certificate");
-                let cert = rcgen::generate_simple_self_signed(vec![self.hostname.clone()])?;
-                let key = PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
-                let cert = cert.cert.into();
-                fs::create_dir_all(path).context("failed to create certificate directory")?;
-                fs::write(&cert_path, &cert).context("failed to write certificate")?;
-                fs::write(&key_path, key.secret_pkcs8_der())
-                    .context("failed to write private key")?;
-                (cert, key.into())
-            }
-            Err(e) => {
-                bail!("failed to read certificate: {}", e);
+        let cert_chain = read_certificates(&self.path.join(CERT_FILE))
+            .with_context(|| format!("failed to read certificate of {}", self.path.display()))?;
+        let key = read_private_key(&self.path.join(KEY_FILE))
+            .with_context(|| format!("failed to read private key of {}", self.path.display()))?;
+
+        Ok((cert_chain, key))
+    }
+
+    fn get_ca_certificates(&self) -> Result<Option<Vec<CertificateDer<'static>>>> {
+        let ca_certs = read_certificates(&self.path.join(CA_CERT_FILE))
+            .with_context(|| format!("failed to read CA of {}", self.path.display()))?;
+
+        Ok(Some(ca_certs))
+    }
+}
+
+/// Returns the bundle of the hostname signed by the development CA, generating both on first use.
+#[cfg(debug_assertions)]
+fn dev_certificate_bundle(hostname: Hostname) -> Result<BundleCertificateProvider> {
+    use crate::common::server::ca::CertificateUsage;
+    use tracing::info;
+
+    let dir = Path::new(DEV_CERTIFICATES_DIR);
+    let bundle = dir.join(&hostname);
+    if !bundle.exists() {
+        if !dev_ca_certificate_path().exists() {
+            info!("generating development CA");
+            // Fails if another server started at the same time has just created it.
+            if let Err(e) = CertificateAuthority::create(dir, "storage development CA")
+                && !dev_ca_certificate_path().exists()
+            {
+                return Err(e);
             }
-        };
+        }
 
-        dbg_println!("Created self-signed certificates");
-        Ok((vec![cert], key))
+        info!("generating certificate signed by the development CA");
+        let ca = CertificateAuthority::load(dir)?;
+        if let Err(e) = ca.issue(dir, vec![hostname], CertificateUsage::Server)
+            && !bundle.exists()
+        {
+            return Err(e);
+        }
     }
+
+    Ok(BundleCertificateProvider::new(bundle))
 }
@@ -0,0 +1,118 @@
+use crate::common::config::CERTIFICATE_RELOAD_INTERVAL;
+use crate::common::server::certificate_provider::CertificateProvider;
+use anyhow::{Context, Result, bail};
+use arc_swap::ArcSwap;
+use rustls::SignatureScheme;
+use rustls::client::ResolvesClientCert;
+use rustls::crypto::CryptoProvider;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::fmt;
+use std::sync::Arc;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::time::{MissedTickBehavior, interval};
+use tracing::{info, warn};
+
+/// Resolves the certificate of a server, which is reloaded from its provider periodically
+/// and on `SIGHUP`. New handshakes use the reloaded certificate, established connections
+/// are not affected.
+///
+/// Resolves both the certificate presented to clients and the one presented to servers
+/// on internal connections.
+pub struct CertificateReloader {
+    provider: Box<dyn CertificateProvider>,
+    crypto_provider: Arc<CryptoProvider>,
+    current: ArcSwap<CertifiedKey>,
+}
+
+impl CertificateReloader {
+    pub fn new(provider: Box<dyn CertificateProvider>) -> Result<Arc<Self>> {
+        let crypto_provider = CryptoProvider::get_default()
+            .context("No rustls crypto provider installed")?
+            .clone();
+        let current = load(&*provider, &crypto_provider)?;
+
+        Ok(Arc::new(CertificateReloader {
+            provider,
+            crypto_provider,
+            current: ArcSwap::from_pointee(current),
+        }))
+    }
+
+    /// Loads the certificate again, returns whether it has changed.
+    pub fn reload(&self) -> Result<bool> {
+        let certified_key = load(&*self.provider, &self.crypto_provider)?;
+        if certified_key.cert == self.current.load().cert {
+            return Ok(false);
+        }
+
+        self.current.store(Arc::new(certified_key));
+        Ok(true)
+    }
+
+    /// Reloads the certificate every [`CERTIFICATE_RELOAD_INTERVAL`] and on `SIGHUP`.
+    /// A certificate which fails to load, e.g. because its files are being replaced,
+    /// is skipped and the previous one is kept.
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut interval = interval(CERTIFICATE_RELOAD_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => info!("Reloading certificate on SIGHUP"),
+                _ = interval.tick() => {}
+            }
+
+            match self.reload() {
+                Ok(true) => info!("Reloaded certificate"),
+                Ok(false) => {}
+                Err(e) => warn!(
+                    "Couldn't reload certificate, keeping the previous one: {:#}",
+                    e
+                ),
+            }
+        }
+    }
+}
+
+fn load(
+    provider: &dyn CertificateProvider,
+    crypto_provider: &CryptoProvider,
+) -> Result<CertifiedKey> {
+    let (certs, key) = provider.get_certificate()?;
+    if certs.is_empty() {
+        bail!("Empty certificate chain");
+    }
+
+    CertifiedKey::from_der(certs, key, crypto_provider)
+        .context("Certificate doesn't match the private key")
+}
+
+impl fmt::Debug for CertificateReloader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertificateReloader")
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for CertificateReloader {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.load_full())
+    }
+}
+
+impl ResolvesClientCert for CertificateReloader {
+    fn resolve(
+        &self,
+        _root_hint_subjects: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.load_full())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
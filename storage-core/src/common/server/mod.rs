@@ -1,5 +1,10 @@
+pub mod ca;
 pub mod certificate_provider;
+pub mod certificate_reloader;
+#[allow(clippy::module_inception)]
 mod server;
+pub mod tls;
 
-pub use certificate_provider::{CertificateProvider, certificate_provider};
-pub use server::QuicServer;
+pub use certificate_provider::{CertificateProvider, certificate_provider, read_certificates};
+pub use certificate_reloader::CertificateReloader;
+pub use server::{QuicServer, peer_identity};
@@ -1,52 +1,204 @@
+use crate::common::config::SHUTDOWN_TIMEOUT;
+use crate::common::messages::messages::Message;
+use crate::common::metrics;
+use crate::common::protocol::accept_handshake;
+use crate::common::server::tls::peer_certificate_identity;
+use crate::common::shutdown::Shutdown;
+use crate::common::telemetry::with_request_id;
 use anyhow::Result;
 use async_trait::async_trait;
 use quinn::{Connecting, Endpoint, RecvStream, SendStream};
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tracing::{Instrument, Span, debug, field, info, info_span, warn};
+
+tokio::task_local! {
+    static PEER_IDENTITY: Option<Arc<str>>;
+}
+
+/// Identity of the peer which sent the request handled by the current task,
+/// i.e. the name in the client certificate the peer has presented, if any.
+pub fn peer_identity() -> Option<Arc<str>> {
+    PEER_IDENTITY.try_with(Clone::clone).ok().flatten()
+}
 
 #[async_trait]
 pub trait QuicServer: Send + Sync + Clone + 'static {
+    /// Message the server receives at the start of every stream.
+    type Request: Message;
+
+    /// Name of the server, used to label its metrics.
+    const NAME: &'static str;
+
     fn listening_endpoint(&self) -> &Endpoint;
 
     async fn setup(&self) -> Result<()>;
 
-    async fn run(&self) -> Result<()> {
+    /// Accepts connections until the shutdown is triggered, then waits for the requests
+    /// in progress to finish, aborting those still running after [`SHUTDOWN_TIMEOUT`].
+    async fn run(&self, shutdown: Shutdown) -> Result<()> {
         self.setup().await?;
 
         let endpoint = self.listening_endpoint();
+        let mut connections = JoinSet::new();
         loop {
-            if let Some(incoming) = endpoint.accept().await {
-                if let Ok(connecting) = incoming.accept() {
-                    let server_clone = self.clone();
-                    tokio::spawn(async move {
-                        server_clone.handle_connection_handshake(connecting).await
-                    });
+            tokio::select! {
+                incoming = endpoint.accept() => {
+                    let Some(incoming) = incoming else {
+                        break;
+                    };
+                    if let Ok(connecting) = incoming.accept() {
+                        let server_clone = self.clone();
+                        let shutdown = shutdown.clone();
+                        connections.spawn(async move {
+                            server_clone
+                                .handle_connection_handshake(connecting, shutdown)
+                                .await
+                        });
+                    }
                 }
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                _ = shutdown.triggered() => break,
             }
         }
+
+        // The endpoint is shared with outgoing connections, so only incoming ones are refused.
+        endpoint.set_server_config(None);
+        info!(
+            server = Self::NAME,
+            connections = connections.len(),
+            "Waiting for requests in progress"
+        );
+        let finished = timeout(SHUTDOWN_TIMEOUT, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if finished.is_err() {
+            warn!(
+                server = Self::NAME,
+                connections = connections.len(),
+                "Aborting requests still in progress"
+            );
+            connections.shutdown().await;
+        }
+
+        Ok(())
     }
 
-    async fn handle_connection_handshake(&self, connecting: Connecting) {
-        match connecting.await {
-            Ok(conn) => {
-                if let Err(e) = self.handle_connection_loop(conn).await {
-                    eprintln!("Connection loop error: {:?}", e);
+    async fn handle_connection_handshake(&self, connecting: Connecting, shutdown: Shutdown) {
+        let span = info_span!(
+            "connection",
+            server = Self::NAME,
+            peer = %connecting.remote_address(),
+            protocol_version = field::Empty,
+            identity = field::Empty
+        );
+
+        async {
+            match connecting.await {
+                Ok(conn) => {
+                    match accept_handshake(&conn).await {
+                        Ok(negotiated) => {
+                            Span::current().record("protocol_version", negotiated.protocol_version);
+                        }
+                        Err(e) => {
+                            warn!(error = ?e, "Protocol handshake failed");
+                            return;
+                        }
+                    }
+
+                    let identity: Option<Arc<str>> =
+                        peer_certificate_identity(&conn).map(Arc::from);
+                    if let Some(identity) = &identity {
+                        Span::current().record("identity", &**identity);
+                    }
+
+                    let connections = metrics::ACTIVE_CONNECTIONS.with_label_values(&[Self::NAME]);
+                    connections.inc();
+                    if let Err(e) = self.handle_connection_loop(conn, identity, shutdown).await {
+                        warn!(error = ?e, "Connection loop error");
+                    }
+                    connections.dec();
                 }
+                Err(e) => warn!(error = ?e, "Handshake failed"),
             }
-            Err(e) => eprintln!("Handshake failed: {:?}", e),
         }
+        .instrument(span)
+        .await
     }
 
-    async fn handle_connection_loop(&self, conn: quinn::Connection) -> Result<()> {
+    /// Handles the streams of the connection one by one. On shutdown the stream in progress
+    /// is handled to the end, after which the connection is closed.
+    async fn handle_connection_loop(
+        &self,
+        conn: quinn::Connection,
+        identity: Option<Arc<str>>,
+        shutdown: Shutdown,
+    ) -> Result<()> {
+        let mut last_send: Option<SendStream> = None;
         loop {
-            let stream = match conn.accept_bi().await {
-                Ok(s) => s,
-                Err(quinn::ConnectionError::ApplicationClosed { .. }) => return Ok(()),
-                Err(e) => return Err(e.into()),
+            let stream = tokio::select! {
+                stream = conn.accept_bi() => match stream {
+                    Ok(s) => s,
+                    Err(quinn::ConnectionError::ApplicationClosed { .. }) => return Ok(()),
+                    Err(e) => return Err(e.into()),
+                },
+                _ = shutdown.triggered() => {
+                    // Closing the connection discards data which hasn't been delivered yet,
+                    // so the peer has to receive the last response first.
+                    if let Some(send) = last_send {
+                        let _ = send.stopped().await;
+                    }
+                    conn.close(0u32.into(), b"shutting down");
+                    return Ok(());
+                }
             };
 
-            let (send, recv) = stream;
-            self.handle_request(send, recv).await?;
+            let (mut send, recv) = stream;
+            PEER_IDENTITY
+                .scope(identity.clone(), self.handle_stream(&mut send, recv))
+                .await?;
+            let _ = send.finish();
+            last_send = Some(send);
+        }
+    }
+
+    /// Receives the request and handles it within the request's span,
+    /// recording the request's metrics. Failure of the handling doesn't close the connection.
+    ///
+    /// Handlers may record the `chunk_id` and `filename` fields of the span.
+    async fn handle_stream(&self, send: &mut SendStream, mut recv: RecvStream) -> Result<()> {
+        let (request_id, request) = Self::Request::recv_with_request_id(&mut recv).await?;
+        let labels = [Self::NAME, request.name()];
+        let span = info_span!(
+            "request",
+            %request_id,
+            message = labels[1],
+            chunk_id = field::Empty,
+            filename = field::Empty
+        );
+
+        metrics::REQUESTS.with_label_values(&labels).inc();
+        let timer = metrics::REQUEST_DURATION
+            .with_label_values(&labels)
+            .start_timer();
+
+        async {
+            match with_request_id(request_id, self.handle_request(send, request)).await {
+                Ok(()) => debug!("Request handled"),
+                Err(e) => {
+                    metrics::REQUEST_ERRORS.with_label_values(&labels).inc();
+                    warn!(error = ?e, "Request failed");
+                }
+            }
         }
+        .instrument(span)
+        .await;
+
+        timer.observe_duration();
+        Ok(())
     }
 
-    async fn handle_request(&self, send: SendStream, recv: RecvStream) -> Result<()>;
+    async fn handle_request(&self, send: &mut SendStream, request: Self::Request) -> Result<()>;
 }
@@ -0,0 +1,73 @@
+//! TLS configuration of the internal endpoints, on which servers of the cluster
+//! authenticate each other with certificates signed by the cluster CA.
+
+use crate::common::ALPN_QUIC_HTTP;
+use crate::common::server::certificate_provider::{CertificateProvider, read_certificates};
+use crate::common::server::certificate_reloader::CertificateReloader;
+use anyhow::{Context, Result};
+use quinn::Connection;
+use rustls::pki_types::CertificateDer;
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Loads the cluster CA, which signs the certificates of all servers of the cluster.
+/// Defaults to the CA of the certificate bundle the server's certificate comes from.
+pub fn internal_ca_roots(
+    ca_cert: Option<&Path>,
+    certificate_provider: &dyn CertificateProvider,
+) -> Result<RootCertStore> {
+    let ca_certs = match ca_cert {
+        Some(ca_cert) => read_certificates(ca_cert)
+            .with_context(|| format!("Couldn't read internal CA {}", ca_cert.display()))?,
+        None => certificate_provider
+            .get_ca_certificates()?
+            .context("No internal CA certificate provided")?,
+    };
+
+    let mut roots = RootCertStore::empty();
+    for cert in ca_certs {
+        roots.add(cert)?;
+    }
+
+    Ok(roots)
+}
+
+/// Config of a server accepting only peers with a certificate signed by the cluster CA.
+pub fn internal_server_crypto(
+    roots: RootCertStore,
+    certificate: Arc<CertificateReloader>,
+) -> Result<ServerConfig> {
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+    let mut crypto = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_cert_resolver(certificate);
+    crypto.alpn_protocols = ALPN_QUIC_HTTP.iter().map(|&x| x.into()).collect();
+
+    Ok(crypto)
+}
+
+/// Config of a client trusting only the cluster CA and presenting the given certificate.
+pub fn internal_client_crypto(
+    roots: RootCertStore,
+    certificate: Arc<CertificateReloader>,
+) -> Result<ClientConfig> {
+    let mut crypto = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_client_cert_resolver(certificate);
+    crypto.alpn_protocols = ALPN_QUIC_HTTP.iter().map(|&x| x.into()).collect();
+
+    Ok(crypto)
+}
+
+/// Identity of the peer: the first DNS name of its verified client certificate.
+pub(crate) fn peer_certificate_identity(conn: &Connection) -> Option<String> {
+    let certs = conn
+        .peer_identity()?
+        .downcast::<Vec<CertificateDer<'static>>>()
+        .ok()?;
+    let cert = webpki::EndEntityCert::try_from(certs.first()?).ok()?;
+
+    cert.valid_dns_names().next().map(str::to_string)
+}
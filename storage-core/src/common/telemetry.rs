@@ -0,0 +1,58 @@
+use clap::ValueEnum;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+/// Identifies a single operation (e.g. an upload of a file) across all the processes it involves.
+pub type RequestId = Uuid;
+
+#[cfg(debug_assertions)]
+const DEFAULT_LOG_FILTER: &str = "info,storage_core=debug,metadataserver=debug,chunkserver=debug";
+#[cfg(not(debug_assertions))]
+const DEFAULT_LOG_FILTER: &str = "info";
+
+tokio::task_local! {
+    static REQUEST_ID: RequestId;
+}
+
+/// Returns the id of the request handled by the current task.
+/// Outside of [`with_request_id`] every call returns a new id.
+pub fn current_request_id() -> RequestId {
+    REQUEST_ID
+        .try_with(|request_id| *request_id)
+        .unwrap_or_else(|_| Uuid::new_v4())
+}
+
+/// Runs the future with the given request id, which is attached to all messages it sends.
+pub async fn with_request_id<F: Future>(request_id: RequestId, f: F) -> F::Output {
+    REQUEST_ID.scope(request_id, f).await
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable, multi-line output.
+    Pretty,
+    /// One JSON object per line, including the enclosing spans.
+    Json,
+}
+
+/// Installs the global subscriber writing logs to stdout.
+/// Levels are set with the `filter` directives (e.g. `info,metadataserver=debug`)
+/// or with the `RUST_LOG` environment variable.
+pub fn init_tracing(format: LogFormat, filter: Option<&str>) {
+    let filter = match filter {
+        Some(filter) => EnvFilter::new(filter),
+        None => {
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER))
+        }
+    };
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Pretty => builder.pretty().init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init(),
+    }
+}
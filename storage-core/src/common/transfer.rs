@@ -0,0 +1,230 @@
+//! Scheduling of the chunk transfers of a client.
+//!
+//! Transfers share the connections to the chunkservers and are limited in how many of them
+//! run at the same time, overall and per chunkserver, so that a client transferring many
+//! files doesn't overload the chunkservers a lot of its chunks are placed on. The bytes
+//! transferred per second can be capped as well, the cap holds on average as every chunk
+//! is transferred as a whole once its turn comes.
+//!
+//! The scheduler counts the chunks and bytes transferred, from which the progress of the
+//! transfers and the time they will take is estimated.
+
+use crate::common::types::{ChunkLocations, ServerConnections, ServerLocation};
+use anyhow::Result;
+use moka::future::Cache;
+use std::fmt;
+use std::num::NonZeroU64;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TransferLimits {
+    /// Chunks transferred at the same time.
+    pub max_concurrent_chunks: usize,
+    /// Chunks transferred at the same time to or from a single chunkserver.
+    pub max_chunks_per_server: usize,
+    /// Bytes transferred per second on average, not limited if None.
+    pub bytes_per_second: Option<NonZeroU64>,
+}
+
+impl Default for TransferLimits {
+    fn default() -> Self {
+        TransferLimits {
+            max_concurrent_chunks: 4,
+            max_chunks_per_server: 2,
+            bytes_per_second: None,
+        }
+    }
+}
+
+/// Progress of the transfers scheduled so far.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransferProgress {
+    pub bytes: u64,
+    pub total_bytes: u64,
+    pub chunks: u64,
+    pub total_chunks: u64,
+    /// Time since the scheduler was created.
+    pub elapsed: Duration,
+}
+
+impl TransferProgress {
+    /// Time the remaining bytes will take at the rate of the ones already transferred,
+    /// None until some are.
+    pub fn eta(&self) -> Option<Duration> {
+        if self.bytes == 0 {
+            return None;
+        }
+        let remaining = self.total_bytes.saturating_sub(self.bytes);
+        Some(self.elapsed.mul_f64(remaining as f64 / self.bytes as f64))
+    }
+}
+
+impl fmt::Display for TransferProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} bytes, {} of {} chunks",
+            self.bytes, self.total_bytes, self.chunks, self.total_chunks
+        )?;
+        match self.eta() {
+            // Whole seconds, the estimate isn't any more precise.
+            Some(eta) => write!(
+                f,
+                ", ETA {}",
+                humantime::format_duration(Duration::from_secs(eta.as_secs()))
+            ),
+            None => write!(f, ", ETA unknown"),
+        }
+    }
+}
+
+pub struct TransferScheduler {
+    connections: ServerConnections,
+    limits: TransferLimits,
+    chunks: Semaphore,
+    server_chunks: scc::HashMap<ServerLocation, Arc<Semaphore>>,
+    /// When the next transfer may start so that the bytes transferred stay under the cap.
+    next_start: Mutex<Instant>,
+
+    started: Instant,
+    bytes: AtomicU64,
+    total_bytes: AtomicU64,
+    transferred_chunks: AtomicU64,
+    total_chunks: AtomicU64,
+}
+
+impl TransferScheduler {
+    pub fn new(limits: TransferLimits) -> Self {
+        let now = Instant::now();
+        TransferScheduler {
+            // Connections are cached per chunkserver, not per transfer. Transfers of
+            // erasure-coded chunks span several chunkservers, so the running transfers
+            // reach more of them than there are transfers.
+            connections: Cache::new(
+                limits
+                    .max_concurrent_chunks
+                    .saturating_mul(limits.max_chunks_per_server) as u64,
+            ),
+            limits,
+            chunks: Semaphore::new(limits.max_concurrent_chunks),
+            server_chunks: scc::HashMap::new(),
+            next_start: Mutex::new(now),
+            started: now,
+            bytes: AtomicU64::new(0),
+            total_bytes: AtomicU64::new(0),
+            transferred_chunks: AtomicU64::new(0),
+            total_chunks: AtomicU64::new(0),
+        }
+    }
+
+    /// Adds the chunks about to be transferred to the progress.
+    pub fn add_pending(&self, chunks: u64, bytes: u64) {
+        self.total_chunks.fetch_add(chunks, Ordering::Relaxed);
+        self.total_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn progress(&self) -> TransferProgress {
+        TransferProgress {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            total_bytes: self.total_bytes.load(Ordering::Relaxed),
+            chunks: self.transferred_chunks.load(Ordering::Relaxed),
+            total_chunks: self.total_chunks.load(Ordering::Relaxed),
+            elapsed: self.started.elapsed(),
+        }
+    }
+
+    /// Runs the transfer of a chunk of `bytes` to or from the chunkservers once it's within
+    /// the limits. The chunk is counted as transferred if the transfer succeeds, failed
+    /// transfers retried on other chunkservers are scheduled again.
+    pub async fn transfer<T, F>(
+        &self,
+        servers: impl IntoIterator<Item = ServerLocation>,
+        bytes: u64,
+        transfer: impl FnOnce(ServerConnections) -> F,
+    ) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let mut servers: Vec<_> = servers.into_iter().collect();
+        // Always taken in the same order, so that transfers spanning several chunkservers
+        // can't wait on each other.
+        servers.sort();
+        servers.dedup();
+        let mut server_permits = Vec::with_capacity(servers.len());
+        for server in servers {
+            server_permits.push(self.server_permit(server).await);
+        }
+        let _permit = self
+            .chunks
+            .acquire()
+            .await
+            .expect("Transfer semaphore is never closed");
+        self.wait_for_bandwidth(bytes).await;
+
+        let result = transfer(self.connections.clone()).await;
+        if result.is_ok() {
+            self.bytes.fetch_add(bytes, Ordering::Relaxed);
+            self.transferred_chunks.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Closes the connections to the chunkservers once all transfers are done.
+    pub fn close(&self) {
+        for (_, conn) in self.connections.iter() {
+            conn.close(0u32.into(), b"done");
+        }
+    }
+
+    async fn server_permit(&self, server: ServerLocation) -> OwnedSemaphorePermit {
+        let semaphore = self
+            .server_chunks
+            .entry_async(server)
+            .await
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limits.max_chunks_per_server)))
+            .get()
+            .clone();
+        semaphore
+            .acquire_owned()
+            .await
+            .expect("Transfer semaphore is never closed")
+    }
+
+    async fn wait_for_bandwidth(&self, bytes: u64) {
+        let Some(bytes_per_second) = self.limits.bytes_per_second else {
+            return;
+        };
+        let start = {
+            let mut next_start = self.next_start.lock().unwrap();
+            let start = (*next_start).max(Instant::now());
+            *next_start =
+                start + Duration::from_secs_f64(bytes as f64 / bytes_per_second.get() as f64);
+            start
+        };
+        tokio::time::sleep_until(start).await;
+    }
+}
+
+impl ChunkLocations {
+    /// Chunkservers the chunk is transferred to or from first, the primary or the stripes.
+    pub fn transfer_servers(&self) -> Vec<ServerLocation> {
+        match &self.stripes {
+            Some(stripes) => stripes
+                .stripes
+                .iter()
+                .flatten()
+                .map(|stripe| stripe.server_location)
+                .collect(),
+            None => self
+                .primary
+                .iter()
+                .map(|primary| primary.server_location)
+                .collect(),
+        }
+    }
+}
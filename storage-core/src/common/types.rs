@@ -1,19 +1,349 @@
 use crate::common::ChunkserverLocation;
+use crate::common::config::cluster_config;
+use crate::common::telemetry::RequestId;
 use moka::future::Cache;
 use quinn::Connection;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 use uuid::Uuid;
 
 pub(crate) type ChunkId = Uuid;
+pub(crate) type ChunkserverId = Uuid;
 pub(crate) type ServerLocation = SocketAddr;
-pub(crate) type ServerConnections = Cache<ServerLocation, Connection>;
+pub type ServerConnections = Cache<ServerLocation, Connection>;
 pub(crate) type Hostname = String;
+pub(crate) type RackId = String;
 pub type PrimaryLocation = ChunkserverLocation;
 pub type ReplicaLocation = ChunkserverLocation;
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChunkLocations {
     pub chunk_id: ChunkId,
-    pub primary: PrimaryLocation,
+    /// Chunkserver storing the whole chunk, None for erasure-coded chunks.
+    pub primary: Option<PrimaryLocation>,
     pub replicas: Vec<ReplicaLocation>,
+    /// Stripes of an erasure-coded chunk, stored instead of the primary and replicas.
+    #[serde(default)]
+    pub stripes: Option<StripeLocations>,
+    /// Whether the chunk is already stored, as a deduplicated copy of the same content.
+    /// Such chunks aren't uploaded and have no locations.
+    #[serde(default)]
+    pub stored: bool,
+    /// Chunkservers the chunk is uploaded to instead if the upload to the primary fails.
+    /// Only placements of new chunks have them.
+    #[serde(default)]
+    pub alternates: Vec<ChunkserverLocation>,
+}
+
+/// Stripes an erasure-coded chunk is stored as, see [`crate::common::erasure`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StripeLocations {
+    pub coding: ErasureCoding,
+    /// Size of the chunk before it was encoded, in bytes.
+    pub chunk_size: u64,
+    /// Data stripes followed by parity stripes, None for the stripes which aren't available.
+    pub stripes: Vec<Option<ChunkserverLocation>>,
+}
+
+/// Instruction to copy a chunk to another chunkserver.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplicationOrder {
+    /// Chunk to copy and the chunkserver (internal address) to copy it to.
+    pub target: ChunkserverLocation,
+    /// Id of the request which created the chunk, so that its copies can be traced.
+    pub request_id: RequestId,
+}
+
+/// State of a chunkserver as seen by the MetadataServer.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkserverStatus {
+    pub server_id: ChunkserverId,
+    pub hostname: Hostname,
+    pub rack_id: RackId,
+    pub external_address: SocketAddr,
+    /// Available space on the chunkserver's disk in bytes.
+    pub available_space: u64,
+    /// Seconds elapsed since the last heartbeat of the chunkserver.
+    pub secs_since_heartbeat: u64,
+    /// Number of chunks the chunkserver reported to store.
+    pub chunk_count: u64,
+    /// Whether the chunkserver is being drained of its chunks.
+    pub draining: bool,
+}
+
+/// Placement of a chunk as seen by the MetadataServer.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkStatus {
+    pub chunk_id: ChunkId,
+    /// Id of the primary server or None, if the primary isn't selected yet.
+    pub primary: Option<ChunkserverId>,
+    pub replicas: Vec<ChunkserverId>,
+    /// Chunkservers (among the primary and replicas) which reported storing the chunk.
+    pub stored_on: Vec<ChunkserverId>,
+    /// Number of chunkservers the chunk has to be stored on.
+    #[serde(default)]
+    pub required_copies: Option<u64>,
+    /// Number of references to the chunk from files, more than one if the chunk is shared.
+    #[serde(default)]
+    pub references: Option<u64>,
+    /// Stripes of an erasure-coded chunk, data stripes followed by parity stripes.
+    #[serde(default)]
+    pub stripes: Vec<ChunkStatus>,
+}
+
+/// Durability of a file, deciding how many copies of its chunks are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageClass {
+    /// A single copy, for data which can be recreated.
+    Scratch,
+    /// The number of copies configured for the cluster, 3 by default.
+    Standard,
+    /// Five copies, for data which must survive the loss of several chunkservers.
+    Archive,
+}
+
+/// Number of copies of the chunks of a file, chosen when the file is uploaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Replication {
+    Class(StorageClass),
+    /// Explicit number of copies, including the primary one.
+    Copies(u64),
+    /// Chunks split into stripes with a Reed-Solomon code, each stripe stored once.
+    ErasureCoded(ErasureCoding),
+}
+
+impl Replication {
+    /// Number of chunkservers every chunk of the file has to be stored on,
+    /// or every stripe of the chunks of an erasure-coded file.
+    pub fn copies(self) -> usize {
+        match self {
+            Replication::Class(StorageClass::Scratch) => 1,
+            Replication::Class(StorageClass::Standard) => cluster_config().n_chunk_replicas + 1,
+            Replication::Class(StorageClass::Archive) => 5,
+            Replication::Copies(copies) => copies as usize,
+            Replication::ErasureCoded(_) => 1,
+        }
+    }
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Replication::Class(StorageClass::Standard)
+    }
+}
+
+impl fmt::Display for Replication {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Replication::Class(StorageClass::Scratch) => f.write_str("scratch"),
+            Replication::Class(StorageClass::Standard) => f.write_str("standard"),
+            Replication::Class(StorageClass::Archive) => f.write_str("archive"),
+            Replication::Copies(copies) => write!(f, "{}", copies),
+            Replication::ErasureCoded(coding) => write!(f, "{}", coding),
+        }
+    }
+}
+
+/// Parses a storage class (`scratch`, `standard` or `archive`), a number of copies
+/// or a Reed-Solomon code (e.g. `rs-6-3`).
+impl FromStr for Replication {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scratch" => Ok(Replication::Class(StorageClass::Scratch)),
+            "standard" => Ok(Replication::Class(StorageClass::Standard)),
+            "archive" => Ok(Replication::Class(StorageClass::Archive)),
+            coding if coding.starts_with("rs-") => coding.parse().map(Replication::ErasureCoded),
+            copies => match copies.parse() {
+                Ok(copies) if copies > 0 => Ok(Replication::Copies(copies)),
+                _ => Err(format!(
+                    "Expected scratch, standard, archive, rs-<data>-<parity> \
+                     or a positive number of copies, got {}",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Reed-Solomon code splitting a chunk into `data_stripes` stripes
+/// and adding `parity_stripes` stripes, any `data_stripes` of which restore the chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErasureCoding {
+    pub data_stripes: u8,
+    pub parity_stripes: u8,
+}
+
+impl ErasureCoding {
+    /// Maximum number of stripes of a chunk, given by the size of the Galois field.
+    pub const MAX_STRIPES: usize = 256;
+
+    pub fn new(data_stripes: u8, parity_stripes: u8) -> anyhow::Result<Self> {
+        let coding = ErasureCoding {
+            data_stripes,
+            parity_stripes,
+        };
+        coding.validate()?;
+        Ok(coding)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.data_stripes == 0 || self.parity_stripes == 0 {
+            anyhow::bail!("Erasure coding needs at least one data and one parity stripe");
+        }
+        if self.stripes() > Self::MAX_STRIPES {
+            anyhow::bail!(
+                "Erasure coding allows at most {} stripes",
+                Self::MAX_STRIPES
+            );
+        }
+        Ok(())
+    }
+
+    /// Number of stripes of every chunk, data and parity ones.
+    pub fn stripes(self) -> usize {
+        self.data_stripes as usize + self.parity_stripes as usize
+    }
+
+    /// Size of every stripe of a chunk of the given size, the last data stripe is padded to it.
+    pub fn stripe_size(self, chunk_size: u64) -> u64 {
+        chunk_size.div_ceil(self.data_stripes as u64)
+    }
+}
+
+impl fmt::Display for ErasureCoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rs-{}-{}", self.data_stripes, self.parity_stripes)
+    }
+}
+
+/// Parses `rs-<data stripes>-<parity stripes>`, e.g. `rs-6-3`.
+impl FromStr for ErasureCoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Expected rs-<data>-<parity>, got {}", s);
+        let (data_stripes, parity_stripes) = s
+            .strip_prefix("rs-")
+            .and_then(|stripes| stripes.split_once('-'))
+            .ok_or_else(invalid)?;
+
+        ErasureCoding::new(
+            data_stripes.parse().map_err(|_| invalid())?,
+            parity_stripes.parse().map_err(|_| invalid())?,
+        )
+        .map_err(|e| e.to_string())
+    }
+}
+
+/// Keyed hash of the content of a chunk, see [`crate::common::dedup`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ContentHash(pub [u8; 32]);
+
+impl fmt::Display for ContentHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Chunk of a file split by its content, identified by the hash of the content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkDigest {
+    pub size: u64,
+    pub hash: ContentHash,
+}
+
+/// Part of a chunk or a file, starting at the offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ByteRange {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// How long the previous versions of a versioned file are kept. A version is dropped
+/// once it's past either of the limits, versions are kept forever without any.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Retention {
+    /// Number of versions kept, including the current one.
+    pub keep_versions: Option<u32>,
+    /// How long a version is kept after it's replaced by a newer one.
+    pub keep_for: Option<Duration>,
+}
+
+/// Version of a versioned file, created by every upload of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileVersion {
+    /// Number of the version, starting at 1.
+    pub version: u64,
+    pub created_at: SystemTime,
+}
+
+/// Chunk stored on a chunkserver, as reported by the chunkserver.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct StoredChunk {
+    pub chunk_id: ChunkId,
+    /// Size of the stored chunk in bytes.
+    pub size: u64,
+    /// Number of writes applied to the chunk since it was uploaded.
+    #[serde(default)]
+    pub version: u64,
+}
+
+/// Result of checking consistency of the files' metadata with chunks stored on chunkservers.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FsckReport {
+    pub checked_files: u64,
+    pub checked_chunks: u64,
+    /// Chunks of files which aren't stored on any chunkserver.
+    pub missing_chunks: Vec<MissingChunk>,
+    /// Chunks known to the MetadataServer or stored on chunkservers which don't belong to any file.
+    pub orphaned_chunks: Vec<OrphanedChunk>,
+    /// Chunks of files without an elected primary.
+    pub chunks_without_primary: Vec<ChunkId>,
+    /// Copies of chunks which size differs from the size expected by the MetadataServer.
+    pub size_mismatches: Vec<SizeMismatch>,
+    /// Chunks of files stored on fewer chunkservers than required.
+    pub replication_deficits: Vec<ReplicationDeficit>,
+    /// Whether re-replication and removal of the broken copies has been scheduled.
+    pub repair_scheduled: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MissingChunk {
+    pub filename: String,
+    pub chunk_id: ChunkId,
+    /// Whether the MetadataServer has any metadata of the chunk.
+    pub has_metadata: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrphanedChunk {
+    pub chunk_id: ChunkId,
+    /// Whether the MetadataServer has any metadata of the chunk.
+    pub has_metadata: bool,
+    /// Chunkservers which reported storing the chunk.
+    pub stored_on: Vec<ChunkserverId>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SizeMismatch {
+    pub chunk_id: ChunkId,
+    pub server_id: ChunkserverId,
+    pub expected_size: u64,
+    pub stored_size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplicationDeficit {
+    pub chunk_id: ChunkId,
+    pub required_copies: u64,
+    pub stored_copies: u64,
 }
@@ -0,0 +1,230 @@
+use crate::gc::GarbageCollector;
+use crate::replication::{ReplicationScheduler, stored_copies};
+use crate::types::{
+    ActiveChunkserver, ChunkId, ChunkMetadata, ChunkserverId, FileId, FileMetadata,
+};
+use async_trait::async_trait;
+use quinn::Endpoint;
+use std::sync::Arc;
+use storage_core::common::telemetry::RequestId;
+use storage_core::common::types::{ChunkStatus, ChunkserverStatus};
+use storage_core::common::{
+    DrainChunkserverRequestPayload, ErrorCode, ErrorPayload, FsckRequestPayload,
+    FsckResponsePayload, GetFileChunkMapRequestPayload, GetFileChunkMapResponsePayload,
+    ListChunkserversRequestPayload, ListChunkserversResponsePayload,
+    ListUnderReplicatedChunksRequestPayload, ListUnderReplicatedChunksResponsePayload,
+    MetadataServerAdminHandler, RebalanceRequestPayload, ReplicationScheduledPayload,
+};
+use tokio::time::Instant;
+use tracing::{Span, info};
+
+/// 'MetadataServerAdmin' is a struct used for communication with cluster administrators.
+#[derive(Clone)]
+pub struct MetadataServerAdmin {
+    pub(super) admin_endpoint: Arc<Endpoint>,
+
+    pub(super) active_chunkservers: Arc<scc::HashMap<ChunkserverId, ActiveChunkserver>>,
+
+    pub(super) files: Arc<scc::HashMap<FileId, FileMetadata>>,
+    pub(super) chunks: Arc<scc::HashMap<ChunkId, ChunkMetadata>>,
+    pub(super) gc: GarbageCollector,
+
+    pub(super) replication: ReplicationScheduler,
+}
+
+impl MetadataServerAdmin {
+    pub(crate) fn new(
+        admin_endpoint: Arc<Endpoint>,
+        active_chunkservers: Arc<scc::HashMap<ChunkserverId, ActiveChunkserver>>,
+        files: Arc<scc::HashMap<FileId, FileMetadata>>,
+        chunks: Arc<scc::HashMap<ChunkId, ChunkMetadata>>,
+        gc: GarbageCollector,
+        replication: ReplicationScheduler,
+    ) -> Self {
+        MetadataServerAdmin {
+            admin_endpoint,
+            active_chunkservers,
+            files,
+            chunks,
+            gc,
+            replication,
+        }
+    }
+
+    async fn chunk_status(&self, chunk: ChunkMetadata) -> ChunkStatus {
+        let stored_on = stored_copies(&self.active_chunkservers, &chunk)
+            .await
+            .into_iter()
+            .map(|copy| copy.server_id)
+            .collect();
+
+        let mut stripes = Vec::new();
+        for stripe_id in chunk.stripes.iter().flat_map(|s| s.stripe_ids.iter()) {
+            if let Some(stripe) = self.chunks.read_async(stripe_id, |_, s| s.clone()).await {
+                // Stripes don't have stripes of their own, the recursion ends with them.
+                stripes.push(Box::pin(self.chunk_status(stripe)).await);
+            }
+        }
+
+        ChunkStatus {
+            chunk_id: chunk.chunk_id,
+            primary: chunk.primary,
+            replicas: chunk.replicas,
+            stored_on,
+            required_copies: Some(chunk.required_copies as u64),
+            references: Some(chunk.references as u64),
+            stripes,
+        }
+    }
+}
+
+#[async_trait]
+impl MetadataServerAdminHandler for MetadataServerAdmin {
+    async fn list_chunkservers(
+        &self,
+        _payload: ListChunkserversRequestPayload,
+    ) -> anyhow::Result<ListChunkserversResponsePayload> {
+        let mut chunkservers = Vec::new();
+        self.active_chunkservers
+            .iter_async(|_, server| {
+                chunkservers.push(ChunkserverStatus {
+                    server_id: server.server_id,
+                    hostname: server.hostname.clone(),
+                    rack_id: server.rack_id.clone(),
+                    external_address: server.external_address,
+                    available_space: server.available_space,
+                    secs_since_heartbeat: server.last_heartbeat.elapsed().as_secs(),
+                    chunk_count: server.chunks.len() as u64,
+                    draining: server.draining,
+                });
+                true
+            })
+            .await;
+
+        Ok(ListChunkserversResponsePayload { chunkservers })
+    }
+
+    async fn fetch_file_chunk_map(
+        &self,
+        payload: GetFileChunkMapRequestPayload,
+    ) -> anyhow::Result<GetFileChunkMapResponsePayload> {
+        Span::current().record("filename", payload.filename.as_str());
+        let Some((file_chunks_ids, replication)) = self
+            .files
+            .read_async(&payload.filename, |_, file| {
+                (file.chunks.clone(), file.replication)
+            })
+            .await
+        else {
+            return Err(ErrorPayload::new(
+                ErrorCode::FileNotFound,
+                format!("File {} doesn't exist", payload.filename),
+            )
+            .into());
+        };
+
+        let mut chunks = Vec::with_capacity(file_chunks_ids.len());
+        for chunk_id in file_chunks_ids {
+            let chunk = self
+                .chunks
+                .read_async(&chunk_id, |_, chunk| chunk.clone())
+                .await
+                .unwrap_or(ChunkMetadata {
+                    chunk_id,
+                    size: 0,
+                    request_id: RequestId::nil(),
+                    required_copies: replication.copies(),
+                    references: 0,
+                    primary: None,
+                    replicas: Vec::new(),
+                    stripes: None,
+                    content_hash: None,
+                    version: 0,
+                    lease: None,
+                    alternates: Vec::new(),
+                    created_at: Instant::now(),
+                });
+
+            chunks.push(self.chunk_status(chunk).await);
+        }
+
+        Ok(GetFileChunkMapResponsePayload { chunks })
+    }
+
+    async fn list_under_replicated_chunks(
+        &self,
+        _payload: ListUnderReplicatedChunksRequestPayload,
+    ) -> anyhow::Result<ListUnderReplicatedChunksResponsePayload> {
+        let mut all_chunks = Vec::new();
+        self.chunks
+            .iter_async(|_, chunk| {
+                all_chunks.push(chunk.clone());
+                true
+            })
+            .await;
+
+        let mut chunks = Vec::new();
+        for chunk in all_chunks {
+            let required_copies = chunk.required_copies;
+            let status = self.chunk_status(chunk).await;
+            if status.stored_on.len() < required_copies {
+                chunks.push(status);
+            }
+        }
+
+        Ok(ListUnderReplicatedChunksResponsePayload { chunks })
+    }
+
+    async fn drain_chunkserver(
+        &self,
+        payload: DrainChunkserverRequestPayload,
+    ) -> anyhow::Result<ReplicationScheduledPayload> {
+        let Some(scheduled_chunks) = self
+            .active_chunkservers
+            .update_async(&payload.server_id, |_, server| {
+                server.draining = true;
+                server.chunks.len() as u64
+            })
+            .await
+        else {
+            return Err(ErrorPayload::new(
+                ErrorCode::ChunkserverNotFound,
+                format!("Chunkserver {} isn't active", payload.server_id),
+            )
+            .into());
+        };
+
+        info!(server_id = %payload.server_id, "Draining chunkserver");
+
+        // Chunks are moved out by the reconciliation, which we don't want to wait for.
+        let server_clone = self.clone();
+        tokio::spawn(async move {
+            server_clone
+                .replication
+                .reconcile(&server_clone.active_chunkservers, &server_clone.chunks)
+                .await
+        });
+
+        Ok(ReplicationScheduledPayload { scheduled_chunks })
+    }
+
+    async fn rebalance(
+        &self,
+        _payload: RebalanceRequestPayload,
+    ) -> anyhow::Result<ReplicationScheduledPayload> {
+        let scheduled_chunks = self
+            .replication
+            .rebalance(&self.active_chunkservers, &self.chunks)
+            .await;
+
+        info!(scheduled_chunks, "Rebalancing chunks");
+
+        Ok(ReplicationScheduledPayload { scheduled_chunks })
+    }
+
+    async fn fsck(&self, payload: FsckRequestPayload) -> anyhow::Result<FsckResponsePayload> {
+        Ok(FsckResponsePayload {
+            report: self.check_consistency(payload.repair).await,
+        })
+    }
+}
@@ -0,0 +1,211 @@
+use crate::admin::MetadataServerAdmin;
+use crate::types::{ChunkId, ChunkMetadata, ChunkserverId};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use storage_core::common::types::{
+    FsckReport, MissingChunk, OrphanedChunk, ReplicationDeficit, SizeMismatch,
+};
+use tracing::info;
+
+/// Chunks placed this recently aren't freed as orphans. Fsck goes through the files before
+/// the chunks, a chunk placed in between isn't referenced by any of the files it checked.
+const ORPHAN_MIN_AGE: Duration = Duration::from_secs(5 * 60);
+
+impl MetadataServerAdmin {
+    /// Cross-checks chunks of every file against the chunks' metadata
+    /// and the chunks reported by the active chunkservers.
+    pub(super) async fn check_consistency(&self, repair: bool) -> FsckReport {
+        let mut files = Vec::new();
+        let mut pending = Vec::new();
+        self.files
+            .iter_async(|filename, file| {
+                // Previous versions of versioned files reference their chunks as well.
+                let chunks: Vec<_> = file
+                    .all_versions()
+                    .flat_map(|(chunks, _)| chunks.iter().copied())
+                    .collect();
+                files.push((filename.clone(), chunks));
+                // Chunks appended by pending writes aren't checked until they're written.
+                pending.extend(file.pending_writes.iter().flat_map(|p| p.appended.clone()));
+                true
+            })
+            .await;
+
+        let mut chunks = HashMap::new();
+        self.chunks
+            .iter_async(|chunk_id, chunk| {
+                chunks.insert(*chunk_id, chunk.clone());
+                true
+            })
+            .await;
+
+        // Chunkservers storing each chunk, with the reported size of the chunk.
+        let mut reported: HashMap<ChunkId, Vec<(ChunkserverId, u64)>> = HashMap::new();
+        self.active_chunkservers
+            .iter_async(|server_id, server| {
+                for (&chunk_id, &size) in server.chunks.iter() {
+                    reported
+                        .entry(chunk_id)
+                        .or_default()
+                        .push((*server_id, size));
+                }
+                true
+            })
+            .await;
+
+        let mut report = FsckReport {
+            checked_files: files.len() as u64,
+            ..Default::default()
+        };
+
+        let mut referenced: HashSet<_> = pending.into_iter().collect();
+        for (filename, file_chunks) in files {
+            for chunk_id in file_chunks {
+                report.checked_chunks += 1;
+                referenced.insert(chunk_id);
+
+                // Erasure-coded chunks are stored, and checked, as their stripes.
+                let stored_ids = match chunks.get(&chunk_id).and_then(|c| c.stripes.as_ref()) {
+                    Some(stripes) => stripes.stripe_ids.clone(),
+                    None => vec![chunk_id],
+                };
+                referenced.extend(stored_ids.iter().copied());
+
+                for chunk_id in stored_ids {
+                    let Some(chunk) = chunks.get(&chunk_id) else {
+                        report.missing_chunks.push(MissingChunk {
+                            filename: filename.clone(),
+                            chunk_id,
+                            has_metadata: false,
+                        });
+                        continue;
+                    };
+
+                    Self::check_chunk(
+                        &mut report,
+                        &filename,
+                        chunk,
+                        reported.get(&chunk_id).map_or(&[][..], Vec::as_slice),
+                    );
+                }
+            }
+        }
+
+        let mut orphaned: HashSet<_> = chunks.keys().copied().collect();
+        orphaned.extend(reported.keys().copied());
+        for chunk_id in orphaned.difference(&referenced) {
+            report.orphaned_chunks.push(OrphanedChunk {
+                chunk_id: *chunk_id,
+                has_metadata: chunks.contains_key(chunk_id),
+                stored_on: reported
+                    .get(chunk_id)
+                    .into_iter()
+                    .flatten()
+                    .map(|&(server_id, _)| server_id)
+                    .collect(),
+            });
+        }
+
+        info!(
+            checked_files = report.checked_files,
+            checked_chunks = report.checked_chunks,
+            missing_chunks = report.missing_chunks.len(),
+            orphaned_chunks = report.orphaned_chunks.len(),
+            "Fsck finished"
+        );
+
+        if repair {
+            self.repair(&report).await;
+            report.repair_scheduled = true;
+        }
+
+        report
+    }
+
+    fn check_chunk(
+        report: &mut FsckReport,
+        filename: &str,
+        chunk: &ChunkMetadata,
+        reported: &[(ChunkserverId, u64)],
+    ) {
+        if chunk.primary.is_none() {
+            report.chunks_without_primary.push(chunk.chunk_id);
+        }
+
+        let mut stored_copies = 0;
+        for &(server_id, size) in reported {
+            // The size of a chunk changes while its primary writes to it.
+            if size != chunk.size && !chunk.is_leased() {
+                report.size_mismatches.push(SizeMismatch {
+                    chunk_id: chunk.chunk_id,
+                    server_id,
+                    expected_size: chunk.size,
+                    stored_size: size,
+                });
+            } else if chunk.holders().any(|s_id| s_id == server_id) {
+                stored_copies += 1;
+            }
+        }
+
+        if stored_copies == 0 {
+            report.missing_chunks.push(MissingChunk {
+                filename: filename.to_string(),
+                chunk_id: chunk.chunk_id,
+                has_metadata: true,
+            });
+        } else if stored_copies < chunk.required_copies {
+            report.replication_deficits.push(ReplicationDeficit {
+                chunk_id: chunk.chunk_id,
+                required_copies: chunk.required_copies as u64,
+                stored_copies: stored_copies as u64,
+            });
+        }
+    }
+
+    /// Frees orphaned chunks and removes copies of chunks with mismatched size,
+    /// then orders re-replication of the chunks which lack copies.
+    async fn repair(&self, report: &FsckReport) {
+        let mut freed = 0;
+        for orphan in report.orphaned_chunks.iter() {
+            if self
+                .gc
+                .free_orphan(orphan.chunk_id, &orphan.stored_on, ORPHAN_MIN_AGE)
+                .await
+            {
+                freed += 1;
+            }
+        }
+
+        info!(
+            freed,
+            kept = report.orphaned_chunks.len() - freed,
+            "Orphaned chunks freed"
+        );
+
+        let missing: HashSet<_> = report
+            .missing_chunks
+            .iter()
+            .map(|missing| missing.chunk_id)
+            .collect();
+
+        for mismatch in report.size_mismatches.iter() {
+            // Broken copies are kept if there is no other copy of the chunk.
+            if missing.contains(&mismatch.chunk_id) {
+                continue;
+            }
+
+            self.chunks
+                .update_async(&mismatch.chunk_id, |_, chunk| {
+                    chunk.remove_holder(mismatch.server_id)
+                })
+                .await;
+            self.replication
+                .order_deletion(mismatch.server_id, mismatch.chunk_id)
+                .await;
+        }
+
+        self.replication
+            .reconcile(&self.active_chunkservers, &self.chunks)
+            .await;
+    }
+}
@@ -0,0 +1,5 @@
+mod definition;
+mod fsck;
+mod server_impl;
+
+pub use definition::MetadataServerAdmin;
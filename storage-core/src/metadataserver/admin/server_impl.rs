@@ -0,0 +1,27 @@
+use crate::admin::MetadataServerAdmin;
+use async_trait::async_trait;
+use quinn::{Endpoint, SendStream};
+use storage_core::common::{MetadataServerAdminHandler, MetadataServerAdminMessage, QuicServer};
+
+#[async_trait]
+impl QuicServer for MetadataServerAdmin {
+    type Request = MetadataServerAdminMessage;
+
+    const NAME: &'static str = "metadataserver_admin";
+
+    fn listening_endpoint(&self) -> &Endpoint {
+        &self.admin_endpoint
+    }
+
+    async fn setup(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn handle_request(
+        &self,
+        send: &mut SendStream,
+        request: Self::Request,
+    ) -> anyhow::Result<()> {
+        self.dispatch(send, request).await
+    }
+}
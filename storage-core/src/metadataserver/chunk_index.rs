@@ -0,0 +1,43 @@
+//! Index of deduplicated chunks by the hashes of their content,
+//! see [`storage_core::common::dedup`].
+
+use crate::types::{ChunkId, ChunkMetadata};
+use storage_core::common::types::ContentHash;
+
+/// Chunks with known content, which are stored only once and referenced by every file
+/// containing them. A chunk is dropped from the index once it's freed, see [`crate::gc`].
+#[derive(Default)]
+pub(crate) struct ChunkIndex {
+    chunks: scc::HashMap<ContentHash, ChunkId>,
+}
+
+impl ChunkIndex {
+    /// Rebuilds the index from the loaded metadata.
+    pub(crate) fn rebuild(&self, chunks: &scc::HashMap<ChunkId, ChunkMetadata>) {
+        chunks.iter_sync(|&chunk_id, chunk| {
+            if let Some(hash) = chunk.content_hash
+                && chunk.references > 0
+            {
+                self.chunks.upsert_sync(hash, chunk_id);
+            }
+            true
+        });
+    }
+
+    /// Returns the chunk with the given content, if any.
+    pub(crate) async fn get(&self, hash: &ContentHash) -> Option<ChunkId> {
+        self.chunks.read_async(hash, |_, &chunk_id| chunk_id).await
+    }
+
+    /// Indexes a new chunk, in place of the chunk previously indexed with the same content.
+    pub(crate) async fn insert(&self, hash: ContentHash, chunk_id: ChunkId) {
+        self.chunks.upsert_async(hash, chunk_id).await;
+    }
+
+    /// Removes the chunk from the index, if it's still indexed.
+    pub(crate) async fn remove(&self, hash: &ContentHash, chunk_id: ChunkId) {
+        self.chunks
+            .remove_if_async(hash, |indexed| *indexed == chunk_id)
+            .await;
+    }
+}
@@ -1,11 +1,18 @@
 use super::types::Hostname;
+use anyhow::{Result, bail};
 use clap::Parser;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use storage_core::common::config::ClusterConfig;
+use storage_core::common::telemetry::LogFormat;
 
 #[derive(Parser, Debug)]
 #[clap(name = "server")]
 pub(super) struct MetadataServerOpt {
+    /// Configuration file in TOML, setting any of the other flags by its long name.
+    /// Flags given on the command line take precedence over it.
+    #[clap(long = "config")]
+    pub(super) config: Option<PathBuf>,
     /// file to log TLS keys to for debugging
     #[clap(long = "keylog", default_value = "false")]
     pub(super) keylog: bool,
@@ -15,13 +22,95 @@ pub(super) struct MetadataServerOpt {
     /// TLS certificate in PEM format
     #[clap(short = 'c', long = "cert", requires = "key")]
     pub(super) cert: Option<PathBuf>,
+    /// Directory of the certificate bundle issued by `admin certs issue`.
+    #[clap(long = "certificates", conflicts_with = "cert")]
+    pub(super) certificates: Option<PathBuf>,
+    /// Certificate of the cluster CA in PEM format. Chunkservers have to present a certificate
+    /// signed by it. Defaults to the CA of the certificate bundle.
+    #[clap(long = "internal-ca")]
+    pub(super) internal_ca: Option<PathBuf>,
     /// Address to listen on for connection from clients.
     #[clap(long = "client-socket-addr", default_value = "[::1]:4422")]
     pub(super) client_socket_addr: SocketAddr,
     /// Address to listen on for connection from internal servers.
     #[clap(long = "internal-socket-addr", default_value = "[::1]:4433")]
     pub(super) internal_socket_addr: SocketAddr,
+    /// Address to listen on for connection from cluster administrators.
+    #[clap(long = "admin-socket-addr", default_value = "[::1]:4444")]
+    pub(super) admin_socket_addr: SocketAddr,
+    /// Format of the logs.
+    #[clap(long = "log-format", value_enum, default_value = "pretty")]
+    pub(super) log_format: LogFormat,
+    /// Log filter directives, e.g. `info,storage_core=debug`. Overrides `RUST_LOG`.
+    #[clap(long = "log-filter")]
+    pub(super) log_filter: Option<String>,
+    /// Address to serve metrics in Prometheus format on (HTTP `GET /metrics`).
+    #[clap(long = "metrics-addr", default_value = "[::1]:9100")]
+    pub(super) metrics_addr: SocketAddr,
+    /// File the metadata of files and chunks is loaded from on startup and flushed to
+    /// on shutdown. Without it, the metadata is lost when the metadata server stops.
+    #[clap(long = "metadata-file")]
+    pub(super) metadata_file: Option<PathBuf>,
     /// Metadata server hostname.
     #[clap(long = "hostname", default_value = "metadata-server")]
     pub(super) hostname: Hostname,
+    /// Size of the chunks files are split into, in bytes. Sent to chunkservers on discovery.
+    #[clap(long = "max-chunk-size", default_value_t = ClusterConfig::DEFAULT.max_chunk_size)]
+    pub(super) max_chunk_size: u64,
+    /// Number of copies of every chunk stored besides the primary one.
+    #[clap(long = "chunk-replicas", default_value_t = ClusterConfig::DEFAULT.n_chunk_replicas)]
+    pub(super) chunk_replicas: usize,
+    /// How often chunkservers send heartbeats, e.g. `60s`. Sent to chunkservers on discovery.
+    #[clap(
+        long = "heartbeat-interval",
+        default_value_t = ClusterConfig::DEFAULT.heartbeat_interval.into()
+    )]
+    pub(super) heartbeat_interval: humantime::Duration,
+    /// How late a heartbeat may be before the chunkserver is considered inactive.
+    #[clap(long = "heartbeat-margin", default_value = "10s")]
+    pub(super) heartbeat_margin: humantime::Duration,
+    /// How often idle connections of chunkservers are kept alive.
+    #[clap(long = "keepalive-interval", default_value = "10s")]
+    pub(super) keepalive_interval: humantime::Duration,
+    /// How often the previous versions of files past their retention are dropped.
+    #[clap(long = "version-expiry-interval", default_value = "1m")]
+    pub(super) version_expiry_interval: humantime::Duration,
+    /// How long a primary holds the lease on a chunk it writes to, e.g. `60s`.
+    #[clap(long = "lease-duration", default_value = "60s")]
+    pub(super) lease_duration: humantime::Duration,
+    /// Maximum number of chunks handled concurrently within a single client request.
+    #[clap(long = "max-spawned-tasks", default_value = "16")]
+    pub(super) max_spawned_tasks: usize,
+}
+
+impl MetadataServerOpt {
+    pub(super) fn cluster_config(&self) -> ClusterConfig {
+        ClusterConfig {
+            max_chunk_size: self.max_chunk_size,
+            n_chunk_replicas: self.chunk_replicas,
+            heartbeat_interval: *self.heartbeat_interval,
+        }
+    }
+
+    pub(super) fn validate(&self) -> Result<()> {
+        self.cluster_config().validate()?;
+        if *self.heartbeat_margin >= *self.heartbeat_interval {
+            bail!("Heartbeat margin has to be less than the heartbeat interval");
+        }
+        if self.keepalive_interval.is_zero()
+            || *self.keepalive_interval >= *self.heartbeat_interval + *self.heartbeat_margin
+        {
+            bail!("Keepalive interval has to be positive and less than the heartbeat timeout");
+        }
+        if self.version_expiry_interval.is_zero() {
+            bail!("Version expiry interval has to be positive");
+        }
+        if self.lease_duration.is_zero() {
+            bail!("Lease duration has to be positive");
+        }
+        if self.max_spawned_tasks == 0 {
+            bail!("Maximum number of spawned tasks has to be positive");
+        }
+        Ok(())
+    }
 }
@@ -1,22 +1,47 @@
+use crate::chunk_index::ChunkIndex;
 use crate::external::placement_strategy::{PlacementStrategy, RandomPlacementStrategy};
+use crate::gc::GarbageCollector;
+use crate::replication::stored_copies;
 use crate::types::{
-    ActiveChunkserver, ChunkId, ChunkMetadata, ChunkserverId, FileId, FileMetadata,
+    ActiveChunkserver, ChunkId, ChunkMetadata, ChunkserverId, FileId, FileMetadata, FileVersions,
+    PreviousVersion, Stripes,
 };
+use crate::writes::WriteTracker;
 use anyhow::Context;
+use async_trait::async_trait;
 use futures::future::join_all;
 use futures::{StreamExt, TryStreamExt, stream};
-use quinn::{Endpoint, SendStream};
+use quinn::Endpoint;
+use scc::hash_map::Entry;
+use std::collections::HashMap;
+use std::mem;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use storage_core::common::config::{MAX_CHUNK_SIZE, MAX_SPAWNED_TASKS};
-use storage_core::common::types::ChunkLocations;
+use std::time::SystemTime;
+use storage_core::common::config::cluster_config;
+use storage_core::common::telemetry::current_request_id;
+use storage_core::common::types::{
+    ChunkDigest, ChunkLocations, ContentHash, ErasureCoding, FileVersion, Replication, Retention,
+    StripeLocations,
+};
 use storage_core::common::{
-    ChunkPlacementRequestPayload, ChunkPlacementResponsePayload, ChunkserverLocation,
-    ClientMessage, GetClientFolderStructureRequestPayload, GetFilePlacementRequestPayload,
-    GetFilePlacementResponsePayload, Message, RequestStatusPayload,
-    UpdateClientFolderStructurePayload,
+    ChunkPlacementRequestPayload, ChunkPlacementResponsePayload, ChunkWrite, ChunkserverLocation,
+    CopyFilePayload, DeleteFilePayload, ErrorCode, ErrorPayload,
+    GetClientFolderStructureRequestPayload, GetClientFolderStructureResponsePayload,
+    GetFilePlacementRequestPayload, GetFilePlacementResponsePayload,
+    ListFileVersionsRequestPayload, ListFileVersionsResponsePayload, MetadataServerExternalHandler,
+    SnapshotDirectoryRequestPayload, SnapshotDirectoryResponsePayload,
+    UpdateClientFolderStructurePayload, UploadFailoverPayload, WriteFileRequestPayload,
+    WriteFileResponsePayload,
 };
+use tokio::time::Instant;
+use tracing::{Span, field, info};
 use uuid::Uuid;
 
+/// Chunkservers offered for every new chunk besides the ones storing it,
+/// which the client uploads the chunk to if the upload to the primary fails.
+const UPLOAD_ALTERNATES: usize = 2;
+
 /// 'MetadataServerExternal' is a struct used for communication with clients.
 #[derive(Clone)]
 pub struct MetadataServerExternal {
@@ -28,149 +53,952 @@ pub struct MetadataServerExternal {
 
     files: Arc<scc::HashMap<FileId, FileMetadata>>,
     chunks: Arc<scc::HashMap<ChunkId, ChunkMetadata>>,
+    chunk_index: Arc<ChunkIndex>,
+    pub(super) gc: GarbageCollector,
+    writes: WriteTracker,
+
+    /// Maximum number of chunks handled concurrently within a request.
+    max_spawned_tasks: usize,
 }
 
 impl MetadataServerExternal {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         client_endpoint: Arc<Endpoint>,
         active_chunkservers: Arc<scc::HashMap<ChunkserverId, ActiveChunkserver>>,
+        files: Arc<scc::HashMap<FileId, FileMetadata>>,
         chunks: Arc<scc::HashMap<ChunkId, ChunkMetadata>>,
+        chunk_index: Arc<ChunkIndex>,
+        gc: GarbageCollector,
+        writes: WriteTracker,
+        max_spawned_tasks: usize,
     ) -> Self {
         MetadataServerExternal {
             client_endpoint,
             placement_strategy: RandomPlacementStrategy {},
             active_chunkservers,
-            files: Arc::new(scc::HashMap::new()),
+            files,
             chunks,
+            chunk_index,
+            gc,
+            writes,
+            max_spawned_tasks,
         }
     }
 
+    async fn resolve_location(
+        active_chunkservers: &scc::HashMap<ChunkserverId, ActiveChunkserver>,
+        chunk_id: ChunkId,
+        server_id: ChunkserverId,
+    ) -> Option<ChunkserverLocation> {
+        active_chunkservers
+            .read_async(&server_id, |_, server| ChunkserverLocation {
+                chunk_id,
+                server_location: server.external_address,
+                server_hostname: server.hostname.clone(),
+            })
+            .await
+    }
+
     async fn resolve_chunk_locations(
         active_chunkservers: Arc<scc::HashMap<ChunkserverId, ActiveChunkserver>>,
         chunk_id: ChunkId,
         primary: ChunkserverId,
         replicas: Vec<ChunkserverId>,
+        alternates: Vec<ChunkserverId>,
     ) -> anyhow::Result<ChunkLocations> {
-        let to_location = move |s_id: ChunkserverId| {
-            let active_chunkservers = active_chunkservers.clone();
-
+        let primary = Self::resolve_location(&active_chunkservers, chunk_id, primary)
+            .await
+            .context("Primary not found")?;
+        let resolve_all = |server_ids: Vec<ChunkserverId>| {
+            let active_chunkservers = &active_chunkservers;
             async move {
-                active_chunkservers
-                    .get_async(&s_id)
-                    .await
-                    .map(|server_entry| ChunkserverLocation {
-                        chunk_id: s_id,
-                        server_location: server_entry.get().external_address,
-                        server_hostname: server_entry.get().hostname.clone(),
-                    })
-            }
-        };
-
-        let to_location_for_batch = to_location.clone();
-        let to_locations = move |s_ids: Vec<ChunkserverId>| async move {
-            join_all(s_ids.iter().map(|&id| to_location_for_batch(id)))
+                join_all(
+                    server_ids
+                        .into_iter()
+                        .map(|s_id| Self::resolve_location(active_chunkservers, chunk_id, s_id)),
+                )
                 .await
                 .into_iter()
                 .flatten()
                 .collect()
+            }
         };
 
         Ok(ChunkLocations {
             chunk_id,
-            primary: to_location(primary).await.context("Primary not found")?,
-            replicas: to_locations(replicas).await,
+            primary: Some(primary),
+            replicas: resolve_all(replicas).await,
+            stripes: None,
+            stored: false,
+            alternates: resolve_all(alternates).await,
         })
     }
 
-    pub(super) async fn place_file(
-        &self,
-        send: &mut SendStream,
-        payload: ChunkPlacementRequestPayload,
-    ) -> anyhow::Result<()> {
-        let n_chunks = payload.file_size.div_ceil(MAX_CHUNK_SIZE);
-        let chunk_ids: Vec<_> = (0..n_chunks).map(|_| Uuid::new_v4()).collect();
+    /// Finds the active chunkserver serving clients at the address.
+    async fn find_chunkserver(&self, address: SocketAddr) -> Option<ChunkserverId> {
+        let mut found = None;
+        self.active_chunkservers
+            .iter_async(|server_id, server| {
+                if server.external_address == address {
+                    found = Some(*server_id);
+                }
+                found.is_none()
+            })
+            .await;
+        found
+    }
 
-        if self
-            .files
-            .insert_async(
-                payload.filename,
-                FileMetadata {
-                    chunks: chunk_ids.clone(),
-                },
-            )
-            .await
-            .is_err()
-        {
-            // Prevent from creating the same file again (TODO: for given user).
+    /// Resolves the stripes of an erasure-coded chunk stored on the given chunkservers,
+    /// the stripes which chunkservers aren't active are left out.
+    async fn resolve_stripe_locations(
+        active_chunkservers: &scc::HashMap<ChunkserverId, ActiveChunkserver>,
+        chunk_id: ChunkId,
+        chunk_size: u64,
+        coding: ErasureCoding,
+        stripes: impl IntoIterator<Item = (ChunkId, Option<ChunkserverId>)>,
+    ) -> ChunkLocations {
+        let stripes = join_all(
+            stripes
+                .into_iter()
+                .map(|(stripe_id, server_id)| async move {
+                    Self::resolve_location(active_chunkservers, stripe_id, server_id?).await
+                }),
+        )
+        .await;
 
-            let _ = ClientMessage::RequestStatus(RequestStatusPayload::InvalidRequest)
-                .send(send)
-                .await;
-            return Ok(());
+        ChunkLocations {
+            chunk_id,
+            primary: None,
+            replicas: Vec::new(),
+            stripes: Some(StripeLocations {
+                coding,
+                chunk_size,
+                stripes,
+            }),
+            stored: false,
+            alternates: Vec::new(),
         }
+    }
 
+    /// Places every chunk on `copies` chunkservers.
+    async fn place_copies(
+        &self,
+        chunks: &[NewChunk],
+        copies: usize,
+    ) -> anyhow::Result<Vec<ChunkLocations>> {
         let selected_servers_ids = self
             .placement_strategy
-            .select_servers(n_chunks, self.active_chunkservers.clone())
+            .select_servers(
+                chunks.len(),
+                copies,
+                UPLOAD_ALTERNATES,
+                self.active_chunkservers.clone(),
+            )
             .await;
 
-        let chunk_server_matchings: Vec<_> = chunk_ids
+        if selected_servers_ids.len() < chunks.len() {
+            return Err(not_enough_chunkservers().into());
+        }
+
+        let chunk_server_matchings: Vec<_> = chunks
             .iter()
-            .copied()
+            .map(|chunk| chunk.chunk_id)
             .zip(selected_servers_ids)
             .collect();
 
-        for (chunk_id, (primary, secondaries)) in &chunk_server_matchings {
+        for (chunk, (_, (primary, secondaries, alternates))) in
+            chunks.iter().zip(chunk_server_matchings.iter())
+        {
             let _ = self
                 .chunks
                 .insert_async(
-                    *chunk_id,
+                    chunk.chunk_id,
                     ChunkMetadata {
-                        chunk_id: *chunk_id,
-                        primary: Some(primary.clone()),
+                        chunk_id: chunk.chunk_id,
+                        size: chunk.size,
+                        request_id: current_request_id(),
+                        required_copies: copies,
+                        references: chunk.references,
+                        primary: Some(*primary),
                         replicas: secondaries.clone(),
+                        stripes: None,
+                        content_hash: chunk.content_hash,
+                        version: 0,
+                        lease: None,
+                        alternates: alternates.clone(),
+                        created_at: Instant::now(),
                     },
                 )
                 .await;
         }
 
         let active_chunkservers = self.active_chunkservers.clone();
-        let selected_chunkservers = stream::iter(chunk_server_matchings)
-            .map(|(chunk_id, (primary, secondaries))| {
+        stream::iter(chunk_server_matchings)
+            .map(|(chunk_id, (primary, secondaries, alternates))| {
                 Self::resolve_chunk_locations(
                     active_chunkservers.clone(),
                     chunk_id,
                     primary,
                     secondaries,
+                    alternates,
                 )
             })
-            .buffer_unordered(MAX_SPAWNED_TASKS)
+            .buffered(self.max_spawned_tasks)
             .try_collect()
+            .await
+    }
+
+    /// Splits every chunk into stripes, each stored on a different chunkserver.
+    async fn place_stripes(
+        &self,
+        chunks: &[NewChunk],
+        coding: ErasureCoding,
+    ) -> anyhow::Result<Vec<ChunkLocations>> {
+        let selected_servers_ids = self
+            .placement_strategy
+            .select_stripe_servers(
+                chunks.len(),
+                coding.stripes(),
+                self.active_chunkservers.clone(),
+            )
+            .await;
+
+        if selected_servers_ids.len() < chunks.len() {
+            return Err(not_enough_chunkservers().into());
+        }
+
+        let mut locations = Vec::with_capacity(chunks.len());
+        for (chunk, servers) in chunks.iter().zip(selected_servers_ids) {
+            let (chunk_id, size) = (chunk.chunk_id, chunk.size);
+            let stripes: Vec<_> = servers
+                .into_iter()
+                .map(|server_id| (Uuid::new_v4(), server_id))
+                .collect();
+
+            for &(stripe_id, server_id) in stripes.iter() {
+                let _ = self
+                    .chunks
+                    .insert_async(
+                        stripe_id,
+                        ChunkMetadata {
+                            chunk_id: stripe_id,
+                            size: coding.stripe_size(size),
+                            request_id: current_request_id(),
+                            required_copies: Replication::ErasureCoded(coding).copies(),
+                            // Referenced by their chunk.
+                            references: 1,
+                            primary: Some(server_id),
+                            replicas: Vec::new(),
+                            stripes: None,
+                            content_hash: None,
+                            version: 0,
+                            lease: None,
+                            alternates: Vec::new(),
+                            created_at: Instant::now(),
+                        },
+                    )
+                    .await;
+            }
+
+            let _ = self
+                .chunks
+                .insert_async(
+                    chunk_id,
+                    ChunkMetadata {
+                        chunk_id,
+                        size,
+                        request_id: current_request_id(),
+                        // Only the stripes are stored.
+                        required_copies: 0,
+                        references: chunk.references,
+                        primary: None,
+                        replicas: Vec::new(),
+                        stripes: Some(Stripes {
+                            coding,
+                            stripe_ids: stripes.iter().map(|&(stripe_id, _)| stripe_id).collect(),
+                        }),
+                        content_hash: chunk.content_hash,
+                        version: 0,
+                        lease: None,
+                        alternates: Vec::new(),
+                        created_at: Instant::now(),
+                    },
+                )
+                .await;
+
+            locations.push(
+                Self::resolve_stripe_locations(
+                    &self.active_chunkservers,
+                    chunk_id,
+                    size,
+                    coding,
+                    stripes
+                        .into_iter()
+                        .map(|(stripe_id, server_id)| (stripe_id, Some(server_id))),
+                )
+                .await,
+            );
+        }
+
+        Ok(locations)
+    }
+
+    /// Matches the chunks of a file split by its content against the chunk index. Stored chunks
+    /// are referenced by the file, the other ones get new ids and are indexed once placed.
+    async fn deduplicate(&self, digests: &[ChunkDigest], copies: usize) -> FileChunks {
+        let mut file_chunks = FileChunks::default();
+        // Chunks first found at an earlier position of the file, by their position in `new`.
+        let mut new_chunks: HashMap<ContentHash, usize> = HashMap::new();
+
+        for digest in digests {
+            if let Some(&idx) = new_chunks.get(&digest.hash) {
+                let chunk = &mut file_chunks.new[idx];
+                chunk.references += 1;
+                file_chunks.positions.push((chunk.chunk_id, true));
+            } else if let Some(chunk_id) = self.acquire_stored_chunk(digest, copies).await {
+                file_chunks.acquired.push(chunk_id);
+                file_chunks.positions.push((chunk_id, true));
+            } else {
+                let chunk_id = Uuid::new_v4();
+                new_chunks.insert(digest.hash, file_chunks.new.len());
+                file_chunks.new.push(NewChunk {
+                    chunk_id,
+                    size: digest.size,
+                    content_hash: Some(digest.hash),
+                    references: 1,
+                });
+                file_chunks.positions.push((chunk_id, false));
+            }
+        }
+
+        file_chunks
+    }
+
+    /// Takes a reference to the indexed chunk with the digest's content,
+    /// if it's stored on any chunkserver.
+    async fn acquire_stored_chunk(&self, digest: &ChunkDigest, copies: usize) -> Option<ChunkId> {
+        let chunk_id = self.chunk_index.get(&digest.hash).await?;
+        let chunk = self
+            .chunks
+            .read_async(&chunk_id, |_, chunk| chunk.clone())
             .await?;
 
-        ClientMessage::ChunkPlacementResponse(ChunkPlacementResponsePayload {
-            selected_chunkservers,
-        })
-        .send(send)
-        .await?;
+        // A chunk which isn't stored yet, or anymore, is uploaded again and replaces it in the index.
+        if chunk.size != digest.size
+            || stored_copies(&self.active_chunkservers, &chunk)
+                .await
+                .is_empty()
+            || !self.gc.acquire(chunk_id).await
+        {
+            return None;
+        }
+
+        // Shared chunks are stored in as many copies as the most demanding of their files.
+        self.chunks
+            .update_async(&chunk_id, |_, chunk| {
+                chunk.required_copies = chunk.required_copies.max(copies)
+            })
+            .await;
+
+        Some(chunk_id)
+    }
+
+    /// Creates a file referencing the chunks of `source`, of its current version if it's versioned.
+    /// The copy isn't versioned.
+    async fn copy(&self, source: &str, destination: String) -> Result<(), ErrorPayload> {
+        let Some((chunks, replication)) = self
+            .files
+            .read_async(source, |_, file| (file.chunks.clone(), file.replication))
+            .await
+        else {
+            return Err(ErrorPayload::new(
+                ErrorCode::FileNotFound,
+                format!("File {} doesn't exist", source),
+            ));
+        };
+
+        let file_exists = || {
+            ErrorPayload::new(
+                ErrorCode::FileAlreadyExists,
+                format!("File {} already exists", destination),
+            )
+        };
+        if self.files.contains_async(&destination).await {
+            return Err(file_exists());
+        }
+
+        if self.gc.acquire_all(&chunks).await.is_err() {
+            return Err(ErrorPayload::new(
+                ErrorCode::FileNotFound,
+                format!("File {} was deleted while being copied", source),
+            ));
+        }
+        if let Err((destination, file)) = self
+            .files
+            .insert_async(
+                destination,
+                FileMetadata {
+                    chunks,
+                    replication,
+                    versions: None,
+                    pending_writes: None,
+                },
+            )
+            .await
+        {
+            self.gc.release_all(&file.chunks).await;
+            return Err(ErrorPayload::new(
+                ErrorCode::FileAlreadyExists,
+                format!("File {} already exists", destination),
+            ));
+        }
 
         Ok(())
     }
 
-    pub(super) async fn fetch_file_placement(
+    /// Removes the file with all its versions and drops their references to the chunks,
+    /// returns false if it doesn't exist.
+    async fn remove_file(&self, filename: &str) -> bool {
+        let Some((_, file)) = self.files.remove_async(filename).await else {
+            return false;
+        };
+
+        info!(filename, n_chunks = file.chunks.len(), "Removing file");
+        for (chunks, _) in file.all_versions() {
+            self.gc.release_all(chunks).await;
+        }
+        if let Some(pending) = &file.pending_writes {
+            self.gc.release_all(&pending.appended).await;
+        }
+        true
+    }
+
+    /// Adds an uploaded file, or a new version of it if it's versioned.
+    async fn add_file(
+        &self,
+        filename: &str,
+        chunks: Vec<ChunkId>,
+        replication: Replication,
+        versioning: Option<Retention>,
+    ) -> Result<Option<FileVersion>, ErrorPayload> {
+        let now = SystemTime::now();
+        let mut entry = match self.files.entry_async(filename.to_owned()).await {
+            Entry::Occupied(entry) => entry,
+            Entry::Vacant(entry) => {
+                let versions = versioning.map(|retention| FileVersions {
+                    current: FileVersion {
+                        version: 1,
+                        created_at: now,
+                    },
+                    retention,
+                    previous: Vec::new(),
+                });
+                let version = versions.as_ref().map(|versions| versions.current);
+                entry.insert_entry(FileMetadata {
+                    chunks,
+                    replication,
+                    versions,
+                    pending_writes: None,
+                });
+                return Ok(version);
+            }
+        };
+
+        let file = entry.get_mut();
+        let (Some(versions), Some(retention)) = (&mut file.versions, versioning) else {
+            // Prevent from creating the same file again (TODO: for given user).
+            return Err(ErrorPayload::new(
+                ErrorCode::FileAlreadyExists,
+                match file.versions {
+                    Some(_) => format!("File {} already exists", filename),
+                    None => format!("File {} already exists and isn't versioned", filename),
+                },
+            ));
+        };
+
+        let current = FileVersion {
+            version: versions.current.version + 1,
+            created_at: now,
+        };
+        versions.previous.push(PreviousVersion {
+            version: mem::replace(&mut versions.current, current),
+            replaced_at: now,
+            chunks: mem::replace(&mut file.chunks, chunks),
+            replication: mem::replace(&mut file.replication, replication),
+        });
+        versions.retention = retention;
+
+        Ok(Some(current))
+    }
+
+    /// Removes the file, or the version of it, added by an upload which failed.
+    /// Returns false if it's already gone, e.g. the file was deleted meanwhile.
+    async fn remove_failed_upload(&self, filename: &str, version: Option<FileVersion>) -> bool {
+        let Some(version) = version else {
+            return self.files.remove_async(filename).await.is_some();
+        };
+        let Entry::Occupied(mut entry) = self.files.entry_async(filename.to_owned()).await else {
+            return false;
+        };
+
+        let file = entry.get_mut();
+        let Some(versions) = &mut file.versions else {
+            return false;
+        };
+        if versions.current != version {
+            // Replaced by a newer version meanwhile.
+            let n_versions = versions.previous.len();
+            versions
+                .previous
+                .retain(|previous| previous.version != version);
+            return versions.previous.len() < n_versions;
+        }
+
+        match versions.previous.pop() {
+            Some(previous) => {
+                versions.current = previous.version;
+                file.chunks = previous.chunks;
+                file.replication = previous.replication;
+            }
+            None => {
+                let _ = entry.remove_entry();
+            }
+        }
+        true
+    }
+
+    /// Plans a write into the file: splits the written data into the parts going into
+    /// the chunks of the file, and places new chunks for the data appended past them.
+    /// The file takes the written data once the chunkservers report it, see [`crate::writes`],
+    /// writes following this one are planned after it meanwhile.
+    async fn plan_write(
+        &self,
+        payload: &WriteFileRequestPayload,
+    ) -> Result<WriteFileResponsePayload, anyhow::Error> {
+        let Entry::Occupied(mut entry) = self.files.entry_async(payload.filename.clone()).await
+        else {
+            return Err(ErrorPayload::new(
+                ErrorCode::FileNotFound,
+                format!("File {} doesn't exist", payload.filename),
+            )
+            .into());
+        };
+
+        let file = entry.get_mut();
+        let invalid = |reason: String| ErrorPayload::new(ErrorCode::InvalidRequest, reason);
+        if file.versions.is_some() {
+            return Err(invalid(format!(
+                "File {} is versioned, upload a new version instead",
+                payload.filename
+            ))
+            .into());
+        }
+        if let Replication::ErasureCoded(_) = file.replication {
+            return Err(invalid(format!("File {} is erasure-coded", payload.filename)).into());
+        }
+
+        let pending = file.pending_writes.as_ref();
+        let appended_chunks = pending.into_iter().flat_map(|p| p.appended.iter());
+        let mut chunks = Vec::with_capacity(file.chunks.len());
+        for chunk_id in file.chunks.iter().chain(appended_chunks) {
+            let mut chunk = self
+                .chunks
+                .read_async(chunk_id, |_, chunk| chunk.clone())
+                .await
+                .ok_or_else(|| {
+                    ErrorPayload::new(
+                        ErrorCode::ChunkNotFound,
+                        format!("Chunk {} missing from metadata", chunk_id),
+                    )
+                })?;
+            if let Some(pending) = pending {
+                chunk.size = pending.planned_size(&chunk);
+            }
+            chunks.push(chunk);
+        }
+
+        let file_size: u64 = chunks.iter().map(|chunk| chunk.size).sum();
+        let offset = payload.offset.unwrap_or(file_size);
+        let end = offset.checked_add(payload.length).ok_or_else(|| {
+            invalid(format!(
+                "Write of {} bytes at offset {} overflows",
+                payload.length, offset
+            ))
+        })?;
+        if end > file_size && payload.offset.is_some() {
+            return Err(invalid(format!(
+                "Write past the end of file {} of {} bytes, append instead",
+                payload.filename, file_size
+            ))
+            .into());
+        }
+
+        // Parts of the data going into the existing chunks, by their index.
+        let max_chunk_size = cluster_config().max_chunk_size;
+        let mut parts = Vec::new();
+        let mut chunk_start = 0;
+        for (idx, chunk) in chunks.iter().enumerate() {
+            let chunk_end = chunk_start + chunk.size;
+            let is_last = idx + 1 == chunks.len();
+            // The last chunk is filled up by appends, unless other files share it.
+            let writable_end = match is_last && chunk.references == 1 {
+                true => chunk_start + max_chunk_size.max(chunk.size),
+                false => chunk_end,
+            };
+            let (part_start, part_end) = (offset.max(chunk_start), end.min(writable_end));
+            chunk_start = chunk_end;
+            if part_start >= part_end {
+                continue;
+            }
+
+            if chunk.references > 1 {
+                return Err(invalid(format!(
+                    "Chunk {} of file {} is shared with other files",
+                    chunk.chunk_id, payload.filename
+                ))
+                .into());
+            }
+            parts.push((idx, part_start, part_end));
+        }
+
+        let mut writes = Vec::with_capacity(parts.len());
+        for &(idx, part_start, part_end) in parts.iter() {
+            let chunk = &chunks[idx];
+            let file_offset = chunk_start_of(&chunks, idx);
+            // Primary is elected and comes back with the chunkservers' heartbeats.
+            let unavailable = || {
+                ErrorPayload::new(
+                    ErrorCode::ChunkUnavailable,
+                    format!("Chunk {} has no available primary", chunk.chunk_id),
+                )
+                .with_retry_after(cluster_config().heartbeat_interval)
+            };
+            let primary = chunk.primary.ok_or_else(unavailable)?;
+            let locations = Self::resolve_chunk_locations(
+                self.active_chunkservers.clone(),
+                chunk.chunk_id,
+                primary,
+                chunk.replicas.clone(),
+                Vec::new(),
+            )
+            .await
+            .map_err(|_| unavailable())?;
+
+            writes.push(ChunkWrite {
+                offset: part_start - offset,
+                length: part_end - part_start,
+                chunk_offset: part_start - file_offset,
+                chunk: locations,
+                created: false,
+            });
+        }
+
+        let written = parts.last().map_or(offset, |&(_, _, part_end)| part_end);
+        let appended = FileChunks::split(end - written, max_chunk_size);
+        if !appended.new.is_empty() {
+            let placed = self
+                .place_copies(&appended.new, file.replication.copies())
+                .await?;
+            let mut part_start = written;
+            for (chunk, locations) in appended.new.iter().zip(placed) {
+                writes.push(ChunkWrite {
+                    offset: part_start - offset,
+                    length: chunk.size,
+                    chunk_offset: 0,
+                    chunk: locations,
+                    created: true,
+                });
+                part_start += chunk.size;
+            }
+        }
+
+        // The written chunks change, so they no longer have the indexed content.
+        let mut grown = Vec::new();
+        for &(idx, _, part_end) in parts.iter() {
+            let chunk_id = chunks[idx].chunk_id;
+            let chunk_end = part_end - chunk_start_of(&chunks, idx);
+            if chunk_end > chunks[idx].size {
+                grown.push((chunk_id, chunk_end));
+            }
+            let hash = self
+                .chunks
+                .update_async(&chunk_id, |_, chunk| chunk.content_hash.take())
+                .await
+                .flatten();
+            if let Some(hash) = hash {
+                self.chunk_index.remove(&hash, chunk_id).await;
+            }
+        }
+        let appended_ids = appended.new.iter().map(|chunk| chunk.chunk_id).collect();
+        self.writes
+            .planned(&payload.filename, file, grown, appended_ids)
+            .await;
+
+        info!(
+            offset,
+            length = payload.length,
+            written_chunks = parts.len(),
+            new_chunks = appended.new.len(),
+            "Writing file"
+        );
+
+        Ok(WriteFileResponsePayload { offset, writes })
+    }
+
+    /// Indexes the placed chunks of a file split by its content.
+    async fn index_chunks(&self, new_chunks: &[NewChunk]) {
+        for chunk in new_chunks {
+            if let Some(hash) = chunk.content_hash {
+                self.chunk_index.insert(hash, chunk.chunk_id).await;
+            }
+        }
+    }
+}
+
+/// Chunks of a file being placed.
+#[derive(Default)]
+struct FileChunks {
+    /// Chunk at every position of the file, with whether it's already stored,
+    /// or uploaded at an earlier position, and isn't uploaded again.
+    positions: Vec<(ChunkId, bool)>,
+    /// Chunks which have to be placed.
+    new: Vec<NewChunk>,
+    /// Stored chunks the file took references to.
+    acquired: Vec<ChunkId>,
+}
+
+impl FileChunks {
+    /// Splits the file into chunks of the maximum size, only the last chunk may be smaller.
+    fn split(file_size: u64, max_chunk_size: u64) -> Self {
+        let new: Vec<_> = (0..file_size.div_ceil(max_chunk_size))
+            .map(|idx| NewChunk {
+                chunk_id: Uuid::new_v4(),
+                size: max_chunk_size.min(file_size - idx * max_chunk_size),
+                content_hash: None,
+                references: 1,
+            })
+            .collect();
+
+        FileChunks {
+            positions: new.iter().map(|chunk| (chunk.chunk_id, false)).collect(),
+            new,
+            acquired: Vec::new(),
+        }
+    }
+}
+
+struct NewChunk {
+    chunk_id: ChunkId,
+    size: u64,
+    /// Hash of the content of a chunk of a file split by its content.
+    content_hash: Option<ContentHash>,
+    /// Number of positions of the file the chunk is at.
+    references: usize,
+}
+
+/// Offset in the file of the chunk at the given index.
+fn chunk_start_of(chunks: &[ChunkMetadata], idx: usize) -> u64 {
+    chunks[..idx].iter().map(|chunk| chunk.size).sum()
+}
+
+/// Checks that the chunks a file was split into make up the file.
+fn validate_digests(
+    digests: &[ChunkDigest],
+    file_size: u64,
+    max_chunk_size: u64,
+) -> Result<(), ErrorPayload> {
+    if digests
+        .iter()
+        .any(|digest| digest.size == 0 || digest.size > max_chunk_size)
+    {
+        return Err(ErrorPayload::new(
+            ErrorCode::InvalidRequest,
+            format!("Chunks have to have between 1 and {} bytes", max_chunk_size),
+        ));
+    }
+
+    let chunks_size: u64 = digests.iter().map(|digest| digest.size).sum();
+    if chunks_size != file_size {
+        return Err(ErrorPayload::new(
+            ErrorCode::InvalidRequest,
+            format!(
+                "Chunks have {} bytes in total, the file has {} bytes",
+                chunks_size, file_size
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Locations of a chunk which is already stored and isn't uploaded.
+fn stored_chunk_locations(chunk_id: ChunkId) -> ChunkLocations {
+    ChunkLocations {
+        chunk_id,
+        primary: None,
+        replicas: Vec::new(),
+        stripes: None,
+        stored: true,
+        alternates: Vec::new(),
+    }
+}
+
+fn not_enough_chunkservers() -> ErrorPayload {
+    ErrorPayload::new(
+        ErrorCode::NotEnoughChunkservers,
+        "Not enough active chunkservers to place the file",
+    )
+    .with_retry_after(cluster_config().heartbeat_interval)
+}
+
+#[async_trait]
+impl MetadataServerExternalHandler for MetadataServerExternal {
+    async fn place_file(
+        &self,
+        payload: ChunkPlacementRequestPayload,
+    ) -> anyhow::Result<ChunkPlacementResponsePayload> {
+        Span::current().record("filename", payload.filename.as_str());
+        let copies = payload.replication.copies();
+        if copies == 0 {
+            return Err(ErrorPayload::new(
+                ErrorCode::InvalidRequest,
+                "Files have to be stored in at least one copy",
+            )
+            .into());
+        }
+        if let Replication::ErasureCoded(coding) = payload.replication
+            && let Err(e) = coding.validate()
+        {
+            return Err(ErrorPayload::new(ErrorCode::InvalidRequest, e.to_string()).into());
+        }
+
+        if let Some(retention) = payload.versioning
+            && retention.keep_versions == Some(0)
+        {
+            return Err(ErrorPayload::new(
+                ErrorCode::InvalidRequest,
+                "At least the current version of a file has to be kept",
+            )
+            .into());
+        }
+
+        let max_chunk_size = cluster_config().max_chunk_size;
+        let file_size = payload.file_size as u64;
+        let file_chunks = match &payload.chunks {
+            Some(digests) => {
+                if let Replication::ErasureCoded(_) = payload.replication {
+                    return Err(ErrorPayload::new(
+                        ErrorCode::InvalidRequest,
+                        "Erasure-coded files can't be deduplicated",
+                    )
+                    .into());
+                }
+                validate_digests(digests, file_size, max_chunk_size)?;
+
+                self.deduplicate(digests, copies).await
+            }
+            None => FileChunks::split(file_size, max_chunk_size),
+        };
+
+        let filename = payload.filename;
+        let version = match self
+            .add_file(
+                &filename,
+                file_chunks
+                    .positions
+                    .iter()
+                    .map(|&(chunk_id, _)| chunk_id)
+                    .collect(),
+                payload.replication,
+                payload.versioning,
+            )
+            .await
+        {
+            Ok(version) => version,
+            Err(e) => {
+                self.gc.release_all(&file_chunks.acquired).await;
+                return Err(e.into());
+            }
+        };
+
+        info!(
+            file_size,
+            n_chunks = file_chunks.positions.len(),
+            new_chunks = file_chunks.new.len(),
+            replication = %payload.replication,
+            version = version.map(|version| version.version),
+            "Placing file"
+        );
+
+        let placed = match payload.replication {
+            Replication::ErasureCoded(coding) => self.place_stripes(&file_chunks.new, coding).await,
+            _ => self.place_copies(&file_chunks.new, copies).await,
+        };
+        let placed = match placed {
+            Ok(placed) => placed,
+            Err(e) => {
+                // The file can be placed again, e.g. once more chunkservers join.
+                if self.remove_failed_upload(&filename, version).await {
+                    self.gc.release_all(&file_chunks.acquired).await;
+                }
+                return Err(e);
+            }
+        };
+        self.index_chunks(&file_chunks.new).await;
+
+        // Placed chunks come in the order of the new chunks.
+        let mut placed = placed.into_iter();
+        let selected_chunkservers = file_chunks
+            .positions
+            .into_iter()
+            .map(|(chunk_id, stored)| match stored {
+                true => Some(stored_chunk_locations(chunk_id)),
+                false => placed.next(),
+            })
+            .collect::<Option<_>>()
+            .context("Not every chunk was placed")?;
+
+        Ok(ChunkPlacementResponsePayload {
+            selected_chunkservers,
+            chunk_size: payload.chunks.is_none().then_some(max_chunk_size),
+            version,
+        })
+    }
+
+    async fn fetch_file_placement(
         &self,
-        send: &mut SendStream,
         payload: GetFilePlacementRequestPayload,
-    ) -> anyhow::Result<()> {
-        let Some(file_chunks_ids) = self
+    ) -> anyhow::Result<GetFilePlacementResponsePayload> {
+        Span::current().record("filename", payload.filename.as_str());
+        if payload.version.is_some() && payload.as_of.is_some() {
+            return Err(ErrorPayload::new(
+                ErrorCode::InvalidRequest,
+                "Version can be fetched either by its number or by time",
+            )
+            .into());
+        }
+
+        let Some(found) = self
             .files
-            .read_async(&payload.filename, |_, file| file.chunks.clone())
+            .read_async(&payload.filename, |_, file| {
+                file.find_version(payload.version, payload.as_of)
+                    .map(|(chunks, version)| (chunks.to_vec(), version))
+            })
             .await
         else {
-            let _ = ClientMessage::RequestStatus(RequestStatusPayload::InvalidRequest)
-                .send(send)
-                .await;
-            return Ok(());
+            return Err(ErrorPayload::new(
+                ErrorCode::FileNotFound,
+                format!("File {} doesn't exist", payload.filename),
+            )
+            .into());
+        };
+        let Some((file_chunks_ids, version)) = found else {
+            return Err(ErrorPayload::new(
+                ErrorCode::FileNotFound,
+                format!("File {} has no such version", payload.filename),
+            )
+            .into());
         };
 
         let active_chunkservers_handle = self.active_chunkservers.clone();
@@ -185,51 +1013,302 @@ impl MetadataServerExternal {
                         .read_async(&chunk_id, |_, chunk| chunk.clone())
                         .await
                         .ok_or_else(|| {
-                            anyhow::anyhow!("Chunk {} missing from metadata", chunk_id)
+                            ErrorPayload::new(
+                                ErrorCode::ChunkNotFound,
+                                format!("Chunk {} missing from metadata", chunk_id),
+                            )
                         })?;
 
+                    // Primary is elected and comes back with the chunkservers' heartbeats.
+                    let unavailable = |reason: &str| {
+                        ErrorPayload::new(
+                            ErrorCode::ChunkUnavailable,
+                            format!("Chunk {} {}", chunk_id, reason),
+                        )
+                        .with_retry_after(cluster_config().heartbeat_interval)
+                    };
+
+                    if let Some(stripes) = chunk.stripes {
+                        let mut stripe_holders = Vec::with_capacity(stripes.stripe_ids.len());
+                        for stripe_id in stripes.stripe_ids {
+                            let primary = chunks
+                                .read_async(&stripe_id, |_, stripe| stripe.primary)
+                                .await
+                                .flatten();
+                            stripe_holders.push((stripe_id, primary));
+                        }
+
+                        let locations = Self::resolve_stripe_locations(
+                            &active_chunkservers,
+                            chunk_id,
+                            chunk.size,
+                            stripes.coding,
+                            stripe_holders,
+                        )
+                        .await;
+                        let available = locations
+                            .stripes
+                            .iter()
+                            .flat_map(|stripes| stripes.stripes.iter().flatten())
+                            .count();
+                        if available < stripes.coding.data_stripes as usize {
+                            return Err(unavailable("has too few available stripes"));
+                        }
+
+                        return Ok((locations, chunk.size));
+                    }
+
                     let Some(chunk_primary) = chunk.primary else {
-                        return Err(anyhow::anyhow!(
-                            "Chunk {} hasn't elected primary server",
-                            chunk_id
-                        ));
+                        return Err(unavailable("hasn't elected primary server"));
                     };
 
-                    Self::resolve_chunk_locations(
+                    let locations = Self::resolve_chunk_locations(
                         active_chunkservers,
                         chunk_id,
                         chunk_primary,
                         chunk.replicas,
+                        Vec::new(),
                     )
                     .await
+                    .map_err(|_| unavailable("has inactive primary server"))?;
+
+                    Ok((locations, chunk.size))
                 }
             })
-            .buffer_unordered(MAX_SPAWNED_TASKS)
+            .buffered(self.max_spawned_tasks)
             .try_collect::<Vec<_>>()
             .await?;
+        let (chunks_locations, chunk_sizes) = chunks_locations.into_iter().unzip();
 
-        ClientMessage::GetFilePlacementResponse(GetFilePlacementResponsePayload {
+        Ok(GetFilePlacementResponsePayload {
             chunks_locations,
+            version,
+            chunk_sizes,
         })
-        .send(send)
-        .await?;
-
-        Ok(())
     }
 
-    pub(super) async fn fetch_folder_structure(
+    async fn fetch_folder_structure(
         &self,
-        _send: &mut SendStream,
         _payload: GetClientFolderStructureRequestPayload,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<GetClientFolderStructureResponsePayload> {
         todo!("unimplemented fetch_folder_structure")
     }
 
-    pub(super) async fn update_folder_structure(
+    async fn update_folder_structure(
         &self,
-        _send: &mut SendStream,
         _payload: UpdateClientFolderStructurePayload,
     ) -> anyhow::Result<()> {
         todo!("unimplemented update_folder_structure")
     }
+
+    async fn copy_file(&self, payload: CopyFilePayload) -> anyhow::Result<()> {
+        Span::current().record("filename", payload.source.as_str());
+        self.copy(&payload.source, payload.destination).await?;
+
+        Ok(())
+    }
+
+    async fn snapshot_directory(
+        &self,
+        payload: SnapshotDirectoryRequestPayload,
+    ) -> anyhow::Result<SnapshotDirectoryResponsePayload> {
+        Span::current().record("filename", payload.directory.as_str());
+        let directory = payload.directory.trim_end_matches('/');
+        let snapshot = payload.snapshot.trim_end_matches('/');
+        if directory.is_empty() || snapshot.is_empty() {
+            return Err(ErrorPayload::new(
+                ErrorCode::InvalidRequest,
+                "Directory and snapshot names can't be empty",
+            )
+            .into());
+        }
+        if snapshot == directory || snapshot.starts_with(&format!("{}/", directory)) {
+            return Err(ErrorPayload::new(
+                ErrorCode::InvalidRequest,
+                format!("Snapshot can't be created within {}", directory),
+            )
+            .into());
+        }
+
+        let mut filenames = Vec::new();
+        self.files
+            .iter_async(|filename, _| {
+                if let Some(name) = filename.strip_prefix(directory)
+                    && name.starts_with('/')
+                {
+                    filenames.push((filename.clone(), format!("{}{}", snapshot, name)));
+                }
+                true
+            })
+            .await;
+        if filenames.is_empty() {
+            return Err(ErrorPayload::new(
+                ErrorCode::FileNotFound,
+                format!("Directory {} has no files", directory),
+            )
+            .into());
+        }
+
+        info!(files = filenames.len(), snapshot, "Creating snapshot");
+        // Files are copied one by one, those changed meanwhile may be copied in either state.
+        for (idx, (source, destination)) in filenames.iter().enumerate() {
+            if let Err(e) = self.copy(source, destination.clone()).await {
+                for (_, copied) in filenames[..idx].iter() {
+                    self.remove_file(copied).await;
+                }
+                return Err(e.into());
+            }
+        }
+
+        Ok(SnapshotDirectoryResponsePayload {
+            copied_files: filenames.len() as u64,
+        })
+    }
+
+    async fn delete_file(&self, payload: DeleteFilePayload) -> anyhow::Result<()> {
+        Span::current().record("filename", payload.filename.as_str());
+        if !self.remove_file(&payload.filename).await {
+            return Err(ErrorPayload::new(
+                ErrorCode::FileNotFound,
+                format!("File {} doesn't exist", payload.filename),
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    async fn list_file_versions(
+        &self,
+        payload: ListFileVersionsRequestPayload,
+    ) -> anyhow::Result<ListFileVersionsResponsePayload> {
+        Span::current().record("filename", payload.filename.as_str());
+        let Some(versions) = self
+            .files
+            .read_async(&payload.filename, |_, file| {
+                file.versions
+                    .as_ref()
+                    .map_or_else(Vec::new, FileVersions::list)
+            })
+            .await
+        else {
+            return Err(ErrorPayload::new(
+                ErrorCode::FileNotFound,
+                format!("File {} doesn't exist", payload.filename),
+            )
+            .into());
+        };
+
+        Ok(ListFileVersionsResponsePayload { versions })
+    }
+
+    async fn write_file(
+        &self,
+        payload: WriteFileRequestPayload,
+    ) -> anyhow::Result<WriteFileResponsePayload> {
+        Span::current().record("filename", payload.filename.as_str());
+        if payload.length == 0 {
+            return Err(ErrorPayload::new(
+                ErrorCode::InvalidRequest,
+                "Writes have to have at least one byte",
+            )
+            .into());
+        }
+
+        self.plan_write(&payload).await
+    }
+
+    async fn report_upload_failover(&self, payload: UploadFailoverPayload) -> anyhow::Result<()> {
+        Span::current().record("chunk_id", field::display(payload.chunk_id));
+        let chunk_id = payload.chunk_id;
+        let Some(stored_on) = self.find_chunkserver(payload.stored_on).await else {
+            return Err(ErrorPayload::new(
+                ErrorCode::ChunkserverNotFound,
+                format!("No active chunkserver at {}", payload.stored_on),
+            )
+            .into());
+        };
+
+        let Some((primary, striped, offered, placed_by)) = self
+            .chunks
+            .read_async(&chunk_id, |_, chunk| {
+                (
+                    chunk.primary,
+                    chunk.stripes.is_some(),
+                    chunk.alternates.contains(&stored_on),
+                    chunk.request_id,
+                )
+            })
+            .await
+        else {
+            return Err(ErrorPayload::new(
+                ErrorCode::ChunkNotFound,
+                format!("Chunk {} doesn't exist", chunk_id),
+            )
+            .into());
+        };
+        if striped {
+            return Err(ErrorPayload::new(
+                ErrorCode::InvalidRequest,
+                "Stripes of erasure-coded chunks have no alternates",
+            )
+            .into());
+        }
+        // Only the upload which placed the chunk was offered its alternates, resumed uploads
+        // run with the request id of the placement, see the client's upload journal.
+        if placed_by != current_request_id() {
+            return Err(ErrorPayload::new(
+                ErrorCode::NotAuthorized,
+                format!("Chunk {} was placed by another upload", chunk_id),
+            )
+            .into());
+        }
+        // The failover was already accepted, e.g. a resumed upload reports it again.
+        if primary == Some(stored_on) {
+            return Ok(());
+        }
+        if !offered {
+            return Err(ErrorPayload::new(
+                ErrorCode::NotAuthorized,
+                format!(
+                    "Chunkserver at {} isn't an alternate of chunk {}",
+                    payload.stored_on, chunk_id
+                ),
+            )
+            .into());
+        }
+
+        // The primary is unassigned only if it's the one which failed and it doesn't store
+        // the chunk after all, or if it isn't active anymore.
+        let primary_failed = match primary {
+            Some(primary) => self
+                .active_chunkservers
+                .read_async(&primary, |_, server| {
+                    server.external_address == payload.failed
+                        && !server.chunks.contains_key(&chunk_id)
+                })
+                .await
+                .unwrap_or(true),
+            None => false,
+        };
+
+        info!(
+            failed = %payload.failed,
+            %stored_on,
+            primary_failed,
+            "Chunk uploaded to an alternate"
+        );
+        self.chunks
+            .update_async(&chunk_id, |_, chunk| {
+                if let Some(primary) = primary.filter(|_| primary_failed) {
+                    chunk.remove_holder(primary);
+                }
+                chunk.set_primary(stored_on);
+                // The upload fails over only once.
+                chunk.alternates.clear();
+            })
+            .await;
+
+        Ok(())
+    }
 }
@@ -1,27 +1,49 @@
-use crate::types::{ActiveChunkserver, ChunkserverId};
+use crate::types::{ActiveChunkserver, ChunkserverId, RackId};
 use async_trait::async_trait;
 use rand::rng;
-use rand::seq::IndexedRandom;
+use rand::seq::{IndexedRandom, SliceRandom};
 use std::sync::Arc;
-use storage_core::common::config::N_CHUNK_REPLICAS;
 type PrimaryServerId = ChunkserverId;
 type SecondaryServerId = ChunkserverId;
+type AlternateServerId = ChunkserverId;
 
-/// Selects chunkservers for new chunks.
-///
-/// # Arguments
-/// * `n_chunks` - Number of chunks being placed.
-/// * `active_chunkservers` - hashmap of active chunkservers.
-///
-/// # Returns
-/// A vector of length 'n_chunks' of ids of primary and secondary servers for each chunk.
+/// Selects chunkservers for new chunks. Draining chunkservers are never selected.
 #[async_trait]
 pub(crate) trait PlacementStrategy {
+    /// # Arguments
+    /// * `n_chunks` - Number of chunks being placed.
+    /// * `copies` - Number of chunkservers every chunk is stored on.
+    /// * `alternates` - Number of other chunkservers to select for every chunk, which the chunk
+    ///   is uploaded to if its primary fails. Fewer are selected if there aren't enough.
+    /// * `active_chunkservers` - hashmap of active chunkservers.
+    ///
+    /// # Returns
+    /// A vector of length 'n_chunks' of ids of primary, secondary and alternate servers
+    /// for each chunk.
     async fn select_servers(
         &self,
         n_chunks: usize,
+        copies: usize,
+        alternates: usize,
         active_chunkservers: Arc<scc::HashMap<ChunkserverId, ActiveChunkserver>>,
-    ) -> Vec<(PrimaryServerId, Vec<SecondaryServerId>)>;
+    ) -> Vec<(
+        PrimaryServerId,
+        Vec<SecondaryServerId>,
+        Vec<AlternateServerId>,
+    )>;
+
+    /// Selects chunkservers for the stripes of erasure-coded chunks. Stripes of a chunk
+    /// are placed on distinct chunkservers, spread over as many racks as possible.
+    ///
+    /// # Returns
+    /// A vector of length 'n_chunks' of ids of the servers for each stripe of a chunk,
+    /// or an empty vector if there are fewer than `n_stripes` chunkservers.
+    async fn select_stripe_servers(
+        &self,
+        n_chunks: usize,
+        n_stripes: usize,
+        active_chunkservers: Arc<scc::HashMap<ChunkserverId, ActiveChunkserver>>,
+    ) -> Vec<Vec<ChunkserverId>>;
 }
 
 #[derive(Debug, Clone)]
@@ -32,17 +54,25 @@ impl PlacementStrategy for RandomPlacementStrategy {
     async fn select_servers(
         &self,
         n_chunks: usize,
+        copies: usize,
+        alternates: usize,
         available_servers: Arc<scc::HashMap<ChunkserverId, ActiveChunkserver>>,
-    ) -> Vec<(PrimaryServerId, Vec<SecondaryServerId>)> {
+    ) -> Vec<(
+        PrimaryServerId,
+        Vec<SecondaryServerId>,
+        Vec<AlternateServerId>,
+    )> {
         let mut candidates = Vec::new();
         available_servers
-            .iter_async(|k, _| {
-                candidates.push(k.clone());
+            .iter_async(|k, server| {
+                if !server.draining {
+                    candidates.push(*k);
+                }
                 true
             })
             .await;
 
-        if candidates.len() < N_CHUNK_REPLICAS + 1 {
+        if candidates.len() < copies {
             return Vec::new();
         }
 
@@ -51,14 +81,68 @@ impl PlacementStrategy for RandomPlacementStrategy {
         (0..n_chunks)
             .map(|_| {
                 let mut selected: Vec<_> = candidates
-                    .choose_multiple(&mut rng, N_CHUNK_REPLICAS + 1)
+                    .choose_multiple(&mut rng, copies + alternates)
                     .copied()
                     .collect();
 
                 // Already considered the case where too few servers were generated.
+                let alternates = selected.split_off(copies);
                 let primary = selected.remove(0);
 
-                (primary, selected)
+                (primary, selected, alternates)
+            })
+            .collect()
+    }
+
+    async fn select_stripe_servers(
+        &self,
+        n_chunks: usize,
+        n_stripes: usize,
+        available_servers: Arc<scc::HashMap<ChunkserverId, ActiveChunkserver>>,
+    ) -> Vec<Vec<ChunkserverId>> {
+        let mut candidates = Vec::new();
+        available_servers
+            .iter_async(|k, server| {
+                if !server.draining {
+                    candidates.push((*k, server.rack_id.clone()));
+                }
+                true
+            })
+            .await;
+
+        if candidates.len() < n_stripes {
+            return Vec::new();
+        }
+
+        let mut rng = rng();
+
+        (0..n_chunks)
+            .map(|_| {
+                candidates.shuffle(&mut rng);
+
+                let mut racks: Vec<(&RackId, Vec<ChunkserverId>)> = Vec::new();
+                for (server_id, rack_id) in candidates.iter() {
+                    match racks.iter_mut().find(|(id, _)| *id == rack_id) {
+                        Some((_, servers)) => servers.push(*server_id),
+                        None => racks.push((rack_id, vec![*server_id])),
+                    }
+                }
+
+                // Takes one chunkserver from every rack in turn.
+                let mut selected = Vec::with_capacity(n_stripes);
+                for round in 0.. {
+                    for (_, servers) in racks.iter() {
+                        if let Some(&server_id) = servers.get(round) {
+                            selected.push(server_id);
+                        }
+                    }
+                    if selected.len() >= n_stripes {
+                        break;
+                    }
+                }
+
+                selected.truncate(n_stripes);
+                selected
             })
             .collect()
     }
@@ -1,46 +1,31 @@
 use crate::external::MetadataServerExternal;
 use async_trait::async_trait;
-use quinn::{Endpoint, RecvStream, SendStream};
-use storage_core::common::MetadataServerExternalMessage::{
-    ChunkPlacementRequest, GetClientFolderStructureRequest, GetFilePlacementRequest,
-    UpdateClientFolderStructure,
-};
+use quinn::{Endpoint, SendStream};
 use storage_core::common::{
-    ClientMessage, Message, MetadataServerExternalMessage, QuicServer, RequestStatusPayload,
+    MetadataServerExternalHandler, MetadataServerExternalMessage, QuicServer,
 };
 
 #[async_trait]
 impl QuicServer for MetadataServerExternal {
+    type Request = MetadataServerExternalMessage;
+
+    const NAME: &'static str = "metadataserver_external";
+
     fn listening_endpoint(&self) -> &Endpoint {
         &self.client_endpoint
     }
 
     async fn setup(&self) -> anyhow::Result<()> {
+        let gc = self.gc.clone();
+        tokio::spawn(async move { gc.expire_versions().await });
         Ok(())
     }
 
     async fn handle_request(
         &self,
-        mut send: SendStream,
-        mut recv: RecvStream,
+        send: &mut SendStream,
+        request: Self::Request,
     ) -> anyhow::Result<()> {
-        let res = match MetadataServerExternalMessage::recv(&mut recv).await? {
-            ChunkPlacementRequest(payload) => self.place_file(&mut send, payload).await,
-            GetFilePlacementRequest(payload) => self.fetch_file_placement(&mut send, payload).await,
-            GetClientFolderStructureRequest(payload) => {
-                self.fetch_folder_structure(&mut send, payload).await
-            }
-            UpdateClientFolderStructure(payload) => {
-                self.update_folder_structure(&mut send, payload).await
-            }
-        };
-
-        if res.is_err() {
-            let _ = ClientMessage::RequestStatus(RequestStatusPayload::InternalServerError)
-                .send(&mut send)
-                .await;
-        }
-
-        Ok(())
+        self.dispatch(send, request).await
     }
 }
@@ -0,0 +1,165 @@
+//! Reference counting and garbage collection of chunks.
+//!
+//! Chunks are shared by the files created from the same content: deduplicated uploads,
+//! copies and snapshots. Every position of a file holding a chunk is a reference to it,
+//! stripes of an erasure-coded chunk are referenced by their chunk. A chunk is freed once
+//! its last reference goes away - its metadata is removed and the chunkservers storing
+//! it are ordered to delete it with their next heartbeat.
+//!
+//! Previous versions of versioned files keep their chunks referenced until they're past
+//! the retention of their file, they're dropped periodically.
+
+use crate::chunk_index::ChunkIndex;
+use crate::replication::ReplicationScheduler;
+use crate::types::{ChunkId, ChunkMetadata, ChunkserverId, FileId, FileMetadata};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::time::sleep;
+use tracing::{debug, info};
+
+#[derive(Clone)]
+pub(crate) struct GarbageCollector {
+    files: Arc<scc::HashMap<FileId, FileMetadata>>,
+    chunks: Arc<scc::HashMap<ChunkId, ChunkMetadata>>,
+    chunk_index: Arc<ChunkIndex>,
+    replication: ReplicationScheduler,
+    /// How often the expired versions of files are dropped.
+    interval: Duration,
+}
+
+impl GarbageCollector {
+    pub(crate) fn new(
+        files: Arc<scc::HashMap<FileId, FileMetadata>>,
+        chunks: Arc<scc::HashMap<ChunkId, ChunkMetadata>>,
+        chunk_index: Arc<ChunkIndex>,
+        replication: ReplicationScheduler,
+        interval: Duration,
+    ) -> Self {
+        GarbageCollector {
+            files,
+            chunks,
+            chunk_index,
+            replication,
+            interval,
+        }
+    }
+
+    /// Periodically drops the previous versions of files past their retention.
+    pub(crate) async fn expire_versions(&self) {
+        loop {
+            sleep(self.interval).await;
+
+            let now = SystemTime::now();
+            let mut expired = Vec::new();
+            self.files
+                .retain_async(|filename, file| {
+                    for version in file.versions.iter_mut().flat_map(|v| v.expire(now)) {
+                        info!(
+                            filename,
+                            version = version.version.version,
+                            "Dropping expired file version"
+                        );
+                        expired.extend(version.chunks);
+                    }
+                    true
+                })
+                .await;
+
+            self.release_all(&expired).await;
+        }
+    }
+
+    /// Takes a reference to the chunk, returns false if it has already been freed.
+    pub(crate) async fn acquire(&self, chunk_id: ChunkId) -> bool {
+        self.chunks
+            .update_async(&chunk_id, |_, chunk| chunk.references += 1)
+            .await
+            .is_some()
+    }
+
+    /// Takes a reference to every chunk of a file. If any of them has already been freed,
+    /// no reference is taken and the chunk is returned.
+    pub(crate) async fn acquire_all(&self, chunk_ids: &[ChunkId]) -> Result<(), ChunkId> {
+        for (idx, &chunk_id) in chunk_ids.iter().enumerate() {
+            if !self.acquire(chunk_id).await {
+                self.release_all(&chunk_ids[..idx]).await;
+                return Err(chunk_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops a reference to the chunk, freeing the chunk after the last one.
+    pub(crate) async fn release(&self, chunk_id: ChunkId) {
+        let freed = self
+            .chunks
+            .remove_if_async(&chunk_id, |chunk| {
+                chunk.references = chunk.references.saturating_sub(1);
+                chunk.references == 0
+            })
+            .await;
+
+        if let Some((_, chunk)) = freed {
+            self.free(chunk).await;
+        }
+    }
+
+    pub(crate) async fn release_all(&self, chunk_ids: &[ChunkId]) {
+        for &chunk_id in chunk_ids {
+            self.release(chunk_id).await;
+        }
+    }
+
+    /// Frees a chunk found unreferenced by fsck and deletes the copies of it fsck found.
+    /// The chunk is checked again under its entry and kept if it has been referenced since,
+    /// or if it's younger than `min_age`. Copies of a chunk without metadata are deleted
+    /// unless the chunk has been placed since. Returns whether the chunk was freed.
+    pub(crate) async fn free_orphan(
+        &self,
+        chunk_id: ChunkId,
+        stored_on: &[ChunkserverId],
+        min_age: Duration,
+    ) -> bool {
+        let freed = self
+            .chunks
+            .remove_if_async(&chunk_id, |chunk| {
+                chunk.references == 0 && chunk.created_at.elapsed() >= min_age
+            })
+            .await;
+        let holders: Vec<_> = match freed {
+            Some((_, chunk)) => {
+                let holders = chunk.holders().collect();
+                self.free(chunk).await;
+                holders
+            }
+            None if !self.chunks.contains_async(&chunk_id).await => Vec::new(),
+            None => return false,
+        };
+
+        for &server_id in stored_on.iter().filter(|id| !holders.contains(id)) {
+            self.replication.order_deletion(server_id, chunk_id).await;
+        }
+        true
+    }
+
+    async fn free(&self, chunk: ChunkMetadata) {
+        debug!(chunk_id = %chunk.chunk_id, "Freeing chunk");
+        if let Some(hash) = chunk.content_hash {
+            self.chunk_index.remove(&hash, chunk.chunk_id).await;
+        }
+        for server_id in chunk.holders() {
+            self.replication
+                .order_deletion(server_id, chunk.chunk_id)
+                .await;
+        }
+
+        for &stripe_id in chunk.stripes.iter().flat_map(|s| s.stripe_ids.iter()) {
+            if let Some((_, stripe)) = self.chunks.remove_async(&stripe_id).await {
+                for server_id in stripe.holders() {
+                    self.replication.order_deletion(server_id, stripe_id).await;
+                }
+            }
+        }
+    }
+}
@@ -1,12 +1,22 @@
-use crate::types::{ActiveChunkserver, ChunkId, ChunkMetadata, ChunkserverId};
-use quinn::{Endpoint, SendStream};
-use std::collections::HashSet;
+use crate::replication::ReplicationScheduler;
+use crate::types::{ActiveChunkserver, ChunkId, ChunkMetadata, ChunkserverId, Lease};
+use crate::writes::WriteTracker;
+use async_trait::async_trait;
+use quinn::Endpoint;
+use std::collections::HashMap;
 use std::mem;
 use std::sync::Arc;
-use storage_core::common::config::{HEARTBEAT_INTERVAL, HEARTBEAT_MARGIN};
-use storage_core::common::{ChunkServerDiscoverPayload, HeartbeatPayload};
-use storage_core::dbg_println;
+use std::time::Duration;
+use storage_core::common::config::{METRICS_REFRESH_INTERVAL, cluster_config};
+use storage_core::common::metrics;
+use storage_core::common::{
+    AcceptNewChunkServerPayload, ChunkServerDiscoverPayload, ChunkserverLocation, ErrorCode,
+    ErrorPayload, GrantLeaseRequestPayload, HeartbeatPayload, HeartbeatResponsePayload,
+    LeaseGrantedPayload, LeaseReplica, MetadataServerInternalHandler, StaleReplicaPayload,
+    peer_identity,
+};
 use tokio::time::{Instant, sleep};
+use tracing::{debug, info, warn};
 
 /// 'MetadataServerInternal' is a struct used for communication with chunkservers.
 #[derive(Clone)]
@@ -16,6 +26,17 @@ pub struct MetadataServerInternal {
     active_chunkservers: Arc<scc::HashMap<ChunkserverId, ActiveChunkserver>>,
 
     chunks: Arc<scc::HashMap<ChunkId, ChunkMetadata>>,
+
+    replication: ReplicationScheduler,
+    writes: WriteTracker,
+
+    /// How late a heartbeat may be before the chunkserver is considered inactive.
+    heartbeat_margin: Duration,
+    /// How long a primary holds the lease on a chunk it writes to.
+    lease_duration: Duration,
+    /// Leases granted before the metadata server started may still be held until
+    /// a lease duration passes, no leases are granted until then.
+    started_at: Instant,
 }
 
 impl MetadataServerInternal {
@@ -23,22 +44,228 @@ impl MetadataServerInternal {
         internal_endpoint: Arc<Endpoint>,
         active_chunkservers: Arc<scc::HashMap<ChunkserverId, ActiveChunkserver>>,
         chunks: Arc<scc::HashMap<ChunkId, ChunkMetadata>>,
+        replication: ReplicationScheduler,
+        writes: WriteTracker,
+        heartbeat_margin: Duration,
+        lease_duration: Duration,
     ) -> Self {
         MetadataServerInternal {
             internal_endpoint,
             active_chunkservers,
             chunks,
+            replication,
+            writes,
+            heartbeat_margin,
+            lease_duration,
+            started_at: Instant::now(),
+        }
+    }
+
+    pub(super) async fn prune_inactive_chunkservers(&self) {
+        let heartbeat_timeout = cluster_config().heartbeat_interval + self.heartbeat_margin;
+        loop {
+            debug!(
+                active_chunkservers = self.active_chunkservers.len(),
+                "Pruning inactive chunkservers"
+            );
+
+            let mut lost_chunk_replicas = Vec::new();
+            self.active_chunkservers
+                .retain_async(|_, server| {
+                    if server.last_heartbeat + heartbeat_timeout >= Instant::now() {
+                        return true;
+                    }
+
+                    info!(server_id = %server.server_id, "Chunkserver became inactive");
+                    lost_chunk_replicas.push((server.server_id, mem::take(&mut server.chunks)));
+                    false
+                })
+                .await;
+
+            for (server_id, chunks) in lost_chunk_replicas {
+                self.forget_chunkserver(server_id, chunks);
+            }
+
+            self.replication
+                .reconcile(&self.active_chunkservers, &self.chunks)
+                .await;
+
+            sleep(heartbeat_timeout).await;
+        }
+    }
+
+    /// Removes a chunkserver which has shut down and re-replicates its chunks right away.
+    async fn chunkserver_left(&self, server_id: ChunkserverId) {
+        let Some((_, mut server)) = self.active_chunkservers.remove_async(&server_id).await else {
+            return;
+        };
+
+        info!(%server_id, "Chunkserver left");
+        self.forget_chunkserver(server_id, mem::take(&mut server.chunks));
+        self.replication
+            .reconcile(&self.active_chunkservers, &self.chunks)
+            .await;
+    }
+
+    /// Unassigns the chunks of a chunkserver which has been removed from the active ones.
+    fn forget_chunkserver(&self, server_id: ChunkserverId, chunks: HashMap<ChunkId, u64>) {
+        let server_id_label = server_id.to_string();
+        let _ = metrics::HEARTBEAT_LAG.remove_label_values(&[&server_id_label]);
+        let _ = metrics::DISK_AVAILABLE.remove_label_values(&[&server_id_label]);
+
+        for chunk_id in chunks.keys() {
+            self.chunks.update_sync(chunk_id, |_, chunk_metadata| {
+                chunk_metadata.remove_holder(server_id);
+            });
+        }
+    }
+
+    /// Periodically updates the metrics describing the state of the chunkservers.
+    pub(super) async fn refresh_metrics(&self) {
+        loop {
+            metrics::CHUNKS.set(self.chunks.len() as i64);
+            self.active_chunkservers
+                .iter_async(|server_id, server| {
+                    let server_id = server_id.to_string();
+                    metrics::HEARTBEAT_LAG
+                        .with_label_values(&[&server_id])
+                        .set(server.last_heartbeat.elapsed().as_secs_f64());
+                    metrics::DISK_AVAILABLE
+                        .with_label_values(&[&server_id])
+                        .set(server.available_space as i64);
+                    true
+                })
+                .await;
+
+            sleep(METRICS_REFRESH_INTERVAL).await;
         }
     }
 
-    pub(super) async fn discover_new_chunkserver(
+    /// Checks that the peer is the active chunkserver with the given id.
+    async fn authorize_active_chunkserver(&self, server_id: ChunkserverId) -> anyhow::Result<()> {
+        let Some(hostname) = self
+            .active_chunkservers
+            .read_async(&server_id, |_, server| server.hostname.clone())
+            .await
+        else {
+            return Err(ErrorPayload::new(
+                ErrorCode::ChunkserverNotFound,
+                format!("Chunkserver {} isn't active", server_id),
+            )
+            .into());
+        };
+
+        Self::authorize_chunkserver(&hostname)
+    }
+
+    /// Grants the lease on the chunk to its primary, unless another chunkserver holds it.
+    /// Returns the replicas which store the chunk.
+    async fn lease_chunk(
+        &self,
+        payload: &GrantLeaseRequestPayload,
+    ) -> Result<Vec<ChunkserverId>, ErrorPayload> {
+        let now = Instant::now();
+        let leased = self
+            .chunks
+            .update_async(&payload.chunk_id, |_, chunk| {
+                if chunk.primary != Some(payload.server_id) {
+                    return Err(ErrorPayload::new(
+                        ErrorCode::NotAuthorized,
+                        format!(
+                            "Chunkserver {} isn't the primary of chunk {}",
+                            payload.server_id, payload.chunk_id
+                        ),
+                    ));
+                }
+                if payload.version < chunk.version {
+                    return Err(ErrorPayload::new(
+                        ErrorCode::ChunkVersionMismatch,
+                        format!(
+                            "Copy of chunk {} is stale, version {} of {}",
+                            payload.chunk_id, payload.version, chunk.version
+                        ),
+                    ));
+                }
+                if let Some(lease) = chunk.lease
+                    && lease.holder != payload.server_id
+                    && lease.expires_at > now
+                {
+                    return Err(ErrorPayload::new(
+                        ErrorCode::ChunkUnavailable,
+                        format!(
+                            "Chunk {} is leased to another chunkserver",
+                            payload.chunk_id
+                        ),
+                    )
+                    .with_retry_after(lease.expires_at - now));
+                }
+
+                chunk.version = payload.version;
+                chunk.lease = Some(Lease {
+                    holder: payload.server_id,
+                    expires_at: now + self.lease_duration,
+                });
+                Ok(chunk.replicas.clone())
+            })
+            .await;
+
+        leased.unwrap_or_else(|| {
+            Err(ErrorPayload::new(
+                ErrorCode::ChunkNotFound,
+                format!("Chunk {} doesn't exist", payload.chunk_id),
+            ))
+        })
+    }
+
+    /// Checks that the peer's certificate was issued for the hostname of the chunkserver.
+    fn authorize_chunkserver(hostname: &str) -> anyhow::Result<()> {
+        match peer_identity() {
+            Some(identity) if *identity == *hostname => Ok(()),
+            identity => {
+                warn!(hostname, ?identity, "Chunkserver identity mismatch");
+                Err(ErrorPayload::new(
+                    ErrorCode::NotAuthorized,
+                    format!("Certificate isn't issued for chunkserver {}", hostname),
+                )
+                .into())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MetadataServerInternalHandler for MetadataServerInternal {
+    async fn discover_chunkserver(
         &self,
-        _send: &mut SendStream,
         payload: ChunkServerDiscoverPayload,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<AcceptNewChunkServerPayload> {
         // TODO: check which chunks haven't been deleted yet and accept only those.
         // TODO: send a response with chunks the chunkserver has to delete - they're to old.
-        dbg_println!("New chunk server discovered: {}", payload.server_id);
+        Self::authorize_chunkserver(&payload.hostname)?;
+        let registered_hostname = self
+            .active_chunkservers
+            .read_async(&payload.server_id, |_, server| server.hostname.clone())
+            .await;
+        if let Some(registered_hostname) = registered_hostname
+            && registered_hostname != payload.hostname
+        {
+            return Err(ErrorPayload::new(
+                ErrorCode::NotAuthorized,
+                format!(
+                    "Chunkserver {} is registered as {}",
+                    payload.server_id, registered_hostname
+                ),
+            )
+            .into());
+        }
+
+        info!(
+            server_id = %payload.server_id,
+            hostname = payload.hostname,
+            rack_id = payload.rack_id,
+            stored_chunks = payload.stored_chunks.len(),
+            "New chunkserver discovered"
+        );
 
         let _ = self
             .active_chunkservers
@@ -48,78 +275,150 @@ impl MetadataServerInternal {
             )
             .await;
 
-        Ok(())
+        for chunk in payload.stored_chunks.iter() {
+            self.replication
+                .chunk_stored(&self.chunks, payload.server_id, chunk)
+                .await;
+        }
+
+        Ok(AcceptNewChunkServerPayload {
+            chunkserver_new_id: payload.server_id,
+            cluster_config: Some(cluster_config()),
+        })
     }
 
-    pub(super) async fn accept_heartbeat(
+    async fn heartbeat(
         &self,
-        _send: &mut SendStream,
         payload: HeartbeatPayload,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<HeartbeatResponsePayload> {
+        // Unknown chunkservers have to be discovered before their chunks are accepted.
+        self.authorize_active_chunkserver(payload.server_id).await?;
+
         self.active_chunkservers
             .update_async(&payload.server_id, |_, server| {
-                server.last_heartbeat = Instant::now();
-                server.client_request_count = payload.client_requests_count;
-                server.available_space = payload.available_space;
+                server.update_from_heartbeat(&payload)
             })
             .await;
 
-        // TODO: send instructions about replication, deletion etc.
-        dbg_println!(
-            "Heartbeat received from {}. free_space = {}, client_rpm = {}",
-            payload.server_id,
-            payload.available_space,
-            payload.client_requests_count
+        for chunk in payload.added_chunks.iter() {
+            self.replication
+                .chunk_stored(&self.chunks, payload.server_id, chunk)
+                .await;
+        }
+        self.writes.settle().await;
+
+        debug!(
+            server_id = %payload.server_id,
+            available_space = payload.available_space,
+            client_requests_count = payload.client_requests_count,
+            "Heartbeat received"
         );
 
-        Ok(())
+        if payload.leaving {
+            // The chunkserver won't carry out any more instructions.
+            self.replication.drop_instructions(payload.server_id).await;
+            self.chunkserver_left(payload.server_id).await;
+            return Ok(HeartbeatResponsePayload::default());
+        }
+
+        Ok(self.replication.take_instructions(payload.server_id).await)
     }
 
-    pub(super) async fn prune_inactive_chunkservers(&self) {
-        loop {
-            dbg_println!(
-                "Pruning inactive chunkservers; active_chunkservers: {}",
-                self.active_chunkservers.len()
-            );
+    async fn grant_lease(
+        &self,
+        payload: GrantLeaseRequestPayload,
+    ) -> anyhow::Result<LeaseGrantedPayload> {
+        self.authorize_active_chunkserver(payload.server_id).await?;
 
-            let mut lost_chunk_replicas = Vec::new();
-            self.active_chunkservers
-                .retain_async(|_, server| {
-                    if server.last_heartbeat + HEARTBEAT_INTERVAL + HEARTBEAT_MARGIN
-                        >= Instant::now()
-                    {
-                        return true;
-                    }
+        let since_start = self.started_at.elapsed();
+        if since_start < self.lease_duration {
+            return Err(ErrorPayload::new(
+                ErrorCode::ChunkUnavailable,
+                "Leases granted before the metadata server restarted may still be held",
+            )
+            .with_retry_after(self.lease_duration - since_start)
+            .into());
+        }
+        // The new copy would miss the writes.
+        if self.replication.is_in_progress(payload.chunk_id).await {
+            return Err(ErrorPayload::new(
+                ErrorCode::ChunkUnavailable,
+                format!("Chunk {} is being copied", payload.chunk_id),
+            )
+            .with_retry_after(cluster_config().heartbeat_interval)
+            .into());
+        }
 
-                    lost_chunk_replicas.push((server.server_id, mem::take(&mut server.chunks)));
-                    false
+        let replica_ids = self.lease_chunk(&payload).await?;
+        let mut replicas = Vec::with_capacity(replica_ids.len());
+        for server_id in replica_ids {
+            let location = self
+                .active_chunkservers
+                .read_async(&server_id, |_, server| {
+                    server
+                        .chunks
+                        .contains_key(&payload.chunk_id)
+                        .then(|| ChunkserverLocation {
+                            chunk_id: payload.chunk_id,
+                            server_location: server.internal_address,
+                            server_hostname: server.hostname.clone(),
+                        })
                 })
-                .await;
+                .await
+                .flatten();
 
-            for (server_id, chunks) in lost_chunk_replicas.iter() {
-                for chunk_id in chunks.iter() {
-                    self.chunks.update_sync(chunk_id, |_, chunk_metadata| {
-                        if chunk_metadata
-                            .primary
-                            .is_some_and(|primary| primary == *server_id)
-                        {
-                            chunk_metadata.primary = None;
-                        }
-
-                        chunk_metadata.replicas.retain(|&s_id| s_id != *server_id);
-                    });
-                }
+            // Replicas which don't store the chunk yet get a copy with the writes applied.
+            if let Some(location) = location {
+                replicas.push(LeaseReplica {
+                    server_id,
+                    location,
+                });
             }
+        }
 
-            let _updated_chunks: HashSet<_> = lost_chunk_replicas
-                .into_iter()
-                .map(|(_, chunks)| chunks)
-                .flatten()
-                .collect();
+        debug!(
+            chunk_id = %payload.chunk_id,
+            server_id = %payload.server_id,
+            version = payload.version,
+            "Lease granted"
+        );
+        Ok(LeaseGrantedPayload {
+            duration: self.lease_duration,
+            replicas,
+        })
+    }
 
-            // TODO: replicate & select primary"
+    async fn report_stale_replica(&self, payload: StaleReplicaPayload) -> anyhow::Result<()> {
+        self.authorize_active_chunkserver(payload.server_id).await?;
 
-            sleep(HEARTBEAT_INTERVAL + HEARTBEAT_MARGIN).await;
+        let is_holder = self
+            .chunks
+            .read_async(&payload.chunk_id, |_, chunk| {
+                chunk
+                    .lease
+                    .is_some_and(|lease| lease.holder == payload.server_id)
+            })
+            .await
+            .unwrap_or(false);
+        if !is_holder {
+            return Err(ErrorPayload::new(
+                ErrorCode::NotAuthorized,
+                format!(
+                    "Chunkserver {} doesn't hold the lease on chunk {}",
+                    payload.server_id, payload.chunk_id
+                ),
+            )
+            .into());
         }
+
+        info!(
+            chunk_id = %payload.chunk_id,
+            replica_id = %payload.replica_id,
+            "Dropping replica which failed a write"
+        );
+        self.replication
+            .drop_copy(&self.chunks, payload.replica_id, payload.chunk_id)
+            .await;
+        Ok(())
     }
 }
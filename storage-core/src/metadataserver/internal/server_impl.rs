@@ -1,10 +1,16 @@
 use crate::internal::MetadataServerInternal;
 use async_trait::async_trait;
-use quinn::{Endpoint, RecvStream, SendStream};
-use storage_core::common::{Message, MetadataServerInternalMessage, QuicServer};
+use quinn::{Endpoint, SendStream};
+use storage_core::common::{
+    MetadataServerInternalHandler, MetadataServerInternalMessage, QuicServer,
+};
 
 #[async_trait]
 impl QuicServer for MetadataServerInternal {
+    type Request = MetadataServerInternalMessage;
+
+    const NAME: &'static str = "metadataserver_internal";
+
     fn listening_endpoint(&self) -> &Endpoint {
         &self.internal_endpoint
     }
@@ -12,23 +18,16 @@ impl QuicServer for MetadataServerInternal {
     async fn setup(&self) -> anyhow::Result<()> {
         let server_clone = self.clone();
         tokio::spawn(async move { server_clone.prune_inactive_chunkservers().await });
+        let server_clone = self.clone();
+        tokio::spawn(async move { server_clone.refresh_metrics().await });
         Ok(())
     }
 
     async fn handle_request(
         &self,
-        mut send: SendStream,
-        mut recv: RecvStream,
+        send: &mut SendStream,
+        request: Self::Request,
     ) -> anyhow::Result<()> {
-        let _ = match MetadataServerInternalMessage::recv(&mut recv).await? {
-            MetadataServerInternalMessage::ChunkServerDiscover(payload) => {
-                self.discover_new_chunkserver(&mut send, payload).await
-            }
-            MetadataServerInternalMessage::Heartbeat(payload) => {
-                self.accept_heartbeat(&mut send, payload).await
-            }
-        };
-
-        Ok(())
+        self.dispatch(send, request).await
     }
 }
@@ -1,35 +1,57 @@
 //! Creates and runs **metadata server**.
 //!
 //! # Running in Debug Mode
-//! - **Behavior:** Prints debugging info about operations and auto-generates self-signed certificates
+//! - **Behavior:** Prints debugging info about operations and auto-generates certificates signed by a development CA
 //! - **Command:** `cargo run --bin metadataserver`
 //!
 //! # Running in Release Mode
 //! - **Command:** `cargo run --release --bin metadataserver -- [OPTIONS]`
 //! - **Requirements:**
-//!   - Valid TLS certificate files (`--cert`, `--key`)
+//!   - A certificate bundle issued by the cluster CA (`--certificates`, see `admin certs`),
+//!     or TLS certificate files (`--cert`, `--key`) with the cluster CA (`--internal-ca`)
 //!   - All required arguments must be provided (see `--help` for full list)
 //!   - Run `cargo run --release --bin metadataserver -- --help` for details
-//! - **WARNING:** Self-signed certificates are NOT available in release builds for security reasons.
+//! - **WARNING:** The development CA is NOT available in release builds for security reasons.
+//! - Certificate files are reloaded on `SIGHUP` and when they change, without dropping connections.
+//!
+//! # Logging
+//! Logs are written to stdout at `debug` level in debug builds (`info` for dependencies)
+//! and `info` level in release builds.
+//! Use `--log-filter` (or `RUST_LOG`) to change the levels and `--log-format json` for machine-readable output.
+//!
+//! # Configuration
+//! Flags may also be set in a TOML file passed with `--config`, by their long names,
+//! e.g. `chunk-replicas = 1`. Flags given on the command line take precedence over the file.
+//! The chunk size, the number of replicas and the heartbeat interval apply to the whole cluster,
+//! they're sent to chunkservers when they discover the metadata server.
 //!
 //! ## Important Note
-//! The Metadataserver will **panic** (fail to start or process requests) unless at least
-//! **N_CHUNK_REPLICAS + 1** chunkservers are connected.
+//! Files can't be placed unless at least **`--chunk-replicas` + 1** chunkservers are
+//! connected, uploads are refused with `NotEnoughChunkservers` until then.
 //!
-//! For example, if `N_CHUNK_REPLICAS` is 2, you need **3** connected chunkservers.
+//! For example, if `--chunk-replicas` is 2, you need **3** connected chunkservers.
 
 use crate::config::MetadataServerOpt;
-use crate::external::MetadataServerExternal;
-use crate::internal::MetadataServerInternal;
-use crate::setup::metadata_server_setup;
-use clap::Parser;
+use crate::setup::{MetadataServer, metadata_server_setup};
+use std::net::SocketAddr;
 use storage_core::common::QuicServer;
+use storage_core::common::config_file::parse_with_config_file;
+use storage_core::common::metrics::serve_metrics;
+use storage_core::common::shutdown::{Shutdown, wait_for_signal};
+use storage_core::common::telemetry::init_tracing;
+use tracing::info;
 
+mod admin;
+mod chunk_index;
 mod config;
 mod external;
+mod gc;
 mod internal;
+mod replication;
 mod setup;
+mod state;
 mod types;
+mod writes;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -37,21 +59,41 @@ async fn main() -> anyhow::Result<()> {
         .install_default()
         .expect("Failed to install rustls crypto provider");
 
-    let opt = MetadataServerOpt::parse();
-    let (metadata_server_internal, metadata_server_external) =
-        metadata_server_setup(opt).expect("Couldn't setup metadata servers");
+    let opt: MetadataServerOpt = parse_with_config_file()?;
+    init_tracing(opt.log_format, opt.log_filter.as_deref());
+    let metrics_addr = opt.metrics_addr;
+    let metadata_server = metadata_server_setup(opt).expect("Couldn't setup metadata servers");
 
-    run(metadata_server_internal, metadata_server_external).await
+    run(metadata_server, metrics_addr).await
 }
 
-async fn run(
-    internal_chunkserver: MetadataServerInternal,
-    external_chunkserver: MetadataServerExternal,
-) -> anyhow::Result<()> {
-    let internal_handle = tokio::spawn(async move { internal_chunkserver.run().await });
-    let external_handle = tokio::spawn(async move { external_chunkserver.run().await });
+async fn run(metadata_server: MetadataServer, metrics_addr: SocketAddr) -> anyhow::Result<()> {
+    let shutdown = Shutdown::new();
+    let servers = async {
+        tokio::try_join!(
+            metadata_server.internal.run(shutdown.clone()),
+            metadata_server.external.run(shutdown.clone()),
+            metadata_server.admin.run(shutdown.clone())
+        )
+    };
+    tokio::pin!(servers);
+    let certificate_handle = tokio::spawn(metadata_server.certificate.clone().run());
+    let metrics_handle = tokio::spawn(serve_metrics(metrics_addr));
 
     // If one of the sides of the server crashes, we want to exit immediately.
-    let _ = tokio::try_join!(internal_handle, external_handle)?;
+    tokio::select! {
+        result = &mut servers => return result.map(|_| ()),
+        result = certificate_handle => return result?,
+        result = metrics_handle => return result?,
+        result = wait_for_signal() => result?,
+    }
+
+    info!("Shutting down");
+    shutdown.trigger();
+    servers.await?;
+    if let Some(store) = &metadata_server.store {
+        store.flush().await?;
+    }
+
     Ok(())
 }
@@ -1,3 +1,5 @@
+pub(crate) mod admin;
 pub(crate) mod external;
 pub(crate) mod internal;
+pub(crate) mod replication;
 pub(crate) mod types;
@@ -0,0 +1,560 @@
+use crate::types::{ActiveChunkserver, ChunkId, ChunkMetadata, ChunkserverId, Hostname, RackId};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use storage_core::common::config::cluster_config;
+use storage_core::common::metrics;
+use storage_core::common::types::{ReplicationOrder, StoredChunk};
+use storage_core::common::{ChunkserverLocation, HeartbeatResponsePayload};
+use tokio::time::Instant;
+use tracing::info;
+
+/// Copy of a chunk reported by one of the chunkservers the chunk is assigned to.
+pub(crate) struct StoredCopy {
+    pub(crate) server_id: ChunkserverId,
+    pub(crate) draining: bool,
+}
+
+/// Returns the chunkservers, among the ones the chunk is assigned to,
+/// which are active and reported storing the chunk.
+pub(crate) async fn stored_copies(
+    active_chunkservers: &scc::HashMap<ChunkserverId, ActiveChunkserver>,
+    chunk: &ChunkMetadata,
+) -> Vec<StoredCopy> {
+    let mut copies = Vec::new();
+    for server_id in chunk.holders() {
+        let copy = active_chunkservers
+            .read_async(&server_id, |_, server| {
+                server
+                    .chunks
+                    .contains_key(&chunk.chunk_id)
+                    .then_some(server.draining)
+            })
+            .await
+            .flatten();
+
+        if let Some(draining) = copy {
+            copies.push(StoredCopy {
+                server_id,
+                draining,
+            });
+        }
+    }
+
+    copies
+}
+
+/// Chunkservers assigned the stripes of erasure-coded chunks. A stripe isn't copied to
+/// a chunkserver with another stripe of its chunk, so that losing a chunkserver
+/// loses at most one stripe of the chunk.
+#[derive(Default)]
+struct StripePlacement {
+    /// Erasure-coded chunk of every stripe.
+    chunk_of: HashMap<ChunkId, ChunkId>,
+    /// Chunkservers assigned any of the stripes of every erasure-coded chunk.
+    holders: HashMap<ChunkId, HashSet<ChunkserverId>>,
+}
+
+impl StripePlacement {
+    fn new(all_chunks: &[ChunkMetadata]) -> Self {
+        let mut placement = StripePlacement::default();
+        for chunk in all_chunks.iter() {
+            for &stripe_id in chunk.stripes.iter().flat_map(|s| s.stripe_ids.iter()) {
+                placement.chunk_of.insert(stripe_id, chunk.chunk_id);
+            }
+        }
+        for stripe in all_chunks.iter() {
+            for server_id in stripe.holders() {
+                placement.assign(stripe.chunk_id, server_id);
+            }
+        }
+
+        placement
+    }
+
+    /// Chunkservers the chunk mustn't be copied to besides its own holders.
+    fn excluded(&self, chunk_id: ChunkId) -> Option<&HashSet<ChunkserverId>> {
+        self.holders.get(self.chunk_of.get(&chunk_id)?)
+    }
+
+    fn assign(&mut self, chunk_id: ChunkId, server_id: ChunkserverId) {
+        if let Some(&striped_chunk_id) = self.chunk_of.get(&chunk_id) {
+            self.holders
+                .entry(striped_chunk_id)
+                .or_default()
+                .insert(server_id);
+        }
+    }
+}
+
+/// Chunkserver which may receive copies of chunks.
+struct Candidate {
+    server_id: ChunkserverId,
+    rack_id: RackId,
+    hostname: Hostname,
+    internal_address: SocketAddr,
+    available_space: u64,
+    draining: bool,
+    chunk_count: usize,
+}
+
+/// 'ReplicationScheduler' decides which chunks have to be copied between chunkservers
+/// or removed from them. The instructions are handed out to chunkservers in responses
+/// to their heartbeats.
+#[derive(Clone)]
+pub(crate) struct ReplicationScheduler {
+    /// Chunks to be copied, grouped by the chunkserver which sends the copy.
+    orders: Arc<scc::HashMap<ChunkserverId, Vec<ReplicationOrder>>>,
+    /// Chunks to be removed, grouped by the chunkserver which stores them.
+    deletions: Arc<scc::HashMap<ChunkserverId, Vec<ChunkId>>>,
+    /// Chunks being copied, with the time the copying has been ordered.
+    in_progress: Arc<scc::HashMap<ChunkId, Instant>>,
+    /// Chunkservers which drop their copy of the chunk once the chunk is copied elsewhere.
+    evictions: Arc<scc::HashMap<ChunkId, ChunkserverId>>,
+    /// How late a heartbeat may be before the chunkserver is considered inactive.
+    heartbeat_margin: Duration,
+}
+
+impl ReplicationScheduler {
+    pub(crate) fn new(heartbeat_margin: Duration) -> Self {
+        ReplicationScheduler {
+            orders: Arc::default(),
+            deletions: Arc::default(),
+            in_progress: Arc::default(),
+            evictions: Arc::default(),
+            heartbeat_margin,
+        }
+    }
+
+    /// Time after which copying of a chunk that hasn't been confirmed is considered failed.
+    /// The order reaches the source with its next heartbeat and the target confirms the copy
+    /// with its next heartbeat.
+    fn copy_timeout(&self) -> Duration {
+        cluster_config()
+            .heartbeat_interval
+            .saturating_mul(2)
+            .saturating_add(self.heartbeat_margin)
+    }
+
+    /// Takes all instructions waiting for the given chunkserver.
+    pub(crate) async fn take_instructions(
+        &self,
+        server_id: ChunkserverId,
+    ) -> HeartbeatResponsePayload {
+        HeartbeatResponsePayload {
+            replicate: self
+                .orders
+                .remove_async(&server_id)
+                .await
+                .map(|(_, orders)| orders)
+                .unwrap_or_default(),
+            delete: self
+                .deletions
+                .remove_async(&server_id)
+                .await
+                .map(|(_, deletions)| deletions)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Drops the instructions waiting for a chunkserver which won't carry them out,
+    /// the chunks it was to copy are no longer considered being copied.
+    pub(crate) async fn drop_instructions(&self, server_id: ChunkserverId) {
+        self.deletions.remove_async(&server_id).await;
+        let Some((_, orders)) = self.orders.remove_async(&server_id).await else {
+            return;
+        };
+        for order in orders {
+            self.in_progress.remove_async(&order.target.chunk_id).await;
+        }
+    }
+
+    /// Updates chunk's placement after a chunkserver reported storing it.
+    /// A copy which missed writes applied to the chunk is dropped instead.
+    pub(crate) async fn chunk_stored(
+        &self,
+        chunks: &scc::HashMap<ChunkId, ChunkMetadata>,
+        server_id: ChunkserverId,
+        stored: &StoredChunk,
+    ) {
+        let chunk_id = stored.chunk_id;
+        self.in_progress.remove_async(&chunk_id).await;
+
+        let stale = chunks
+            .read_async(&chunk_id, |_, chunk| {
+                self.is_stale(chunk, server_id, stored)
+            })
+            .await
+            .unwrap_or(false);
+        if stale {
+            info!(%chunk_id, %server_id, version = stored.version, "Dropping stale copy");
+            self.drop_copy(chunks, server_id, chunk_id).await;
+            return;
+        }
+
+        let evicted = self
+            .evictions
+            .remove_if_async(&chunk_id, |evicted| *evicted != server_id)
+            .await
+            .map(|(_, evicted)| evicted);
+
+        let known_chunk = chunks
+            .update_async(&chunk_id, |_, chunk| {
+                if !chunk.holders().any(|s_id| s_id == server_id) {
+                    chunk.replicas.push(server_id);
+                }
+                // Written data counts once it's stored, see [`crate::writes`].
+                if stored.version > 0 && stored.version >= chunk.version {
+                    chunk.size = chunk.size.max(stored.size);
+                }
+                chunk.version = chunk.version.max(stored.version);
+
+                if let Some(evicted) = evicted {
+                    chunk.remove_holder(evicted);
+                }
+                // E.g. after the primary became inactive or the metadata server restarted.
+                if chunk.primary.is_none() {
+                    chunk.set_primary(server_id);
+                }
+            })
+            .await
+            .is_some();
+
+        if let Some(evicted) = evicted
+            && known_chunk
+        {
+            self.order_deletion(evicted, chunk_id).await;
+        }
+    }
+
+    /// Whether the reported copy of the chunk missed some of the writes applied to it.
+    /// The versions of the writes are reported with heartbeats, so copies of chunks written
+    /// to recently are trusted only from the chunkservers the writes were forwarded to.
+    fn is_stale(
+        &self,
+        chunk: &ChunkMetadata,
+        server_id: ChunkserverId,
+        stored: &StoredChunk,
+    ) -> bool {
+        let heartbeat_timeout = cluster_config().heartbeat_interval + self.heartbeat_margin;
+        let recently_leased = chunk
+            .lease
+            .is_some_and(|lease| lease.expires_at + heartbeat_timeout > Instant::now());
+
+        stored.version < chunk.version
+            || (recently_leased && !chunk.holders().any(|s_id| s_id == server_id))
+    }
+
+    /// Orders copying of under-replicated chunks, elects missing primaries
+    /// and removes chunks from draining chunkservers once they're stored elsewhere.
+    pub(crate) async fn reconcile(
+        &self,
+        active_chunkservers: &scc::HashMap<ChunkserverId, ActiveChunkserver>,
+        chunks: &scc::HashMap<ChunkId, ChunkMetadata>,
+    ) {
+        let candidates = Self::candidates(active_chunkservers).await;
+
+        let mut all_chunks = Vec::new();
+        chunks
+            .iter_async(|_, chunk| {
+                all_chunks.push(chunk.clone());
+                true
+            })
+            .await;
+
+        let mut stripe_placement = StripePlacement::new(&all_chunks);
+        let mut under_replicated = 0;
+        for chunk in all_chunks {
+            let stored = stored_copies(active_chunkservers, &chunk).await;
+            if stored.len() < chunk.required_copies {
+                under_replicated += 1;
+            }
+            // Copies aren't added nor moved while the primary writes to the chunk.
+            if chunk.is_leased() {
+                continue;
+            }
+
+            self.reconcile_chunk(chunks, &candidates, &mut stripe_placement, chunk, stored)
+                .await;
+        }
+
+        metrics::UNDER_REPLICATED_CHUNKS.set(under_replicated);
+    }
+
+    async fn reconcile_chunk(
+        &self,
+        chunks: &scc::HashMap<ChunkId, ChunkMetadata>,
+        candidates: &[Candidate],
+        stripe_placement: &mut StripePlacement,
+        chunk: ChunkMetadata,
+        stored: Vec<StoredCopy>,
+    ) {
+        // The chunk is lost or its upload hasn't been confirmed yet.
+        // TODO: rebuild lost stripes of erasure-coded chunks from the remaining ones.
+        let Some(source) = stored.first().map(|copy| copy.server_id) else {
+            return;
+        };
+
+        let healthy: Vec<_> = stored
+            .iter()
+            .filter(|copy| !copy.draining)
+            .map(|copy| copy.server_id)
+            .collect();
+
+        if let Some(&new_primary) = healthy.first()
+            && !chunk
+                .primary
+                .is_some_and(|primary| healthy.contains(&primary))
+        {
+            chunks
+                .update_async(&chunk.chunk_id, |_, chunk| chunk.set_primary(new_primary))
+                .await;
+        }
+
+        if healthy.len() >= chunk.required_copies {
+            for copy in stored.iter().filter(|copy| copy.draining) {
+                chunks
+                    .update_async(&chunk.chunk_id, |_, chunk| {
+                        chunk.remove_holder(copy.server_id)
+                    })
+                    .await;
+                self.order_deletion(copy.server_id, chunk.chunk_id).await;
+            }
+            return;
+        }
+
+        if self.is_in_progress(chunk.chunk_id).await {
+            return;
+        }
+
+        let targets = Self::select_targets(
+            candidates,
+            &chunk,
+            &stored,
+            &healthy,
+            stripe_placement.excluded(chunk.chunk_id),
+            chunk.required_copies - healthy.len(),
+        );
+
+        for target in targets {
+            stripe_placement.assign(chunk.chunk_id, target.server_id);
+            if !chunk.holders().any(|s_id| s_id == target.server_id) {
+                chunks
+                    .update_async(&chunk.chunk_id, |_, chunk| {
+                        chunk.replicas.push(target.server_id)
+                    })
+                    .await;
+            }
+
+            self.order_copy(source, target, &chunk).await;
+        }
+    }
+
+    /// Moves chunks from the chunkservers storing the most chunks to the ones storing the fewest.
+    /// Returns the number of chunks which are going to be moved.
+    pub(crate) async fn rebalance(
+        &self,
+        active_chunkservers: &scc::HashMap<ChunkserverId, ActiveChunkserver>,
+        chunks: &scc::HashMap<ChunkId, ChunkMetadata>,
+    ) -> u64 {
+        let mut candidates = Self::candidates(active_chunkservers).await;
+        candidates.retain(|candidate| !candidate.draining);
+        if candidates.len() < 2 {
+            return 0;
+        }
+
+        let mut all_chunks = Vec::new();
+        chunks
+            .iter_async(|_, chunk| {
+                all_chunks.push(chunk.clone());
+                true
+            })
+            .await;
+        let mut stripe_placement = StripePlacement::new(&all_chunks);
+
+        let total_chunks: usize = candidates.iter().map(|c| c.chunk_count).sum();
+        let mean = total_chunks.div_ceil(candidates.len());
+
+        let mut scheduled = 0;
+        for source_idx in 0..candidates.len() {
+            if candidates[source_idx].chunk_count <= mean {
+                continue;
+            }
+
+            let source_id = candidates[source_idx].server_id;
+            let source_chunks = active_chunkservers
+                .read_async(&source_id, |_, server| {
+                    server.chunks.keys().copied().collect::<Vec<_>>()
+                })
+                .await
+                .unwrap_or_default();
+
+            for chunk_id in source_chunks {
+                if candidates[source_idx].chunk_count <= mean {
+                    break;
+                }
+
+                if self.is_in_progress(chunk_id).await
+                    || self.evictions.contains_async(&chunk_id).await
+                {
+                    continue;
+                }
+
+                let Some(chunk) = chunks.read_async(&chunk_id, |_, c| c.clone()).await else {
+                    continue;
+                };
+                if chunk.is_leased() {
+                    continue;
+                }
+
+                let Some(target_idx) = candidates
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, c)| c.chunk_count < mean)
+                    .filter(|(_, c)| !chunk.holders().any(|s_id| s_id == c.server_id))
+                    .filter(|(_, c)| {
+                        !stripe_placement
+                            .excluded(chunk_id)
+                            .is_some_and(|excluded| excluded.contains(&c.server_id))
+                    })
+                    .min_by_key(|(_, c)| c.chunk_count)
+                    .map(|(idx, _)| idx)
+                else {
+                    continue;
+                };
+
+                let target = &candidates[target_idx];
+                stripe_placement.assign(chunk_id, target.server_id);
+                chunks
+                    .update_async(&chunk_id, |_, chunk| chunk.replicas.push(target.server_id))
+                    .await;
+                let _ = self.evictions.upsert_async(chunk_id, source_id).await;
+                self.order_copy(source_id, target, &chunk).await;
+
+                candidates[target_idx].chunk_count += 1;
+                candidates[source_idx].chunk_count -= 1;
+                scheduled += 1;
+            }
+        }
+
+        scheduled
+    }
+
+    async fn candidates(
+        active_chunkservers: &scc::HashMap<ChunkserverId, ActiveChunkserver>,
+    ) -> Vec<Candidate> {
+        let mut candidates = Vec::new();
+        active_chunkservers
+            .iter_async(|_, server| {
+                candidates.push(Candidate {
+                    server_id: server.server_id,
+                    rack_id: server.rack_id.clone(),
+                    hostname: server.hostname.clone(),
+                    internal_address: server.internal_address,
+                    available_space: server.available_space,
+                    draining: server.draining,
+                    chunk_count: server.chunks.len(),
+                });
+                true
+            })
+            .await;
+
+        candidates
+    }
+
+    /// Selects up to `n_targets` chunkservers to copy the chunk to, other than the `excluded` ones.
+    /// Chunkservers the chunk is assigned to, but which don't store it yet, go first.
+    /// Other chunkservers are preferred if they're in a rack without a copy of the chunk
+    /// (nor another stripe of it), and then by the available space.
+    fn select_targets<'a>(
+        candidates: &'a [Candidate],
+        chunk: &ChunkMetadata,
+        stored: &[StoredCopy],
+        healthy: &[ChunkserverId],
+        excluded: Option<&HashSet<ChunkserverId>>,
+        n_targets: usize,
+    ) -> Vec<&'a Candidate> {
+        let is_stored = |s_id: ChunkserverId| stored.iter().any(|copy| copy.server_id == s_id);
+        let is_holder = |s_id: ChunkserverId| chunk.holders().any(|holder| holder == s_id);
+        let is_excluded = |s_id: ChunkserverId| {
+            !is_holder(s_id) && excluded.is_some_and(|excluded| excluded.contains(&s_id))
+        };
+        let used_racks: HashSet<_> = candidates
+            .iter()
+            .filter(|c| {
+                healthy.contains(&c.server_id)
+                    || excluded.is_some_and(|excluded| excluded.contains(&c.server_id))
+            })
+            .map(|c| &c.rack_id)
+            .collect();
+
+        let (mut assigned, mut others): (Vec<_>, Vec<_>) = candidates
+            .iter()
+            .filter(|c| !c.draining && !is_stored(c.server_id) && !is_excluded(c.server_id))
+            .partition(|c| is_holder(c.server_id));
+
+        others.sort_by_key(|c| {
+            (
+                used_racks.contains(&c.rack_id),
+                std::cmp::Reverse(c.available_space),
+            )
+        });
+
+        assigned.append(&mut others);
+        assigned.truncate(n_targets);
+        assigned
+    }
+
+    pub(crate) async fn is_in_progress(&self, chunk_id: ChunkId) -> bool {
+        self.in_progress
+            .read_async(&chunk_id, |_, ordered_at| {
+                ordered_at.elapsed() < self.copy_timeout()
+            })
+            .await
+            .unwrap_or(false)
+    }
+
+    async fn order_copy(&self, source: ChunkserverId, target: &Candidate, chunk: &ChunkMetadata) {
+        self.orders
+            .entry_async(source)
+            .await
+            .or_default()
+            .get_mut()
+            .push(ReplicationOrder {
+                target: ChunkserverLocation {
+                    chunk_id: chunk.chunk_id,
+                    server_location: target.internal_address,
+                    server_hostname: target.hostname.clone(),
+                },
+                request_id: chunk.request_id,
+            });
+
+        let _ = self
+            .in_progress
+            .upsert_async(chunk.chunk_id, Instant::now())
+            .await;
+    }
+
+    /// Unassigns the chunk from the chunkserver and orders the chunkserver to delete it.
+    pub(crate) async fn drop_copy(
+        &self,
+        chunks: &scc::HashMap<ChunkId, ChunkMetadata>,
+        server_id: ChunkserverId,
+        chunk_id: ChunkId,
+    ) {
+        chunks
+            .update_async(&chunk_id, |_, chunk| chunk.remove_holder(server_id))
+            .await;
+        self.order_deletion(server_id, chunk_id).await;
+    }
+
+    pub(crate) async fn order_deletion(&self, server_id: ChunkserverId, chunk_id: ChunkId) {
+        self.deletions
+            .entry_async(server_id)
+            .await
+            .or_default()
+            .get_mut()
+            .push(chunk_id);
+    }
+}
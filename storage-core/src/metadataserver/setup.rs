@@ -1,40 +1,72 @@
+use crate::admin::MetadataServerAdmin;
+use crate::chunk_index::ChunkIndex;
 use crate::config::MetadataServerOpt;
 use crate::external::MetadataServerExternal;
+use crate::gc::GarbageCollector;
 use crate::internal::MetadataServerInternal;
+use crate::replication::ReplicationScheduler;
+use crate::state::MetadataStore;
+use crate::writes::WriteTracker;
 use anyhow::Result;
 use quinn::Endpoint;
 use quinn::crypto::rustls::QuicServerConfig;
 use std::sync::Arc;
 use storage_core::common;
-use storage_core::common::config::{HEARTBEAT_INTERVAL, HEARTBEAT_MARGIN, KEEPALIVE_INTERVAL};
+use storage_core::common::config::set_cluster_config;
+use storage_core::common::server::{CertificateReloader, tls};
+
+/// Servers and tasks the metadata server consists of.
+pub(crate) struct MetadataServer {
+    pub(crate) internal: MetadataServerInternal,
+    pub(crate) external: MetadataServerExternal,
+    pub(crate) admin: MetadataServerAdmin,
+    pub(crate) certificate: Arc<CertificateReloader>,
+    /// Persists the metadata, if a metadata file is configured.
+    pub(crate) store: Option<MetadataStore>,
+}
+
+pub(crate) fn metadata_server_setup(options: MetadataServerOpt) -> Result<MetadataServer> {
+    options.validate()?;
+    let cluster_config = options.cluster_config();
+    set_cluster_config(cluster_config);
+    let heartbeat_margin = *options.heartbeat_margin;
 
-pub(crate) fn metadata_server_setup(
-    options: MetadataServerOpt,
-) -> Result<(MetadataServerInternal, MetadataServerExternal)> {
     // Set up QUIC endpoints
     let certificate_provider = common::certificate_provider(
         Some(options.hostname.clone()),
         options.key,
         options.cert.clone(),
+        options.certificates,
     )?;
-    let (certs, key) = certificate_provider.get_certificate()?;
+    let internal_roots =
+        tls::internal_ca_roots(options.internal_ca.as_deref(), &*certificate_provider)?;
+    let certificate = CertificateReloader::new(certificate_provider)?;
+
+    let mut internal_crypto =
+        tls::internal_server_crypto(internal_roots.clone(), certificate.clone())?;
+    // Admins drain chunkservers and repair metadata, so they need a certificate of the
+    // cluster CA as well, see `admin certs issue --client`.
+    let mut admin_crypto = tls::internal_server_crypto(internal_roots, certificate.clone())?;
 
     let mut server_crypto = rustls::ServerConfig::builder()
         .with_no_client_auth()
-        .with_single_cert(certs, key)?;
+        .with_cert_resolver(certificate.clone());
 
     server_crypto.alpn_protocols = common::ALPN_QUIC_HTTP.iter().map(|&x| x.into()).collect();
     if options.keylog {
         server_crypto.key_log = Arc::new(rustls::KeyLogFile::new());
+        internal_crypto.key_log = server_crypto.key_log.clone();
+        admin_crypto.key_log = server_crypto.key_log.clone();
     }
 
-    let client_crypto = server_crypto.clone();
-    let internal_crypto = server_crypto;
+    let client_crypto = server_crypto;
 
     let mut internal_transport_config = quinn::TransportConfig::default();
     internal_transport_config
-        .max_idle_timeout(Some((HEARTBEAT_INTERVAL + HEARTBEAT_MARGIN).try_into()?))
-        .keep_alive_interval(Some(KEEPALIVE_INTERVAL));
+        .max_idle_timeout(Some(
+            (cluster_config.heartbeat_interval + heartbeat_margin).try_into()?,
+        ))
+        .keep_alive_interval(Some(*options.keepalive_interval));
 
     let internal_transport_config = Arc::new(internal_transport_config);
 
@@ -45,26 +77,84 @@ pub(crate) fn metadata_server_setup(
     let client_config =
         quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(client_crypto)?));
 
+    let admin_config =
+        quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(admin_crypto)?));
+
     let internal_endpoint = Endpoint::server(internal_config, options.internal_socket_addr)
         .expect("Couldn't create internal endpoint");
     let clients_endpoint = Endpoint::server(client_config, options.client_socket_addr)
         .expect("Couldn't create client endpoint");
+    let admin_endpoint = Endpoint::server(admin_config, options.admin_socket_addr)
+        .expect("Couldn't create admin endpoint");
 
     // Create servers
     let internal_endpoint = Arc::new(internal_endpoint);
     let clients_endpoint = Arc::new(clients_endpoint);
+    let admin_endpoint = Arc::new(admin_endpoint);
 
     let active_chunkservers = Arc::new(scc::HashMap::new());
+    let files = Arc::new(scc::HashMap::new());
     let chunks = Arc::new(scc::HashMap::new());
+    let store = options
+        .metadata_file
+        .map(|path| MetadataStore::new(path, files.clone(), chunks.clone()));
+    if let Some(store) = &store {
+        store.load()?;
+    }
+    let chunk_index = Arc::new(ChunkIndex::default());
+    chunk_index.rebuild(&chunks);
+    let replication = ReplicationScheduler::new(heartbeat_margin);
+    let gc = GarbageCollector::new(
+        files.clone(),
+        chunks.clone(),
+        chunk_index.clone(),
+        replication.clone(),
+        *options.version_expiry_interval,
+    );
+    // Writes are applied under leases, and reported with the next heartbeat.
+    let writes = WriteTracker::new(
+        files.clone(),
+        chunks.clone(),
+        active_chunkservers.clone(),
+        gc.clone(),
+        *options.lease_duration + cluster_config.heartbeat_interval + heartbeat_margin,
+    );
 
     let metadata_server_internal = MetadataServerInternal::new(
         internal_endpoint,
         active_chunkservers.clone(),
         chunks.clone(),
+        replication.clone(),
+        writes.clone(),
+        heartbeat_margin,
+        *options.lease_duration,
+    );
+
+    let metadata_server_external = MetadataServerExternal::new(
+        clients_endpoint,
+        active_chunkservers.clone(),
+        files.clone(),
+        chunks.clone(),
+        chunk_index,
+        gc.clone(),
+        writes,
+        options.max_spawned_tasks,
     );
 
-    let metadata_server_external =
-        MetadataServerExternal::new(clients_endpoint, active_chunkservers, chunks);
+    let metadata_server_admin = MetadataServerAdmin::new(
+        admin_endpoint,
+        active_chunkservers,
+        files,
+        chunks,
+        gc,
+        replication,
+    );
 
-    Ok((metadata_server_internal, metadata_server_external))
+    Ok(MetadataServer {
+        internal: metadata_server_internal,
+        external: metadata_server_external,
+        admin: metadata_server_admin,
+        certificate,
+        store,
+    })
 }
@@ -0,0 +1,178 @@
+//! Metadata of files and chunks persisted across restarts of the metadata server.
+//!
+//! Placement of the chunks isn't persisted, chunkservers report the chunks they store
+//! when they discover the metadata server.
+
+use crate::types::{ChunkId, ChunkMetadata, FileId, FileMetadata, FileVersions, Stripes};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use storage_core::common::encoding;
+use storage_core::common::telemetry::RequestId;
+use storage_core::common::types::{ContentHash, Replication};
+use tokio::time::Instant;
+use tracing::info;
+
+#[derive(Serialize, Deserialize, Default)]
+struct PersistedMetadata {
+    files: Vec<PersistedFile>,
+    chunks: Vec<PersistedChunk>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedFile {
+    filename: FileId,
+    chunks: Vec<ChunkId>,
+    #[serde(default)]
+    replication: Replication,
+    #[serde(default)]
+    versions: Option<FileVersions>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedChunk {
+    chunk_id: ChunkId,
+    size: u64,
+    request_id: RequestId,
+    #[serde(default)]
+    stripes: Option<Stripes>,
+    #[serde(default)]
+    content_hash: Option<ContentHash>,
+    #[serde(default)]
+    version: u64,
+}
+
+/// File the metadata is loaded from on startup and flushed to on shutdown.
+pub(crate) struct MetadataStore {
+    path: PathBuf,
+    files: Arc<scc::HashMap<FileId, FileMetadata>>,
+    chunks: Arc<scc::HashMap<ChunkId, ChunkMetadata>>,
+}
+
+impl MetadataStore {
+    pub(crate) fn new(
+        path: PathBuf,
+        files: Arc<scc::HashMap<FileId, FileMetadata>>,
+        chunks: Arc<scc::HashMap<ChunkId, ChunkMetadata>>,
+    ) -> Self {
+        MetadataStore {
+            path,
+            files,
+            chunks,
+        }
+    }
+
+    /// Loads the metadata, if the file exists.
+    pub(crate) fn load(&self) -> Result<()> {
+        if !self.path.exists() {
+            return Ok(());
+        }
+
+        let bytes = std::fs::read(&self.path)
+            .with_context(|| format!("Couldn't read {}", self.path.display()))?;
+        let metadata: PersistedMetadata = encoding::decode(&bytes)
+            .with_context(|| format!("Malformed {}", self.path.display()))?;
+
+        info!(
+            files = metadata.files.len(),
+            chunks = metadata.chunks.len(),
+            "Loaded metadata"
+        );
+        // Chunks are stored in as many copies as their file requires, deduplicated chunks
+        // as the most demanding of their files, erasure-coded chunks only as their stripes.
+        let mut required_copies = HashMap::new();
+        let mut references: HashMap<ChunkId, usize> = HashMap::new();
+        for file in metadata.files {
+            let file_metadata = FileMetadata {
+                chunks: file.chunks,
+                replication: file.replication,
+                versions: file.versions,
+                pending_writes: None,
+            };
+            for (chunk_ids, replication) in file_metadata.all_versions() {
+                for &chunk_id in chunk_ids {
+                    let copies = required_copies.entry(chunk_id).or_insert(0);
+                    *copies = replication.copies().max(*copies);
+                    *references.entry(chunk_id).or_default() += 1;
+                }
+            }
+            let _ = self.files.insert_sync(file.filename, file_metadata);
+        }
+        for chunk in metadata.chunks.iter() {
+            if let Some(stripes) = &chunk.stripes {
+                let copies = required_copies
+                    .insert(chunk.chunk_id, 0)
+                    .unwrap_or_else(|| Replication::default().copies());
+                for &stripe_id in stripes.stripe_ids.iter() {
+                    required_copies.insert(stripe_id, copies);
+                    references.insert(stripe_id, 1);
+                }
+            }
+        }
+        for chunk in metadata.chunks {
+            let _ = self.chunks.insert_sync(
+                chunk.chunk_id,
+                ChunkMetadata {
+                    chunk_id: chunk.chunk_id,
+                    size: chunk.size,
+                    request_id: chunk.request_id,
+                    required_copies: required_copies
+                        .get(&chunk.chunk_id)
+                        .copied()
+                        .unwrap_or_else(|| Replication::default().copies()),
+                    references: references.get(&chunk.chunk_id).copied().unwrap_or(0),
+                    primary: None,
+                    replicas: Vec::new(),
+                    stripes: chunk.stripes,
+                    content_hash: chunk.content_hash,
+                    version: chunk.version,
+                    lease: None,
+                    alternates: Vec::new(),
+                    created_at: Instant::now(),
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Writes the current metadata to the file.
+    pub(crate) async fn flush(&self) -> Result<()> {
+        let mut metadata = PersistedMetadata::default();
+        self.files
+            .iter_async(|filename, file| {
+                metadata.files.push(PersistedFile {
+                    filename: filename.clone(),
+                    chunks: file.chunks.clone(),
+                    replication: file.replication,
+                    versions: file.versions.clone(),
+                });
+                true
+            })
+            .await;
+        self.chunks
+            .iter_async(|_, chunk| {
+                metadata.chunks.push(PersistedChunk {
+                    chunk_id: chunk.chunk_id,
+                    size: chunk.size,
+                    request_id: chunk.request_id,
+                    stripes: chunk.stripes.clone(),
+                    content_hash: chunk.content_hash,
+                    version: chunk.version,
+                });
+                true
+            })
+            .await;
+
+        encoding::write_file(&self.path, &metadata).await?;
+        info!(
+            files = metadata.files.len(),
+            chunks = metadata.chunks.len(),
+            "Flushed metadata"
+        );
+
+        Ok(())
+    }
+}
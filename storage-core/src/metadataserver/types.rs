@@ -1,5 +1,12 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use storage_core::common::ChunkServerDiscoverPayload;
+use std::time::SystemTime;
+use storage_core::common::telemetry::RequestId;
+use storage_core::common::types::{
+    ContentHash, ErasureCoding, FileVersion, Replication, Retention,
+};
+use storage_core::common::{ChunkServerDiscoverPayload, HeartbeatPayload};
 use tokio::time::Instant;
 use uuid::Uuid;
 
@@ -11,15 +18,208 @@ pub(crate) type Hostname = String;
 
 pub(crate) struct FileMetadata {
     pub(crate) chunks: Vec<ChunkId>,
+    /// Number of copies of the chunks requested when the file was uploaded.
+    pub(crate) replication: Replication,
+    /// Versions of a versioned file, None if the file can't be uploaded again.
+    /// The chunks and replication above are the ones of the current version.
+    pub(crate) versions: Option<FileVersions>,
+    /// Writes into the file which the chunkservers haven't applied yet, see [`crate::writes`].
+    /// They aren't persisted, chunks appended by writes lost on restart are left orphaned.
+    pub(crate) pending_writes: Option<PendingWrites>,
+}
+
+impl FileMetadata {
+    /// Chunks of every kept version of the file, each with the replication of its version.
+    pub(crate) fn all_versions(&self) -> impl Iterator<Item = (&[ChunkId], Replication)> {
+        let previous = self
+            .versions
+            .iter()
+            .flat_map(|versions| versions.previous.iter());
+        std::iter::once((self.chunks.as_slice(), self.replication))
+            .chain(previous.map(|version| (version.chunks.as_slice(), version.replication)))
+    }
+
+    /// Chunks of the version with the given number, or of the version current at the given
+    /// time, or of the current version if neither is given. None if there's no such version.
+    pub(crate) fn find_version(
+        &self,
+        number: Option<u64>,
+        as_of: Option<SystemTime>,
+    ) -> Option<(&[ChunkId], Option<FileVersion>)> {
+        let Some(versions) = &self.versions else {
+            // Files which aren't versioned have only the current version.
+            return (number.is_none() && as_of.is_none()).then_some((&self.chunks, None));
+        };
+
+        let mut all = versions
+            .previous
+            .iter()
+            .map(|version| (version.chunks.as_slice(), version.version))
+            .chain(std::iter::once((self.chunks.as_slice(), versions.current)));
+        let found = match (number, as_of) {
+            (Some(number), _) => all.find(|(_, version)| version.version == number),
+            // Expired versions are always the oldest ones, so the latest version created
+            // before the time is the one current at that time, unless it has expired.
+            (None, Some(time)) => all.rev().find(|(_, version)| version.created_at <= time),
+            (None, None) => all.next_back(),
+        };
+
+        found.map(|(chunks, version)| (chunks, Some(version)))
+    }
+}
+
+/// Data planned into a file by writes, which it takes once the chunkservers report it.
+#[derive(Debug, Clone)]
+pub(crate) struct PendingWrites {
+    /// Sizes the chunks written past their end grow to.
+    pub(crate) sizes: HashMap<ChunkId, u64>,
+    /// Chunks appended to the file, in their order in it.
+    pub(crate) appended: Vec<ChunkId>,
+    /// When the latest of the writes was planned.
+    pub(crate) planned_at: Instant,
+}
+
+impl PendingWrites {
+    /// Size of the chunk once the writes are applied.
+    pub(crate) fn planned_size(&self, chunk: &ChunkMetadata) -> u64 {
+        self.sizes
+            .get(&chunk.chunk_id)
+            .map_or(chunk.size, |&size| size.max(chunk.size))
+    }
+}
+
+/// Versions of a versioned file, see [`storage_core::common::ChunkPlacementRequestPayload`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct FileVersions {
+    pub(crate) current: FileVersion,
+    pub(crate) retention: Retention,
+    /// Versions replaced by newer ones, oldest first.
+    pub(crate) previous: Vec<PreviousVersion>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PreviousVersion {
+    pub(crate) version: FileVersion,
+    /// When the version was replaced by the next one.
+    pub(crate) replaced_at: SystemTime,
+    pub(crate) chunks: Vec<ChunkId>,
+    pub(crate) replication: Replication,
+}
+
+impl FileVersions {
+    /// Versions kept, oldest first and ending with the current one.
+    pub(crate) fn list(&self) -> Vec<FileVersion> {
+        self.previous
+            .iter()
+            .map(|version| version.version)
+            .chain(std::iter::once(self.current))
+            .collect()
+    }
+
+    /// Removes the previous versions past the retention and returns them.
+    pub(crate) fn expire(&mut self, now: SystemTime) -> Vec<PreviousVersion> {
+        let kept = self
+            .retention
+            .keep_versions
+            .map_or(usize::MAX, |n| (n as usize).saturating_sub(1));
+        let excess = self.previous.len().saturating_sub(kept);
+        let mut expired: Vec<_> = self.previous.drain(..excess).collect();
+
+        if let Some(keep_for) = self.retention.keep_for {
+            let past_retention = self
+                .previous
+                .iter()
+                .take_while(|version| {
+                    now.duration_since(version.replaced_at)
+                        .is_ok_and(|age| age >= keep_for)
+                })
+                .count();
+            expired.extend(self.previous.drain(..past_retention));
+        }
+
+        expired
+    }
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct ChunkMetadata {
     pub(crate) chunk_id: ChunkId,
+    /// Size of the chunk in bytes, grown by writes once the chunkservers report them.
+    pub(crate) size: u64,
+    /// Id of the request which created the chunk.
+    pub(crate) request_id: RequestId,
+    /// Number of chunkservers the chunk has to be stored on, given by the file's replication.
+    pub(crate) required_copies: usize,
+    /// Number of references to the chunk from files, or from its chunk for stripes.
+    /// The chunk is freed after the last one is dropped, see [`crate::gc`].
+    pub(crate) references: usize,
 
     // Id of the primary server or None, if the primary isn't selected yet.
     pub(crate) primary: Option<ChunkserverId>,
     pub(crate) replicas: Vec<ChunkserverId>,
+
+    /// Stripes of an erasure-coded chunk. They're tracked as chunks of their own
+    /// and stored instead of the chunk, which has no primary nor replicas.
+    pub(crate) stripes: Option<Stripes>,
+
+    /// Hash of the content of a deduplicated chunk, see [`crate::chunk_index::ChunkIndex`].
+    pub(crate) content_hash: Option<ContentHash>,
+
+    /// Number of writes applied to the chunk since it was uploaded. Copies reported
+    /// with a lower version missed some of them.
+    pub(crate) version: u64,
+    /// Lease of the primary writing to the chunk, see [`crate::internal`].
+    pub(crate) lease: Option<Lease>,
+    /// Chunkservers offered to the client as alternates when the chunk was placed, the only
+    /// ones its upload may fail over to. They aren't persisted.
+    pub(crate) alternates: Vec<ChunkserverId>,
+    /// When the chunk was placed, or restored from the metadata file. Not persisted.
+    pub(crate) created_at: Instant,
+}
+
+/// Lease on a chunk granted to its primary, during which the primary applies writes
+/// to the chunk and no copies of the chunk are added or moved.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Lease {
+    pub(crate) holder: ChunkserverId,
+    pub(crate) expires_at: Instant,
+}
+
+/// Stripes an erasure-coded chunk has been split into.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Stripes {
+    pub(crate) coding: ErasureCoding,
+    /// Ids of the data stripes followed by the parity stripes.
+    pub(crate) stripe_ids: Vec<ChunkId>,
+}
+
+impl ChunkMetadata {
+    /// Ids of all chunkservers the chunk is assigned to, starting with the primary.
+    pub(crate) fn holders(&self) -> impl Iterator<Item = ChunkserverId> + '_ {
+        self.primary.iter().chain(self.replicas.iter()).copied()
+    }
+
+    /// Unassigns the chunk from the given chunkserver.
+    pub(crate) fn remove_holder(&mut self, server_id: ChunkserverId) {
+        if self.primary == Some(server_id) {
+            self.primary = None;
+        }
+        self.replicas.retain(|&s_id| s_id != server_id);
+    }
+
+    /// Whether a primary holds a lease on the chunk, which hasn't expired yet.
+    pub(crate) fn is_leased(&self) -> bool {
+        self.lease
+            .is_some_and(|lease| lease.expires_at > Instant::now())
+    }
+
+    /// Makes the given chunkserver the primary, the previous primary becomes a replica.
+    pub(crate) fn set_primary(&mut self, server_id: ChunkserverId) {
+        self.replicas.retain(|&s_id| s_id != server_id);
+        if let Some(primary) = self.primary.replace(server_id) {
+            self.replicas.push(primary);
+        }
+    }
 }
 
 pub(crate) struct ActiveChunkserver {
@@ -37,9 +237,12 @@ pub(crate) struct ActiveChunkserver {
     pub(crate) client_request_count: u64,
     /// Available space on chunkserver's disk in bytes.
     pub(crate) available_space: u64,
+    /// Whether the chunkserver is being drained - it's not selected for new chunks
+    /// and its chunks are moved to other chunkservers.
+    pub(crate) draining: bool,
 
-    /// Chunks stored on the chunkserver.
-    pub(crate) chunks: Vec<ChunkId>,
+    /// Chunks stored on the chunkserver with their sizes, as reported by the chunkserver.
+    pub(crate) chunks: HashMap<ChunkId, u64>,
 }
 
 impl ActiveChunkserver {
@@ -53,19 +256,28 @@ impl ActiveChunkserver {
             last_heartbeat: Instant::now(),
             client_request_count: 0,
             available_space: 0,
-            chunks: payload.stored_chunks.clone(),
+            draining: false,
+            chunks: payload
+                .stored_chunks
+                .iter()
+                .map(|chunk| (chunk.chunk_id, chunk.size))
+                .collect(),
         }
     }
 
-    pub(crate) fn update_from_heartbeat(
-        mut self,
-        client_requests_count: u64,
-        available_space: u64,
-    ) -> Self {
+    pub(crate) fn update_from_heartbeat(&mut self, payload: &HeartbeatPayload) {
         self.last_heartbeat = Instant::now();
-        self.client_request_count = client_requests_count;
-        self.available_space = available_space;
+        self.client_request_count = payload.client_requests_count;
+        self.available_space = payload.available_space;
 
-        self
+        self.chunks.extend(
+            payload
+                .added_chunks
+                .iter()
+                .map(|chunk| (chunk.chunk_id, chunk.size)),
+        );
+        for chunk_id in payload.removed_chunks.iter() {
+            self.chunks.remove(chunk_id);
+        }
     }
 }
@@ -0,0 +1,185 @@
+//! Writes into existing files.
+//!
+//! A write is planned by the metadata server, which splits the written data into the parts
+//! going into the chunks of the file and places new chunks for the data appended past them,
+//! and applied by the primaries of the chunks. The file takes the written data only once the
+//! chunkservers report it: a written chunk grows to the size reported for its latest version,
+//! see [`ReplicationScheduler::chunk_stored`], and appended chunks join the file in order
+//! once they're stored and the chunk before them has all of its planned data. Until then
+//! the data is pending, writes following it are planned after it.
+//!
+//! Writes which aren't applied by the time their leases are over are given up, the chunks
+//! appended by them are freed.
+//!
+//! [`ReplicationScheduler::chunk_stored`]: crate::replication::ReplicationScheduler::chunk_stored
+
+use crate::gc::GarbageCollector;
+use crate::replication::stored_copies;
+use crate::types::{
+    ActiveChunkserver, ChunkId, ChunkMetadata, ChunkserverId, FileId, FileMetadata, PendingWrites,
+};
+use scc::hash_map::Entry;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+#[derive(Clone)]
+pub(crate) struct WriteTracker {
+    files: Arc<scc::HashMap<FileId, FileMetadata>>,
+    chunks: Arc<scc::HashMap<ChunkId, ChunkMetadata>>,
+    active_chunkservers: Arc<scc::HashMap<ChunkserverId, ActiveChunkserver>>,
+    gc: GarbageCollector,
+    /// Files with pending writes. Always updated with the entry of the file held.
+    pending: Arc<scc::HashSet<FileId>>,
+    /// How long after being planned writes are given up if they aren't applied.
+    timeout: Duration,
+}
+
+impl WriteTracker {
+    pub(crate) fn new(
+        files: Arc<scc::HashMap<FileId, FileMetadata>>,
+        chunks: Arc<scc::HashMap<ChunkId, ChunkMetadata>>,
+        active_chunkservers: Arc<scc::HashMap<ChunkserverId, ActiveChunkserver>>,
+        gc: GarbageCollector,
+        timeout: Duration,
+    ) -> Self {
+        WriteTracker {
+            files,
+            chunks,
+            active_chunkservers,
+            gc,
+            pending: Arc::new(scc::HashSet::new()),
+            timeout,
+        }
+    }
+
+    /// Records the data planned into the file by a write: the chunks written past their
+    /// end with their new sizes, and the chunks appended. The caller holds the file's entry.
+    pub(crate) async fn planned(
+        &self,
+        filename: &str,
+        file: &mut FileMetadata,
+        grown: Vec<(ChunkId, u64)>,
+        appended: Vec<ChunkId>,
+    ) {
+        if grown.is_empty() && appended.is_empty() {
+            return;
+        }
+
+        let now = Instant::now();
+        let pending = file.pending_writes.get_or_insert_with(|| PendingWrites {
+            sizes: HashMap::new(),
+            appended: Vec::new(),
+            planned_at: now,
+        });
+        for (chunk_id, size) in grown {
+            let planned = pending.sizes.entry(chunk_id).or_default();
+            *planned = size.max(*planned);
+        }
+        pending.appended.extend(appended);
+        pending.planned_at = now;
+        let _ = self.pending.insert_async(filename.to_owned()).await;
+    }
+
+    /// Takes the data reported by the chunkservers into the files with pending writes,
+    /// and gives up the writes which weren't applied in time.
+    pub(crate) async fn settle(&self) {
+        let mut filenames = Vec::new();
+        self.pending
+            .iter_async(|filename| {
+                filenames.push(filename.clone());
+                true
+            })
+            .await;
+
+        for filename in filenames {
+            let given_up = match self.files.entry_async(filename.clone()).await {
+                Entry::Occupied(mut entry) => {
+                    let file = entry.get_mut();
+                    let given_up = self.settle_file(&filename, file).await;
+                    if file.pending_writes.is_none() {
+                        self.pending.remove_async(&filename).await;
+                    }
+                    given_up
+                }
+                // Chunks appended to the file were released when it was deleted.
+                Entry::Vacant(_entry) => {
+                    self.pending.remove_async(&filename).await;
+                    Vec::new()
+                }
+            };
+            self.gc.release_all(&given_up).await;
+        }
+    }
+
+    /// Settles the pending writes of the file, returns the chunks appended by the writes
+    /// if they're given up.
+    async fn settle_file(&self, filename: &str, file: &mut FileMetadata) -> Vec<ChunkId> {
+        let Some(pending) = &mut file.pending_writes else {
+            return Vec::new();
+        };
+
+        while let Some(&next) = pending.appended.first() {
+            if let Some(last) = file.chunks.last()
+                && let Some(&planned) = pending.sizes.get(last)
+                && self.committed_size(*last).await < planned
+            {
+                break;
+            }
+            if !self.is_stored(next).await {
+                break;
+            }
+            info!(filename, chunk_id = %next, "Appended chunk written");
+            file.chunks.push(pending.appended.remove(0));
+        }
+
+        for (chunk_id, planned) in mem::take(&mut pending.sizes) {
+            if self.committed_size(chunk_id).await < planned {
+                pending.sizes.insert(chunk_id, planned);
+            }
+        }
+
+        if pending.sizes.is_empty() && pending.appended.is_empty() {
+            file.pending_writes = None;
+            return Vec::new();
+        }
+        if pending.planned_at.elapsed() < self.timeout {
+            return Vec::new();
+        }
+
+        warn!(
+            filename,
+            unwritten_chunks = pending.sizes.len(),
+            unappended_chunks = pending.appended.len(),
+            "Giving up writes which weren't applied"
+        );
+        file.pending_writes
+            .take()
+            .map(|pending| pending.appended)
+            .unwrap_or_default()
+    }
+
+    async fn committed_size(&self, chunk_id: ChunkId) -> u64 {
+        self.chunks
+            .read_async(&chunk_id, |_, chunk| chunk.size)
+            .await
+            .unwrap_or(0)
+    }
+
+    /// Whether the chunk is stored on an active chunkserver.
+    async fn is_stored(&self, chunk_id: ChunkId) -> bool {
+        let Some(chunk) = self
+            .chunks
+            .read_async(&chunk_id, |_, chunk| chunk.clone())
+            .await
+        else {
+            return false;
+        };
+        !stored_copies(&self.active_chunkservers, &chunk)
+            .await
+            .is_empty()
+    }
+}
@@ -0,0 +1,85 @@
+//! Splitting of files into content-defined chunks.
+
+use std::collections::HashSet;
+use storage_core::common::dedup::{self, DedupKey, FileChunk};
+use storage_core::common::types::ContentHash;
+
+const MAX_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Pseudo-random bytes, which content-defined chunking finds boundaries in.
+fn data(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn hashes(chunks: &[FileChunk]) -> HashSet<ContentHash> {
+    chunks.iter().map(|chunk| chunk.digest.hash).collect()
+}
+
+#[test]
+fn chunks_cover_the_data() {
+    let data = data(1_000_000, 1);
+    let chunks = dedup::split(&data, &DedupKey::derive(b"tenant"), MAX_CHUNK_SIZE).unwrap();
+
+    assert!(chunks.len() > 1);
+    let mut offset = 0;
+    for chunk in chunks.iter() {
+        assert_eq!(chunk.offset, offset);
+        assert!(chunk.digest.size > 0 && chunk.digest.size <= MAX_CHUNK_SIZE);
+        offset += chunk.digest.size;
+    }
+    assert_eq!(offset, data.len() as u64);
+}
+
+#[test]
+fn insertion_changes_only_nearby_chunks() {
+    let key = DedupKey::derive(b"tenant");
+    let original = data(1_000_000, 2);
+    let mut modified = original.clone();
+    modified.splice(500_000..500_000, b"inserted".iter().copied());
+
+    let original = dedup::split(&original, &key, MAX_CHUNK_SIZE).unwrap();
+    let modified = dedup::split(&modified, &key, MAX_CHUNK_SIZE).unwrap();
+
+    let changed = hashes(&modified).difference(&hashes(&original)).count();
+    assert!(
+        changed <= 2,
+        "{} of {} chunks changed",
+        changed,
+        modified.len()
+    );
+}
+
+#[test]
+fn hashes_depend_on_the_key() {
+    let data = data(100_000, 3);
+    let first = dedup::split(&data, &DedupKey::derive(b"tenant-1"), MAX_CHUNK_SIZE).unwrap();
+    let second = dedup::split(&data, &DedupKey::derive(b"tenant-2"), MAX_CHUNK_SIZE).unwrap();
+
+    assert!(hashes(&first).is_disjoint(&hashes(&second)));
+}
+
+#[tokio::test]
+async fn file_is_split_as_in_memory() {
+    let data = data(300_000, 4);
+    let path = std::env::temp_dir().join(format!("storage-core-dedup-{}", std::process::id()));
+    std::fs::write(&path, &data).unwrap();
+
+    let key = DedupKey::derive(b"tenant");
+    let from_file = dedup::split_file(&path, &key, MAX_CHUNK_SIZE)
+        .await
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(
+        from_file,
+        dedup::split(&data, &key, MAX_CHUNK_SIZE).unwrap()
+    );
+}
@@ -0,0 +1,358 @@
+//! Compatibility of the encoding with values written by older versions.
+//!
+//! `tests/fixtures/encoding-v<N>` contains the payloads below encoded with version `N`
+//! of the encoding. They must never be regenerated, only fixtures of new versions are added
+//! with `cargo test --test encoding -- --ignored` after `ENCODING_VERSION` is bumped.
+
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use storage_core::common::encoding::{self, ENCODING_VERSION};
+use storage_core::common::protocol::Features;
+use storage_core::common::types::{
+    ChunkLocations, ChunkserverStatus, FsckReport, MissingChunk, Replication, ReplicationOrder,
+    StoredChunk,
+};
+use storage_core::common::*;
+use uuid::Uuid;
+
+fn fixtures_dir(version: u8) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(format!("encoding-v{}", version))
+}
+
+fn id(n: u128) -> Uuid {
+    Uuid::from_u128(n)
+}
+
+fn location(n: u128) -> ChunkserverLocation {
+    ChunkserverLocation {
+        chunk_id: id(n),
+        server_location: SocketAddr::from(([10, 0, 0, n as u8], 5000)),
+        server_hostname: format!("chunkserver-{}", n),
+    }
+}
+
+fn hello() -> HelloPayload {
+    HelloPayload {
+        protocol_version: 2,
+        min_protocol_version: 2,
+        features: Features::NONE,
+        required_features: Features::NONE,
+    }
+}
+
+fn hello_accepted() -> HelloAcceptedPayload {
+    HelloAcceptedPayload {
+        protocol_version: 2,
+        features: Features::NONE,
+    }
+}
+
+fn request_status() -> RequestStatusPayload {
+    RequestStatusPayload::Error(
+        ErrorPayload::new(ErrorCode::ChunkUnavailable, "Chunk unavailable")
+            .with_retry_after(Duration::from_secs(60)),
+    )
+}
+
+fn chunkserver_discover() -> ChunkServerDiscoverPayload {
+    ChunkServerDiscoverPayload {
+        server_id: id(1),
+        hostname: "chunkserver-1".to_string(),
+        rack_id: "rack-1".to_string(),
+        internal_address: "[::1]:11002".parse().unwrap(),
+        external_address: "10.0.0.1:5000".parse().unwrap(),
+        stored_chunks: vec![StoredChunk {
+            chunk_id: id(2),
+            size: 4096,
+            version: 0,
+        }],
+    }
+}
+
+fn heartbeat() -> HeartbeatPayload {
+    HeartbeatPayload {
+        server_id: id(1),
+        client_requests_count: 17,
+        available_space: 1 << 40,
+        added_chunks: vec![StoredChunk {
+            chunk_id: id(2),
+            size: 1,
+            version: 0,
+        }],
+        removed_chunks: vec![id(3)],
+        leaving: false,
+    }
+}
+
+fn heartbeat_response() -> HeartbeatResponsePayload {
+    HeartbeatResponsePayload {
+        replicate: vec![ReplicationOrder {
+            target: location(4),
+            request_id: id(5),
+        }],
+        delete: vec![id(6)],
+    }
+}
+
+fn chunk_placement_request() -> ChunkPlacementRequestPayload {
+    ChunkPlacementRequestPayload {
+        filename: "dir/file.txt".to_string(),
+        file_size: 123_456,
+        replication: Replication::default(),
+        chunks: None,
+        versioning: None,
+    }
+}
+
+fn chunk_placement_response() -> ChunkPlacementResponsePayload {
+    ChunkPlacementResponsePayload {
+        selected_chunkservers: vec![ChunkLocations {
+            chunk_id: id(7),
+            primary: Some(location(1)),
+            replicas: vec![location(2), location(3)],
+            stripes: None,
+            stored: false,
+            alternates: vec![],
+        }],
+        chunk_size: None,
+        version: None,
+    }
+}
+
+fn upload_chunk() -> UploadChunkPayload {
+    UploadChunkPayload {
+        chunk_id: id(7),
+        chunk_size: 1024,
+        version: 0,
+        chunk_transfer: ChunkTransfer::default(),
+    }
+}
+
+fn download_chunk_request() -> DownloadChunkRequestPayload {
+    DownloadChunkRequestPayload {
+        chunk_id: id(7),
+        range: None,
+    }
+}
+
+fn get_file_placement_response() -> GetFilePlacementResponsePayload {
+    GetFilePlacementResponsePayload {
+        chunks_locations: vec![ChunkLocations {
+            chunk_id: id(7),
+            primary: Some(location(1)),
+            replicas: vec![],
+            stripes: None,
+            stored: false,
+            alternates: vec![],
+        }],
+        version: None,
+        chunk_sizes: vec![],
+    }
+}
+
+fn list_chunkservers_response() -> ListChunkserversResponsePayload {
+    ListChunkserversResponsePayload {
+        chunkservers: vec![ChunkserverStatus {
+            server_id: id(1),
+            hostname: "chunkserver-1".to_string(),
+            rack_id: "rack-1".to_string(),
+            external_address: "10.0.0.1:5000".parse().unwrap(),
+            available_space: 1 << 30,
+            secs_since_heartbeat: 3,
+            chunk_count: 42,
+            draining: true,
+        }],
+    }
+}
+
+fn fsck_response() -> FsckResponsePayload {
+    FsckResponsePayload {
+        report: FsckReport {
+            checked_files: 2,
+            checked_chunks: 5,
+            missing_chunks: vec![MissingChunk {
+                filename: "file.txt".to_string(),
+                chunk_id: id(8),
+                has_metadata: true,
+            }],
+            chunks_without_primary: vec![id(9)],
+            ..FsckReport::default()
+        },
+    }
+}
+
+/// Calls the macro with the name of every fixture and the function creating its payload.
+macro_rules! for_each_fixture {
+    ($macro:ident) => {
+        $macro!(
+            hello,
+            hello_accepted,
+            request_status,
+            chunkserver_discover,
+            heartbeat,
+            heartbeat_response,
+            chunk_placement_request,
+            chunk_placement_response,
+            upload_chunk,
+            download_chunk_request,
+            get_file_placement_response,
+            list_chunkservers_response,
+            fsck_response
+        )
+    };
+}
+
+fn assert_decodes<T: serde::de::DeserializeOwned + Debug>(version: u8, name: &str, expected: T) {
+    let path = fixtures_dir(version).join(format!("{}.bin", name));
+    let bytes = std::fs::read(&path)
+        .unwrap_or_else(|e| panic!("Couldn't read fixture {}: {}", path.display(), e));
+
+    let decoded: T = encoding::decode(&bytes)
+        .unwrap_or_else(|e| panic!("Couldn't decode fixture {}: {:?}", path.display(), e));
+    assert_eq!(
+        format!("{:?}", decoded),
+        format!("{:?}", expected),
+        "{}",
+        name
+    );
+}
+
+#[test]
+fn fixtures_of_version_1_are_decoded() {
+    macro_rules! check {
+        ($($name:ident),*) => {
+            $(assert_decodes(1, stringify!($name), $name());)*
+        };
+    }
+    for_each_fixture!(check);
+}
+
+#[test]
+#[ignore = "writes fixtures of the current encoding version"]
+fn write_fixtures() {
+    let dir = fixtures_dir(ENCODING_VERSION);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    macro_rules! write {
+        ($($name:ident),*) => {
+            $(
+                let path = dir.join(concat!(stringify!($name), ".bin"));
+                assert!(!path.exists(), "Fixture {} already exists", path.display());
+                std::fs::write(&path, encoding::encode(&$name(), u32::MAX).unwrap()).unwrap();
+            )*
+        };
+    }
+    for_each_fixture!(write);
+}
+
+/// `HeartbeatPayload` as a newer version could send it, with additional fields.
+#[derive(Serialize, Deserialize, Debug)]
+struct NewerHeartbeatPayload {
+    server_id: Uuid,
+    client_requests_count: u64,
+    available_space: u64,
+    added_chunks: Vec<StoredChunk>,
+    removed_chunks: Vec<Uuid>,
+    #[serde(default)]
+    used_space: u64,
+    total_space: Option<u64>,
+}
+
+#[test]
+fn unknown_fields_are_ignored() {
+    let newer = NewerHeartbeatPayload {
+        server_id: id(1),
+        client_requests_count: 17,
+        available_space: 1 << 40,
+        added_chunks: vec![StoredChunk {
+            chunk_id: id(2),
+            size: 1,
+            version: 0,
+        }],
+        removed_chunks: vec![id(3)],
+        used_space: 1 << 20,
+        total_space: Some(1 << 41),
+    };
+
+    let bytes = encoding::encode(&newer, u32::MAX).unwrap();
+    let decoded: HeartbeatPayload = encoding::decode(&bytes).unwrap();
+    assert_eq!(format!("{:?}", decoded), format!("{:?}", heartbeat()));
+}
+
+#[test]
+fn missing_optional_and_defaulted_fields_are_filled_in() {
+    assert_decodes(
+        1,
+        "heartbeat",
+        NewerHeartbeatPayload {
+            server_id: id(1),
+            client_requests_count: 17,
+            available_space: 1 << 40,
+            added_chunks: vec![StoredChunk {
+                chunk_id: id(2),
+                size: 1,
+                version: 0,
+            }],
+            removed_chunks: vec![id(3)],
+            used_space: 0,
+            total_space: None,
+        },
+    );
+}
+
+/// `AcceptNewChunkServerPayload` of a metadata server which doesn't send the configuration.
+#[derive(Serialize, Deserialize, Debug)]
+struct AcceptWithoutConfigPayload {
+    chunkserver_new_id: Uuid,
+}
+
+#[test]
+fn missing_cluster_config_is_none() {
+    let bytes = encoding::encode(
+        &AcceptWithoutConfigPayload {
+            chunkserver_new_id: id(1),
+        },
+        u32::MAX,
+    )
+    .unwrap();
+    let decoded: AcceptNewChunkServerPayload = encoding::decode(&bytes).unwrap();
+    assert_eq!(decoded.chunkserver_new_id, id(1));
+    assert!(decoded.cluster_config.is_none());
+}
+
+#[test]
+fn unsupported_version_is_rejected() {
+    let mut bytes = encoding::encode(&hello(), u32::MAX).unwrap();
+    bytes[0] = ENCODING_VERSION + 1;
+
+    let error = encoding::decode::<HelloPayload>(&bytes).unwrap_err();
+    assert!(
+        error.to_string().contains("Unsupported encoding version"),
+        "{:?}",
+        error
+    );
+}
+
+#[test]
+fn trailing_bytes_are_rejected() {
+    let mut bytes = encoding::encode(&hello(), u32::MAX).unwrap();
+    bytes.push(0);
+
+    assert!(encoding::decode::<HelloPayload>(&bytes).is_err());
+}
+
+#[tokio::test]
+async fn files_are_written_and_read() {
+    let path = std::env::temp_dir().join(format!("storage-core-encoding-{}", std::process::id()));
+
+    encoding::write_file(&path, &fsck_response()).await.unwrap();
+    let read: FsckResponsePayload = encoding::read_file(&path).await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(format!("{:?}", read), format!("{:?}", fsck_response()));
+}
@@ -0,0 +1,282 @@
+//! Fsck repairing a cluster of the built servers while files are placed on it.
+
+use anyhow::{Context, Result};
+use futures::future::try_join_all;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use quinn::Endpoint;
+use quinn::crypto::rustls::QuicClientConfig;
+use std::collections::HashSet;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use storage_core::common::server::certificate_provider::dev_ca_certificate_path;
+use storage_core::common::server::{CertificateReloader, read_certificates, tls};
+use storage_core::common::types::Replication;
+use storage_core::common::{
+    self, ALPN_QUIC_HTTP, ChunkPlacementRequestPayload, FsckRequestPayload,
+    ListChunkserversRequestPayload, MetadataServerAdminClient, MetadataServerExternalClient,
+    protocol,
+};
+use tokio::time::{Instant, sleep};
+use uuid::Uuid;
+
+const METADATA_SERVER_HOSTNAME: &str = "metadata-server";
+const CHUNKSERVERS: usize = 2;
+const MAX_CHUNK_SIZE: u64 = 1024;
+const LARGE_FILES: usize = 10;
+const CHUNKS_PER_LARGE_FILE: u64 = 2000;
+const PLACED_FILES: usize = 500;
+const CONCURRENT_REQUESTS: usize = 4;
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn free_udp_addr() -> SocketAddr {
+    let socket = UdpSocket::bind("[::1]:0").expect("Couldn't bind a UDP socket");
+    socket.local_addr().unwrap()
+}
+
+fn free_tcp_addr() -> SocketAddr {
+    let listener = TcpListener::bind("[::1]:0").expect("Couldn't bind a TCP socket");
+    listener.local_addr().unwrap()
+}
+
+/// Metadata server with its chunkservers, killed when dropped.
+struct Cluster {
+    dir: PathBuf,
+    servers: Vec<Child>,
+    client_addr: SocketAddr,
+    internal_addr: SocketAddr,
+    admin_addr: SocketAddr,
+}
+
+impl Cluster {
+    fn spawn(dir: &Path, binary: &str, args: &[String]) -> Child {
+        Command::new(binary)
+            .args(args)
+            // Requests are handled in parallel even on a single core.
+            .env("TOKIO_WORKER_THREADS", "4")
+            .current_dir(dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|e| panic!("Couldn't start {}: {}", binary, e))
+    }
+
+    /// Starts the metadata server, the chunkservers are started by [`Cluster::add_chunkserver`]
+    /// once the metadata server has generated the certificates of the cluster.
+    fn start() -> Self {
+        let dir = std::env::temp_dir().join(format!("storage-core-fsck-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).expect("Couldn't create cluster directory");
+
+        let client_addr = free_udp_addr();
+        let internal_addr = free_udp_addr();
+        let admin_addr = free_udp_addr();
+        let metadata_server = Self::spawn(
+            &dir,
+            env!("CARGO_BIN_EXE_metadataserver"),
+            &[
+                format!("--client-socket-addr={}", client_addr),
+                format!("--internal-socket-addr={}", internal_addr),
+                format!("--admin-socket-addr={}", admin_addr),
+                format!("--metrics-addr={}", free_tcp_addr()),
+                "--chunk-replicas=1".to_string(),
+                format!("--max-chunk-size={}", MAX_CHUNK_SIZE),
+            ],
+        );
+
+        Cluster {
+            dir,
+            servers: vec![metadata_server],
+            client_addr,
+            internal_addr,
+            admin_addr,
+        }
+    }
+
+    fn add_chunkserver(&mut self) {
+        let idx = self.servers.len();
+        let external_addr = free_udp_addr();
+        let chunkserver_internal_addr = free_udp_addr();
+        let chunkserver = Self::spawn(
+            &self.dir,
+            env!("CARGO_BIN_EXE_chunkserver"),
+            &[
+                format!("--advertised-external-addr={}", external_addr),
+                format!("--advertised-internal-addr={}", chunkserver_internal_addr),
+                format!("--client-socket-addr={}", external_addr),
+                format!("--internal-socket-addr={}", chunkserver_internal_addr),
+                format!("--tmp-root=cs{}/tmp", idx),
+                format!("--final-root=cs{}/final", idx),
+                format!("--metrics-addr={}", free_tcp_addr()),
+                format!("--metadata-server-addr={}", self.internal_addr),
+            ],
+        );
+        self.servers.push(chunkserver);
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for server in self.servers.iter_mut() {
+            let _ = server.kill();
+            let _ = server.wait();
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn client_endpoint() -> Result<Endpoint> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in read_certificates(&dev_ca_certificate_path())? {
+        roots.add(cert)?;
+    }
+    let mut client_crypto = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    client_crypto.alpn_protocols = ALPN_QUIC_HTTP.iter().map(|&x| x.into()).collect();
+
+    let mut endpoint = Endpoint::client("[::]:0".parse()?)?;
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(client_crypto)?,
+    )));
+    Ok(endpoint)
+}
+
+/// Endpoint of the admin, authenticated with the certificate generated in debug mode.
+fn admin_endpoint() -> Result<Endpoint> {
+    let certificate_provider =
+        common::certificate_provider(Some("admin".to_string()), None, None, None)?;
+    let roots = tls::internal_ca_roots(None, &*certificate_provider)?;
+    let certificate = CertificateReloader::new(certificate_provider)?;
+    let client_crypto = tls::internal_client_crypto(roots, certificate)?;
+
+    let mut endpoint = Endpoint::client("[::]:0".parse()?)?;
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(client_crypto)?,
+    )));
+    Ok(endpoint)
+}
+
+/// Connects to the admin endpoint of the metadata server once it's up.
+async fn connect_admin(cluster: &Cluster) -> Result<MetadataServerAdminClient> {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    loop {
+        // The certificates are generated by the metadata server as it starts.
+        let connected = async {
+            let endpoint = admin_endpoint()?;
+            protocol::connect(&endpoint, cluster.admin_addr, METADATA_SERVER_HOSTNAME).await
+        };
+        match connected.await {
+            Ok(conn) => return Ok(MetadataServerAdminClient::new(conn)),
+            Err(e) if Instant::now() >= deadline => {
+                return Err(e).context("Metadata server didn't start");
+            }
+            Err(_) => sleep(Duration::from_millis(200)).await,
+        }
+    }
+}
+
+async fn wait_for_chunkservers(admin: &MetadataServerAdminClient, count: usize) -> Result<()> {
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    loop {
+        let listed = admin
+            .list_chunkservers(ListChunkserversRequestPayload {})
+            .await?;
+        if listed.chunkservers.len() >= count {
+            return Ok(());
+        }
+        anyhow::ensure!(Instant::now() < deadline, "Chunkservers didn't join");
+        sleep(Duration::from_millis(200)).await;
+    }
+}
+
+#[tokio::test]
+async fn repair_keeps_chunks_placed_during_the_check() -> Result<()> {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    let mut cluster = Cluster::start();
+    // The servers and this test share the certificates generated in the cluster's directory.
+    std::env::set_current_dir(&cluster.dir)?;
+    let admin = connect_admin(&cluster).await?;
+
+    // One at a time, as the chunkservers share the certificate generated for their hostname.
+    for count in 1..=CHUNKSERVERS {
+        cluster.add_chunkserver();
+        wait_for_chunkservers(&admin, count).await?;
+    }
+
+    let endpoint = client_endpoint()?;
+    let conn = protocol::connect(&endpoint, cluster.client_addr, METADATA_SERVER_HOSTNAME).await?;
+    let metadata_server = MetadataServerExternalClient::new(conn);
+
+    let place = async |filename: String, chunks: u64| {
+        let placement = metadata_server
+            .place_file(ChunkPlacementRequestPayload {
+                filename,
+                file_size: (MAX_CHUNK_SIZE * chunks) as usize,
+                replication: Replication::Copies(1),
+                chunks: None,
+                versioning: None,
+            })
+            .await?;
+        let chunk_ids: Vec<_> = placement
+            .selected_chunkservers
+            .iter()
+            .map(|chunk| chunk.chunk_id)
+            .collect();
+        anyhow::Ok(chunk_ids)
+    };
+
+    // Fsck goes through the files before the chunks, the more chunks there are the longer
+    // it misses the files placed in between.
+    let mut chunk_ids = HashSet::new();
+    for idx in 0..LARGE_FILES {
+        chunk_ids.extend(place(format!("/large-{}", idx), CHUNKS_PER_LARGE_FILE).await?);
+    }
+
+    // Fsck repairs the cluster over and over while small files are placed.
+    let placed = AtomicBool::new(false);
+    let placing = async {
+        let placed_chunks: Vec<Vec<_>> = stream::iter(0..PLACED_FILES)
+            .map(|idx| place(format!("/file-{}", idx), 1))
+            .buffer_unordered(CONCURRENT_REQUESTS)
+            .try_collect()
+            .await?;
+        placed.store(true, Ordering::Relaxed);
+        anyhow::Ok(placed_chunks.concat())
+    };
+    let repairing = (0..CONCURRENT_REQUESTS).map(|_| async {
+        let mut repairs = 0;
+        while !placed.load(Ordering::Relaxed) {
+            admin.fsck(FsckRequestPayload { repair: true }).await?;
+            repairs += 1;
+        }
+        anyhow::Ok(repairs)
+    });
+    let (placed_chunks, repairs) = tokio::try_join!(placing, try_join_all(repairing))?;
+    assert!(repairs.iter().all(|&repairs| repairs > 0));
+    chunk_ids.extend(placed_chunks);
+
+    // None of the chunks is uploaded, but all of them are still known to the metadata server.
+    let report = admin
+        .fsck(FsckRequestPayload { repair: false })
+        .await?
+        .report;
+    assert!(report.orphaned_chunks.is_empty());
+    let missing: HashSet<_> = report
+        .missing_chunks
+        .iter()
+        .map(|missing| {
+            assert!(
+                missing.has_metadata,
+                "Metadata of chunk {} was removed",
+                missing.chunk_id
+            );
+            missing.chunk_id
+        })
+        .collect();
+    assert_eq!(missing, chunk_ids);
+    Ok(())
+}
@@ -0,0 +1,117 @@
+//! Journals of resumable uploads and downloads.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+use storage_core::common::ChunkserverLocation;
+use storage_core::common::journal::{
+    DownloadJournal, DownloadedChunk, UploadJournal, UploadedChunk, content_hash,
+};
+use storage_core::common::types::ChunkLocations;
+use uuid::Uuid;
+
+fn journal_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("storage-core-journal-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).expect("Couldn't create journal directory");
+    dir
+}
+
+fn uploaded_chunk(offset: u64, confirmed: bool) -> UploadedChunk {
+    let primary = ChunkserverLocation::new(
+        SocketAddr::from(([127, 0, 0, 1], 5000)),
+        "chunkserver-1".to_string(),
+    );
+    UploadedChunk {
+        locations: ChunkLocations {
+            chunk_id: primary.chunk_id,
+            primary: Some(primary),
+            replicas: vec![],
+            stripes: None,
+            stored: false,
+            alternates: vec![],
+        },
+        offset,
+        length: 100,
+        confirmed,
+    }
+}
+
+fn upload_journal() -> UploadJournal {
+    UploadJournal {
+        filename: "/file".to_string(),
+        request_id: Some(Uuid::new_v4()),
+        file_size: 300,
+        modified: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        chunks: vec![
+            uploaded_chunk(0, true),
+            uploaded_chunk(100, false),
+            uploaded_chunk(200, false),
+        ],
+    }
+}
+
+#[test]
+fn journals_are_kept_next_to_the_local_file() {
+    let local_path = Path::new("/data/file.tar.gz");
+    assert_eq!(
+        UploadJournal::path_for(local_path),
+        Path::new("/data/file.tar.gz.upload.journal")
+    );
+    assert_eq!(
+        DownloadJournal::path_for(local_path),
+        Path::new("/data/file.tar.gz.download.journal")
+    );
+}
+
+#[tokio::test]
+async fn upload_journal_is_saved_and_loaded() {
+    let saved = upload_journal();
+    let path = UploadJournal::path_for(&journal_dir().join("file"));
+    saved.save(&path).await.unwrap();
+
+    let journal = UploadJournal::load(&path).await.unwrap();
+    assert_eq!(format!("{:?}", journal), format!("{:?}", saved));
+    let remaining: Vec<_> = journal.remaining().map(|(idx, _)| idx).collect();
+    assert_eq!(remaining, vec![1, 2]);
+}
+
+#[test]
+fn upload_journal_belongs_to_the_same_local_file() {
+    let journal = upload_journal();
+    let modified = journal.modified;
+
+    assert!(journal.check_matches("/file", 300, modified).is_ok());
+    assert!(journal.check_matches("/other", 300, modified).is_err());
+    assert!(journal.check_matches("/file", 301, modified).is_err());
+    assert!(
+        journal
+            .check_matches("/file", 300, modified + Duration::from_secs(1))
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn download_journal_keeps_downloaded_chunks() {
+    let chunk_id = Uuid::new_v4();
+    let journal = DownloadJournal {
+        filename: "/file".to_string(),
+        chunks: vec![
+            None,
+            Some(DownloadedChunk {
+                chunk_id,
+                size: 100,
+                hash: content_hash(b"chunk"),
+            }),
+        ],
+    };
+    let path = DownloadJournal::path_for(&journal_dir().join("file"));
+    journal.save(&path).await.unwrap();
+    let journal = DownloadJournal::load(&path).await.unwrap();
+
+    let downloaded = journal.downloaded(1, chunk_id, 100).unwrap();
+    assert_eq!(downloaded.hash, content_hash(b"chunk"));
+    assert!(journal.downloaded(0, chunk_id, 100).is_none());
+    assert!(journal.downloaded(1, Uuid::new_v4(), 100).is_none());
+    assert!(journal.downloaded(1, chunk_id, 99).is_none());
+    assert!(journal.downloaded(2, chunk_id, 100).is_none());
+}
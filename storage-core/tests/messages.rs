@@ -0,0 +1,822 @@
+//! Round trips of all messages over in-memory streams.
+
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+use storage_core::common::config::{
+    ClusterConfig, MAX_MESSAGE_SIZE, TMP_STORAGE_ROOT, cluster_config,
+};
+use storage_core::common::protocol::Features;
+use storage_core::common::telemetry::{RequestId, with_request_id};
+use storage_core::common::types::{
+    ByteRange, ChunkDigest, ChunkLocations, ChunkStatus, ChunkserverStatus, ContentHash,
+    ErasureCoding, FileVersion, FsckReport, Replication, ReplicationOrder, Retention, StorageClass,
+    StoredChunk, StripeLocations,
+};
+use storage_core::common::*;
+use tokio::io::{AsyncWriteExt, duplex};
+use uuid::Uuid;
+
+/// Size of the in-memory pipe, smaller than the chunks so that they are streamed.
+const PIPE_CAPACITY: usize = 1024;
+
+fn tmp_storage_root() -> &'static Path {
+    TMP_STORAGE_ROOT.get_or_init(|| {
+        let root =
+            std::env::temp_dir().join(format!("storage-core-messages-{}", std::process::id()));
+        std::fs::create_dir_all(&root).expect("Couldn't create temporary storage");
+        root
+    })
+}
+
+/// Writes `data` to a new file and returns its path.
+fn chunk_file(data: &[u8]) -> PathBuf {
+    static NEXT_FILE: AtomicU64 = AtomicU64::new(0);
+
+    let path = tmp_storage_root().join(format!(
+        "source-{}",
+        NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&path, data).expect("Couldn't write chunk file");
+    path
+}
+
+/// Sends the message within a request and receives it on the other end of a pipe.
+async fn round_trip<M: Message + Debug>(message: &M) -> (RequestId, M) {
+    let (mut send, mut recv) = duplex(PIPE_CAPACITY);
+    let request_id = Uuid::new_v4();
+
+    let (sent, received) = tokio::join!(
+        with_request_id(request_id, async {
+            let result = message.send(&mut send).await;
+            send.shutdown().await.unwrap();
+            result
+        }),
+        M::recv_with_request_id(&mut recv),
+    );
+    sent.expect("Couldn't send message");
+    let (received_request_id, received) = received.expect("Couldn't receive message");
+
+    assert_eq!(received_request_id, request_id);
+    (received_request_id, received)
+}
+
+async fn assert_round_trip<M: Message + Debug>(message: M) {
+    let (_, received) = round_trip(&message).await;
+    assert_eq!(format!("{:?}", received), format!("{:?}", message));
+}
+
+fn chunkserver_location() -> ChunkserverLocation {
+    ChunkserverLocation::new(
+        SocketAddr::from(([127, 0, 0, 1], 5000)),
+        "chunkserver-1".to_string(),
+    )
+}
+
+fn chunk_status() -> ChunkStatus {
+    ChunkStatus {
+        chunk_id: Uuid::new_v4(),
+        primary: Some(Uuid::new_v4()),
+        replicas: vec![Uuid::new_v4(), Uuid::new_v4()],
+        stored_on: vec![Uuid::new_v4()],
+        required_copies: Some(3),
+        references: Some(2),
+        stripes: Vec::new(),
+    }
+}
+
+fn file_version(version: u64) -> FileVersion {
+    FileVersion {
+        version,
+        created_at: UNIX_EPOCH + Duration::from_secs(1_790_000_000 + version),
+    }
+}
+
+#[tokio::test]
+async fn metadata_server_external_messages() {
+    assert_round_trip(MetadataServerExternalMessage::ChunkPlacementRequest(
+        ChunkPlacementRequestPayload {
+            filename: "dir/file.txt".to_string(),
+            file_size: 123_456,
+            replication: Replication::Class(StorageClass::Archive),
+            chunks: None,
+            versioning: None,
+        },
+    ))
+    .await;
+    assert_round_trip(MetadataServerExternalMessage::ChunkPlacementRequest(
+        ChunkPlacementRequestPayload {
+            filename: "dir/file.txt".to_string(),
+            file_size: 123_456,
+            replication: Replication::Copies(4),
+            chunks: None,
+            versioning: None,
+        },
+    ))
+    .await;
+    assert_round_trip(MetadataServerExternalMessage::ChunkPlacementRequest(
+        ChunkPlacementRequestPayload {
+            filename: "dir/file.txt".to_string(),
+            file_size: 123_456,
+            replication: Replication::ErasureCoded(ErasureCoding::new(6, 3).unwrap()),
+            chunks: None,
+            versioning: None,
+        },
+    ))
+    .await;
+    assert_round_trip(MetadataServerExternalMessage::ChunkPlacementRequest(
+        ChunkPlacementRequestPayload {
+            filename: "dir/file.txt".to_string(),
+            file_size: 3_000,
+            replication: Replication::default(),
+            chunks: Some(vec![
+                ChunkDigest {
+                    size: 1_000,
+                    hash: ContentHash([1; 32]),
+                },
+                ChunkDigest {
+                    size: 2_000,
+                    hash: ContentHash([2; 32]),
+                },
+            ]),
+            versioning: None,
+        },
+    ))
+    .await;
+    assert_round_trip(MetadataServerExternalMessage::ChunkPlacementRequest(
+        ChunkPlacementRequestPayload {
+            filename: "dir/file.txt".to_string(),
+            file_size: 123_456,
+            replication: Replication::default(),
+            chunks: None,
+            versioning: Some(Retention {
+                keep_versions: Some(5),
+                keep_for: Some(Duration::from_secs(30 * 24 * 3600)),
+            }),
+        },
+    ))
+    .await;
+    assert_round_trip(MetadataServerExternalMessage::GetFilePlacementRequest(
+        GetFilePlacementRequestPayload {
+            filename: "dir/file.txt".to_string(),
+            version: None,
+            as_of: None,
+        },
+    ))
+    .await;
+    assert_round_trip(MetadataServerExternalMessage::GetFilePlacementRequest(
+        GetFilePlacementRequestPayload {
+            filename: "dir/file.txt".to_string(),
+            version: Some(3),
+            as_of: None,
+        },
+    ))
+    .await;
+    assert_round_trip(MetadataServerExternalMessage::GetFilePlacementRequest(
+        GetFilePlacementRequestPayload {
+            filename: "dir/file.txt".to_string(),
+            version: None,
+            as_of: Some(UNIX_EPOCH + Duration::from_secs(1_790_000_000)),
+        },
+    ))
+    .await;
+    assert_round_trip(
+        MetadataServerExternalMessage::GetClientFolderStructureRequest(
+            GetClientFolderStructureRequestPayload {},
+        ),
+    )
+    .await;
+    assert_round_trip(MetadataServerExternalMessage::UpdateClientFolderStructure(
+        UpdateClientFolderStructurePayload {},
+    ))
+    .await;
+    assert_round_trip(MetadataServerExternalMessage::CopyFile(CopyFilePayload {
+        source: "dir/file.txt".to_string(),
+        destination: "dir/copy.txt".to_string(),
+    }))
+    .await;
+    assert_round_trip(MetadataServerExternalMessage::SnapshotDirectoryRequest(
+        SnapshotDirectoryRequestPayload {
+            directory: "dir".to_string(),
+            snapshot: "dir-snapshot".to_string(),
+        },
+    ))
+    .await;
+    assert_round_trip(MetadataServerExternalMessage::DeleteFile(
+        DeleteFilePayload {
+            filename: "dir/file.txt".to_string(),
+        },
+    ))
+    .await;
+    assert_round_trip(MetadataServerExternalMessage::ListFileVersionsRequest(
+        ListFileVersionsRequestPayload {
+            filename: "dir/file.txt".to_string(),
+        },
+    ))
+    .await;
+    assert_round_trip(MetadataServerExternalMessage::WriteFileRequest(
+        WriteFileRequestPayload {
+            filename: "dir/file.txt".to_string(),
+            offset: None,
+            length: 4096,
+        },
+    ))
+    .await;
+    assert_round_trip(MetadataServerExternalMessage::WriteFileRequest(
+        WriteFileRequestPayload {
+            filename: "dir/file.txt".to_string(),
+            offset: Some(100),
+            length: 10,
+        },
+    ))
+    .await;
+    assert_round_trip(MetadataServerExternalMessage::UploadFailover(
+        UploadFailoverPayload {
+            chunk_id: Uuid::new_v4(),
+            failed: SocketAddr::from(([127, 0, 0, 1], 5000)),
+            stored_on: SocketAddr::from(([127, 0, 0, 1], 5001)),
+        },
+    ))
+    .await;
+}
+
+#[tokio::test]
+async fn metadata_server_internal_messages() {
+    assert_round_trip(MetadataServerInternalMessage::ChunkServerDiscover(
+        ChunkServerDiscoverPayload {
+            server_id: Uuid::new_v4(),
+            hostname: "chunkserver-1".to_string(),
+            rack_id: "rack-1".to_string(),
+            internal_address: SocketAddr::from(([10, 0, 0, 1], 6000)),
+            external_address: SocketAddr::from(([10, 0, 0, 1], 5000)),
+            stored_chunks: vec![StoredChunk {
+                chunk_id: Uuid::new_v4(),
+                size: 4096,
+                version: 0,
+            }],
+        },
+    ))
+    .await;
+    assert_round_trip(MetadataServerInternalMessage::Heartbeat(HeartbeatPayload {
+        server_id: Uuid::new_v4(),
+        client_requests_count: 17,
+        available_space: u64::MAX,
+        added_chunks: vec![StoredChunk {
+            chunk_id: Uuid::new_v4(),
+            size: 1,
+            version: 3,
+        }],
+        removed_chunks: vec![Uuid::new_v4()],
+        leaving: true,
+    }))
+    .await;
+    assert_round_trip(MetadataServerInternalMessage::GrantLeaseRequest(
+        GrantLeaseRequestPayload {
+            server_id: Uuid::new_v4(),
+            chunk_id: Uuid::new_v4(),
+            version: 2,
+        },
+    ))
+    .await;
+    assert_round_trip(MetadataServerInternalMessage::StaleReplica(
+        StaleReplicaPayload {
+            server_id: Uuid::new_v4(),
+            chunk_id: Uuid::new_v4(),
+            replica_id: Uuid::new_v4(),
+        },
+    ))
+    .await;
+}
+
+#[tokio::test]
+async fn chunkserver_internal_messages() {
+    assert_round_trip(ChunkserverInternalMessage::AcceptNewChunkserver(
+        AcceptNewChunkServerPayload {
+            chunkserver_new_id: Uuid::new_v4(),
+            cluster_config: Some(ClusterConfig {
+                max_chunk_size: 1024,
+                n_chunk_replicas: 1,
+                heartbeat_interval: Duration::from_secs(5),
+            }),
+        },
+    ))
+    .await;
+    assert_round_trip(ChunkserverInternalMessage::HeartbeatResponse(
+        HeartbeatResponsePayload {
+            replicate: vec![ReplicationOrder {
+                target: chunkserver_location(),
+                request_id: Uuid::new_v4(),
+            }],
+            delete: vec![Uuid::new_v4()],
+        },
+    ))
+    .await;
+    assert_round_trip(ChunkserverInternalMessage::LeaseGranted(
+        LeaseGrantedPayload {
+            duration: Duration::from_secs(60),
+            replicas: vec![LeaseReplica {
+                server_id: Uuid::new_v4(),
+                location: chunkserver_location(),
+            }],
+        },
+    ))
+    .await;
+    assert_round_trip(ChunkserverInternalMessage::RequestStatus(
+        RequestStatusPayload::Ok,
+    ))
+    .await;
+}
+
+#[tokio::test]
+async fn client_messages() {
+    let chunk_locations = || ChunkLocations {
+        chunk_id: Uuid::new_v4(),
+        primary: Some(chunkserver_location()),
+        replicas: vec![chunkserver_location()],
+        stripes: None,
+        stored: false,
+        alternates: vec![chunkserver_location()],
+    };
+    let stripe_locations = || ChunkLocations {
+        chunk_id: Uuid::new_v4(),
+        primary: None,
+        replicas: Vec::new(),
+        stripes: Some(StripeLocations {
+            coding: ErasureCoding::new(2, 1).unwrap(),
+            chunk_size: 1000,
+            stripes: vec![
+                Some(chunkserver_location()),
+                None,
+                Some(chunkserver_location()),
+            ],
+        }),
+        stored: false,
+        alternates: Vec::new(),
+    };
+    let stored_locations = || ChunkLocations {
+        chunk_id: Uuid::new_v4(),
+        primary: None,
+        replicas: Vec::new(),
+        stripes: None,
+        stored: true,
+        alternates: Vec::new(),
+    };
+
+    assert_round_trip(ClientMessage::ChunkPlacementResponse(
+        ChunkPlacementResponsePayload {
+            selected_chunkservers: vec![chunk_locations(), stripe_locations(), stored_locations()],
+            chunk_size: Some(1024),
+            version: Some(file_version(1)),
+        },
+    ))
+    .await;
+    assert_round_trip(ClientMessage::GetFilePlacementResponse(
+        GetFilePlacementResponsePayload {
+            chunks_locations: vec![chunk_locations()],
+            version: None,
+            chunk_sizes: vec![],
+        },
+    ))
+    .await;
+    assert_round_trip(ClientMessage::GetFilePlacementResponse(
+        GetFilePlacementResponsePayload {
+            chunks_locations: vec![chunk_locations()],
+            version: Some(file_version(2)),
+            chunk_sizes: vec![4096],
+        },
+    ))
+    .await;
+    assert_round_trip(ClientMessage::ListFileVersionsResponse(
+        ListFileVersionsResponsePayload {
+            versions: vec![file_version(1), file_version(2)],
+        },
+    ))
+    .await;
+    assert_round_trip(ClientMessage::WriteFileResponse(WriteFileResponsePayload {
+        offset: 1000,
+        writes: vec![
+            ChunkWrite {
+                offset: 0,
+                length: 24,
+                chunk_offset: 1000,
+                chunk: chunk_locations(),
+                created: false,
+            },
+            ChunkWrite {
+                offset: 24,
+                length: 1024,
+                chunk_offset: 0,
+                chunk: chunk_locations(),
+                created: true,
+            },
+        ],
+    }))
+    .await;
+    assert_round_trip(ClientMessage::SnapshotDirectoryResponse(
+        SnapshotDirectoryResponsePayload { copied_files: 12 },
+    ))
+    .await;
+    assert_round_trip(ClientMessage::GetClientFolderStructureResponse(
+        GetClientFolderStructureResponsePayload {},
+    ))
+    .await;
+    assert_round_trip(ClientMessage::RequestStatus(RequestStatusPayload::Error(
+        ErrorPayload::new(
+            ErrorCode::NotEnoughChunkservers,
+            "Only 1 chunkserver available",
+        )
+        .with_retry_after(Duration::from_secs(60)),
+    )))
+    .await;
+}
+
+#[tokio::test]
+async fn admin_messages() {
+    assert_round_trip(MetadataServerAdminMessage::ListChunkserversRequest(
+        ListChunkserversRequestPayload {},
+    ))
+    .await;
+    assert_round_trip(MetadataServerAdminMessage::GetFileChunkMapRequest(
+        GetFileChunkMapRequestPayload {
+            filename: "file.txt".to_string(),
+        },
+    ))
+    .await;
+    assert_round_trip(
+        MetadataServerAdminMessage::ListUnderReplicatedChunksRequest(
+            ListUnderReplicatedChunksRequestPayload {},
+        ),
+    )
+    .await;
+    assert_round_trip(MetadataServerAdminMessage::DrainChunkserverRequest(
+        DrainChunkserverRequestPayload {
+            server_id: Uuid::new_v4(),
+        },
+    ))
+    .await;
+    assert_round_trip(MetadataServerAdminMessage::RebalanceRequest(
+        RebalanceRequestPayload {},
+    ))
+    .await;
+    assert_round_trip(MetadataServerAdminMessage::FsckRequest(
+        FsckRequestPayload { repair: true },
+    ))
+    .await;
+
+    assert_round_trip(AdminMessage::ListChunkserversResponse(
+        ListChunkserversResponsePayload {
+            chunkservers: vec![ChunkserverStatus {
+                server_id: Uuid::new_v4(),
+                hostname: "chunkserver-1".to_string(),
+                rack_id: "rack-1".to_string(),
+                external_address: SocketAddr::from(([10, 0, 0, 1], 5000)),
+                available_space: 1 << 40,
+                secs_since_heartbeat: 3,
+                chunk_count: 42,
+                draining: false,
+            }],
+        },
+    ))
+    .await;
+    assert_round_trip(AdminMessage::GetFileChunkMapResponse(
+        GetFileChunkMapResponsePayload {
+            chunks: vec![chunk_status()],
+        },
+    ))
+    .await;
+    assert_round_trip(AdminMessage::ListUnderReplicatedChunksResponse(
+        ListUnderReplicatedChunksResponsePayload {
+            chunks: vec![chunk_status(), chunk_status()],
+        },
+    ))
+    .await;
+    assert_round_trip(AdminMessage::ReplicationScheduled(
+        ReplicationScheduledPayload {
+            scheduled_chunks: 7,
+        },
+    ))
+    .await;
+    assert_round_trip(AdminMessage::FsckResponse(FsckResponsePayload {
+        report: FsckReport {
+            checked_files: 2,
+            checked_chunks: 5,
+            chunks_without_primary: vec![Uuid::new_v4()],
+            repair_scheduled: true,
+            ..FsckReport::default()
+        },
+    }))
+    .await;
+    assert_round_trip(AdminMessage::RequestStatus(RequestStatusPayload::error(
+        ErrorCode::FileNotFound,
+        "File file.txt not found",
+    )))
+    .await;
+}
+
+#[tokio::test]
+async fn handshake_messages() {
+    assert_round_trip(HandshakeMessage::Hello(HelloPayload {
+        protocol_version: 3,
+        min_protocol_version: 1,
+        features: Features::SUPPORTED,
+        required_features: Features::REQUIRED,
+    }))
+    .await;
+    assert_round_trip(HandshakeMessage::HelloAccepted(HelloAcceptedPayload {
+        protocol_version: 1,
+        features: Features::NONE,
+    }))
+    .await;
+    assert_round_trip(HandshakeMessage::RequestStatus(
+        RequestStatusPayload::error(
+            ErrorCode::IncompatibleProtocol,
+            "Protocol versions don't overlap",
+        ),
+    ))
+    .await;
+}
+
+#[tokio::test]
+async fn chunk_is_streamed() {
+    let data: Vec<u8> = (0..16 * PIPE_CAPACITY).map(|i| (i % 251) as u8).collect();
+    let source = chunk_file(&data);
+    let chunk_id = Uuid::new_v4();
+
+    let message = ChunkserverExternalMessage::UploadChunk(UploadChunkPayload {
+        chunk_id,
+        chunk_size: data.len() as u64,
+        version: 0,
+        chunk_transfer: ChunkTransfer::from_file(source.clone(), None),
+    });
+    let (_, received) = round_trip(&message).await;
+
+    let ChunkserverExternalMessage::UploadChunk(payload) = received else {
+        panic!("Unexpected message {:?}", received);
+    };
+    assert_eq!(payload.chunk_id, chunk_id);
+    assert_eq!(payload.chunk_size, data.len() as u64);
+    assert_eq!(std::fs::read(&payload.chunk_transfer.data).unwrap(), data);
+
+    // The received chunk is removed with the payload, the sent one is left untouched.
+    let received_path = payload.chunk_transfer.data.clone();
+    drop(payload);
+    assert!(!received_path.exists());
+    assert!(source.exists());
+}
+
+#[tokio::test]
+async fn chunk_is_streamed_from_offset() {
+    let data: Vec<u8> = (0..4 * PIPE_CAPACITY).map(|i| (i % 13) as u8).collect();
+    let source = chunk_file(&data);
+    let offset = PIPE_CAPACITY + 7;
+    let chunk_size = 2 * PIPE_CAPACITY;
+
+    let message = ClientMessage::DownloadChunkResponse(DownloadChunkResponsePayload {
+        chunk_id: Uuid::new_v4(),
+        chunk_size: chunk_size as u64,
+        chunk_transfer: ChunkTransfer::from_file(source, Some(offset as u64)),
+    });
+    let (_, received) = round_trip(&message).await;
+
+    let ClientMessage::DownloadChunkResponse(payload) = received else {
+        panic!("Unexpected message {:?}", received);
+    };
+    assert_eq!(
+        std::fs::read(&payload.chunk_transfer.data).unwrap(),
+        &data[offset..offset + chunk_size]
+    );
+}
+
+#[tokio::test]
+async fn chunk_is_streamed_from_memory() {
+    let data: Vec<u8> = (0..3 * PIPE_CAPACITY).map(|i| (i % 17) as u8).collect();
+
+    let message = ChunkserverExternalMessage::UploadChunk(UploadChunkPayload {
+        chunk_id: Uuid::new_v4(),
+        chunk_size: data.len() as u64,
+        version: 0,
+        chunk_transfer: ChunkTransfer::from_bytes(data.clone()),
+    });
+    let (_, received) = round_trip(&message).await;
+
+    let ChunkserverExternalMessage::UploadChunk(payload) = received else {
+        panic!("Unexpected message {:?}", received);
+    };
+    assert_eq!(std::fs::read(&payload.chunk_transfer.data).unwrap(), data);
+}
+
+#[tokio::test]
+async fn write_is_streamed_with_its_offset_and_version() {
+    let data: Vec<u8> = (0..2 * PIPE_CAPACITY).map(|i| (i % 19) as u8).collect();
+    let chunk_id = Uuid::new_v4();
+
+    let message = ChunkserverInternalMessage::ApplyWrite(WriteChunkPayload {
+        chunk_id,
+        offset: 512,
+        chunk_size: data.len() as u64,
+        version: 4,
+        chunk_transfer: ChunkTransfer::from_file(chunk_file(&data), None),
+    });
+    let (_, received) = round_trip(&message).await;
+
+    let ChunkserverInternalMessage::ApplyWrite(payload) = received else {
+        panic!("Unexpected message {:?}", received);
+    };
+    assert_eq!(payload.chunk_id, chunk_id);
+    assert_eq!((payload.offset, payload.version), (512, 4));
+    assert_eq!(std::fs::read(&payload.chunk_transfer.data).unwrap(), data);
+}
+
+#[tokio::test]
+async fn download_request_keeps_its_range() {
+    let range = ByteRange {
+        offset: 100,
+        length: 28,
+    };
+    let message = ChunkserverExternalMessage::DownloadChunkRequest(DownloadChunkRequestPayload {
+        chunk_id: Uuid::new_v4(),
+        range: Some(range),
+    });
+    let (_, received) = round_trip(&message).await;
+
+    let ChunkserverExternalMessage::DownloadChunkRequest(payload) = received else {
+        panic!("Unexpected message {:?}", received);
+    };
+    assert_eq!(payload.range, Some(range));
+}
+
+#[tokio::test]
+async fn message_after_chunk_is_received() {
+    let data = vec![7u8; 3 * PIPE_CAPACITY];
+    let source = chunk_file(&data);
+    let (mut send, mut recv) = duplex(PIPE_CAPACITY);
+
+    let replica = ChunkserverInternalMessage::StoreReplica(UploadChunkPayload {
+        chunk_id: Uuid::new_v4(),
+        chunk_size: data.len() as u64,
+        version: 0,
+        chunk_transfer: ChunkTransfer::from_file(source, None),
+    });
+    let status = ChunkserverInternalMessage::RequestStatus(RequestStatusPayload::Ok);
+
+    let (sent, received) = tokio::join!(
+        async {
+            replica.send(&mut send).await?;
+            status.send(&mut send).await
+        },
+        async {
+            let replica = ChunkserverInternalMessage::recv(&mut recv).await?;
+            let status = ChunkserverInternalMessage::recv(&mut recv).await?;
+            anyhow::Ok((replica, status))
+        },
+    );
+    sent.unwrap();
+    let (replica, status) = received.unwrap();
+
+    let ChunkserverInternalMessage::StoreReplica(payload) = replica else {
+        panic!("Unexpected message {:?}", replica);
+    };
+    assert_eq!(std::fs::read(&payload.chunk_transfer.data).unwrap(), data);
+    assert!(matches!(
+        status,
+        ChunkserverInternalMessage::RequestStatus(RequestStatusPayload::Ok)
+    ));
+}
+
+#[tokio::test]
+async fn chunk_shorter_than_its_size_is_not_sent() {
+    let source = chunk_file(&[1, 2, 3]);
+    let (mut send, _recv) = duplex(PIPE_CAPACITY);
+
+    let message = ChunkserverExternalMessage::UploadChunk(UploadChunkPayload {
+        chunk_id: Uuid::new_v4(),
+        chunk_size: 10,
+        version: 0,
+        chunk_transfer: ChunkTransfer::from_file(source, None),
+    });
+
+    assert!(message.send(&mut send).await.is_err());
+}
+
+#[tokio::test]
+async fn unknown_variant_is_rejected() {
+    let mut frame = vec![200u8];
+    frame.extend_from_slice(Uuid::new_v4().as_bytes());
+    frame.extend_from_slice(&0u32.to_be_bytes());
+
+    let error = HandshakeMessage::recv(&mut frame.as_slice())
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("Unknown variant ID: 200"));
+}
+
+#[tokio::test]
+async fn truncated_message_is_rejected() {
+    let (mut send, mut recv) = duplex(PIPE_CAPACITY);
+    MetadataServerExternalMessage::GetFilePlacementRequest(GetFilePlacementRequestPayload {
+        filename: "file.txt".to_string(),
+        version: None,
+        as_of: None,
+    })
+    .send(&mut send)
+    .await
+    .unwrap();
+    drop(send);
+
+    let mut frame = Vec::new();
+    tokio::io::AsyncReadExt::read_to_end(&mut recv, &mut frame)
+        .await
+        .unwrap();
+    frame.truncate(frame.len() - 1);
+
+    assert!(
+        MetadataServerExternalMessage::recv(&mut frame.as_slice())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn oversized_message_is_rejected_before_allocation() {
+    let mut frame = vec![1u8];
+    frame.extend_from_slice(Uuid::new_v4().as_bytes());
+    frame.extend_from_slice(&u32::MAX.to_be_bytes());
+
+    let error = MetadataServerExternalMessage::recv(&mut frame.as_slice())
+        .await
+        .unwrap_err();
+    assert!(
+        error.to_string().contains("exceeds the limit"),
+        "{:?}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn oversized_message_is_not_sent() {
+    let (mut send, _recv) = duplex(PIPE_CAPACITY);
+
+    let message =
+        MetadataServerExternalMessage::GetFilePlacementRequest(GetFilePlacementRequestPayload {
+            filename: "a".repeat(MAX_MESSAGE_SIZE as usize),
+            version: None,
+            as_of: None,
+        });
+
+    assert!(message.send(&mut send).await.is_err());
+}
+
+/// Sends the chunk of `chunk_size` bytes from a file of `data`, closing the stream afterwards.
+async fn recv_chunk(
+    data: &[u8],
+    chunk_size: u64,
+) -> (Uuid, anyhow::Result<ChunkserverExternalMessage>) {
+    let source = chunk_file(data);
+    let chunk_id = Uuid::new_v4();
+    let (mut send, mut recv) = duplex(PIPE_CAPACITY);
+
+    let message = ChunkserverExternalMessage::UploadChunk(UploadChunkPayload {
+        chunk_id,
+        chunk_size,
+        version: 0,
+        chunk_transfer: ChunkTransfer::from_file(source, None),
+    });
+
+    let (_, received) = tokio::join!(
+        async move {
+            let _ = message.send(&mut send).await;
+        },
+        ChunkserverExternalMessage::recv(&mut recv),
+    );
+    (chunk_id, received)
+}
+
+#[tokio::test]
+async fn chunk_above_max_size_is_rejected() {
+    let (chunk_id, received) = recv_chunk(&[1, 2, 3], cluster_config().max_chunk_size + 1).await;
+
+    let error = received.unwrap_err();
+    assert!(
+        error.to_string().contains("exceeds the limit"),
+        "{:?}",
+        error
+    );
+    assert!(!tmp_storage_root().join(chunk_id.to_string()).exists());
+}
+
+#[tokio::test]
+async fn incomplete_chunk_is_removed() {
+    let (chunk_id, received) = recv_chunk(&[1, 2, 3], 10).await;
+
+    let error = received.unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("Chunk ended after 3 of 10 bytes"),
+        "{:?}",
+        error
+    );
+    assert!(!tmp_storage_root().join(chunk_id.to_string()).exists());
+}
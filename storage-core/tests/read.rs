@@ -0,0 +1,51 @@
+//! Mapping of file ranges onto the chunks of the file.
+
+use storage_core::common::read::chunk_ranges;
+use storage_core::common::types::ByteRange;
+
+fn range(offset: u64, length: u64) -> ByteRange {
+    ByteRange { offset, length }
+}
+
+#[test]
+fn range_within_one_chunk() {
+    assert_eq!(
+        chunk_ranges(&[100, 100, 100], range(120, 30)),
+        vec![(1, range(20, 30))]
+    );
+}
+
+#[test]
+fn range_spanning_chunks() {
+    assert_eq!(
+        chunk_ranges(&[100, 50, 100], range(90, 100)),
+        vec![(0, range(90, 10)), (1, range(0, 50)), (2, range(0, 40))]
+    );
+}
+
+#[test]
+fn range_ending_at_chunk_boundary() {
+    assert_eq!(
+        chunk_ranges(&[100, 100], range(0, 100)),
+        vec![(0, range(0, 100))]
+    );
+    assert_eq!(
+        chunk_ranges(&[100, 100], range(100, 100)),
+        vec![(1, range(0, 100))]
+    );
+}
+
+#[test]
+fn range_is_cut_at_end_of_file() {
+    assert_eq!(
+        chunk_ranges(&[100, 60], range(150, 1000)),
+        vec![(1, range(50, 10))]
+    );
+    assert_eq!(chunk_ranges(&[100, 60], range(u64::MAX, 10)), vec![]);
+}
+
+#[test]
+fn empty_range_spans_no_chunks() {
+    assert_eq!(chunk_ranges(&[100, 100], range(50, 0)), vec![]);
+    assert_eq!(chunk_ranges(&[], range(0, 10)), vec![]);
+}
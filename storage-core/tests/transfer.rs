@@ -0,0 +1,143 @@
+//! Scheduling of client chunk transfers.
+
+use futures::future::join_all;
+use std::net::SocketAddr;
+use std::num::NonZeroU64;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use storage_core::common::transfer::{TransferLimits, TransferProgress, TransferScheduler};
+
+fn server(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+/// Counts the transfers running at the same time, remembering the highest count.
+#[derive(Default)]
+struct Running {
+    now: AtomicUsize,
+    max: AtomicUsize,
+}
+
+impl Running {
+    async fn transfer(&self) -> anyhow::Result<()> {
+        let now = self.now.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max.fetch_max(now, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        self.now.fetch_sub(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn transfers_stay_within_the_concurrency_limits() {
+    let scheduler = TransferScheduler::new(TransferLimits {
+        max_concurrent_chunks: 3,
+        max_chunks_per_server: 2,
+        bytes_per_second: None,
+    });
+    let (overall, first_server) = (Running::default(), Running::default());
+
+    join_all((0..6).map(|_| {
+        scheduler.transfer([server(1)], 10, |_| async {
+            first_server.transfer().await?;
+            overall.transfer().await
+        })
+    }))
+    .await;
+    assert_eq!(first_server.max.load(Ordering::SeqCst), 2);
+
+    join_all((0..8).map(|idx| scheduler.transfer([server(idx % 4)], 10, |_| overall.transfer())))
+        .await;
+    assert_eq!(overall.max.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn transfers_spanning_several_servers_take_all_of_them() {
+    let scheduler = TransferScheduler::new(TransferLimits {
+        max_concurrent_chunks: 8,
+        max_chunks_per_server: 1,
+        bytes_per_second: None,
+    });
+    let running = Running::default();
+
+    let servers = [
+        [server(1), server(2)],
+        [server(2), server(1)],
+        [server(2), server(3)],
+    ];
+    join_all(
+        servers
+            .into_iter()
+            .map(|servers| scheduler.transfer(servers, 10, |_| running.transfer())),
+    )
+    .await;
+    assert_eq!(running.max.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn bandwidth_limit_spaces_out_transfers() {
+    let scheduler = TransferScheduler::new(TransferLimits {
+        max_concurrent_chunks: 4,
+        max_chunks_per_server: 4,
+        bytes_per_second: NonZeroU64::new(1000),
+    });
+
+    let started = Instant::now();
+    let results =
+        join_all((0..3).map(|idx| scheduler.transfer([server(idx)], 100, |_| async { Ok(()) })))
+            .await;
+    assert!(results.iter().all(|result| result.is_ok()));
+    // The first transfer starts right away, each of the others 100ms after the previous one.
+    assert!(started.elapsed() >= Duration::from_millis(200));
+}
+
+#[tokio::test]
+async fn only_successful_transfers_make_progress() {
+    let scheduler = TransferScheduler::new(TransferLimits::default());
+    scheduler.add_pending(3, 300);
+
+    scheduler
+        .transfer([server(1)], 100, |_| async { Ok(()) })
+        .await
+        .unwrap();
+    let failed: anyhow::Result<()> = scheduler
+        .transfer([server(2)], 100, |_| async {
+            anyhow::bail!("Chunkserver unreachable")
+        })
+        .await;
+    assert!(failed.is_err());
+
+    let progress = scheduler.progress();
+    assert_eq!(
+        (
+            progress.bytes,
+            progress.total_bytes,
+            progress.chunks,
+            progress.total_chunks
+        ),
+        (100, 300, 1, 3)
+    );
+}
+
+#[test]
+fn eta_follows_the_rate_of_transferred_bytes() {
+    let progress = TransferProgress {
+        bytes: 250,
+        total_bytes: 1000,
+        chunks: 1,
+        total_chunks: 4,
+        elapsed: Duration::from_secs(10),
+    };
+    assert_eq!(progress.eta(), Some(Duration::from_secs(30)));
+    assert_eq!(
+        progress.to_string(),
+        "250 of 1000 bytes, 1 of 4 chunks, ETA 30s"
+    );
+
+    let started = TransferProgress {
+        bytes: 0,
+        chunks: 0,
+        ..progress
+    };
+    assert_eq!(started.eta(), None);
+}
@@ -1,22 +1,32 @@
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{Data, DataEnum, DeriveInput, Fields, parse_macro_input};
+use std::collections::HashMap;
+use syn::{Data, DataEnum, DeriveInput, Fields, LitInt, Variant, parse_macro_input};
+
+mod rpc;
 
 /// `Message` trait derivation for an enum to act as a network protocol dispatcher.
 ///
 /// # Functionality
 /// Macro transforms a Rust `enum` into a binary format for sending and receiving messages.
 ///
-/// - **Format:** `[1 byte Variant ID] + [Payload Data]`
-/// - **Sending:** Matches the enum variant, writes its index as a `u8` ID, and delegates
-///   serialization to the inner payload's `send_payload` method.
-/// - **Receiving:** Reads a `u8` ID from the stream, matches it to the correct enum variant,
-///   delegates deserialization to the inner payload's `recv_payload`, and wraps it in the variant.
+/// - **Format:** `[1 byte Variant ID] + [16 bytes Request ID] + [Payload Data]`
+/// - **Sending:** Matches the enum variant, writes its ID as a `u8` followed by the id
+///   of the current request, and delegates serialization to the inner payload's `send_payload` method.
+/// - **Receiving:** Reads a `u8` ID and the request id from the stream, matches the ID to the correct
+///   enum variant, delegates deserialization to the inner payload's `recv_payload`, and wraps it in the variant.
 ///
 /// # Constraints
 /// - The enum must only contain variants with a single unnamed field (payload of the message).
-/// - The variants are indexed based on their order in the definition (0, 1, 2, ...).
-#[proc_macro_derive(Message)]
+/// - Every variant must be given an ID with the `#[message(id = N)]` attribute, where `N` is
+///   a `u8` unique within the enum. IDs are part of the protocol, so they must never be
+///   changed or reused once released, regardless of the order of the variants.
+///
+/// `name` returns the name of the variant, e.g. for labeling metrics.
+///
+/// The enum also implements `Carries<P>` for the payload `P` of every variant, so the payload
+/// types must be unique within the enum.
+#[proc_macro_derive(Message, attributes(message))]
 pub fn derive_message_payload_enum(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
     let name = &input.ident;
@@ -29,13 +39,38 @@ pub fn derive_message_payload_enum(input: TokenStream) -> TokenStream {
 
     let mut variant_idxs = Vec::new();
     let mut variant_names = Vec::new();
+    let mut variant_strs = Vec::new();
     let mut payload_types = Vec::new();
 
-    for (idx, variant) in variants.iter().enumerate() {
+    let mut used_ids = HashMap::new();
+    let mut errors: Option<syn::Error> = None;
+    let mut push_error = |error: syn::Error| match errors.as_mut() {
+        Some(errors) => errors.combine(error),
+        None => errors = Some(error),
+    };
+
+    for variant in variants.iter() {
+        let idx = match variant_id(variant) {
+            Ok((idx, lit)) => {
+                if let Some(other) = used_ids.insert(idx, &variant.ident) {
+                    push_error(syn::Error::new_spanned(
+                        lit,
+                        format!("Message ID {} is already used by variant `{}`", idx, other),
+                    ));
+                }
+                idx
+            }
+            Err(error) => {
+                push_error(error);
+                continue;
+            }
+        };
+
         if let Fields::Unnamed(fields) = &variant.fields {
             if fields.unnamed.len() == 1 {
-                variant_idxs.push(idx as u8);
+                variant_idxs.push(idx);
                 variant_names.push(&variant.ident);
+                variant_strs.push(variant.ident.to_string());
                 payload_types.push(&fields.unnamed[0].ty);
             } else {
                 panic!("Enum variants must have exactly one field");
@@ -45,14 +80,22 @@ pub fn derive_message_payload_enum(input: TokenStream) -> TokenStream {
         }
     }
 
+    if let Some(errors) = errors {
+        return errors.to_compile_error().into();
+    }
+
     // Implement sending and receiving message
     let expanded = quote! {
         impl crate::common::messages::messages::Message for #name {
-            async fn send(&self, send: &mut ::quinn::SendStream) -> ::anyhow::Result<()> {
+            async fn send<W: ::tokio::io::AsyncWrite + Unpin + Send>(
+                &self,
+                send: &mut W,
+            ) -> ::anyhow::Result<()> {
                 match self {
                     #(
                         #name::#variant_names(payload) => {
                             send.write_u8(#variant_idxs).await?;
+                            send.write_all(crate::common::telemetry::current_request_id().as_bytes()).await?;
                             crate::common::messages::payload::MessagePayload::send_payload(payload, send).await?
                         }
                     )*
@@ -60,21 +103,114 @@ pub fn derive_message_payload_enum(input: TokenStream) -> TokenStream {
                 ::anyhow::Ok(())
             }
 
-            async fn recv(recv: &mut ::quinn::RecvStream) -> ::anyhow::Result<Self> {
+            async fn recv_with_request_id<R: ::tokio::io::AsyncRead + Unpin + Send>(
+                recv: &mut R,
+            ) -> ::anyhow::Result<(crate::common::telemetry::RequestId, Self)> {
                 use ::tokio::io::AsyncReadExt;
                 let variant_id = recv.read_u8().await?;
+                let mut request_id = [0u8; 16];
+                recv.read_exact(&mut request_id).await?;
+                let request_id = crate::common::telemetry::RequestId::from_bytes(request_id);
+
                 match variant_id {
                     #(
                         #variant_idxs => {
                             let payload = <#payload_types as crate::common::messages::payload::MessagePayload>::recv_payload(recv).await?;
-                            ::anyhow::Ok(#name::#variant_names(payload))
+                            ::anyhow::Ok((request_id, #name::#variant_names(payload)))
                         }
                     )*
                     _ => ::anyhow::bail!("Unknown variant ID: {}", variant_id),
                 }
             }
+
+            fn name(&self) -> &'static str {
+                match self {
+                    #(
+                        #name::#variant_names(_) => #variant_strs,
+                    )*
+                }
+            }
         }
+
+        #(
+            impl crate::common::messages::messages::Carries<#payload_types> for #name {
+                fn from_payload(payload: #payload_types) -> Self {
+                    #name::#variant_names(payload)
+                }
+
+                #[allow(unreachable_patterns)]
+                fn into_payload(self) -> ::core::result::Result<#payload_types, Self> {
+                    match self {
+                        #name::#variant_names(payload) => ::core::result::Result::Ok(payload),
+                        other => ::core::result::Result::Err(other),
+                    }
+                }
+            }
+        )*
     };
 
     TokenStream::from(expanded)
 }
+
+/// Derivation of a typed RPC client and server dispatch trait for an enum of requests.
+///
+/// # Usage
+/// ```ignore
+/// #[derive(Message, Rpc)]
+/// #[rpc(client = ExampleClient, handler = ExampleHandler, response = ResponseMessage)]
+/// pub enum ExampleMessage {
+///     #[message(id = 0)]
+///     #[rpc(method = fetch_example, response = ExampleResponsePayload)]
+///     ExampleRequest(ExampleRequestPayload),
+///     #[message(id = 1)]
+///     #[rpc(method = update_example)]
+///     UpdateExample(UpdateExamplePayload),
+/// }
+/// ```
+///
+/// # Generated items
+/// - **Client** (`client`): wraps a connection and has an async method for every request,
+///   which sends the request on a new stream and returns the payload of the response
+///   or `RpcError`, e.g. `fetch_example(payload) -> Result<ExampleResponsePayload, RpcError>`.
+/// - **Handler** (`handler`): trait with a method for every request returning the payload of
+///   the response, and a provided `dispatch` method, which calls the matching method and sends
+///   its response (or error status) back.
+///
+/// Responses are sent as variants of the `response` message enum, which must also have
+/// a variant carrying `RequestStatusPayload` for errors. Requests without `response` are answered
+/// with `RequestStatusPayload::Ok`. Variants without the `rpc` attribute aren't requests
+/// and are rejected by `dispatch` with `InvalidRequest`.
+#[proc_macro_derive(Rpc, attributes(rpc))]
+pub fn derive_rpc(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    rpc::derive(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Reads the ID of the variant from its `#[message(id = N)]` attribute.
+fn variant_id(variant: &Variant) -> syn::Result<(u8, LitInt)> {
+    let mut id = None;
+    for attr in variant.attrs.iter() {
+        if !attr.path().is_ident("message") {
+            continue;
+        }
+
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("id") {
+                let lit: LitInt = meta.value()?.parse()?;
+                id = Some((lit.base10_parse::<u8>()?, lit));
+                Ok(())
+            } else {
+                Err(meta.error("Unsupported message attribute, expected `id`"))
+            }
+        })?;
+    }
+
+    id.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &variant.ident,
+            "Missing message ID, add the `#[message(id = N)]` attribute",
+        )
+    })
+}
@@ -0,0 +1,218 @@
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{Attribute, Data, DataEnum, DeriveInput, Fields, Ident, Type};
+
+/// Enum-level `#[rpc(client = ..., handler = ..., response = ...)]` attribute.
+struct EnumAttr {
+    client: Ident,
+    handler: Ident,
+    response: Type,
+}
+
+/// Variant-level `#[rpc(method = ..., response = ...)]` attribute.
+struct VariantAttr {
+    method: Ident,
+    /// Payload of the response, `None` if the request is answered with a status only.
+    response: Option<Type>,
+}
+
+pub(crate) fn derive(input: DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let enum_attr = parse_enum_attr(&input)?;
+
+    let variants = match &input.data {
+        Data::Enum(DataEnum { variants, .. }) => variants,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "Only enums with single unnamed field variants are supported",
+            ));
+        }
+    };
+
+    let response = &enum_attr.response;
+    let mut client_methods = Vec::new();
+    let mut handler_methods = Vec::new();
+    let mut dispatch_arms = Vec::new();
+
+    for variant in variants.iter() {
+        let variant_name = &variant.ident;
+        let payload_type = match &variant.fields {
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => &fields.unnamed[0].ty,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    variant,
+                    "Enum variants must have exactly one unnamed field",
+                ));
+            }
+        };
+
+        let Some(attr) = parse_variant_attr(&variant.attrs)? else {
+            // Not a request, e.g. a response sent on a stream opened by the other side.
+            let message = format!("{} is not a request", variant_name);
+            dispatch_arms.push(quote! {
+                #name::#variant_name(_) => {
+                    crate::common::rpc::respond_status::<#response, _>(
+                        send,
+                        ::core::result::Result::Err(
+                            crate::common::ErrorPayload::new(
+                                crate::common::ErrorCode::InvalidRequest,
+                                #message,
+                            )
+                            .into(),
+                        ),
+                    )
+                    .await
+                }
+            });
+            continue;
+        };
+
+        let method = &attr.method;
+        let (output, call, respond) = match &attr.response {
+            Some(response_payload) => (
+                quote! { #response_payload },
+                quote! { crate::common::rpc::call::<_, #response, #response_payload> },
+                quote! { crate::common::rpc::respond::<#response, #response_payload, _> },
+            ),
+            None => (
+                quote! { () },
+                quote! { crate::common::rpc::call_status::<_, #response> },
+                quote! { crate::common::rpc::respond_status::<#response, _> },
+            ),
+        };
+
+        client_methods.push(quote! {
+            pub async fn #method(
+                &self,
+                payload: #payload_type,
+            ) -> ::core::result::Result<#output, crate::common::rpc::RpcError> {
+                #call(&self.conn, #name::#variant_name(payload)).await
+            }
+        });
+
+        handler_methods.push(quote! {
+            async fn #method(&self, payload: #payload_type) -> ::anyhow::Result<#output>;
+        });
+
+        dispatch_arms.push(quote! {
+            #name::#variant_name(payload) => {
+                let result = self.#method(payload).await;
+                #respond(send, result).await
+            }
+        });
+    }
+
+    let client = &enum_attr.client;
+    let handler = &enum_attr.handler;
+    let client_doc = format!("Typed client sending [`{}`] requests.", name);
+    let handler_doc = format!("Handlers of [`{}`] requests.", name);
+
+    Ok(quote! {
+        #[doc = #client_doc]
+        #[derive(Clone)]
+        pub struct #client {
+            conn: ::quinn::Connection,
+        }
+
+        impl #client {
+            pub fn new(conn: ::quinn::Connection) -> Self {
+                #client { conn }
+            }
+
+            pub fn connection(&self) -> &::quinn::Connection {
+                &self.conn
+            }
+
+            #(#client_methods)*
+        }
+
+        #[doc = #handler_doc]
+        ///
+        /// Errors carrying `ErrorPayload` are sent back as they are,
+        /// any other error is sent back as `InternalServerError` and returned from `dispatch`.
+        #[::async_trait::async_trait]
+        pub trait #handler: Send + Sync {
+            #(#handler_methods)*
+
+            /// Calls the handler of the request and sends back its response.
+            async fn dispatch<W: ::tokio::io::AsyncWrite + Unpin + Send>(
+                &self,
+                send: &mut W,
+                request: #name,
+            ) -> ::anyhow::Result<()> {
+                match request {
+                    #(#dispatch_arms)*
+                }
+            }
+        }
+    })
+}
+
+fn parse_enum_attr(input: &DeriveInput) -> syn::Result<EnumAttr> {
+    let mut client = None;
+    let mut handler = None;
+    let mut response = None;
+
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("rpc"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("client") {
+                client = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("handler") {
+                handler = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("response") {
+                response = Some(meta.value()?.parse()?);
+            } else {
+                return Err(meta.error(
+                    "Unsupported rpc attribute, expected `client`, `handler` or `response`",
+                ));
+            }
+            Ok(())
+        })?;
+    }
+
+    let missing = |what: &str| {
+        syn::Error::new_spanned(
+            &input.ident,
+            format!(
+                "Missing `{}` in the `#[rpc(...)]` attribute of the enum",
+                what
+            ),
+        )
+    };
+
+    Ok(EnumAttr {
+        client: client.ok_or_else(|| missing("client"))?,
+        handler: handler.ok_or_else(|| missing("handler"))?,
+        response: response.ok_or_else(|| missing("response"))?,
+    })
+}
+
+fn parse_variant_attr(attrs: &[Attribute]) -> syn::Result<Option<VariantAttr>> {
+    let Some(attr) = attrs.iter().find(|attr| attr.path().is_ident("rpc")) else {
+        return Ok(None);
+    };
+
+    let mut method = None;
+    let mut response = None;
+    attr.parse_nested_meta(|meta| {
+        if meta.path.is_ident("method") {
+            method = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("response") {
+            response = Some(meta.value()?.parse()?);
+        } else {
+            return Err(meta.error("Unsupported rpc attribute, expected `method` or `response`"));
+        }
+        Ok(())
+    })?;
+
+    let method = method.ok_or_else(|| {
+        syn::Error::new_spanned(attr, "Missing `method` in the `#[rpc(...)]` attribute")
+    })?;
+
+    Ok(Some(VariantAttr { method, response }))
+}